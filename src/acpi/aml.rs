@@ -0,0 +1,870 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI Machine Language encoding.
+//!
+//! Every AML object implements [Aml](trait.Aml.html), which returns its
+//! bytecode. The objects are encoded when they are built, so a tree of
+//! objects is built from the leaves up, the children being given by
+//! reference to their parent:
+//!
+//! ```
+//! # extern crate vm_device;
+//! use vm_device::acpi::aml::{Aml, Device, Name, Path};
+//!
+//! let adr = Name::new("_ADR".into(), &0x0001_0000u32);
+//! let slot = Device::new("S01".into(), vec![&adr]);
+//! assert_eq!(
+//!     slot.to_aml_bytes(),
+//!     vec![
+//!         0x5b, 0x82, 0x0f, b'S', b'0', b'1', b'_', 0x08, b'_', b'A', b'D', b'R', 0x0c, 0x00,
+//!         0x00, 0x01, 0x00,
+//!     ]
+//! );
+//! ```
+
+/// Object encoded in AML.
+pub trait Aml {
+    /// AML bytecode of the object.
+    fn to_aml_bytes(&self) -> Vec<u8>;
+}
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ONES_OP: u8 = 0xff;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const METHOD_OP: u8 = 0x14;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const ARG0_OP: u8 = 0x68;
+const STORE_OP: u8 = 0x70;
+const ADD_OP: u8 = 0x72;
+const SHIFT_LEFT_OP: u8 = 0x79;
+const AND_OP: u8 = 0x7b;
+const OR_OP: u8 = 0x7d;
+const NOTIFY_OP: u8 = 0x86;
+const LEQUAL_OP: u8 = 0x93;
+const IF_OP: u8 = 0xa0;
+const RETURN_OP: u8 = 0xa4;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+
+// Resource descriptor tags.
+const IO_PORT_DESC: u8 = 0x47;
+const END_TAG_DESC: u8 = 0x79;
+const MEMORY32_FIXED_DESC: u8 = 0x86;
+const WORD_ADDRESS_SPACE_DESC: u8 = 0x88;
+const EXTENDED_IRQ_DESC: u8 = 0x89;
+const QWORD_ADDRESS_SPACE_DESC: u8 = 0x8a;
+
+/// Integer constant 0, also standing for no target of an operator.
+pub const ZERO: Zero = Zero {};
+/// Integer constant 1.
+pub const ONE: One = One {};
+/// Integer constant with all bits set.
+pub const ONES: Ones = Ones {};
+
+/// `Zero` constant.
+pub struct Zero {}
+
+impl Aml for Zero {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        vec![ZERO_OP]
+    }
+}
+
+/// `One` constant.
+pub struct One {}
+
+impl Aml for One {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        vec![ONE_OP]
+    }
+}
+
+/// `Ones` constant.
+pub struct Ones {}
+
+impl Aml for Ones {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        vec![ONES_OP]
+    }
+}
+
+// Integers use the shortest encoding holding their value.
+fn integer_bytes(value: u64) -> Vec<u8> {
+    let (prefix, len) = match value {
+        0 => return vec![ZERO_OP],
+        1 => return vec![ONE_OP],
+        v if v <= u64::from(u8::MAX) => (BYTE_PREFIX, 1),
+        v if v <= u64::from(u16::MAX) => (WORD_PREFIX, 2),
+        v if v <= u64::from(u32::MAX) => (DWORD_PREFIX, 4),
+        _ => (QWORD_PREFIX, 8),
+    };
+    let mut bytes = vec![prefix];
+    bytes.extend_from_slice(&value.to_le_bytes()[..len]);
+    bytes
+}
+
+impl Aml for u8 {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        integer_bytes(u64::from(*self))
+    }
+}
+
+impl Aml for u16 {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        integer_bytes(u64::from(*self))
+    }
+}
+
+impl Aml for u32 {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        integer_bytes(u64::from(*self))
+    }
+}
+
+impl Aml for u64 {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        integer_bytes(*self)
+    }
+}
+
+impl Aml for usize {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        integer_bytes(*self as u64)
+    }
+}
+
+/// ASCII string constant.
+impl Aml for &str {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![STRING_PREFIX];
+        bytes.extend_from_slice(self.as_bytes());
+        bytes.push(0);
+        bytes
+    }
+}
+
+// Package length of `len` bytes of data, the encoding counting itself when
+// `include_self` is set.
+fn pkg_length(len: usize, include_self: bool) -> Vec<u8> {
+    let extra = |bytes: usize| if include_self { bytes } else { 0 };
+    let (len, bytes) = if len + extra(1) < 0x40 {
+        return vec![(len + extra(1)) as u8];
+    } else if len + extra(2) < 0x1000 {
+        (len + extra(2), 2)
+    } else if len + extra(3) < 0x10_0000 {
+        (len + extra(3), 3)
+    } else {
+        (len + extra(4), 4)
+    };
+    let mut encoded = vec![((bytes - 1) << 6 | (len & 0xf)) as u8];
+    for idx in 1..bytes {
+        encoded.push((len >> (4 + (idx - 1) * 8)) as u8);
+    }
+    encoded
+}
+
+// Package of `data` following the `op` bytes.
+fn package(op: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut bytes = op.to_vec();
+    bytes.extend_from_slice(&pkg_length(data.len(), true));
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+fn term_list(children: &[&dyn Aml]) -> Vec<u8> {
+    children.iter().flat_map(|c| c.to_aml_bytes()).collect()
+}
+
+/// `EisaId` compressed hardware id.
+pub struct EisaName {
+    value: u32,
+}
+
+impl EisaName {
+    /// Return true if `name` is an EISA id: three uppercase letters followed
+    /// by four hexadecimal digits, such as `PNP0501`.
+    pub fn is_valid(name: &str) -> bool {
+        let bytes = name.as_bytes();
+        bytes.len() == 7
+            && bytes[..3].iter().all(u8::is_ascii_uppercase)
+            && bytes[3..]
+                .iter()
+                .all(|c| c.is_ascii_digit() || (b'A'..=b'F').contains(c))
+    }
+
+    /// Compress `name`. Panics if it isn't a valid EISA id.
+    pub fn new(name: &str) -> Self {
+        assert!(Self::is_valid(name), "invalid EISA id {}", name);
+        let bytes = name.as_bytes();
+        // Letters are encoded on 5 bits from 'A' as 1, big endian like the
+        // product number.
+        let letter = |idx: usize| u32::from(bytes[idx] - b'@');
+        let product = u32::from_str_radix(&name[3..], 16).unwrap_or(0);
+        let value = letter(0) << 26 | letter(1) << 21 | letter(2) << 16 | product;
+        EisaName {
+            value: value.swap_bytes(),
+        }
+    }
+}
+
+impl Aml for EisaName {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![DWORD_PREFIX];
+        bytes.extend_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+}
+
+/// Name path of an object, such as `\_SB_.PCI0` or `^PCNT`.
+///
+/// Names shorter than 4 characters are padded with underscores.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    root: bool,
+    parents: usize,
+    segments: Vec<[u8; 4]>,
+}
+
+impl Path {
+    /// Parse `name`. Panics if a name segment isn't made of 1 to 4
+    /// uppercase letters, digits or underscores, starting with a letter or
+    /// an underscore.
+    pub fn new(name: &str) -> Self {
+        let root = name.starts_with('\\');
+        let name = name.trim_start_matches('\\');
+        let parents = name.len() - name.trim_start_matches('^').len();
+        let name = name.trim_start_matches('^');
+        let segments = if name.is_empty() {
+            Vec::new()
+        } else {
+            name.split('.').map(Self::segment).collect()
+        };
+        Path {
+            root,
+            parents,
+            segments,
+        }
+    }
+
+    fn segment(name: &str) -> [u8; 4] {
+        let bytes = name.as_bytes();
+        let valid = |c: &u8| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == b'_';
+        assert!(
+            !bytes.is_empty()
+                && bytes.len() <= 4
+                && !bytes[0].is_ascii_digit()
+                && bytes.iter().all(valid),
+            "invalid AML name segment {}",
+            name
+        );
+        let mut segment = [b'_'; 4];
+        segment[..bytes.len()].copy_from_slice(bytes);
+        segment
+    }
+}
+
+impl<'a> From<&'a str> for Path {
+    fn from(name: &'a str) -> Self {
+        Path::new(name)
+    }
+}
+
+impl Aml for Path {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if self.root {
+            bytes.push(ROOT_CHAR);
+        }
+        bytes.resize(bytes.len() + self.parents, PARENT_PREFIX_CHAR);
+        match self.segments.len() {
+            0 => bytes.push(ZERO_OP),
+            1 => {}
+            2 => bytes.push(DUAL_NAME_PREFIX),
+            n => bytes.extend_from_slice(&[MULTI_NAME_PREFIX, n as u8]),
+        }
+        for segment in self.segments.iter() {
+            bytes.extend_from_slice(segment);
+        }
+        bytes
+    }
+}
+
+/// `Name` object, naming a constant.
+pub struct Name {
+    bytes: Vec<u8>,
+}
+
+impl Name {
+    /// Name `value` as `path`.
+    pub fn new(path: Path, value: &dyn Aml) -> Self {
+        let mut bytes = vec![NAME_OP];
+        bytes.extend(path.to_aml_bytes());
+        bytes.extend(value.to_aml_bytes());
+        Name { bytes }
+    }
+}
+
+impl Aml for Name {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `Scope` object, holding objects under an existing name.
+pub struct Scope {
+    bytes: Vec<u8>,
+}
+
+impl Scope {
+    /// Scope of `path` holding `children`.
+    pub fn new(path: Path, children: Vec<&dyn Aml>) -> Self {
+        let mut data = path.to_aml_bytes();
+        data.extend(term_list(&children));
+        Scope {
+            bytes: package(&[SCOPE_OP], &data),
+        }
+    }
+}
+
+impl Aml for Scope {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `Device` object.
+pub struct Device {
+    bytes: Vec<u8>,
+}
+
+impl Device {
+    /// Device `path` holding `children`.
+    pub fn new(path: Path, children: Vec<&dyn Aml>) -> Self {
+        let mut data = path.to_aml_bytes();
+        data.extend(term_list(&children));
+        Device {
+            bytes: package(&[EXT_OP_PREFIX, DEVICE_OP], &data),
+        }
+    }
+}
+
+impl Aml for Device {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `Method` object.
+pub struct Method {
+    bytes: Vec<u8>,
+}
+
+impl Method {
+    /// Method `path` taking `args` arguments, up to 7, whose body is
+    /// `children`.
+    pub fn new(path: Path, args: u8, serialized: bool, children: Vec<&dyn Aml>) -> Self {
+        let mut data = path.to_aml_bytes();
+        data.push((args & 0x7) | (serialized as u8) << 3);
+        data.extend(term_list(&children));
+        Method {
+            bytes: package(&[METHOD_OP], &data),
+        }
+    }
+}
+
+impl Aml for Method {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Invocation of a method.
+pub struct MethodCall {
+    bytes: Vec<u8>,
+}
+
+impl MethodCall {
+    /// Call the method `path` with `args`.
+    pub fn new(path: Path, args: Vec<&dyn Aml>) -> Self {
+        let mut bytes = path.to_aml_bytes();
+        bytes.extend(term_list(&args));
+        MethodCall { bytes }
+    }
+}
+
+impl Aml for MethodCall {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Address space of an operation region.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OpRegionSpace {
+    /// System memory.
+    SystemMemory = 0,
+    /// System I/O ports.
+    SystemIo = 1,
+    /// PCI configuration space.
+    PciConfig = 2,
+}
+
+/// `OperationRegion` object.
+pub struct OpRegion {
+    bytes: Vec<u8>,
+}
+
+impl OpRegion {
+    /// Region `path` of `length` bytes at `offset` of `space`.
+    pub fn new(path: Path, space: OpRegionSpace, offset: u64, length: u64) -> Self {
+        let mut bytes = vec![EXT_OP_PREFIX, OP_REGION_OP];
+        bytes.extend(path.to_aml_bytes());
+        bytes.push(space as u8);
+        bytes.extend(offset.to_aml_bytes());
+        bytes.extend(length.to_aml_bytes());
+        OpRegion { bytes }
+    }
+}
+
+impl Aml for OpRegion {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Access width of a field.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FieldAccess {
+    /// Any width.
+    Any = 0,
+    /// Byte accesses.
+    Byte = 1,
+    /// Word accesses.
+    Word = 2,
+    /// Double word accesses.
+    DWord = 3,
+    /// Quad word accesses.
+    QWord = 4,
+}
+
+/// Handling of the bits of an access not covered by a field.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FieldUpdate {
+    /// The bits are preserved.
+    Preserve = 0,
+    /// The bits are written as ones.
+    WriteAsOnes = 1,
+    /// The bits are written as zeros.
+    WriteAsZeros = 2,
+}
+
+/// Entry of a field list.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FieldEntry<'a> {
+    /// Field named by a name segment, with its size in bits.
+    Named(&'a str, usize),
+    /// Bits skipped.
+    Reserved(usize),
+}
+
+/// `Field` object, splitting an operation region into named fields.
+pub struct Field {
+    bytes: Vec<u8>,
+}
+
+impl Field {
+    /// Fields of the operation region `path`.
+    pub fn new(
+        path: Path,
+        access: FieldAccess,
+        update: FieldUpdate,
+        entries: Vec<FieldEntry>,
+    ) -> Self {
+        let mut data = path.to_aml_bytes();
+        data.push(access as u8 | (update as u8) << 5);
+        for entry in entries {
+            match entry {
+                FieldEntry::Named(name, bits) => {
+                    data.extend_from_slice(&Path::segment(name));
+                    data.extend(pkg_length(bits, false));
+                }
+                FieldEntry::Reserved(bits) => {
+                    data.push(0);
+                    data.extend(pkg_length(bits, false));
+                }
+            }
+        }
+        Field {
+            bytes: package(&[EXT_OP_PREFIX, FIELD_OP], &data),
+        }
+    }
+}
+
+impl Aml for Field {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `ResourceTemplate`, the buffer of resource descriptors returned by
+/// `_CRS`.
+pub struct ResourceTemplate {
+    bytes: Vec<u8>,
+}
+
+impl ResourceTemplate {
+    /// Template of `descriptors`, closed by an end tag.
+    pub fn new(descriptors: Vec<&dyn Aml>) -> Self {
+        let mut data = term_list(&descriptors);
+        // A null checksum is always accepted.
+        data.extend_from_slice(&[END_TAG_DESC, 0]);
+        let mut buffer = data.len().to_aml_bytes();
+        buffer.extend(data);
+        ResourceTemplate {
+            bytes: package(&[BUFFER_OP], &buffer),
+        }
+    }
+}
+
+impl Aml for ResourceTemplate {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `IO` descriptor of ports decoded on 16 bits.
+pub struct Io {
+    bytes: Vec<u8>,
+}
+
+impl Io {
+    /// Range of `length` ports starting at an `alignment` multiple between
+    /// `min` and `max`.
+    pub fn new(min: u16, max: u16, alignment: u8, length: u8) -> Self {
+        let mut bytes = vec![IO_PORT_DESC, 0x01];
+        bytes.extend_from_slice(&min.to_le_bytes());
+        bytes.extend_from_slice(&max.to_le_bytes());
+        bytes.extend_from_slice(&[alignment, length]);
+        Io { bytes }
+    }
+}
+
+impl Aml for Io {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `Memory32Fixed` descriptor.
+pub struct Memory32Fixed {
+    bytes: Vec<u8>,
+}
+
+impl Memory32Fixed {
+    /// Range of `length` bytes at `base`.
+    pub fn new(read_write: bool, base: u32, length: u32) -> Self {
+        let mut bytes = vec![MEMORY32_FIXED_DESC, 0x09, 0x00, read_write as u8];
+        bytes.extend_from_slice(&base.to_le_bytes());
+        bytes.extend_from_slice(&length.to_le_bytes());
+        Memory32Fixed { bytes }
+    }
+}
+
+impl Aml for Memory32Fixed {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Word or QWord address space descriptor of a range consumed by the
+/// device, at a fixed position.
+pub struct AddressSpace {
+    bytes: Vec<u8>,
+}
+
+impl AddressSpace {
+    // Descriptor of `resource_type` whose `fields` are the granularity,
+    // minimum, maximum, translation offset and length.
+    fn new(desc: u8, resource_type: u8, type_flags: u8, fields: &[u8]) -> Self {
+        let mut bytes = vec![desc];
+        bytes.extend_from_slice(&(fields.len() as u16 + 3).to_le_bytes());
+        // Consumed, positive decode, fixed minimum and maximum.
+        bytes.extend_from_slice(&[resource_type, 0x0d, type_flags]);
+        bytes.extend_from_slice(fields);
+        AddressSpace { bytes }
+    }
+
+    /// `WordIO` descriptor of the `min` to `max` ports, decoding the entire
+    /// range.
+    pub fn word_io(min: u16, max: u16) -> Self {
+        let mut fields = Vec::new();
+        for value in &[0, min, max, 0, max - min + 1] {
+            fields.extend_from_slice(&value.to_le_bytes());
+        }
+        AddressSpace::new(WORD_ADDRESS_SPACE_DESC, 1, 0x03, &fields)
+    }
+
+    /// `QWordMemory` descriptor of the `min` to `max` read-write,
+    /// non-cacheable memory.
+    pub fn qword_memory(min: u64, max: u64) -> Self {
+        let mut fields = Vec::new();
+        for value in &[0, min, max, 0, max - min + 1] {
+            fields.extend_from_slice(&value.to_le_bytes());
+        }
+        AddressSpace::new(QWORD_ADDRESS_SPACE_DESC, 0, 0x01, &fields)
+    }
+}
+
+impl Aml for AddressSpace {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Extended `Interrupt` descriptor of an interrupt consumed by the device.
+pub struct Interrupt {
+    bytes: Vec<u8>,
+}
+
+impl Interrupt {
+    /// Interrupt `irq`, level-triggered unless `edge` and active high unless
+    /// `active_low`.
+    pub fn new(edge: bool, active_low: bool, shared: bool, irq: u32) -> Self {
+        let flags = 0x01 | (edge as u8) << 1 | (active_low as u8) << 2 | (shared as u8) << 3;
+        let mut bytes = vec![EXTENDED_IRQ_DESC, 0x06, 0x00, flags, 0x01];
+        bytes.extend_from_slice(&irq.to_le_bytes());
+        Interrupt { bytes }
+    }
+}
+
+impl Aml for Interrupt {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Method argument, `Arg0` to `Arg6`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Arg(pub u8);
+
+impl Aml for Arg {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        assert!(self.0 <= 6, "invalid AML argument {}", self.0);
+        vec![ARG0_OP + self.0]
+    }
+}
+
+/// Method local variable, `Local0` to `Local7`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Local(pub u8);
+
+impl Aml for Local {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        assert!(self.0 <= 7, "invalid AML local {}", self.0);
+        vec![LOCAL0_OP + self.0]
+    }
+}
+
+/// Operator encoded as its opcode followed by its operands.
+pub struct Operator {
+    bytes: Vec<u8>,
+}
+
+impl Operator {
+    fn new(op: u8, operands: &[&dyn Aml]) -> Self {
+        let mut bytes = vec![op];
+        bytes.extend(term_list(operands));
+        Operator { bytes }
+    }
+}
+
+impl Aml for Operator {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// `Store(value, target)`.
+pub fn store(target: &dyn Aml, value: &dyn Aml) -> Operator {
+    Operator::new(STORE_OP, &[value, target])
+}
+
+/// `Add(a, b, target)`, `target` being `ZERO` when the result is only
+/// returned.
+pub fn add(target: &dyn Aml, a: &dyn Aml, b: &dyn Aml) -> Operator {
+    Operator::new(ADD_OP, &[a, b, target])
+}
+
+/// `And(a, b, target)`.
+pub fn and(target: &dyn Aml, a: &dyn Aml, b: &dyn Aml) -> Operator {
+    Operator::new(AND_OP, &[a, b, target])
+}
+
+/// `Or(a, b, target)`.
+pub fn or(target: &dyn Aml, a: &dyn Aml, b: &dyn Aml) -> Operator {
+    Operator::new(OR_OP, &[a, b, target])
+}
+
+/// `ShiftLeft(value, count, target)`.
+pub fn shift_left(target: &dyn Aml, value: &dyn Aml, count: &dyn Aml) -> Operator {
+    Operator::new(SHIFT_LEFT_OP, &[value, count, target])
+}
+
+/// `LEqual(a, b)`.
+pub fn equal(a: &dyn Aml, b: &dyn Aml) -> Operator {
+    Operator::new(LEQUAL_OP, &[a, b])
+}
+
+/// `Notify(object, value)`.
+pub fn notify(object: &dyn Aml, value: &dyn Aml) -> Operator {
+    Operator::new(NOTIFY_OP, &[object, value])
+}
+
+/// `Return(value)`.
+pub fn ret(value: &dyn Aml) -> Operator {
+    Operator::new(RETURN_OP, &[value])
+}
+
+/// `If` block.
+pub struct If {
+    bytes: Vec<u8>,
+}
+
+impl If {
+    /// Run `children` when `predicate` is not zero.
+    pub fn new(predicate: &dyn Aml, children: Vec<&dyn Aml>) -> Self {
+        let mut data = predicate.to_aml_bytes();
+        data.extend(term_list(&children));
+        If {
+            bytes: package(&[IF_OP], &data),
+        }
+    }
+}
+
+impl Aml for If {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aml_encoding() {
+        assert_eq!(
+            Path::new("\\_SB_.PCI0.PCNT").to_aml_bytes(),
+            b"\\\x2f\x03_SB_PCI0PCNT".to_vec()
+        );
+        assert_eq!(
+            Path::new("^PCI0.S1").to_aml_bytes(),
+            b"^\x2ePCI0S1__".to_vec()
+        );
+        assert_eq!(0x10u8.to_aml_bytes(), vec![0x0a, 0x10]);
+        assert_eq!(0xae00u64.to_aml_bytes(), vec![0x0b, 0x00, 0xae]);
+        assert_eq!(
+            0x1_0000_0000u64.to_aml_bytes(),
+            vec![0x0e, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!("PNP0A03".to_aml_bytes(), b"\x0dPNP0A03\x00".to_vec());
+        assert_eq!(pkg_length(0x3e, true), vec![0x3f]);
+        assert_eq!(pkg_length(0x3f, true), vec![0x41, 0x04]);
+        assert_eq!(pkg_length(0xfff, true), vec![0x82, 0x00, 0x01]);
+
+        // OperationRegion (PCST, SystemIO, 0xAE00, 0x10)
+        assert_eq!(
+            OpRegion::new("PCST".into(), OpRegionSpace::SystemIo, 0xae00, 0x10).to_aml_bytes(),
+            vec![0x5b, 0x80, b'P', b'C', b'S', b'T', 0x01, 0x0b, 0x00, 0xae, 0x0a, 0x10]
+        );
+        // Field (PCST, DWordAcc, NoLock, WriteAsZeros) { PCIU, 32, , 32, B0EJ, 32 }
+        assert_eq!(
+            Field::new(
+                "PCST".into(),
+                FieldAccess::DWord,
+                FieldUpdate::WriteAsZeros,
+                vec![
+                    FieldEntry::Named("PCIU", 32),
+                    FieldEntry::Reserved(32),
+                    FieldEntry::Named("B0EJ", 32),
+                ]
+            )
+            .to_aml_bytes(),
+            b"\x5b\x81\x12PCST\x43PCIU\x20\x00\x20B0EJ\x20".to_vec()
+        );
+        // Method (PCEJ, 1, NotSerialized) { Store (ShiftLeft (One, Arg0), B0EJ) }
+        let path = Path::new("B0EJ");
+        let shift = shift_left(&ZERO, &ONE, &Arg(0));
+        let body = store(&path, &shift);
+        assert_eq!(
+            Method::new("PCEJ".into(), 1, false, vec![&body]).to_aml_bytes(),
+            b"\x14\x0fPCEJ\x01\x70\x79\x01\x68\x00B0EJ".to_vec()
+        );
+        // If (And (Local0, 0x02)) { Notify (S01, One) }
+        let and = and(&ZERO, &Local(0), &2u8);
+        let slot = Path::new("S01");
+        let notify = notify(&slot, &ONE);
+        assert_eq!(
+            If::new(&and, vec![&notify]).to_aml_bytes(),
+            b"\xa0\x0c\x7b\x60\x0a\x02\x00\x86S01_\x01".to_vec()
+        );
+
+        // Name (_HID, EisaId ("PNP0501"))
+        assert!(!EisaName::is_valid("LNRO0005"));
+        assert_eq!(
+            Name::new("_HID".into(), &EisaName::new("PNP0501")).to_aml_bytes(),
+            b"\x08_HID\x0c\x41\xd0\x05\x01".to_vec()
+        );
+        // ResourceTemplate () {
+        //     IO (Decode16, 0x03F8, 0x03F8, 0x01, 0x08)
+        //     Memory32Fixed (ReadWrite, 0xD0000000, 0x00001000)
+        //     Interrupt (ResourceConsumer, Level, ActiveLow, Shared) { 0x10 }
+        // }
+        let io = Io::new(0x3f8, 0x3f8, 1, 8);
+        let memory = Memory32Fixed::new(true, 0xd000_0000, 0x1000);
+        let irq = Interrupt::new(false, true, true, 0x10);
+        assert_eq!(
+            ResourceTemplate::new(vec![&io, &memory, &irq]).to_aml_bytes(),
+            vec![
+                0x11, 0x22, 0x0a, 0x1f, 0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08, 0x86, 0x09,
+                0x00, 0x01, 0x00, 0x00, 0x00, 0xd0, 0x00, 0x10, 0x00, 0x00, 0x89, 0x06, 0x00, 0x0d,
+                0x01, 0x10, 0x00, 0x00, 0x00, 0x79, 0x00,
+            ]
+        );
+        // WordIO (ResourceConsumer, MinFixed, MaxFixed, PosDecode, EntireRange,
+        //     0x0000, 0x0CF8, 0x0CFF, 0x0000, 0x0008)
+        assert_eq!(
+            AddressSpace::word_io(0xcf8, 0xcff).to_aml_bytes(),
+            vec![
+                0x88, 0x0d, 0x00, 0x01, 0x0d, 0x03, 0x00, 0x00, 0xf8, 0x0c, 0xff, 0x0c, 0x00, 0x00,
+                0x08, 0x00,
+            ]
+        );
+        // QWordMemory (ResourceConsumer, PosDecode, MinFixed, MaxFixed,
+        //     NonCacheable, ReadWrite, 0x0, 0x100000000, 0x100000FFF, 0x0,
+        //     0x1000)
+        let mut qword = vec![0x8a, 0x2b, 0x00, 0x00, 0x0d, 0x01];
+        for value in &[0u64, 0x1_0000_0000, 0x1_0000_0fff, 0, 0x1000] {
+            qword.extend_from_slice(&value.to_le_bytes());
+        }
+        assert_eq!(
+            AddressSpace::qword_memory(0x1_0000_0000, 0x1_0000_0fff).to_aml_bytes(),
+            qword
+        );
+    }
+}
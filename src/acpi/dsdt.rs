@@ -0,0 +1,201 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! DSDT objects describing the registered devices.
+
+use super::aml::{
+    self, AddressSpace, Aml, EisaName, Interrupt, Io, Memory32Fixed, Name, ResourceTemplate,
+};
+use crate::device::{IoResource, IoType, IrqPolarity, IrqResource, IrqTrigger};
+use crate::device_manager::{enabled_resources, DeviceManager};
+
+// Resource descriptor of an allocated IO resource.
+fn io_descriptor(res: &IoResource) -> Option<Box<dyn Aml>> {
+    let addr = res.addr?.0;
+    let last = addr.checked_add(res.size.checked_sub(1)?)?;
+    let descriptor: Box<dyn Aml> = match res.res_type {
+        IoType::Pio if last > u64::from(u16::MAX) => return None,
+        IoType::Pio if res.size <= u64::from(u8::MAX) => {
+            Box::new(Io::new(addr as u16, addr as u16, 1, res.size as u8))
+        }
+        IoType::Pio => Box::new(AddressSpace::word_io(addr as u16, last as u16)),
+        _ if last <= u64::from(u32::MAX) => {
+            Box::new(Memory32Fixed::new(true, addr as u32, res.size as u32))
+        }
+        _ => Box::new(AddressSpace::qword_memory(addr, last)),
+    };
+    Some(descriptor)
+}
+
+// Resource descriptor of an allocated legacy IRQ.
+fn irq_descriptor(irq: &IrqResource) -> Option<Interrupt> {
+    irq.irq.map(|number| {
+        Interrupt::new(
+            irq.trigger == IrqTrigger::Edge,
+            irq.polarity == IrqPolarity::ActiveLow,
+            irq.shared,
+            number,
+        )
+    })
+}
+
+// Name of the object describing the device at `index`, from D000 to DFFF,
+// then E000 and so on.
+fn device_name(index: usize) -> String {
+    format!(
+        "{}{:03X}",
+        (b'D' + (index >> 12) as u8) as char,
+        index & 0xfff
+    )
+}
+
+impl DeviceManager {
+    /// Describe the registered devices having an ACPI hardware id as DSDT
+    /// `Device` objects, in topology order, e.g. to be put in the `\_SB_`
+    /// scope.
+    ///
+    /// The objects are named `D000`, `D001` and so on. Each holds the `_HID`
+    /// of the device, its instance id as `_UID` and a `_CRS` listing the IO
+    /// resources it currently decodes, followed by its legacy IRQs. Memory
+    /// ranges below 4 GiB are described by `Memory32Fixed` descriptors and
+    /// the others by `QWordMemory` ones, ports by `IO` descriptors, or
+    /// `WordIO` ones past 255 ports.
+    pub fn aml_devices(&self) -> Vec<aml::Device> {
+        let mut devices = Vec::new();
+        for id in self.topology_order() {
+            let descriptor = match self.get_descriptor(id) {
+                Some(descriptor) => descriptor,
+                None => continue,
+            };
+            let hid = match descriptor.device.acpi_hid() {
+                Some(hid) => hid,
+                None => continue,
+            };
+            let hid = if EisaName::is_valid(&hid) {
+                Name::new("_HID".into(), &EisaName::new(&hid))
+            } else {
+                Name::new("_HID".into(), &hid.as_str())
+            };
+            let uid = Name::new("_UID".into(), &id);
+
+            let resources: Vec<Box<dyn Aml>> = enabled_resources(descriptor)
+                .iter()
+                .filter_map(io_descriptor)
+                .collect();
+            let irqs: Vec<Interrupt> = descriptor.irqs.iter().filter_map(irq_descriptor).collect();
+            let mut entries: Vec<&dyn Aml> = resources.iter().map(|r| r.as_ref()).collect();
+            entries.extend(irqs.iter().map(|irq| irq as &dyn Aml));
+            let crs = Name::new("_CRS".into(), &ResourceTemplate::new(entries));
+
+            let name = device_name(devices.len());
+            devices.push(aml::Device::new(
+                name.as_str().into(),
+                vec![&hid, &uid, &crs],
+            ));
+        }
+        devices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::device_manager::{test_system_allocator, Result};
+    use std::sync::Arc;
+    use vm_memory::GuestAddress;
+
+    struct PlatformDevice {
+        hid: Option<&'static str>,
+    }
+
+    impl Device for PlatformDevice {
+        fn name(&self) -> String {
+            "platform".to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+        fn acpi_hid(&self) -> Option<String> {
+            self.hid.map(String::from)
+        }
+    }
+
+    #[test]
+    fn test_aml_devices() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0xd000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let serial = Arc::new(PlatformDevice {
+            hid: Some("PNP0501"),
+        });
+        dev_mgr.register_device(
+            serial,
+            None,
+            &mut [IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio)],
+            &mut [IrqResource::new(Some(5))],
+            None,
+        )?;
+        // Devices without hardware id are left out.
+        let hidden = Arc::new(PlatformDevice { hid: None });
+        dev_mgr.register_device(
+            hidden,
+            None,
+            &mut [IoResource::new(None, 0x1000, IoType::Mmio)],
+            &mut [],
+            None,
+        )?;
+        let virtio = Arc::new(PlatformDevice {
+            hid: Some("LNRO0005"),
+        });
+        dev_mgr.register_device(
+            virtio,
+            None,
+            &mut [IoResource::new(
+                Some(GuestAddress(0xd000_0000)),
+                0x200,
+                IoType::Mmio,
+            )],
+            &mut [IrqResource::shared(Some(6))],
+            None,
+        )?;
+
+        let devices = dev_mgr.aml_devices();
+        assert_eq!(devices.len(), 2);
+        // Device (D000) {
+        //     Name (_HID, EisaId ("PNP0501"))
+        //     Name (_UID, One)
+        //     Name (_CRS, ResourceTemplate () {
+        //         IO (Decode16, 0x03F8, 0x03F8, 0x01, 0x08)
+        //         Interrupt (ResourceConsumer, Edge, ActiveHigh, Exclusive) { 5 }
+        //     })
+        // }
+        assert_eq!(
+            devices[0].to_aml_bytes(),
+            vec![
+                0x5b, 0x82, 0x31, b'D', b'0', b'0', b'0', 0x08, b'_', b'H', b'I', b'D', 0x0c, 0x41,
+                0xd0, 0x05, 0x01, 0x08, b'_', b'U', b'I', b'D', 0x01, 0x08, b'_', b'C', b'R', b'S',
+                0x11, 0x16, 0x0a, 0x13, 0x47, 0x01, 0xf8, 0x03, 0xf8, 0x03, 0x01, 0x08, 0x89, 0x06,
+                0x00, 0x03, 0x01, 0x05, 0x00, 0x00, 0x00, 0x79, 0x00,
+            ]
+        );
+        // Device (D001) {
+        //     Name (_HID, "LNRO0005")
+        //     Name (_UID, 0x03)
+        //     Name (_CRS, ResourceTemplate () {
+        //         Memory32Fixed (ReadWrite, 0xD0000000, 0x00000200)
+        //         Interrupt (ResourceConsumer, Level, ActiveHigh, Shared) { 6 }
+        //     })
+        // }
+        assert_eq!(
+            devices[1].to_aml_bytes(),
+            vec![
+                0x5b, 0x82, 0x3b, b'D', b'0', b'0', b'1', 0x08, b'_', b'H', b'I', b'D', 0x0d, b'L',
+                b'N', b'R', b'O', b'0', b'0', b'0', b'5', 0x00, 0x08, b'_', b'U', b'I', b'D', 0x0a,
+                0x03, 0x08, b'_', b'C', b'R', b'S', 0x11, 0x1a, 0x0a, 0x17, 0x86, 0x09, 0x00, 0x01,
+                0x00, 0x00, 0x00, 0xd0, 0x00, 0x02, 0x00, 0x00, 0x89, 0x06, 0x00, 0x09, 0x01, 0x06,
+                0x00, 0x00, 0x00, 0x79, 0x00,
+            ]
+        );
+        Ok(())
+    }
+}
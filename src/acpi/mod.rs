@@ -0,0 +1,13 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI support.
+//!
+//! The [aml](aml/index.html) module encodes the ACPI Machine Language
+//! objects VMMs put in their DSDT, such as the methods driving the
+//! `AcpiPciHotplug` controller. `DeviceManager::aml_devices()` describes the
+//! registered devices having an ACPI hardware id, along with the resources
+//! allocated to them.
+
+pub mod aml;
+mod dsdt;
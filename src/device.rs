@@ -2,9 +2,35 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 
 //! Handles routing to devices in an address space.
+use crate::interrupt::InterruptSourceGroup;
+use std::fmt::{self, Display};
+use std::result;
 use std::sync::Arc;
 use vm_memory::{GuestAddress, GuestUsize};
 
+/// Errors reported by device lifecycle callbacks.
+#[derive(Debug)]
+pub enum Error {
+    /// The operation is not valid in the current device state.
+    InvalidState,
+    /// The device failed to carry out the operation.
+    Failed(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidState => write!(f, "Operation is invalid in the current device state"),
+            Failed(reason) => write!(f, "Device operation failed: {}", reason),
+        }
+    }
+}
+
+/// Simplify the `Result` type of device lifecycle callbacks.
+pub type Result<T> = result::Result<T, Error>;
+
 /// Trait for devices with basic functions.
 #[allow(unused_variables)]
 pub trait Device: Send {
@@ -18,11 +44,45 @@ pub trait Device: Send {
     ///
     /// This will be called by DeviceManager::register_device() to set
     /// the allocated resource from the vm_allocator back to device.
-    fn set_resources(&self, res: &[IoResource], irq: Option<IrqResource>);
+    fn set_resources(&self, res: &[IoResource], irqs: &[IrqResource]);
+
+    /// Set the interrupt group delivering the device interrupts.
+    ///
+    /// This will be called by DeviceManager::register_device() right after
+    /// set_resources() when an interrupt manager is available, once for each
+    /// legacy IRQ in order and once for the MSI or MSI-X vectors.
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {}
+
+    /// Activate the device once its resources are set.
+    fn activate(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Put the device back into its power-on state.
+    fn reset(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Stop the device from processing anything until resumed.
+    fn pause(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Resume a paused device.
+    fn resume(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Shut the device down before it gets unregistered.
+    fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// ACPI hardware id of a device the guest discovers through the DSDT,
+    /// such as `PNP0501`, or None if it isn't described there.
+    fn acpi_hid(&self) -> Option<String> {
+        None
+    }
 }
 
 /// IO Resource type.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum IoType {
     /// Port I/O resource.
     Pio,
@@ -30,10 +90,16 @@ pub enum IoType {
     Mmio,
     /// Non-exit physically backed mmap IO
     PhysicalMmio,
+    /// Memory I/O resource of a 32-bit PCI BAR, naturally aligned in the PCI
+    /// MMIO window below 4 GiB.
+    PciMmio32,
+    /// Memory I/O resource of a 64-bit PCI BAR, naturally aligned in the PCI
+    /// MMIO window above 4 GiB, or below 4 GiB when there is none.
+    PciMmio64,
 }
 
 /// Device resource information.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct IoResource {
     /// Resource address.
     pub addr: Option<GuestAddress>,
@@ -41,6 +107,10 @@ pub struct IoResource {
     pub size: GuestUsize,
     /// Resource type.
     pub res_type: IoType,
+    /// Prefetchable memory, e.g. for a prefetchable PCI BAR.
+    pub prefetchable: bool,
+    /// Alignment of a PCI memory range when it isn't aligned on its size.
+    pub align: Option<GuestUsize>,
 }
 
 impl IoResource {
@@ -50,8 +120,32 @@ impl IoResource {
             addr,
             size,
             res_type,
+            prefetchable: false,
+            align: None,
         }
     }
+
+    /// Mark the resource as prefetchable memory.
+    pub fn prefetchable(self) -> Self {
+        IoResource {
+            prefetchable: true,
+            ..self
+        }
+    }
+
+    /// Align a PCI memory range on `align` bytes rather than on its size,
+    /// e.g. an aperture holding several BARs.
+    pub fn aligned(self, align: GuestUsize) -> Self {
+        IoResource {
+            align: Some(align),
+            ..self
+        }
+    }
+
+    /// Alignment of a PCI memory range, its size unless set otherwise.
+    pub fn alignment(&self) -> GuestUsize {
+        self.align.unwrap_or(self.size)
+    }
     /// Helper function to unwrap the address.
     /// Being Called when assuming the resource address should not be None,
     /// or else it should be a programming error.
@@ -60,9 +154,104 @@ impl IoResource {
     }
 }
 
+/// Trigger mode of a legacy interrupt.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IrqTrigger {
+    /// Edge-triggered interrupt.
+    Edge,
+    /// Level-triggered interrupt.
+    Level,
+}
+
+/// Polarity of a legacy interrupt.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IrqPolarity {
+    /// Asserted on a high level or a rising edge.
+    ActiveHigh,
+    /// Asserted on a low level or a falling edge.
+    ActiveLow,
+}
+
+/// Interrupt controller a legacy interrupt is wired to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum IrqController {
+    /// x86 8259 PIC pair.
+    Pic,
+    /// x86 IOAPIC.
+    IoApic,
+    /// ARM GIC.
+    Gic,
+}
+
 /// Legacy interrupt resource.
-#[derive(Debug, Copy, Clone)]
-pub struct IrqResource(pub Option<u32>);
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IrqResource {
+    /// IRQ number, or None to let the allocator pick it.
+    pub irq: Option<u32>,
+    /// Level-triggered line which can be shared with other devices.
+    pub shared: bool,
+    /// Trigger mode.
+    pub trigger: IrqTrigger,
+    /// Polarity.
+    pub polarity: IrqPolarity,
+    /// Interrupt controller the IRQ is wired to.
+    pub controller: IrqController,
+}
+
+impl IrqResource {
+    /// Build a request for an edge-triggered, active high IOAPIC IRQ owned
+    /// by a single device.
+    pub fn new(irq: Option<u32>) -> Self {
+        IrqResource {
+            irq,
+            shared: false,
+            trigger: IrqTrigger::Edge,
+            polarity: IrqPolarity::ActiveHigh,
+            controller: IrqController::IoApic,
+        }
+    }
+
+    /// Build a request for a level-triggered, active high IOAPIC IRQ shared
+    /// with other devices.
+    pub fn shared(irq: Option<u32>) -> Self {
+        IrqResource {
+            shared: true,
+            trigger: IrqTrigger::Level,
+            ..IrqResource::new(irq)
+        }
+    }
+}
+
+/// Message signaled interrupt resource, a block of MSI or MSI-X vectors.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MsiResource {
+    /// GSI routing the first vector, or None to let the allocator pick it.
+    pub base: Option<u32>,
+    /// Number of vectors.
+    pub count: u32,
+    /// MSI-X vectors rather than multi-message MSI ones.
+    pub msix: bool,
+}
+
+impl MsiResource {
+    /// Build a request for `count` MSI vectors.
+    pub fn msi(count: u32) -> Self {
+        MsiResource {
+            base: None,
+            count,
+            msix: false,
+        }
+    }
+
+    /// Build a request for `count` MSI-X vectors.
+    pub fn msix(count: u32) -> Self {
+        MsiResource {
+            base: None,
+            count,
+            msix: true,
+        }
+    }
+}
 
 /// Storing Device information and for topology managing.
 pub struct DeviceDescriptor {
@@ -70,14 +259,28 @@ pub struct DeviceDescriptor {
     pub instance_id: u32,
     /// Device type name.
     pub name: String,
+    /// Unique hierarchical path of the device in the topology.
+    pub path: String,
     /// The device to descript.
     pub device: Arc<dyn Device>,
-    /// The parent bus of this device.
-    pub parent_bus: Option<Arc<dyn Device>>,
+    /// Instance id of the parent bus of this device.
+    pub parent_bus: Option<u32>,
+    /// Instance ids of the devices sitting behind this device.
+    pub children: Vec<u32>,
     /// Device resource set.
     pub resources: Vec<IoResource>,
-    /// Device IRQ resource.
-    pub irq: Option<IrqResource>,
+    /// Device resource set as allocated at registration time, restored on reset.
+    pub initial_resources: Vec<IoResource>,
+    /// Indexes of the IO resources allocated but not decoded on their bus.
+    pub disabled_resources: Vec<usize>,
+    /// Device IRQ resources.
+    pub irqs: Vec<IrqResource>,
+    /// Interrupt groups delivering the device IRQs, in the same order.
+    pub interrupt_groups: Vec<Arc<dyn InterruptSourceGroup>>,
+    /// Device MSI or MSI-X vectors.
+    pub msi: Option<MsiResource>,
+    /// Interrupt group delivering the device MSI or MSI-X vectors.
+    pub msi_group: Option<Arc<dyn InterruptSourceGroup>>,
 }
 
 impl DeviceDescriptor {
@@ -86,17 +289,24 @@ impl DeviceDescriptor {
         instance_id: u32,
         name: String,
         dev: Arc<dyn Device>,
-        parent_bus: Option<Arc<dyn Device>>,
+        parent_bus: Option<u32>,
         resources: Vec<IoResource>,
-        irq: Option<IrqResource>,
+        irqs: Vec<IrqResource>,
     ) -> Self {
         DeviceDescriptor {
+            path: format!("/{}", name),
             instance_id,
             name,
             device: dev,
             parent_bus,
+            children: Vec::new(),
+            initial_resources: resources.clone(),
+            disabled_resources: Vec::new(),
             resources,
-            irq,
+            irqs,
+            interrupt_groups: Vec::new(),
+            msi: None,
+            msi_group: None,
         }
     }
 }
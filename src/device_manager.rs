@@ -7,16 +7,45 @@
 //! of virtual machine, store basic device information like name and
 //! parent bus, register IO resources callback, unregister devices and help
 //! VM IO exit handling.
+//!
+//! Devices are kept in a topology tree keyed by instance id: each device
+//! optionally sits behind a parent bus, and a bus tracks the devices behind
+//! it so that a whole subtree can be walked or removed at once.
+//!
+//! Every registered device is also given a unique hierarchical path built
+//! from the names along its topology branch, e.g. `/sys/pci0/00:03.0/virtio-net`.
+//! Sibling devices reporting the same name are told apart with a `#N` suffix.
+//!
+//! Other subsystems can subscribe to the manager to be notified about devices
+//! coming and going, see the [event](../event/index.html) module.
 
-extern crate vm_allocator;
-
-use self::vm_allocator::{Error as AllocatorError, SystemAllocator};
-use crate::device::*;
+use crate::device::{
+    Device, DeviceDescriptor, Error as DeviceError, IoResource, IoType, IrqController, IrqResource,
+    MsiResource,
+};
+use crate::event::{DeviceEvent, DeviceEventKind, DeviceEventListener, DeviceSnapshot, Subscriber};
+use crate::interrupt::level::SharedIrqLine;
+use crate::interrupt::routing::{
+    GsiRoutingTable, Route, RoutedMsiGroup, IRQCHIP_GIC, IRQCHIP_IOAPIC, IRQCHIP_PIC_MASTER,
+    IRQCHIP_PIC_SLAVE,
+};
+use crate::interrupt::{
+    Error as InterruptError, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
+    InterruptSourceType,
+};
+use crate::pci::{
+    AcpiPciHotplug, BridgeWindows, Error as PciError, PciAddress, PciRoot, PcieRootPort,
+    VirtualFunctions,
+};
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::btree_map::BTreeMap;
 use std::collections::HashMap;
+use std::fmt::{self, Display};
 use std::result;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use vm_allocator::{Error as AllocatorError, PciWindow, SystemAllocator};
 use vm_memory::{Address, GuestAddress, GuestUsize};
 
 /// Guest physical address and size pair to describe a range.
@@ -37,7 +66,7 @@ impl Ord for Range {
 
 impl PartialOrd for Range {
     fn partial_cmp(&self, other: &Range) -> Option<Ordering> {
-        self.0.partial_cmp(&other.0)
+        Some(self.cmp(other))
     }
 }
 
@@ -50,27 +79,213 @@ pub enum Error {
     Exist,
     /// The removing fails because the device doesn't exist.
     NonExist,
+    /// The parent bus given at registration doesn't exist.
+    ParentNonExist,
+    /// The removing fails because the bus at the given path still has devices behind it.
+    HasChildren(String),
+    /// No device is registered at the given path.
+    PathNonExist(String),
     /// Io resource allocation failed at some index.
     IoResourceAllocate(usize, AllocatorError),
     /// IRQ allocated failed.
     IrqAllocate(AllocatorError),
+    /// MSI vectors allocation failed.
+    MsiAllocate(AllocatorError),
     /// Instance id allocation failed.
     InstanceIdAllocate(AllocatorError),
+    /// The device has no IO resource at the given index.
+    ResourceNonExist(usize),
+    /// The device has no IRQ resource at the given index.
+    IrqNonExist(usize),
+    /// Interrupt group creation failed.
+    InterruptGroup(InterruptError),
+    /// PCI hierarchy operation failed.
+    Pci(PciError),
+    /// Lifecycle operation failed on the devices at the given paths.
+    Lifecycle(LifecycleOp, Vec<(String, DeviceError)>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Overlap => write!(f, "The device IO range overlaps with another device"),
+            Exist => write!(f, "The device already exists"),
+            NonExist => write!(f, "The device doesn't exist"),
+            ParentNonExist => write!(f, "The parent bus doesn't exist"),
+            HasChildren(path) => write!(f, "The bus {} still has devices behind it", path),
+            PathNonExist(path) => write!(f, "No device is registered at {}", path),
+            IoResourceAllocate(idx, e) => {
+                write!(f, "IO resource {} allocation failed, err={}", idx, e)
+            }
+            ResourceNonExist(idx) => write!(f, "The device has no IO resource {}", idx),
+            IrqNonExist(idx) => write!(f, "The device has no IRQ resource {}", idx),
+            IrqAllocate(e) => write!(f, "IRQ allocation failed, err={}", e),
+            MsiAllocate(e) => write!(f, "MSI vectors allocation failed, err={}", e),
+            InstanceIdAllocate(e) => write!(f, "Instance id allocation failed, err={}", e),
+            InterruptGroup(e) => write!(f, "Interrupt group creation failed, err={}", e),
+            Pci(e) => write!(f, "PCI operation failed, err={}", e),
+            Lifecycle(op, failures) => {
+                write!(f, "{:?} failed on", op)?;
+                for (path, e) in failures.iter() {
+                    write!(f, " {} (err={})", path, e)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 /// Simplify the `Result` type.
 pub type Result<T> = result::Result<T, Error>;
 
+/// Device lifecycle operations broadcast by the `DeviceManager`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LifecycleOp {
+    /// Activate devices once their resources are set.
+    Activate,
+    /// Put devices back into their power-on state.
+    Reset,
+    /// Pause devices.
+    Pause,
+    /// Resume paused devices.
+    Resume,
+    /// Shut devices down.
+    Shutdown,
+}
+
+impl LifecycleOp {
+    // Starting operations go from parents to children, stopping ones go
+    // from children to parents.
+    fn parents_first(self) -> bool {
+        match self {
+            LifecycleOp::Activate | LifecycleOp::Resume => true,
+            LifecycleOp::Reset | LifecycleOp::Pause | LifecycleOp::Shutdown => false,
+        }
+    }
+}
+
+/// IO exit counters of one device.
+#[derive(Default)]
+struct IoCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+}
+
+/// IO exit statistics of one device, reported against its path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IoMetrics {
+    /// Device path.
+    pub path: String,
+    /// Number of read exits handled by the device.
+    pub reads: u64,
+    /// Number of write exits handled by the device.
+    pub writes: u64,
+}
+
+// Return the IO resources of a device currently decoded on their bus.
+pub(crate) fn enabled_resources(descriptor: &DeviceDescriptor) -> Vec<IoResource> {
+    descriptor
+        .resources
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !descriptor.disabled_resources.contains(idx))
+        .map(|(_, res)| *res)
+        .collect()
+}
+
+// Routes of a legacy IRQ. Like in the default KVM routing, the x86 ISA IRQs
+// wired to the IOAPIC also reach the PIC pair.
+fn legacy_routes(irq: &IrqResource) -> Vec<Route> {
+    let pin = match irq.irq {
+        Some(pin) => pin,
+        None => return Vec::new(),
+    };
+    let pic = if pin < 8 {
+        Some(Route::Irqchip {
+            irqchip: IRQCHIP_PIC_MASTER,
+            pin,
+        })
+    } else if pin < 16 {
+        Some(Route::Irqchip {
+            irqchip: IRQCHIP_PIC_SLAVE,
+            pin: pin - 8,
+        })
+    } else {
+        None
+    };
+    match irq.controller {
+        IrqController::Pic => pic.into_iter().collect(),
+        IrqController::IoApic => pic
+            .into_iter()
+            .chain(Some(Route::Irqchip {
+                irqchip: IRQCHIP_IOAPIC,
+                pin,
+            }))
+            .collect(),
+        IrqController::Gic => vec![Route::Irqchip {
+            irqchip: IRQCHIP_GIC,
+            pin,
+        }],
+    }
+}
+
+// Interrupt groups of a device delivering its legacy IRQs and its MSI vectors.
+type InterruptGroups = (
+    Vec<Arc<dyn InterruptSourceGroup>>,
+    Option<Arc<dyn InterruptSourceGroup>>,
+);
+
+/// Bus mapping entry of one registered IO range.
+#[derive(Clone)]
+struct BusEntry {
+    device: Arc<dyn Device>,
+    counters: Arc<IoCounters>,
+}
+
 /// System device manager serving for all devices management and VM exit handling.
 pub struct DeviceManager {
     /// System allocator reference.
-    resource: SystemAllocator,
+    pub(crate) resource: SystemAllocator,
     /// Devices information mapped by instance id.
     devices: HashMap<u32, DeviceDescriptor>,
+    /// Instance ids mapped by device path.
+    paths: HashMap<String, u32>,
+    /// IO exit counters mapped by instance id.
+    counters: HashMap<u32, Arc<IoCounters>>,
     /// Range mapping for VM exit mmio operations.
-    mmio_bus: BTreeMap<Range, Arc<dyn Device>>,
+    mmio_bus: BTreeMap<Range, BusEntry>,
     /// Range mapping for VM exit pio operations.
-    pio_bus: BTreeMap<Range, Arc<dyn Device>>,
+    pio_bus: BTreeMap<Range, BusEntry>,
+    /// Device events subscribers mapped by subscription id.
+    subscribers: BTreeMap<u32, Subscriber>,
+    /// Next subscription id to give out.
+    next_subscription: u32,
+    /// Factory of the interrupt groups handed to devices.
+    interrupt_manager: Option<Arc<dyn InterruptManager>>,
+    /// Level-triggered lines of the shared IRQs mapped by IRQ number.
+    shared_irq_lines: BTreeMap<u32, Arc<SharedIrqLine>>,
+    /// Routing table of the allocated GSIs.
+    gsi_routing: Arc<Mutex<GsiRoutingTable>>,
+    /// MSI groups recording the guest messages into the routing table,
+    /// mapped by instance id.
+    routed_msi_groups: HashMap<u32, Arc<RoutedMsiGroup>>,
+    /// PCI hierarchy and bus number of the PCI bus devices, mapped by
+    /// instance id.
+    pub(crate) pci_buses: HashMap<u32, (Arc<PciRoot>, u8)>,
+    /// PCI hierarchy and address of the PCI functions, mapped by instance id.
+    pub(crate) pci_functions: HashMap<u32, (Arc<PciRoot>, PciAddress)>,
+    /// Windows of the PCI bridges, mapped by instance id.
+    pub(crate) pci_bridges: HashMap<u32, BridgeWindows>,
+    /// PCIe ports with a hotplug slot, mapped by instance id.
+    pub(crate) pci_hotplug_ports: HashMap<u32, Arc<PcieRootPort>>,
+    /// ACPI PCI hotplug controllers and the root bus they manage, mapped by
+    /// instance id.
+    pub(crate) pci_acpi_hotplug: HashMap<u32, (u32, Arc<AcpiPciHotplug>)>,
+    /// Virtual functions of the SR-IOV physical functions, mapped by the
+    /// instance id of the physical function.
+    pub(crate) pci_sriov: HashMap<u32, VirtualFunctions>,
 }
 
 impl DeviceManager {
@@ -80,76 +295,272 @@ impl DeviceManager {
         DeviceManager {
             resource,
             devices: HashMap::new(),
+            paths: HashMap::new(),
+            counters: HashMap::new(),
             mmio_bus: BTreeMap::new(),
             pio_bus: BTreeMap::new(),
+            subscribers: BTreeMap::new(),
+            next_subscription: 0,
+            interrupt_manager: None,
+            shared_irq_lines: BTreeMap::new(),
+            gsi_routing: Arc::new(Mutex::new(GsiRoutingTable::new())),
+            routed_msi_groups: HashMap::new(),
+            pci_buses: HashMap::new(),
+            pci_functions: HashMap::new(),
+            pci_bridges: HashMap::new(),
+            pci_hotplug_ports: HashMap::new(),
+            pci_acpi_hotplug: HashMap::new(),
+            pci_sriov: HashMap::new(),
         }
     }
 
-    fn insert(&mut self, dev: DeviceDescriptor) -> Result<(u32)> {
-        // Insert if the key is non-present, else report error.
-        if self.devices.contains_key(&(dev.instance_id)) {
+    /// Set the interrupt manager creating the interrupt source groups.
+    ///
+    /// Once set, every device registered with an IRQ resource is given the
+    /// interrupt group delivering it through `Device::set_interrupt_group()`.
+    /// Devices sharing an IRQ are each given their own source of the shared
+    /// line, see `SharedIrqLine`.
+    pub fn set_interrupt_manager(&mut self, manager: Arc<dyn InterruptManager>) {
+        self.interrupt_manager = Some(manager);
+    }
+
+    /// Subscribe a listener called synchronously on every device event.
+    /// Return the subscription id.
+    pub fn subscribe(&mut self, listener: Arc<dyn DeviceEventListener>) -> u32 {
+        self.add_subscriber(Subscriber::Listener(listener))
+    }
+
+    /// Subscribe to device events delivered through a channel.
+    /// Return the subscription id and the receiving end of the channel.
+    ///
+    /// The subscription is dropped once the receiver goes away.
+    pub fn subscribe_channel(&mut self) -> (u32, Receiver<DeviceEvent>) {
+        let (sender, receiver) = channel();
+        (self.add_subscriber(Subscriber::Channel(sender)), receiver)
+    }
+
+    /// Cancel a subscription.
+    pub fn unsubscribe(&mut self, subscription: u32) -> Result<()> {
+        self.subscribers
+            .remove(&subscription)
+            .map(|_| ())
+            .ok_or(Error::NonExist)
+    }
+
+    fn add_subscriber(&mut self, subscriber: Subscriber) -> u32 {
+        let id = self.next_subscription;
+        self.next_subscription = self.next_subscription.wrapping_add(1);
+        self.subscribers.insert(id, subscriber);
+        id
+    }
+
+    // Notify subscribers about a change of a registered device.
+    fn notify(&mut self, kind: DeviceEventKind, instance_id: u32) {
+        if let Some(descriptor) = self.devices.get(&instance_id) {
+            let snapshot = DeviceSnapshot::from(descriptor);
+            self.notify_snapshot(kind, snapshot);
+        }
+    }
+
+    fn notify_snapshot(&mut self, kind: DeviceEventKind, descriptor: DeviceSnapshot) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let event = DeviceEvent {
+            kind,
+            instance_id: descriptor.instance_id,
+            descriptor,
+        };
+        // Drop the channels whose receiving end is gone.
+        self.subscribers.retain(|_, s| s.notify(&event));
+    }
+
+    // Check a device `instance_id` can be inserted behind `parent_bus`.
+    fn check_insert(&self, instance_id: u32, parent_bus: Option<u32>) -> Result<()> {
+        if self.devices.contains_key(&instance_id) {
             return Err(Error::Exist);
         }
+        match parent_bus {
+            Some(parent) if !self.devices.contains_key(&parent) => Err(Error::ParentNonExist),
+            _ => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, dev: DeviceDescriptor) -> Result<u32> {
+        // Insert if the key is non-present, else report error.
+        self.check_insert(dev.instance_id, dev.parent_bus)?;
         let id = dev.instance_id;
+        let parent_bus = dev.parent_bus;
 
+        self.paths.insert(dev.path.clone(), id);
         self.devices.insert(id, dev);
+        // Link the device behind its parent bus.
+        if let Some(parent) = parent_bus.and_then(|p| self.devices.get_mut(&p)) {
+            parent.children.push(id);
+        }
         Ok(id)
     }
 
     fn remove(&mut self, instance_id: u32) -> Option<DeviceDescriptor> {
-        self.devices.remove(&instance_id)
+        let descriptor = self.devices.remove(&instance_id)?;
+        self.paths.remove(&descriptor.path);
+        // Unlink the device from its parent bus.
+        if let Some(parent) = descriptor.parent_bus.and_then(|p| self.devices.get_mut(&p)) {
+            parent.children.retain(|&child| child != instance_id);
+        }
+        Some(descriptor)
     }
 
     fn device_descriptor(
         &self,
         id: u32,
         dev: Arc<dyn Device>,
-        parent_bus: Option<Arc<dyn Device>>,
+        parent_bus: Option<u32>,
         resources: Vec<IoResource>,
-        irq: Option<IrqResource>,
+        irqs: Vec<IrqResource>,
     ) -> DeviceDescriptor {
-        DeviceDescriptor::new(id, dev.name(), dev.clone(), parent_bus, resources, irq)
+        let mut descriptor =
+            DeviceDescriptor::new(id, dev.name(), dev.clone(), parent_bus, resources, irqs);
+        descriptor.path = self.device_path(parent_bus, &descriptor.name);
+        descriptor
+    }
+
+    // Build a unique path for a device named `name` behind `parent_bus`.
+    fn device_path(&self, parent_bus: Option<u32>, name: &str) -> String {
+        let parent_path = parent_bus
+            .and_then(|p| self.devices.get(&p))
+            .map_or("", |d| d.path.as_str());
+        // A '/' in a device name would break the path hierarchy.
+        let base = format!("{}/{}", parent_path, name.replace('/', "_"));
+
+        let mut path = base.clone();
+        let mut suffix = 1;
+        while self.paths.contains_key(&path) {
+            path = format!("{}#{}", base, suffix);
+            suffix += 1;
+        }
+        path
+    }
+
+    // Window of the PCI segment `res` is allocated from for a device behind
+    // the bus `owner`, when the segment of the bus has its own windows.
+    fn pci_segment_window(&self, owner: Option<u32>, res: &IoResource) -> Option<(u16, PciWindow)> {
+        let segment = self.pci_buses.get(&owner?)?.0.segment();
+        let windows = self.resource.pci_segment_windows(segment)?;
+        match res.res_type {
+            IoType::Pio if windows.io.is_some() => Some((segment, PciWindow::Io)),
+            IoType::PciMmio32 => Some((segment, PciWindow::Mmio32)),
+            IoType::PciMmio64 => Some((segment, PciWindow::Mmio64)),
+            _ => None,
+        }
+    }
+
+    // Allocate `res` for a device behind the bus `owner`, from the windows of
+    // the bus when it is a PCI bridge forwarding that type of resources, then
+    // from the windows of its PCI segment, or else from the `SystemAllocator`.
+    // The BARs of a virtual function are already carved out of the apertures
+    // of its physical function.
+    fn allocate_io_resource(
+        &mut self,
+        owner: Option<u32>,
+        res: &IoResource,
+    ) -> result::Result<GuestAddress, AllocatorError> {
+        if matches!(owner, Some(id) if self.pci_sriov.contains_key(&id)) {
+            return Ok(res.try_unwrap());
+        }
+        if let Some(ret) = owner
+            .and_then(|id| self.pci_bridges.get_mut(&id))
+            .and_then(|windows| windows.allocate(res))
+        {
+            return ret;
+        }
+        if let Some((segment, window)) = self.pci_segment_window(owner, res) {
+            return self.resource.allocate_pci_segment_addresses(
+                segment,
+                window,
+                res.addr,
+                res.size,
+                res.alignment(),
+            );
+        }
+        match res.res_type {
+            IoType::Pio => {
+                // The None PIO address resource should be a programming error.
+                let addr = res.try_unwrap();
+
+                self.resource.allocate_io_addresses(addr, res.size)
+            }
+            IoType::PhysicalMmio | IoType::Mmio => {
+                self.resource.allocate_mmio_addresses(res.addr, res.size)
+            }
+            IoType::PciMmio32 => {
+                self.resource
+                    .allocate_pci_mmio32_addresses(res.addr, res.size, res.alignment())
+            }
+            IoType::PciMmio64 => {
+                self.resource
+                    .allocate_pci_mmio64_addresses(res.addr, res.size, res.alignment())
+            }
+        }
     }
 
     // Allocate IO resources.
     // In order to transport the SystemAllocator Error, return Err with
-    // the failure allocated index, or else return Ok().
-    fn allocate_io_resources(&mut self, resources: &mut Vec<IoResource>) -> Result<()> {
+    // the failure allocated index, or else return Ok(). The resources
+    // allocated before the failing one are freed.
+    pub(crate) fn allocate_io_resources(
+        &mut self,
+        owner: Option<u32>,
+        resources: &mut [IoResource],
+    ) -> Result<()> {
+        let mut failure = None;
         for (idx, res) in resources.iter_mut().enumerate() {
-            match res.res_type {
-                IoType::Pio => {
-                    // The None PIO address resource should be a programming error.
-                    let addr = res.try_unwrap();
-
-                    res.addr = Some(
-                        self.resource
-                            .allocate_io_addresses(addr, res.size)
-                            .map_err(|e| Error::IoResourceAllocate(idx, e))?,
-                    );
-                }
-                IoType::PhysicalMmio | IoType::Mmio => {
-                    res.addr = Some(
-                        self.resource
-                            .allocate_mmio_addresses(res.addr, res.size)
-                            .map_err(|e| Error::IoResourceAllocate(idx, e))?,
-                    );
+            match self.allocate_io_resource(owner, res) {
+                Ok(addr) => res.addr = Some(addr),
+                Err(e) => {
+                    failure = Some((idx, e));
+                    break;
                 }
             }
         }
-        Ok(())
+        match failure {
+            Some((idx, e)) => {
+                self.free_io_resources(owner, &resources[..idx]);
+                Err(Error::IoResourceAllocate(idx, e))
+            }
+            None => Ok(()),
+        }
     }
 
     // Free valid `resources` which means all entries have a valid address.
-    fn free_io_resources(&mut self, resources: &[IoResource]) {
+    // They were allocated for a device behind the bus `owner`.
+    pub(crate) fn free_io_resources(&mut self, owner: Option<u32>, resources: &[IoResource]) {
         for res in resources.iter() {
             // The resources addresses being free should not be None.
             let addr = res.try_unwrap();
 
+            if matches!(owner, Some(id) if self.pci_sriov.contains_key(&id)) {
+                continue;
+            }
+            if let Some(windows) = owner.and_then(|id| self.pci_bridges.get_mut(&id)) {
+                if windows.free(res) {
+                    continue;
+                }
+            }
+            if let Some((segment, _)) = self.pci_segment_window(owner, res) {
+                self.resource
+                    .free_pci_segment_addresses(segment, addr, res.size);
+                continue;
+            }
+
             match res.res_type {
                 IoType::Pio => self.resource.free_io_addresses(addr, res.size),
                 IoType::PhysicalMmio | IoType::Mmio => {
                     self.resource.free_mmio_addresses(addr, res.size)
                 }
+                IoType::PciMmio32 | IoType::PciMmio64 => {
+                    self.resource.free_pci_mmio_addresses(addr, res.size)
+                }
             }
         }
     }
@@ -159,9 +570,14 @@ impl DeviceManager {
     // or else return resources length.
     fn register_resources(
         &mut self,
+        id: u32,
         dev: Arc<dyn Device>,
-        resources: &mut Vec<IoResource>,
+        resources: &mut [IoResource],
     ) -> usize {
+        let entry = BusEntry {
+            device: dev,
+            counters: self.counters.entry(id).or_default().clone(),
+        };
         for (idx, res) in resources.iter().enumerate() {
             // The resources addresses being registered are sucessfully allocated before.
             let addr = res.try_unwrap();
@@ -170,16 +586,16 @@ impl DeviceManager {
                 IoType::Pio => {
                     if self
                         .pio_bus
-                        .insert(Range(addr, res.size), dev.clone())
+                        .insert(Range(addr, res.size), entry.clone())
                         .is_some()
                     {
                         return idx;
                     }
                 }
-                IoType::Mmio => {
+                IoType::Mmio | IoType::PciMmio32 | IoType::PciMmio64 => {
                     if self
                         .mmio_bus
-                        .insert(Range(addr, res.size), dev.clone())
+                        .insert(Range(addr, res.size), entry.clone())
                         .is_some()
                     {
                         return idx;
@@ -199,36 +615,300 @@ impl DeviceManager {
 
             match res.res_type {
                 IoType::Pio => self.pio_bus.remove(&Range(addr, res.size)),
-                IoType::Mmio => self.mmio_bus.remove(&Range(addr, res.size)),
+                IoType::Mmio | IoType::PciMmio32 | IoType::PciMmio64 => {
+                    self.mmio_bus.remove(&Range(addr, res.size))
+                }
                 IoType::PhysicalMmio => continue,
             };
         }
     }
 
-    fn allocate_irq_resource(
+    fn allocate_irq_resource(&mut self, interrupt: IrqResource) -> Result<IrqResource> {
+        let irq_num = if interrupt.shared {
+            self.resource.allocate_shared_irq(interrupt.irq)
+        } else {
+            self.resource.allocate_irq(interrupt.irq)
+        }
+        .map_err(Error::IrqAllocate)?;
+        Ok(IrqResource {
+            irq: Some(irq_num),
+            ..interrupt
+        })
+    }
+
+    fn free_irq_resource(&mut self, interrupt: IrqResource) {
+        self.resource.free_irq(interrupt.irq);
+    }
+
+    // Allocate all the IRQ resources, or none of them.
+    fn allocate_irq_resources(&mut self, interrupts: &[IrqResource]) -> Result<Vec<IrqResource>> {
+        let mut irqs = Vec::with_capacity(interrupts.len());
+        for interrupt in interrupts.iter() {
+            match self.allocate_irq_resource(*interrupt) {
+                Ok(irq) => irqs.push(irq),
+                Err(e) => {
+                    self.free_irq_resources(&irqs);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(irqs)
+    }
+
+    fn free_irq_resources(&mut self, irqs: &[IrqResource]) {
+        for irq in irqs.iter() {
+            self.free_irq_resource(*irq);
+        }
+    }
+
+    // Create the interrupt group delivering an allocated legacy IRQ to the
+    // device `instance_id`, if an interrupt manager is set. A shared IRQ is
+    // delivered through a source of its line, created along with the first
+    // sharer.
+    fn create_legacy_group(
+        &mut self,
+        instance_id: u32,
+        interrupt: IrqResource,
+    ) -> Result<Option<Arc<dyn InterruptSourceGroup>>> {
+        let (manager, irq) = match (&self.interrupt_manager, interrupt.irq) {
+            (Some(manager), Some(irq)) => (manager.clone(), irq),
+            _ => return Ok(None),
+        };
+        if !interrupt.shared {
+            return manager
+                .create_group(InterruptSourceType::Legacy, irq, 1)
+                .map(Some)
+                .map_err(Error::InterruptGroup);
+        }
+
+        let line = match self.shared_irq_lines.get(&irq) {
+            Some(line) => line.clone(),
+            None => {
+                let group = manager
+                    .create_group(InterruptSourceType::Legacy, irq, 1)
+                    .map_err(Error::InterruptGroup)?;
+                let line = match SharedIrqLine::new(group.clone()) {
+                    Ok(line) => Arc::new(line),
+                    Err(e) => {
+                        let _ = manager.destroy_group(group);
+                        return Err(Error::InterruptGroup(e));
+                    }
+                };
+                self.shared_irq_lines.insert(irq, line.clone());
+                line
+            }
+        };
+        match line.add_sharer(instance_id) {
+            Ok(source) => Ok(Some(Arc::new(source))),
+            Err(e) => {
+                self.destroy_legacy_group(instance_id, interrupt, None);
+                Err(Error::InterruptGroup(e))
+            }
+        }
+    }
+
+    // Destroy the interrupt group delivering the legacy IRQ of the device
+    // `instance_id`. A shared line is destroyed along with its last sharer.
+    fn destroy_legacy_group(
+        &mut self,
+        instance_id: u32,
+        interrupt: IrqResource,
+        group: Option<Arc<dyn InterruptSourceGroup>>,
+    ) {
+        match interrupt.irq {
+            Some(irq) if interrupt.shared => {
+                let line = match self.shared_irq_lines.get(&irq) {
+                    Some(line) => line.clone(),
+                    None => return,
+                };
+                line.remove_sharer(instance_id);
+                if line.sharers() == 0 {
+                    self.shared_irq_lines.remove(&irq);
+                    self.destroy_interrupt_group(Some(line.group().clone()));
+                }
+            }
+            _ => self.destroy_interrupt_group(group),
+        }
+    }
+
+    // Destroy the interrupt groups delivering the legacy IRQs `irqs`, `groups`
+    // being in the same order.
+    fn destroy_legacy_groups(
         &mut self,
-        interrupt: Option<IrqResource>,
-    ) -> Result<Option<IrqResource>> {
-        match interrupt {
-            Some(IrqResource(irq)) => {
-                // Allocate irq resource
-                let irq_num = self
-                    .resource
-                    .allocate_irq(irq)
-                    .map_err(Error::IrqAllocate)?;
-                Ok(Some(IrqResource(Some(irq_num))))
+        instance_id: u32,
+        irqs: &[IrqResource],
+        groups: &[Arc<dyn InterruptSourceGroup>],
+    ) {
+        for (irq, group) in irqs.iter().zip(groups.iter()) {
+            self.destroy_legacy_group(instance_id, *irq, Some(group.clone()));
+        }
+    }
+
+    fn destroy_interrupt_group(&self, group: Option<Arc<dyn InterruptSourceGroup>>) {
+        if let (Some(manager), Some(group)) = (&self.interrupt_manager, group) {
+            // The group has been created by this manager, it is known to it.
+            let _ = manager.destroy_group(group);
+        }
+    }
+
+    fn allocate_msi_resource(&mut self, msi: Option<MsiResource>) -> Result<Option<MsiResource>> {
+        match msi {
+            Some(msi) => {
+                let base = if msi.msix {
+                    self.resource.allocate_msix(msi.base, msi.count)
+                } else {
+                    self.resource.allocate_msi(msi.base, msi.count)
+                }
+                .map_err(Error::MsiAllocate)?;
+                Ok(Some(MsiResource {
+                    base: Some(base),
+                    ..msi
+                }))
             }
             None => Ok(None),
         }
     }
 
-    fn free_irq_resource(&mut self, interrupt: Option<IrqResource>) {
-        match interrupt {
-            Some(IrqResource(irq)) => self.resource.free_irq(irq),
-            None => return,
+    fn free_msi_resource(&mut self, msi: Option<MsiResource>) {
+        if let Some(base) = msi.and_then(|msi| msi.base) {
+            self.resource.free_msi(base);
+        }
+    }
+
+    // Create the interrupt groups delivering the allocated legacy IRQs and
+    // MSI vectors, if an interrupt manager is set.
+    fn create_interrupt_groups(
+        &mut self,
+        instance_id: u32,
+        irqs: &[IrqResource],
+        msi: Option<MsiResource>,
+    ) -> Result<InterruptGroups> {
+        let mut groups = Vec::with_capacity(irqs.len());
+        for irq in irqs.iter() {
+            match self.create_legacy_group(instance_id, *irq) {
+                Ok(group) => groups.extend(group),
+                Err(e) => {
+                    self.destroy_legacy_groups(instance_id, irqs, &groups);
+                    return Err(e);
+                }
+            }
+        }
+        let msi_group = match (&self.interrupt_manager, msi) {
+            (
+                Some(manager),
+                Some(MsiResource {
+                    base: Some(base),
+                    count,
+                    msix,
+                }),
+            ) => {
+                let ty = if msix {
+                    InterruptSourceType::MsiX
+                } else {
+                    InterruptSourceType::Msi
+                };
+                match manager.create_group(ty, base, count) {
+                    Ok(msi_group) => {
+                        let routed =
+                            Arc::new(RoutedMsiGroup::new(msi_group, self.gsi_routing.clone()));
+                        self.routed_msi_groups.insert(instance_id, routed.clone());
+                        Some(routed as Arc<dyn InterruptSourceGroup>)
+                    }
+                    Err(e) => {
+                        self.destroy_legacy_groups(instance_id, irqs, &groups);
+                        return Err(Error::InterruptGroup(e));
+                    }
+                }
+            }
+            _ => None,
+        };
+        Ok((groups, msi_group))
+    }
+
+    // Route the allocated legacy IRQs and MSI vectors of a device. The MSI
+    // routes hold the messages currently programmed in `msi_group`.
+    fn add_routes(
+        &self,
+        irqs: &[IrqResource],
+        msi: Option<MsiResource>,
+        msi_group: Option<&Arc<dyn InterruptSourceGroup>>,
+    ) {
+        let mut table = self.gsi_routing.lock().expect("failed to acquire lock");
+        for irq in irqs.iter() {
+            if let Some(gsi) = irq.irq {
+                table.set(gsi, legacy_routes(irq));
+            }
+        }
+        if let Some(MsiResource {
+            base: Some(base),
+            count,
+            ..
+        }) = msi
+        {
+            for index in 0..count {
+                let config = match msi_group.map(|group| group.config(index)) {
+                    Some(Ok(InterruptSourceConfig::MsiIrq(config))) => config,
+                    _ => Default::default(),
+                };
+                table.set(base + index, vec![Route::Msi(config)]);
+            }
+        }
+    }
+
+    // Drop the routes of freed legacy IRQs and MSI vectors. A shared IRQ is
+    // kept routed until its last sharer is gone.
+    fn remove_routes(&self, irqs: &[IrqResource], msi: Option<MsiResource>) {
+        let mut table = self.gsi_routing.lock().expect("failed to acquire lock");
+        for irq in irqs.iter() {
+            if let Some(gsi) = irq.irq {
+                if !irq.shared || self.resource.irq_sharers(gsi) == 0 {
+                    table.remove(gsi);
+                }
+            }
+        }
+        if let Some(MsiResource {
+            base: Some(base),
+            count,
+            ..
+        }) = msi
+        {
+            for index in 0..count {
+                table.remove(base + index);
+            }
         }
     }
 
+    /// Return the GSI routing table of all the allocated interrupts.
+    ///
+    /// Legacy IRQs are routed to the pins of their interrupt controller and
+    /// MSI vectors to the message last programmed by the guest. The table is
+    /// kept up to date as devices come and go, have their IRQs reassigned or
+    /// reprogram their MSI vectors through the interrupt group they were
+    /// given, and its version tells whether it changed since last pushed to
+    /// the hypervisor.
+    pub fn gsi_routing(&self) -> GsiRoutingTable {
+        self.gsi_routing
+            .lock()
+            .expect("failed to acquire lock")
+            .clone()
+    }
+
+    // Release the resources allocated for a device which failed to register.
+    fn rollback_registration(
+        &mut self,
+        id: u32,
+        parent_bus: Option<u32>,
+        resources: &[IoResource],
+        irqs: &[IrqResource],
+        msi: Option<MsiResource>,
+    ) {
+        self.unregister_resources(resources);
+        self.free_io_resources(parent_bus, resources);
+        self.free_irq_resources(irqs);
+        self.free_msi_resource(msi);
+        self.free_id_resource(id);
+    }
+
     fn allocate_id_resource(&mut self) -> Result<u32> {
         self.resource
             .allocate_instance_id()
@@ -236,152 +916,807 @@ impl DeviceManager {
     }
 
     fn free_id_resource(&mut self, id: u32) {
+        self.counters.remove(&id);
         self.resource.free_instance_id(id);
     }
 
     /// Register a new device with its parent bus and resources request set.
     /// Return Ok(instance_id) when sucessfully registered for caller usage.
+    ///
+    /// `parent_bus` is the instance id of an already registered bus device,
+    /// or None for a device sitting at the root of the topology.
+    /// `interrupts` requests legacy IRQs, each with its own trigger mode,
+    /// polarity and interrupt controller; the allocated numbers are written
+    /// back into it.
+    /// `msi` requests a block of MSI or MSI-X vectors from the MSI range of
+    /// the `SystemAllocator`.
+    ///
+    /// All the resources are allocated together: when one of them can't be,
+    /// the ones already allocated are released before returning the error.
     pub fn register_device(
         &mut self,
         dev: Arc<dyn Device>,
-        parent_bus: Option<Arc<dyn Device>>,
-        resources: &mut Vec<IoResource>,
-        interrupt: Option<IrqResource>,
-    ) -> Result<(u32)> {
+        parent_bus: Option<u32>,
+        resources: &mut [IoResource],
+        interrupts: &mut [IrqResource],
+        msi: Option<MsiResource>,
+    ) -> Result<u32> {
         // Allocate an instance id
         let id = self.allocate_id_resource()?;
+        // Nothing is rolled back once the device is set up, so check it can
+        // be inserted before allocating anything else.
+        if let Err(e) = self.check_insert(id, parent_bus) {
+            self.free_id_resource(id);
+            return Err(e);
+        }
 
         // Reserve resources
-        if let Err(Error::IoResourceAllocate(idx, e)) = self.allocate_io_resources(resources) {
-            // Free allocated resources if one resource failed to allocate.
-            if idx > 0 {
-                self.free_io_resources(&resources[0..idx - 1]);
-                self.free_id_resource(id);
-                return Err(Error::IoResourceAllocate(idx, e));
-            }
+        if let Err(e) = self.allocate_io_resources(parent_bus, resources) {
+            self.free_id_resource(id);
+            return Err(e);
         }
 
         // Register device resources
-        let register_len = self.register_resources(dev.clone(), resources);
+        let register_len = self.register_resources(id, dev.clone(), resources);
         // Unregister and free resources once failed.
-        if register_len < resources.len() && register_len > 0 {
-            self.unregister_resources(&resources[0..register_len - 1]);
-            self.free_io_resources(resources);
-            self.free_id_resource(id);
-            return Err(Error::Overlap);
-        } else if register_len == 0 {
-            self.free_io_resources(resources);
+        if register_len < resources.len() {
+            self.unregister_resources(&resources[..register_len]);
+            self.free_io_resources(parent_bus, resources);
             self.free_id_resource(id);
             return Err(Error::Overlap);
         }
 
-        match self.allocate_irq_resource(interrupt) {
-            Ok(irq) => {
-                // Set the allocated resource back
-                dev.set_resources(resources, irq);
-
-                let descriptor =
-                    self.device_descriptor(id, dev, parent_bus, resources.to_vec(), irq);
-
-                // Insert bus/device to DeviceManager with parent bus
-                self.insert(descriptor)
+        let irqs = match self.allocate_irq_resources(interrupts) {
+            Ok(irqs) => irqs,
+            Err(e) => {
+                self.rollback_registration(id, parent_bus, resources, &[], None);
+                return Err(e);
             }
+        };
+        let msi = match self.allocate_msi_resource(msi) {
+            Ok(msi) => msi,
             Err(e) => {
-                self.unregister_resources(resources);
-                self.free_io_resources(resources);
-                self.free_id_resource(id);
-                Err(e)
+                self.rollback_registration(id, parent_bus, resources, &irqs, None);
+                return Err(e);
             }
+        };
+        let (groups, msi_group) = match self.create_interrupt_groups(id, &irqs, msi) {
+            Ok(groups) => groups,
+            Err(e) => {
+                self.rollback_registration(id, parent_bus, resources, &irqs, msi);
+                return Err(e);
+            }
+        };
+
+        // Set the allocated resource back
+        interrupts.copy_from_slice(&irqs);
+        dev.set_resources(resources, &irqs);
+        for group in groups.iter().chain(msi_group.iter()) {
+            dev.set_interrupt_group(group.clone());
         }
+
+        let mut descriptor = self.device_descriptor(id, dev, parent_bus, resources.to_vec(), irqs);
+        descriptor.interrupt_groups = groups;
+        descriptor.msi = msi;
+        self.add_routes(&descriptor.irqs, msi, msi_group.as_ref());
+        descriptor.msi_group = msi_group;
+
+        // Insert bus/device to DeviceManager with parent bus
+        let id = self.insert(descriptor)?;
+        self.notify(DeviceEventKind::Added, id);
+        Ok(id)
     }
 
     /// Unregister a device from `DeviceManager`.
+    ///
+    /// A bus which still has devices behind it is refused with
+    /// `Error::HasChildren`, use `unregister_subtree()` to remove it
+    /// together with its children.
     pub fn unregister_device(&mut self, instance_id: u32) -> Result<()> {
+        match self.devices.get(&instance_id) {
+            Some(descriptor) if !descriptor.children.is_empty() => {
+                Err(Error::HasChildren(descriptor.path.clone()))
+            }
+            Some(_) => {
+                self.release_device(instance_id);
+                Ok(())
+            }
+            None => Err(Error::NonExist),
+        }
+    }
+
+    /// Unregister a device together with every device behind it.
+    ///
+    /// Devices are removed children first and all their resources are
+    /// released. Return the removed instance ids in removal order.
+    pub fn unregister_subtree(&mut self, instance_id: u32) -> Result<Vec<u32>> {
+        let mut removed = self.descendants(instance_id)?;
+        removed.reverse();
+        removed.push(instance_id);
+
+        for id in removed.iter() {
+            self.release_device(*id);
+        }
+        Ok(removed)
+    }
+
+    // Remove a device from the topology and free all its resources.
+    fn release_device(&mut self, instance_id: u32) {
         if let Some(descriptor) = self.remove(instance_id) {
             // Free instance id resource
             self.free_id_resource(instance_id);
             // Unregister resources
-            self.unregister_resources(&descriptor.resources);
+            self.unregister_resources(&enabled_resources(&descriptor));
             // Free the resources
-            self.free_io_resources(&descriptor.resources);
-            self.free_irq_resource(descriptor.irq);
-            Ok(())
-        } else {
-            Err(Error::NonExist)
+            self.free_io_resources(descriptor.parent_bus, &descriptor.resources);
+            self.free_irq_resources(&descriptor.irqs);
+            self.free_msi_resource(descriptor.msi);
+            self.destroy_legacy_groups(instance_id, &descriptor.irqs, &descriptor.interrupt_groups);
+            let msi_group = match self.routed_msi_groups.remove(&instance_id) {
+                Some(routed) => Some(routed.group().clone()),
+                None => descriptor.msi_group.clone(),
+            };
+            self.destroy_interrupt_group(msi_group);
+            self.remove_routes(&descriptor.irqs, descriptor.msi);
+            self.release_pci(instance_id, descriptor.parent_bus);
+            self.notify_snapshot(DeviceEventKind::Removed, DeviceSnapshot::from(&descriptor));
         }
     }
 
-    fn first_before(
-        &self,
-        addr: GuestAddress,
-        io_type: IoType,
-    ) -> Option<(Range, Arc<dyn Device>)> {
-        match io_type {
-            IoType::Pio => {
-                for (range, dev) in self.pio_bus.iter().rev() {
-                    if range.0 <= addr {
-                        return Some((*range, dev.clone()));
-                    }
-                }
-                None
-            }
-            IoType::Mmio => {
-                for (range, dev) in self.mmio_bus.iter().rev() {
-                    if range.0 <= addr {
-                        return Some((*range, dev.clone()));
-                    }
+    /// Unregister the device registered at `path`.
+    pub fn unregister_device_by_path(&mut self, path: &str) -> Result<()> {
+        let id = self.instance_id_by_path(path)?;
+        self.unregister_device(id)
+    }
+
+    /// Get the descriptor of a registered device.
+    pub fn get_descriptor(&self, instance_id: u32) -> Option<&DeviceDescriptor> {
+        self.devices.get(&instance_id)
+    }
+
+    /// Get the descriptor of the device registered at `path`.
+    pub fn get_descriptor_by_path(&self, path: &str) -> Option<&DeviceDescriptor> {
+        self.paths.get(path).and_then(|id| self.devices.get(id))
+    }
+
+    /// Return the instance id of the device registered at `path`.
+    pub fn instance_id_by_path(&self, path: &str) -> Result<u32> {
+        self.paths
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::PathNonExist(path.to_string()))
+    }
+
+    /// Return the path of a registered device.
+    pub fn device_path_of(&self, instance_id: u32) -> Result<&str> {
+        self.devices
+            .get(&instance_id)
+            .map(|d| d.path.as_str())
+            .ok_or(Error::NonExist)
+    }
+
+    /// Return the IO exit statistics of all registered devices, ordered by path.
+    pub fn metrics(&self) -> Vec<IoMetrics> {
+        let mut metrics: Vec<IoMetrics> = self
+            .devices
+            .values()
+            .map(|d| {
+                let (reads, writes) = self.counters.get(&d.instance_id).map_or((0, 0), |c| {
+                    (
+                        c.reads.load(AtomicOrdering::Relaxed),
+                        c.writes.load(AtomicOrdering::Relaxed),
+                    )
+                });
+                IoMetrics {
+                    path: d.path.clone(),
+                    reads,
+                    writes,
                 }
-                None
-            }
-            IoType::PhysicalMmio => None,
-        }
+            })
+            .collect();
+        metrics.sort_by(|a, b| a.path.cmp(&b.path));
+        metrics
     }
 
-    /// Return the Device mapped the address.
-    fn get_device(&self, addr: GuestAddress, io_type: IoType) -> Option<Arc<dyn Device>> {
-        if let Some((Range(start, len), dev)) = self.first_before(addr, io_type) {
-            if (addr.raw_value() - start.raw_value()) < len {
-                return Some(dev);
-            }
+    /// Return the instance ids of the devices at the root of the topology,
+    /// ordered by instance id.
+    pub fn root_devices(&self) -> Vec<u32> {
+        let mut roots: Vec<u32> = self
+            .devices
+            .values()
+            .filter(|d| d.parent_bus.is_none())
+            .map(|d| d.instance_id)
+            .collect();
+        roots.sort();
+        roots
+    }
+
+    /// Return the instance ids of all registered devices in topology order:
+    /// each root device followed by its descendants, parents before children.
+    pub fn topology_order(&self) -> Vec<u32> {
+        let mut order = Vec::with_capacity(self.devices.len());
+        for root in self.root_devices() {
+            order.push(root);
+            // Every device in the topology is registered, so this can't fail.
+            order.extend(self.descendants(root).unwrap_or_default());
         }
-        None
+        order
     }
 
-    /// A helper function handling PIO/MMIO read commands during VM exit.
+    /// Return the IRQ resources of all the devices in topology order, along
+    /// with the instance id of their device.
     ///
-    /// Figure out the device according to `addr` and hand over the handling to device
-    /// specific read function.
-    /// Return error if failed to get the device.
-    pub fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
-        if let Some(dev) = self.get_device(addr, io_type) {
-            dev.read(addr, data, io_type);
-            Ok(())
-        } else {
-            Err(Error::NonExist)
-        }
+    /// This is meant for firmware table generators, e.g. to describe the
+    /// interrupts of the devices in ACPI or in a device tree.
+    pub fn interrupts(&self) -> Vec<(u32, IrqResource)> {
+        self.topology_order()
+            .into_iter()
+            .filter_map(|id| self.devices.get(&id))
+            .flat_map(|d| d.irqs.iter().map(move |irq| (d.instance_id, *irq)))
+            .collect()
     }
 
-    /// A helper function handling PIO/MMIO write commands during VM exit.
+    /// Run a lifecycle operation on every registered device.
     ///
-    /// Figure out the device according to `addr` and hand over the handling to device
-    /// specific write function.
-    /// Return error if failed to get the device.
-    pub fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
-        if let Some(dev) = self.get_device(addr, io_type) {
-            dev.write(addr, data, io_type);
+    /// Activate and resume go through the topology parents before children,
+    /// reset, pause and shutdown go children before parents. A failing
+    /// device doesn't stop the broadcast, all failures are collected into
+    /// `Error::Lifecycle`.
+    pub fn broadcast_lifecycle(&self, op: LifecycleOp) -> Result<()> {
+        let mut order = self.topology_order();
+        if !op.parents_first() {
+            order.reverse();
+        }
+
+        let mut failures = Vec::new();
+        for id in order {
+            if let Some(descriptor) = self.devices.get(&id) {
+                let dev = &descriptor.device;
+                let ret = match op {
+                    LifecycleOp::Activate => dev.activate(),
+                    LifecycleOp::Reset => dev.reset(),
+                    LifecycleOp::Pause => dev.pause(),
+                    LifecycleOp::Resume => dev.resume(),
+                    LifecycleOp::Shutdown => dev.shutdown(),
+                };
+                if let Err(e) = ret {
+                    failures.push((descriptor.path.clone(), e));
+                }
+            }
+        }
+
+        if failures.is_empty() {
             Ok(())
         } else {
-            Err(Error::NonExist)
+            Err(Error::Lifecycle(op, failures))
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::device::*;
-    use crate::device_manager::*;
-    use std::string::String;
-    use std::sync::Mutex;
+    /// Activate all devices, parents before children.
+    pub fn activate_all(&self) -> Result<()> {
+        self.broadcast_lifecycle(LifecycleOp::Activate)
+    }
+
+    /// Pause all devices, children before parents.
+    pub fn pause_all(&self) -> Result<()> {
+        self.broadcast_lifecycle(LifecycleOp::Pause)
+    }
+
+    /// Resume all devices, parents before children.
+    pub fn resume_all(&self) -> Result<()> {
+        self.broadcast_lifecycle(LifecycleOp::Resume)
+    }
+
+    /// Shut all devices down, children before parents.
+    pub fn shutdown_all(&self) -> Result<()> {
+        self.broadcast_lifecycle(LifecycleOp::Shutdown)
+    }
+
+    /// Move the IO resource at `index` of a registered device to `addr`.
+    ///
+    /// This is typically used when the guest reprograms a BAR. The new range
+    /// is allocated and registered in place of the old one, and the device
+    /// is notified through `set_resources()`. The old range is kept if the
+    /// new one can't be allocated.
+    pub fn relocate_resource(
+        &mut self,
+        instance_id: u32,
+        index: usize,
+        addr: GuestAddress,
+    ) -> Result<()> {
+        let (dev, old, irqs, enabled, parent) = {
+            let descriptor = self.devices.get(&instance_id).ok_or(Error::NonExist)?;
+            let old = *descriptor
+                .resources
+                .get(index)
+                .ok_or(Error::ResourceNonExist(index))?;
+            let enabled = !descriptor.disabled_resources.contains(&index);
+            (
+                descriptor.device.clone(),
+                old,
+                descriptor.irqs.clone(),
+                enabled,
+                descriptor.parent_bus,
+            )
+        };
+        if old.addr == Some(addr) {
+            return Ok(());
+        }
+
+        if enabled {
+            self.unregister_resources(&[old]);
+        }
+        self.free_io_resources(parent, &[old]);
+
+        let mut new = vec![IoResource::new(Some(addr), old.size, old.res_type)];
+        let mut ret = self
+            .allocate_io_resources(parent, &mut new)
+            .map_err(|e| match e {
+                Error::IoResourceAllocate(_, e) => Error::IoResourceAllocate(index, e),
+                e => e,
+            });
+        if ret.is_ok()
+            && enabled
+            && self.register_resources(instance_id, dev.clone(), &mut new) == 0
+        {
+            self.free_io_resources(parent, &new);
+            ret = Err(Error::Overlap);
+        }
+        if let Err(e) = ret {
+            // Put the old range back, it was only released above.
+            let mut old_vec = vec![old];
+            let _ = self.allocate_io_resources(parent, &mut old_vec);
+            if enabled {
+                self.register_resources(instance_id, dev, &mut old_vec);
+            }
+            return Err(e);
+        }
+
+        let resources = match self.devices.get_mut(&instance_id) {
+            Some(descriptor) => {
+                descriptor.resources[index] = new[0];
+                descriptor.resources.clone()
+            }
+            None => return Err(Error::NonExist),
+        };
+        dev.set_resources(&resources, &irqs);
+        self.notify(DeviceEventKind::ResourceRelocated(index), instance_id);
+        Ok(())
+    }
+
+    /// Enable or disable decoding of the IO resource at `index` of a device.
+    ///
+    /// A disabled resource stays allocated but is removed from its bus, like a
+    /// BAR whose decoding is turned off in the PCI command register.
+    pub fn set_resource_enabled(
+        &mut self,
+        instance_id: u32,
+        index: usize,
+        enabled: bool,
+    ) -> Result<()> {
+        let (dev, res, was_enabled) = {
+            let descriptor = self.devices.get(&instance_id).ok_or(Error::NonExist)?;
+            let res = *descriptor
+                .resources
+                .get(index)
+                .ok_or(Error::ResourceNonExist(index))?;
+            let was_enabled = !descriptor.disabled_resources.contains(&index);
+            (descriptor.device.clone(), res, was_enabled)
+        };
+        if enabled == was_enabled {
+            return Ok(());
+        }
+
+        if enabled {
+            if self.register_resources(instance_id, dev, &mut [res]) == 0 {
+                return Err(Error::Overlap);
+            }
+        } else {
+            self.unregister_resources(&[res]);
+        }
+
+        if let Some(descriptor) = self.devices.get_mut(&instance_id) {
+            if enabled {
+                descriptor.disabled_resources.retain(|&idx| idx != index);
+            } else {
+                descriptor.disabled_resources.push(index);
+            }
+        }
+        let kind = if enabled {
+            DeviceEventKind::ResourceEnabled(index)
+        } else {
+            DeviceEventKind::ResourceDisabled(index)
+        };
+        self.notify(kind, instance_id);
+        Ok(())
+    }
+
+    /// Change the IRQ resource at `index` of a registered device, e.g. when
+    /// firmware reroutes it.
+    ///
+    /// `irq` is a specific number to allocate, or None to take any free one.
+    /// The old IRQ and its interrupt group are only released once the new
+    /// ones are set up, and a shared IRQ is rerouted to another shared line,
+    /// or kept on its own one. The trigger mode, polarity and interrupt
+    /// controller are kept.
+    pub fn reassign_irq(
+        &mut self,
+        instance_id: u32,
+        index: usize,
+        irq: Option<u32>,
+    ) -> Result<u32> {
+        let (dev, old, old_group) = {
+            let descriptor = self.devices.get(&instance_id).ok_or(Error::NonExist)?;
+            let old = *descriptor
+                .irqs
+                .get(index)
+                .ok_or(Error::IrqNonExist(index))?;
+            (
+                descriptor.device.clone(),
+                old,
+                descriptor.interrupt_groups.get(index).cloned(),
+            )
+        };
+
+        let new = self.allocate_irq_resource(IrqResource { irq, ..old })?;
+        if new.irq == old.irq {
+            // A shared IRQ kept on its line, which the device only has one
+            // source of.
+            self.free_irq_resource(new);
+            return Ok(new.irq.unwrap_or_default());
+        }
+        let group = match self.create_legacy_group(instance_id, new) {
+            Ok(group) => group,
+            Err(e) => {
+                self.free_irq_resource(new);
+                return Err(e);
+            }
+        };
+        self.destroy_legacy_group(instance_id, old, old_group);
+        self.free_irq_resource(old);
+        self.remove_routes(&[old], None);
+        self.add_routes(&[new], None, None);
+
+        let (resources, irqs) = match self.devices.get_mut(&instance_id) {
+            Some(descriptor) => {
+                descriptor.irqs[index] = new;
+                if let Some(group) = group.clone() {
+                    descriptor.interrupt_groups[index] = group;
+                }
+                (descriptor.resources.clone(), descriptor.irqs.clone())
+            }
+            None => return Err(Error::NonExist),
+        };
+        dev.set_resources(&resources, &irqs);
+        if let Some(group) = group {
+            dev.set_interrupt_group(group);
+        }
+        self.notify(DeviceEventKind::IrqChanged(index), instance_id);
+        // An allocated IRQ resource always holds a number.
+        Ok(new.irq.unwrap_or_default())
+    }
+
+    /// Bring every device back to its power-on state for a warm reboot.
+    ///
+    /// Devices are paused children first, then reset depth-first through the
+    /// topology, children before their parent bus. Any IO resource moved
+    /// since registration is put back where it was allocated, and devices are
+    /// resumed parents first. Instance ids and allocations are kept, so the
+    /// device set doesn't need to be rebuilt.
+    ///
+    /// Holding the `DeviceManager` mutably guarantees no IO exit is dispatched
+    /// while the devices are being reset.
+    ///
+    /// Devices are always resumed, even when a step failed. The error of the
+    /// resources restoration is returned first, or else the devices failing
+    /// a lifecycle operation are collected into `Error::Lifecycle`.
+    pub fn reset_all(&mut self) -> Result<()> {
+        let mut failures = Vec::new();
+        let mut error = None;
+        let mut collect = |ret: Result<()>| match ret {
+            Ok(()) => (),
+            Err(Error::Lifecycle(_, f)) => failures.extend(f),
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        };
+
+        collect(self.pause_all());
+        collect(self.broadcast_lifecycle(LifecycleOp::Reset));
+        collect(self.restore_initial_resources());
+        collect(self.resume_all());
+
+        match error {
+            Some(e) => Err(e),
+            None if failures.is_empty() => Ok(()),
+            None => Err(Error::Lifecycle(LifecycleOp::Reset, failures)),
+        }
+    }
+
+    // Put back the IO resources of every device changed since registration:
+    // ranges are moved back to their original address and decoding is
+    // enabled again. Either all the changed devices get their original
+    // resources back or none does: when one can't, e.g. because a device
+    // registered since took its original range, they all keep their current
+    // resources.
+    fn restore_initial_resources(&mut self) -> Result<()> {
+        let changed: Vec<u32> = self
+            .topology_order()
+            .into_iter()
+            .filter(|id| match self.devices.get(id) {
+                Some(d) => d.resources != d.initial_resources || !d.disabled_resources.is_empty(),
+                None => false,
+            })
+            .collect();
+
+        // Release all the changed ranges first, so that devices which swapped
+        // addresses can get their original ones back.
+        for id in changed.iter() {
+            let (enabled, resources, parent) = {
+                let d = &self.devices[id];
+                (enabled_resources(d), d.resources.clone(), d.parent_bus)
+            };
+            self.unregister_resources(&enabled);
+            self.free_io_resources(parent, &resources);
+        }
+
+        let mut restored = Vec::with_capacity(changed.len());
+        let mut ret = Ok(());
+        for id in changed.iter() {
+            let (dev, mut resources, parent) = {
+                let d = &self.devices[id];
+                (d.device.clone(), d.initial_resources.clone(), d.parent_bus)
+            };
+            if let Err(e) = self.allocate_io_resources(parent, &mut resources) {
+                ret = Err(e);
+                break;
+            }
+            let registered = self.register_resources(*id, dev, &mut resources);
+            if registered < resources.len() {
+                self.unregister_resources(&resources[..registered]);
+                self.free_io_resources(parent, &resources);
+                ret = Err(Error::Overlap);
+                break;
+            }
+            restored.push((*id, resources));
+        }
+
+        if let Err(e) = ret {
+            for (id, resources) in restored.iter() {
+                let parent = self.devices[id].parent_bus;
+                self.unregister_resources(resources);
+                self.free_io_resources(parent, resources);
+            }
+            // The current ranges were all allocated and registered together
+            // until released above, so they can be put back.
+            for id in changed.iter() {
+                let (dev, mut enabled, mut resources, parent) = {
+                    let d = &self.devices[id];
+                    (
+                        d.device.clone(),
+                        enabled_resources(d),
+                        d.resources.clone(),
+                        d.parent_bus,
+                    )
+                };
+                let _ = self.allocate_io_resources(parent, &mut resources);
+                self.register_resources(*id, dev, &mut enabled);
+            }
+            return Err(e);
+        }
+
+        for (id, resources) in restored {
+            let (dev, old, disabled, irqs) = {
+                let d = &self.devices[&id];
+                (
+                    d.device.clone(),
+                    d.resources.clone(),
+                    d.disabled_resources.clone(),
+                    d.irqs.clone(),
+                )
+            };
+            if let Some(descriptor) = self.devices.get_mut(&id) {
+                descriptor.resources = resources.clone();
+                descriptor.disabled_resources.clear();
+            }
+            dev.set_resources(&resources, &irqs);
+
+            for (idx, res) in resources.iter().enumerate() {
+                if old[idx] != *res {
+                    self.notify(DeviceEventKind::ResourceRelocated(idx), id);
+                }
+                if disabled.contains(&idx) {
+                    self.notify(DeviceEventKind::ResourceEnabled(idx), id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the system allocator used to allocate resources for devices.
+    pub fn allocator(&self) -> &SystemAllocator {
+        &self.resource
+    }
+
+    /// Return the parent bus instance id of a registered device.
+    pub fn parent_bus(&self, instance_id: u32) -> Result<Option<u32>> {
+        self.devices
+            .get(&instance_id)
+            .map(|d| d.parent_bus)
+            .ok_or(Error::NonExist)
+    }
+
+    /// Return the instance ids of the devices directly behind a bus.
+    pub fn children(&self, instance_id: u32) -> Result<&[u32]> {
+        self.devices
+            .get(&instance_id)
+            .map(|d| d.children.as_slice())
+            .ok_or(Error::NonExist)
+    }
+
+    /// Return the ancestors of a device, from its parent bus up to the root.
+    pub fn ancestors(&self, instance_id: u32) -> Result<Vec<u32>> {
+        let mut ancestors = Vec::new();
+        let mut current = self.parent_bus(instance_id)?;
+
+        while let Some(id) = current {
+            ancestors.push(id);
+            current = self.parent_bus(id)?;
+        }
+        Ok(ancestors)
+    }
+
+    /// Return all the devices behind a bus, parents before their children.
+    pub fn descendants(&self, instance_id: u32) -> Result<Vec<u32>> {
+        let mut descendants = Vec::new();
+        let mut stack: Vec<u32> = self.children(instance_id)?.iter().rev().cloned().collect();
+
+        while let Some(id) = stack.pop() {
+            descendants.push(id);
+            stack.extend(self.children(id)?.iter().rev());
+        }
+        Ok(descendants)
+    }
+
+    fn first_before(&self, addr: GuestAddress, io_type: IoType) -> Option<(Range, BusEntry)> {
+        match io_type {
+            IoType::Pio => {
+                for (range, dev) in self.pio_bus.iter().rev() {
+                    if range.0 <= addr {
+                        return Some((*range, dev.clone()));
+                    }
+                }
+                None
+            }
+            IoType::Mmio | IoType::PciMmio32 | IoType::PciMmio64 => {
+                for (range, dev) in self.mmio_bus.iter().rev() {
+                    if range.0 <= addr {
+                        return Some((*range, dev.clone()));
+                    }
+                }
+                None
+            }
+            IoType::PhysicalMmio => None,
+        }
+    }
+
+    /// Return the bus entry of the Device mapped the address.
+    fn get_device(&self, addr: GuestAddress, io_type: IoType) -> Option<BusEntry> {
+        if let Some((Range(start, len), dev)) = self.first_before(addr, io_type) {
+            if (addr.raw_value() - start.raw_value()) < len {
+                return Some(dev);
+            }
+        }
+        None
+    }
+
+    /// A helper function handling PIO/MMIO read commands during VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific read function.
+    /// Return error if failed to get the device.
+    pub fn read(&self, addr: GuestAddress, data: &mut [u8], io_type: IoType) -> Result<()> {
+        if let Some(entry) = self.get_device(addr, io_type) {
+            entry.counters.reads.fetch_add(1, AtomicOrdering::Relaxed);
+            entry.device.read(addr, data, io_type);
+            Ok(())
+        } else {
+            Err(Error::NonExist)
+        }
+    }
+
+    /// A helper function handling PIO/MMIO write commands during VM exit.
+    ///
+    /// Figure out the device according to `addr` and hand over the handling to device
+    /// specific write function.
+    /// Return error if failed to get the device.
+    pub fn write(&self, addr: GuestAddress, data: &[u8], io_type: IoType) -> Result<()> {
+        if let Some(entry) = self.get_device(addr, io_type) {
+            entry.counters.writes.fetch_add(1, AtomicOrdering::Relaxed);
+            entry.device.write(addr, data, io_type);
+            Ok(())
+        } else {
+            Err(Error::NonExist)
+        }
+    }
+}
+
+// System allocator shared by the unit tests, with 64 KiB of ports from 0x100
+// and a 256 MiB platform MMIO space at `mmio_base`.
+#[cfg(test)]
+pub(crate) fn test_system_allocator(mmio_base: GuestAddress) -> SystemAllocator {
+    SystemAllocator::new(
+        Some(GuestAddress(0x100)),
+        Some(0x10000),
+        mmio_base,
+        0x1000_0000,
+        5,
+        15,
+        1,
+    )
+    .unwrap()
+}
+
+// System allocator of the PCI unit tests, whose platform MMIO space spans
+// from 256 MiB to 8 GiB so that the PCI MMIO windows can be carved out of it.
+#[cfg(test)]
+pub(crate) fn test_pci_system_allocator(
+    mmio32: (GuestAddress, GuestUsize),
+    mmio64: Option<(GuestAddress, GuestUsize)>,
+) -> SystemAllocator {
+    let mut sys_res = SystemAllocator::new(
+        Some(GuestAddress(0x100)),
+        Some(0x10000),
+        GuestAddress(0x1000_0000),
+        0x1_f000_0000,
+        5,
+        15,
+        1,
+    )
+    .unwrap();
+    sys_res.set_pci_mmio_windows(mmio32, mmio64).unwrap();
+    sys_res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        Device, IoResource, IoType, IrqPolarity, IrqResource, IrqTrigger, MsiResource,
+    };
+    use crate::device_manager::*;
+    use crate::event::{DeviceEvent, DeviceEventKind, DeviceEventListener};
+    use crate::interrupt::eventfd::EventFdInterruptManager;
+    use crate::interrupt::MsiIrqSourceConfig;
+    use std::string::String;
+    use std::sync::Mutex;
+
+    struct DummyDevice {
+        name: String,
+    }
+
+    impl Device for DummyDevice {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+    }
+
+    fn dummy(name: &str) -> Arc<dyn Device> {
+        Arc::new(DummyDevice {
+            name: name.to_string(),
+        })
+    }
+
+    fn new_device_manager() -> DeviceManager {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        DeviceManager::new(sys_res)
+    }
+
+    fn mmio(size: GuestUsize) -> Vec<IoResource> {
+        vec![IoResource::new(None, size, IoType::Mmio)]
+    }
 
     #[test]
     fn test_dev_init() -> Result<()> {
@@ -416,7 +1751,7 @@ mod tests {
             ///
             /// This will be called by DeviceManager::register_device() to set
             /// the allocated resource from the vm_allocator back to device.
-            fn set_resources(&self, _res: &[IoResource], _irq: Option<IrqResource>) {}
+            fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
         }
         impl BusDevice {
             pub fn new(name: String) -> Self {
@@ -434,16 +1769,7 @@ mod tests {
             }
         }
 
-        let sys_res = SystemAllocator::new(
-            Some(GuestAddress(0x100)),
-            Some(0x10000),
-            GuestAddress(0x1000_0000),
-            0x1000_0000,
-            5,
-            15,
-            1,
-        )
-        .unwrap();
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
         let mut dev_mgr = DeviceManager::new(sys_res.clone());
         let dummy_bus = BusDevice::new("dummy-bus".to_string());
         let mut res_req = dummy_bus.get_resource();
@@ -452,9 +1778,840 @@ mod tests {
             Arc::new(dummy_bus),
             None,
             &mut res_req,
-            Some(IrqResource(None)),
+            &mut [IrqResource::new(None)],
+            None,
         )?;
         assert_eq!(id, 1);
         Ok(())
     }
+
+    #[test]
+    fn test_topology() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+
+        let root =
+            dev_mgr.register_device(dummy("root"), None, &mut mmio(0x1000), &mut [], None)?;
+        let bridge = dev_mgr.register_device(
+            dummy("bridge"),
+            Some(root),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        let leaf0 = dev_mgr.register_device(
+            dummy("leaf0"),
+            Some(bridge),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        let leaf1 = dev_mgr.register_device(
+            dummy("leaf1"),
+            Some(bridge),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        let other = dev_mgr.register_device(
+            dummy("other"),
+            Some(root),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+
+        // Nothing is allocated for a device behind an unknown bus.
+        let fixed = || {
+            vec![IoResource::new(
+                Some(GuestAddress(0x1800_0000)),
+                0x1000,
+                IoType::Mmio,
+            )]
+        };
+        match dev_mgr.register_device(dummy("orphan"), Some(100), &mut fixed(), &mut [], None) {
+            Err(Error::ParentNonExist) => (),
+            _ => panic!("device behind an unknown bus should not be registered"),
+        }
+        let fixed_id =
+            dev_mgr.register_device(dummy("fixed"), None, &mut fixed(), &mut [], None)?;
+        dev_mgr.unregister_device(fixed_id)?;
+
+        assert_eq!(dev_mgr.root_devices(), vec![root]);
+        assert_eq!(dev_mgr.parent_bus(leaf1)?, Some(bridge));
+        assert_eq!(dev_mgr.children(root)?, &[bridge, other]);
+        assert_eq!(dev_mgr.ancestors(leaf0)?, vec![bridge, root]);
+        assert_eq!(
+            dev_mgr.descendants(root)?,
+            vec![bridge, leaf0, leaf1, other]
+        );
+
+        // A bus with devices behind it can only be removed recursively.
+        match dev_mgr.unregister_device(bridge) {
+            Err(Error::HasChildren(_)) => (),
+            _ => panic!("bus with children should not be unregistered"),
+        }
+        assert_eq!(
+            dev_mgr.unregister_subtree(bridge)?,
+            vec![leaf1, leaf0, bridge]
+        );
+        assert!(dev_mgr.get_descriptor(leaf0).is_none());
+        assert_eq!(dev_mgr.children(root)?, &[other]);
+
+        // Released resources and instance ids can be allocated again.
+        let again = dev_mgr.register_device(
+            dummy("again"),
+            Some(root),
+            &mut mmio(0x3000),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(again, bridge);
+
+        dev_mgr.unregister_device(other)?;
+        dev_mgr.unregister_device(again)?;
+        dev_mgr.unregister_device(root)?;
+        assert!(dev_mgr.root_devices().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_path() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+
+        let sys = dev_mgr.register_device(dummy("sys"), None, &mut mmio(0x1000), &mut [], None)?;
+        let pci =
+            dev_mgr.register_device(dummy("pci0"), Some(sys), &mut mmio(0x1000), &mut [], None)?;
+        let slot = dev_mgr.register_device(
+            dummy("00:03.0"),
+            Some(pci),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        let net0 = dev_mgr.register_device(
+            dummy("virtio-net"),
+            Some(slot),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        let net1 = dev_mgr.register_device(
+            dummy("virtio-net"),
+            Some(slot),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+
+        assert_eq!(
+            dev_mgr.device_path_of(net0)?,
+            "/sys/pci0/00:03.0/virtio-net"
+        );
+        assert_eq!(
+            dev_mgr.device_path_of(net1)?,
+            "/sys/pci0/00:03.0/virtio-net#1"
+        );
+        assert_eq!(dev_mgr.instance_id_by_path("/sys/pci0")?, pci);
+        assert_eq!(
+            dev_mgr
+                .get_descriptor_by_path("/sys/pci0/00:03.0/virtio-net#1")
+                .map(|d| d.instance_id),
+            Some(net1)
+        );
+
+        match dev_mgr.unregister_device_by_path("/sys/pci0/00:03.0") {
+            Err(Error::HasChildren(path)) => assert_eq!(path, "/sys/pci0/00:03.0"),
+            _ => panic!("bus with children should not be unregistered"),
+        }
+        dev_mgr.unregister_device_by_path("/sys/pci0/00:03.0/virtio-net")?;
+        match dev_mgr.instance_id_by_path("/sys/pci0/00:03.0/virtio-net") {
+            Err(Error::PathNonExist(_)) => (),
+            _ => panic!("unregistered path should not be found"),
+        }
+
+        // The freed path is handed out again to the next device with that name.
+        let net2 = dev_mgr.register_device(
+            dummy("virtio-net"),
+            Some(slot),
+            &mut mmio(0x1000),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(
+            dev_mgr.device_path_of(net2)?,
+            "/sys/pci0/00:03.0/virtio-net"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_io_metrics() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let mut res = vec![IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio)];
+        dev_mgr.register_device(dummy("serial"), None, &mut res, &mut [], None)?;
+
+        let mut data = [0u8; 1];
+        dev_mgr.read(GuestAddress(0x3f8), &mut data, IoType::Pio)?;
+        dev_mgr.read(GuestAddress(0x3fd), &mut data, IoType::Pio)?;
+        dev_mgr.write(GuestAddress(0x3f8), &data, IoType::Pio)?;
+        assert!(dev_mgr
+            .read(GuestAddress(0x400), &mut data, IoType::Pio)
+            .is_err());
+
+        assert_eq!(
+            dev_mgr.metrics(),
+            vec![IoMetrics {
+                path: "/serial".to_string(),
+                reads: 2,
+                writes: 1,
+            }]
+        );
+        Ok(())
+    }
+
+    struct LifecycleDevice {
+        name: String,
+        log: Arc<Mutex<Vec<String>>>,
+        fail_pause: bool,
+    }
+
+    impl LifecycleDevice {
+        fn record(&self, op: &str) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", op, self.name));
+        }
+    }
+
+    impl Device for LifecycleDevice {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+        fn activate(&self) -> std::result::Result<(), DeviceError> {
+            self.record("activate");
+            Ok(())
+        }
+        fn pause(&self) -> std::result::Result<(), DeviceError> {
+            self.record("pause");
+            if self.fail_pause {
+                return Err(DeviceError::InvalidState);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_order() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut register = |name: &str, parent: Option<u32>, fail_pause: bool| {
+            let dev = Arc::new(LifecycleDevice {
+                name: name.to_string(),
+                log: log.clone(),
+                fail_pause,
+            });
+            dev_mgr.register_device(dev, parent, &mut mmio(0x1000), &mut [], None)
+        };
+
+        let bus = register("bus", None, false)?;
+        let dev0 = register("dev0", Some(bus), true)?;
+        register("dev1", Some(dev0), false)?;
+        register("dev2", Some(bus), true)?;
+
+        dev_mgr.activate_all()?;
+        match dev_mgr.pause_all() {
+            Err(Error::Lifecycle(LifecycleOp::Pause, failures)) => {
+                let paths: Vec<&str> = failures.iter().map(|f| f.0.as_str()).collect();
+                assert_eq!(paths, vec!["/bus/dev2", "/bus/dev0"]);
+            }
+            _ => panic!("pause failures should be reported"),
+        }
+        // Operations without a device callback are no-ops.
+        dev_mgr.resume_all()?;
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "activate bus",
+                "activate dev0",
+                "activate dev1",
+                "activate dev2",
+                "pause dev2",
+                "pause dev1",
+                "pause dev0",
+                "pause bus",
+            ]
+        );
+        Ok(())
+    }
+
+    struct ResetDevice {
+        name: String,
+        log: Arc<Mutex<Vec<String>>>,
+        resources: Mutex<Vec<IoResource>>,
+    }
+
+    impl Device for ResetDevice {
+        fn name(&self) -> String {
+            self.name.clone()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+            *self.resources.lock().unwrap() = res.to_vec();
+        }
+        fn reset(&self) -> std::result::Result<(), DeviceError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("reset {}", self.name));
+            Ok(())
+        }
+        fn pause(&self) -> std::result::Result<(), DeviceError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("pause {}", self.name));
+            Ok(())
+        }
+        fn resume(&self) -> std::result::Result<(), DeviceError> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("resume {}", self.name));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relocate_and_reset_all() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let bus = Arc::new(ResetDevice {
+            name: "bus".to_string(),
+            log: log.clone(),
+            resources: Mutex::new(Vec::new()),
+        });
+        let dev = Arc::new(ResetDevice {
+            name: "dev".to_string(),
+            log: log.clone(),
+            resources: Mutex::new(Vec::new()),
+        });
+
+        let mut bus_res = vec![IoResource::new(
+            Some(GuestAddress(0x1000_0000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        let bus_id = dev_mgr.register_device(bus.clone(), None, &mut bus_res, &mut [], None)?;
+        let mut dev_res = vec![IoResource::new(
+            Some(GuestAddress(0x1000_1000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        let dev_id =
+            dev_mgr.register_device(dev.clone(), Some(bus_id), &mut dev_res, &mut [], None)?;
+
+        // The guest swaps both ranges.
+        dev_mgr.relocate_resource(bus_id, 0, GuestAddress(0x1000_2000))?;
+        dev_mgr.relocate_resource(dev_id, 0, GuestAddress(0x1000_0000))?;
+        dev_mgr.relocate_resource(bus_id, 0, GuestAddress(0x1000_1000))?;
+        assert_eq!(
+            dev.resources.lock().unwrap()[0].addr,
+            Some(GuestAddress(0x1000_0000))
+        );
+        // Moving onto an allocated range fails and keeps the old one.
+        assert!(dev_mgr
+            .relocate_resource(dev_id, 0, GuestAddress(0x1000_1000))
+            .is_err());
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(0x1000_0000), &mut data, IoType::Mmio)?;
+        assert!(dev_mgr
+            .relocate_resource(dev_id, 1, GuestAddress(0))
+            .is_err());
+
+        dev_mgr.reset_all()?;
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "pause dev",
+                "pause bus",
+                "reset dev",
+                "reset bus",
+                "resume bus",
+                "resume dev",
+            ]
+        );
+        assert_eq!(*bus.resources.lock().unwrap(), bus_res);
+        assert_eq!(*dev.resources.lock().unwrap(), dev_res);
+        assert_eq!(dev_mgr.get_descriptor(dev_id).unwrap().resources, dev_res);
+        dev_mgr.read(GuestAddress(0x1000_1000), &mut data, IoType::Mmio)?;
+        assert!(dev_mgr
+            .read(GuestAddress(0x1000_2000), &mut data, IoType::Mmio)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_all_failure() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let dev = Arc::new(ResetDevice {
+            name: "dev".to_string(),
+            log: log.clone(),
+            resources: Mutex::new(Vec::new()),
+        });
+        let fixed = |addr| {
+            vec![IoResource::new(
+                Some(GuestAddress(addr)),
+                0x1000,
+                IoType::Mmio,
+            )]
+        };
+
+        let dev_id =
+            dev_mgr.register_device(dev.clone(), None, &mut fixed(0x1000_0000), &mut [], None)?;
+        dev_mgr.relocate_resource(dev_id, 0, GuestAddress(0x1000_2000))?;
+        // Another device takes the original range.
+        let other = dev_mgr.register_device(
+            dummy("other"),
+            None,
+            &mut fixed(0x1000_0000),
+            &mut [],
+            None,
+        )?;
+
+        match dev_mgr.reset_all() {
+            Err(Error::IoResourceAllocate(0, _)) => (),
+            _ => panic!("original range should not be restored"),
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["pause dev", "reset dev", "resume dev"]
+        );
+        // The device keeps decoding its current range.
+        assert_eq!(
+            dev_mgr.get_descriptor(dev_id).unwrap().resources,
+            fixed(0x1000_2000)
+        );
+        assert_eq!(*dev.resources.lock().unwrap(), fixed(0x1000_2000));
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(0x1000_2000), &mut data, IoType::Mmio)?;
+
+        // Releasing the device only frees its own range.
+        dev_mgr.unregister_device(dev_id)?;
+        assert!(dev_mgr
+            .register_device(dummy("again"), None, &mut fixed(0x1000_0000), &mut [], None)
+            .is_err());
+        dev_mgr.register_device(dummy("again"), None, &mut fixed(0x1000_2000), &mut [], None)?;
+        dev_mgr.unregister_device(other)?;
+        Ok(())
+    }
+
+    struct EventLog(Mutex<Vec<(DeviceEventKind, String)>>);
+
+    impl DeviceEventListener for EventLog {
+        fn on_event(&self, event: &DeviceEvent) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((event.kind, event.descriptor.path.clone()));
+        }
+    }
+
+    #[test]
+    fn test_device_events() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let log = Arc::new(EventLog(Mutex::new(Vec::new())));
+        let listener = dev_mgr.subscribe(log.clone());
+        let (_, receiver) = dev_mgr.subscribe_channel();
+
+        let bus = dev_mgr.register_device(dummy("bus"), None, &mut mmio(0x1000), &mut [], None)?;
+        let mut res = vec![
+            IoResource::new(Some(GuestAddress(0x1000_0000)), 0x1000, IoType::Mmio),
+            IoResource::new(Some(GuestAddress(0x3f8)), 0x8, IoType::Pio),
+        ];
+        let dev = dev_mgr.register_device(
+            dummy("dev"),
+            Some(bus),
+            &mut res,
+            &mut [IrqResource::new(None)],
+            None,
+        )?;
+
+        dev_mgr.relocate_resource(dev, 0, GuestAddress(0x1000_4000))?;
+        dev_mgr.set_resource_enabled(dev, 1, false)?;
+        let mut data = [0u8; 1];
+        assert!(dev_mgr
+            .read(GuestAddress(0x3f8), &mut data, IoType::Pio)
+            .is_err());
+        // Disabled resources can still be moved while they are not decoded.
+        dev_mgr.relocate_resource(dev, 1, GuestAddress(0x2f8))?;
+        assert!(dev_mgr
+            .read(GuestAddress(0x2f8), &mut data, IoType::Pio)
+            .is_err());
+        assert_eq!(dev_mgr.reassign_irq(dev, 0, Some(7))?, 7);
+        assert!(dev_mgr.reassign_irq(dev, 1, None).is_err());
+
+        dev_mgr.reset_all()?;
+        dev_mgr.read(GuestAddress(0x3f8), &mut data, IoType::Pio)?;
+        dev_mgr.unsubscribe(listener)?;
+        dev_mgr.unregister_subtree(bus)?;
+
+        let expected = vec![
+            (DeviceEventKind::Added, "/bus"),
+            (DeviceEventKind::Added, "/bus/dev"),
+            (DeviceEventKind::ResourceRelocated(0), "/bus/dev"),
+            (DeviceEventKind::ResourceDisabled(1), "/bus/dev"),
+            (DeviceEventKind::ResourceRelocated(1), "/bus/dev"),
+            (DeviceEventKind::IrqChanged(0), "/bus/dev"),
+            (DeviceEventKind::ResourceRelocated(0), "/bus/dev"),
+            (DeviceEventKind::ResourceRelocated(1), "/bus/dev"),
+            (DeviceEventKind::ResourceEnabled(1), "/bus/dev"),
+        ];
+        let logged = log.0.lock().unwrap().clone();
+        assert_eq!(
+            logged
+                .iter()
+                .map(|(k, p)| (*k, p.as_str()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        // The channel also got the removal events, after the listener was gone.
+        let events: Vec<DeviceEvent> = receiver.try_iter().collect();
+        assert_eq!(events.len(), expected.len() + 2);
+        assert_eq!(events[5].descriptor.irqs[0].irq, Some(7));
+        assert_eq!(events[expected.len()].kind, DeviceEventKind::Removed);
+        assert_eq!(events[expected.len()].instance_id, dev);
+        assert_eq!(events[expected.len() + 1].instance_id, bus);
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct IrqDevice {
+        group: Mutex<Option<Arc<dyn InterruptSourceGroup>>>,
+    }
+
+    impl Device for IrqDevice {
+        fn name(&self) -> String {
+            "irq-dev".to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {
+            if let Some(group) = self.group.lock().unwrap().as_ref() {
+                group.trigger(0).unwrap();
+            }
+        }
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+        fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+            *self.group.lock().unwrap() = Some(group);
+        }
+    }
+
+    #[test]
+    fn test_interrupt_group_delivery() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+
+        let dev = Arc::new(IrqDevice::default());
+        let mut res = vec![IoResource::new(Some(GuestAddress(0x3f8)), 8, IoType::Pio)];
+        let id = dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut res,
+            &mut [IrqResource::new(Some(4))],
+            None,
+        );
+        // IRQ 4 is outside of the allocator range.
+        assert!(id.is_err());
+        let id = dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut res,
+            &mut [IrqResource::new(Some(6))],
+            None,
+        )?;
+
+        let group = dev.group.lock().unwrap().clone().unwrap();
+        assert_eq!(group.interrupt_type(), InterruptSourceType::Legacy);
+        assert_eq!(group.base(), 6);
+        dev_mgr.write(GuestAddress(0x3f8), &[0], IoType::Pio)?;
+        let irqfds = irq_mgr.group(InterruptSourceType::Legacy, 6).unwrap();
+        assert_eq!(irqfds.irqfd(0).unwrap().read().unwrap(), 1);
+
+        // Rerouting the IRQ hands a new group to the device.
+        dev_mgr.reassign_irq(id, 0, Some(9))?;
+        assert_eq!(dev.group.lock().unwrap().as_ref().unwrap().base(), 9);
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 6).is_none());
+
+        dev_mgr.unregister_device(id)?;
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 9).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_irq() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+
+        let a = Arc::new(IrqDevice::default());
+        let b = Arc::new(IrqDevice::default());
+        let id_a = dev_mgr.register_device(
+            a.clone(),
+            None,
+            &mut mmio(0x1000),
+            &mut [IrqResource::shared(Some(10))],
+            None,
+        )?;
+        let id_b = dev_mgr.register_device(
+            b.clone(),
+            None,
+            &mut mmio(0x1000),
+            &mut [IrqResource::shared(None)],
+            None,
+        )?;
+        // A free line is preferred over joining a shared one.
+        assert_eq!(dev_mgr.get_descriptor(id_b).unwrap().irqs[0].irq, Some(5));
+        dev_mgr.reassign_irq(id_b, 0, Some(10))?;
+        assert_eq!(dev_mgr.allocator().irq_sharers(10), 2);
+        assert!(dev_mgr
+            .allocator()
+            .clone()
+            .allocate_shared_irq(Some(5))
+            .is_ok());
+        // An exclusive request can't take a shared line.
+        assert!(dev_mgr
+            .register_device(
+                dummy("excl"),
+                None,
+                &mut mmio(0x1000),
+                &mut [IrqResource::new(Some(10))],
+                None,
+            )
+            .is_err());
+
+        let group_a = a.group.lock().unwrap().clone().unwrap();
+        let group_b = b.group.lock().unwrap().clone().unwrap();
+        let irqfd = irq_mgr.group(InterruptSourceType::Legacy, 10).unwrap();
+        group_a.trigger(0).unwrap();
+        group_b.trigger(0).unwrap();
+        assert_eq!(irqfd.irqfd(0).unwrap().read().unwrap(), 1);
+        group_a.deassert(0).unwrap();
+        assert!(irqfd.irqfd(0).unwrap().read().is_err());
+
+        // The line outlives all but its last sharer.
+        dev_mgr.unregister_device(id_b)?;
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 10).is_some());
+        assert_eq!(dev_mgr.allocator().irq_sharers(10), 1);
+        dev_mgr.unregister_device(id_a)?;
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 10).is_none());
+        assert_eq!(
+            dev_mgr.allocator().clone().allocate_irq(Some(10)).unwrap(),
+            10
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multiple_irqs() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+
+        let uart_irqs = [
+            IrqResource::new(Some(7)),
+            IrqResource {
+                trigger: IrqTrigger::Level,
+                polarity: IrqPolarity::ActiveLow,
+                ..IrqResource::new(None)
+            },
+        ];
+        let mut irqs = uart_irqs;
+        let uart =
+            dev_mgr.register_device(dummy("uart"), None, &mut mmio(0x1000), &mut irqs, None)?;
+        assert_eq!((irqs[0].irq, irqs[1].irq), (Some(7), Some(5)));
+        assert_eq!(irqs[1].trigger, IrqTrigger::Level);
+        assert_eq!(
+            dev_mgr.get_descriptor(uart).unwrap().interrupt_groups.len(),
+            2
+        );
+
+        // A failing IRQ releases all the resources of the device.
+        let mut irqs = [IrqResource::new(Some(8)), IrqResource::new(Some(7))];
+        assert!(dev_mgr
+            .register_device(dummy("gpio"), None, &mut mmio(0x1000), &mut irqs, None)
+            .is_err());
+        assert_eq!(irqs[0].irq, Some(8));
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 8).is_none());
+        let mut irqs = [IrqResource::new(Some(8))];
+        let gpio =
+            dev_mgr.register_device(dummy("gpio"), None, &mut mmio(0x1000), &mut irqs, None)?;
+
+        assert_eq!(dev_mgr.reassign_irq(uart, 1, Some(9))?, 9);
+        let interrupts: Vec<(u32, Option<u32>, IrqTrigger)> = dev_mgr
+            .interrupts()
+            .iter()
+            .map(|(id, irq)| (*id, irq.irq, irq.trigger))
+            .collect();
+        assert_eq!(
+            interrupts,
+            vec![
+                (uart, Some(7), IrqTrigger::Edge),
+                (uart, Some(9), IrqTrigger::Level),
+                (gpio, Some(8), IrqTrigger::Edge),
+            ]
+        );
+
+        dev_mgr.unregister_device(uart)?;
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 7).is_none());
+        assert!(irq_mgr.group(InterruptSourceType::Legacy, 9).is_none());
+        assert_eq!(dev_mgr.allocator().clone().allocate_irq(None).unwrap(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gsi_routing() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        dev_mgr.set_interrupt_manager(Arc::new(EventFdInterruptManager::new()));
+        dev_mgr.allocator().clone().set_msi_range(24, 63).unwrap();
+        let ioapic = |pin| Route::Irqchip {
+            irqchip: IRQCHIP_IOAPIC,
+            pin,
+        };
+
+        let dev = Arc::new(IrqDevice::default());
+        let id = dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut mmio(0x1000),
+            &mut [IrqResource::new(Some(5))],
+            Some(MsiResource::msix(2)),
+        )?;
+        let mut shared = Vec::new();
+        for _ in 0..2 {
+            shared.push(dev_mgr.register_device(
+                dummy("intx"),
+                None,
+                &mut mmio(0x1000),
+                &mut [IrqResource::shared(Some(10))],
+                None,
+            )?);
+        }
+
+        let table = dev_mgr.gsi_routing();
+        let gsis: Vec<u32> = table.entries().iter().map(|e| e.gsi).collect();
+        assert_eq!(gsis, vec![5, 5, 10, 10, 24, 25]);
+        assert_eq!(
+            table.routes(10),
+            &[
+                Route::Irqchip {
+                    irqchip: IRQCHIP_PIC_SLAVE,
+                    pin: 2,
+                },
+                ioapic(10),
+            ]
+        );
+        assert_eq!(table.routes(25), &[Route::Msi(Default::default())]);
+
+        // The guest programming a MSI-X vector updates its route.
+        let msg = MsiIrqSourceConfig {
+            high_addr: 0,
+            low_addr: 0xfee0_0000,
+            data: 0x31,
+        };
+        let msi_group = dev.group.lock().unwrap().clone().unwrap();
+        msi_group
+            .update(1, &InterruptSourceConfig::MsiIrq(msg))
+            .unwrap();
+        let updated = dev_mgr.gsi_routing();
+        assert_eq!(updated.routes(25), &[Route::Msi(msg)]);
+        assert!(updated.version() > table.version());
+
+        dev_mgr.reassign_irq(id, 0, Some(9))?;
+        let table = dev_mgr.gsi_routing();
+        assert!(table.routes(5).is_empty());
+        assert_eq!(table.routes(9)[1], ioapic(9));
+
+        // A shared IRQ stays routed until its last sharer goes away.
+        dev_mgr.unregister_device(shared[0])?;
+        assert_eq!(dev_mgr.gsi_routing().routes(10).len(), 2);
+        dev_mgr.unregister_device(shared[1])?;
+        assert!(dev_mgr.gsi_routing().routes(10).is_empty());
+        dev_mgr.unregister_device(id)?;
+        assert!(dev_mgr.gsi_routing().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_msi_resource() -> Result<()> {
+        let mut dev_mgr = new_device_manager();
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+
+        // No MSI range set yet.
+        assert!(dev_mgr
+            .register_device(
+                dummy("net"),
+                None,
+                &mut mmio(0x1000),
+                &mut [],
+                Some(MsiResource::msi(2))
+            )
+            .is_err());
+        dev_mgr.allocator().clone().set_msi_range(24, 63).unwrap();
+
+        let dev = Arc::new(IrqDevice::default());
+        let blk = dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut mmio(0x1000),
+            &mut [],
+            Some(MsiResource::msix(5)),
+        )?;
+        let net = dev_mgr.register_device(
+            dummy("net"),
+            None,
+            &mut mmio(0x1000),
+            &mut [IrqResource::new(None)],
+            Some(MsiResource::msi(3)),
+        )?;
+        assert_eq!(
+            dev_mgr.get_descriptor(net).unwrap().msi,
+            Some(MsiResource {
+                base: Some(32),
+                count: 3,
+                msix: false,
+            })
+        );
+        assert_eq!(dev_mgr.allocator().msi_blocks(), vec![(24, 5), (32, 4)]);
+
+        let group = dev.group.lock().unwrap().clone().unwrap();
+        assert_eq!(group.interrupt_type(), InterruptSourceType::MsiX);
+        assert_eq!((group.base(), group.len()), (24, 5));
+
+        // A failed registration gives back everything it allocated.
+        assert!(dev_mgr
+            .register_device(
+                dummy("big"),
+                None,
+                &mut mmio(0x1000),
+                &mut [IrqResource::new(None)],
+                Some(MsiResource::msi(64)),
+            )
+            .is_err());
+        assert_eq!(dev_mgr.allocator().msi_blocks().len(), 2);
+
+        dev_mgr.unregister_device(blk)?;
+        dev_mgr.unregister_device(net)?;
+        assert!(dev_mgr.allocator().msi_blocks().is_empty());
+        assert!(irq_mgr.group(InterruptSourceType::Msi, 32).is_none());
+        Ok(())
+    }
 }
@@ -0,0 +1,115 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device events notification.
+//!
+//! Subsystems like ACPI or FDT generation and monitoring need to stay in sync
+//! with the devices known to the
+//! [DeviceManager](../device_manager/struct.DeviceManager.html). They can
+//! subscribe to the manager and get a [DeviceEvent](struct.DeviceEvent.html)
+//! each time a device is added, removed, or has its resources changed, either
+//! through a synchronous [DeviceEventListener](trait.DeviceEventListener.html)
+//! callback or through a channel.
+
+use crate::device::{DeviceDescriptor, IoResource, IrqResource, MsiResource};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// Plain data copy of a `DeviceDescriptor`, without the device object.
+///
+/// It can be sent across threads along with the event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceSnapshot {
+    /// Device instance id.
+    pub instance_id: u32,
+    /// Device type name.
+    pub name: String,
+    /// Device path in the topology.
+    pub path: String,
+    /// Instance id of the parent bus.
+    pub parent_bus: Option<u32>,
+    /// Instance ids of the devices behind this device.
+    pub children: Vec<u32>,
+    /// Device resource set.
+    pub resources: Vec<IoResource>,
+    /// Indexes of the IO resources currently disabled.
+    pub disabled_resources: Vec<usize>,
+    /// Device IRQ resources.
+    pub irqs: Vec<IrqResource>,
+    /// Device MSI or MSI-X vectors.
+    pub msi: Option<MsiResource>,
+}
+
+impl<'a> From<&'a DeviceDescriptor> for DeviceSnapshot {
+    fn from(d: &'a DeviceDescriptor) -> Self {
+        DeviceSnapshot {
+            instance_id: d.instance_id,
+            name: d.name.clone(),
+            path: d.path.clone(),
+            parent_bus: d.parent_bus,
+            children: d.children.clone(),
+            resources: d.resources.clone(),
+            disabled_resources: d.disabled_resources.clone(),
+            irqs: d.irqs.clone(),
+            msi: d.msi,
+        }
+    }
+}
+
+/// Kind of device change.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DeviceEventKind {
+    /// The device has been registered.
+    Added,
+    /// The device has been unregistered.
+    Removed,
+    /// The IO resource at the given index has been moved.
+    ResourceRelocated(usize),
+    /// The IO resource at the given index is decoded again.
+    ResourceEnabled(usize),
+    /// The IO resource at the given index is no longer decoded.
+    ResourceDisabled(usize),
+    /// The IRQ resource at the given index has been changed.
+    IrqChanged(usize),
+}
+
+/// Device change notification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceEvent {
+    /// What happened to the device.
+    pub kind: DeviceEventKind,
+    /// Device instance id.
+    pub instance_id: u32,
+    /// Device state after the change, or before removal.
+    pub descriptor: DeviceSnapshot,
+}
+
+/// Trait for objects notified synchronously of device changes.
+pub trait DeviceEventListener: Send {
+    /// Handle a device event.
+    ///
+    /// This is called from within the `DeviceManager` operation which
+    /// triggered the event, so it must not call back into the manager.
+    fn on_event(&self, event: &DeviceEvent);
+}
+
+/// Subscriber of device events.
+pub(crate) enum Subscriber {
+    /// Synchronous callback.
+    Listener(Arc<dyn DeviceEventListener>),
+    /// Channel the events are sent to.
+    Channel(Sender<DeviceEvent>),
+}
+
+impl Subscriber {
+    /// Deliver an event, return false if the subscriber has gone away.
+    pub(crate) fn notify(&self, event: &DeviceEvent) -> bool {
+        match self {
+            Subscriber::Listener(listener) => {
+                listener.on_event(event);
+                true
+            }
+            Subscriber::Channel(sender) => sender.send(event.clone()).is_ok(),
+        }
+    }
+}
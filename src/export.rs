@@ -0,0 +1,458 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Device topology and address map dumps.
+//!
+//! Helps debugging a guest by exporting the device layout the
+//! [DeviceManager](../device_manager/struct.DeviceManager.html) built:
+//! - a JSON document listing devices, their parent relationships and
+//!   allocated resources,
+//! - a Graphviz DOT graph of the topology,
+//! - a QEMU `info mtree` style dump of the PIO and MMIO address spaces,
+//!   including the free holes left in the `SystemAllocator`.
+
+use crate::device::{DeviceDescriptor, IoResource, IoType, IrqController, IrqPolarity, IrqTrigger};
+use crate::device_manager::DeviceManager;
+use std::fmt::Write;
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// Version of the JSON document layout, bumped on incompatible changes.
+pub const JSON_EXPORT_VERSION: u32 = 1;
+
+fn io_type_name(io_type: IoType) -> &'static str {
+    match io_type {
+        IoType::Pio => "pio",
+        IoType::Mmio => "mmio",
+        IoType::PhysicalMmio => "physical-mmio",
+        IoType::PciMmio32 => "pci-mmio32",
+        IoType::PciMmio64 => "pci-mmio64",
+    }
+}
+
+// Escape a string to be used inside double quotes in JSON or DOT.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn last_address(addr: GuestAddress, size: GuestUsize) -> GuestAddress {
+    addr.unchecked_add(size.saturating_sub(1))
+}
+
+fn irq_controller_name(controller: IrqController) -> &'static str {
+    match controller {
+        IrqController::Pic => "pic",
+        IrqController::IoApic => "ioapic",
+        IrqController::Gic => "gic",
+    }
+}
+
+impl DeviceManager {
+    fn descriptors_in_order(&self) -> Vec<&DeviceDescriptor> {
+        self.topology_order()
+            .into_iter()
+            .filter_map(|id| self.get_descriptor(id))
+            .collect()
+    }
+
+    /// Export the devices, their parent relationships, IO resources, IRQs and
+    /// MSI vectors as a JSON document.
+    ///
+    /// Devices are listed in topology order, with the indexes of the IO
+    /// resources they don't currently decode in `disabled_resources`. Addresses and sizes are
+    /// hexadecimal strings so that 64-bit values survive any JSON parser.
+    pub fn export_json(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{{");
+        let _ = writeln!(out, "  \"version\": {},", JSON_EXPORT_VERSION);
+        let _ = write!(out, "  \"devices\": [");
+
+        for (idx, d) in self.descriptors_in_order().iter().enumerate() {
+            let _ = write!(out, "{}\n    {{\n", if idx == 0 { "" } else { "," });
+            let _ = writeln!(out, "      \"instance_id\": {},", d.instance_id);
+            let _ = writeln!(out, "      \"name\": \"{}\",", escape(&d.name));
+            let _ = writeln!(out, "      \"path\": \"{}\",", escape(&d.path));
+            match d.parent_bus {
+                Some(parent) => {
+                    let _ = writeln!(out, "      \"parent\": {},", parent);
+                }
+                None => {
+                    let _ = writeln!(out, "      \"parent\": null,");
+                }
+            }
+            let children: Vec<String> = d.children.iter().map(u32::to_string).collect();
+            let _ = writeln!(out, "      \"children\": [{}],", children.join(", "));
+            let resources: Vec<String> = d
+                .resources
+                .iter()
+                .map(|r| {
+                    format!(
+                        "\n        {{ \"type\": \"{}\", \"addr\": \"{:#x}\", \"size\": \"{:#x}\" }}",
+                        io_type_name(r.res_type),
+                        r.addr.map_or(0, |a| a.raw_value()),
+                        r.size
+                    )
+                })
+                .collect();
+            if resources.is_empty() {
+                let _ = writeln!(out, "      \"resources\": [],");
+            } else {
+                let _ = writeln!(
+                    out,
+                    "      \"resources\": [{}\n      ],",
+                    resources.join(",")
+                );
+            }
+            let mut disabled = d.disabled_resources.clone();
+            disabled.sort();
+            let disabled: Vec<String> = disabled.iter().map(usize::to_string).collect();
+            let _ = writeln!(
+                out,
+                "      \"disabled_resources\": [{}],",
+                disabled.join(", ")
+            );
+            let irqs: Vec<String> = d
+                .irqs
+                .iter()
+                .map(|irq| {
+                    format!(
+                        "\n        {{ \"irq\": {}, \"trigger\": \"{}\", \"polarity\": \"{}\", \"controller\": \"{}\", \"shared\": {} }}",
+                        irq.irq.unwrap_or_default(),
+                        match irq.trigger {
+                            IrqTrigger::Edge => "edge",
+                            IrqTrigger::Level => "level",
+                        },
+                        match irq.polarity {
+                            IrqPolarity::ActiveHigh => "high",
+                            IrqPolarity::ActiveLow => "low",
+                        },
+                        irq_controller_name(irq.controller),
+                        irq.shared
+                    )
+                })
+                .collect();
+            if irqs.is_empty() {
+                let _ = writeln!(out, "      \"irqs\": [],");
+            } else {
+                let _ = writeln!(out, "      \"irqs\": [{}\n      ],", irqs.join(","));
+            }
+            match d.msi {
+                Some(msi) => {
+                    let _ = writeln!(
+                        out,
+                        "      \"msi\": {{ \"type\": \"{}\", \"base\": {}, \"count\": {} }}",
+                        if msi.msix { "msix" } else { "msi" },
+                        msi.base.unwrap_or_default(),
+                        msi.count
+                    );
+                }
+                None => {
+                    let _ = writeln!(out, "      \"msi\": null");
+                }
+            }
+            let _ = write!(out, "    }}");
+        }
+
+        let _ = writeln!(out, "\n  ]");
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// Export the device topology as a Graphviz DOT graph.
+    ///
+    /// Each node is labelled with the device path, its IO resources and IRQs,
+    /// and edges go from a parent bus to the devices behind it.
+    pub fn export_dot(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph devices {{");
+        let _ = writeln!(out, "  node [shape=box];");
+
+        let descriptors = self.descriptors_in_order();
+        for d in descriptors.iter() {
+            let mut label = escape(&d.path);
+            for r in d.resources.iter() {
+                let addr = r.try_unwrap();
+                let _ = write!(
+                    label,
+                    "\\n{} {:#x}-{:#x}",
+                    io_type_name(r.res_type),
+                    addr.raw_value(),
+                    last_address(addr, r.size).raw_value()
+                );
+            }
+            for irq in d.irqs.iter() {
+                let _ = write!(label, "\\nirq {}", irq.irq.unwrap_or_default());
+            }
+            let _ = writeln!(out, "  dev{} [label=\"{}\"];", d.instance_id, label);
+        }
+        for d in descriptors.iter() {
+            for child in d.children.iter() {
+                let _ = writeln!(out, "  dev{} -> dev{};", d.instance_id, child);
+            }
+        }
+
+        let _ = writeln!(out, "}}");
+        out
+    }
+
+    /// Dump the PIO and MMIO address maps in the style of QEMU `info mtree`.
+    ///
+    /// Every address space lists the device ranges registered in it along
+    /// with the free holes left in the `SystemAllocator` window.
+    pub fn address_map(&self) -> String {
+        let mut out = String::new();
+        let allocator = self.allocator();
+
+        if let Some(window) = allocator.io_window() {
+            self.dump_address_space(&mut out, "I/O", window, &allocator.io_free_ranges(), |r| {
+                match r.res_type {
+                    IoType::Pio => Some("i/o"),
+                    _ => None,
+                }
+            });
+        }
+        self.dump_address_space(
+            &mut out,
+            "memory",
+            allocator.mmio_window(),
+            &allocator.mmio_free_ranges(),
+            |r| match r.res_type {
+                IoType::Mmio => Some("i/o"),
+                IoType::PhysicalMmio => Some("ramd"),
+                _ => None,
+            },
+        );
+        // PCI BARs are listed in the window they were placed in, whatever
+        // their type.
+        let pci_windows = [
+            (
+                "pci-mmio32",
+                allocator.pci_mmio32_window(),
+                allocator.pci_mmio32_free_ranges(),
+            ),
+            (
+                "pci-mmio64",
+                allocator.pci_mmio64_window(),
+                allocator.pci_mmio64_free_ranges(),
+            ),
+        ];
+        for (name, window, holes) in pci_windows.iter() {
+            if let Some((base, size)) = *window {
+                self.dump_address_space(&mut out, name, (base, size), holes, |r| {
+                    match (r.res_type, r.addr) {
+                        (IoType::PciMmio32, Some(addr)) | (IoType::PciMmio64, Some(addr))
+                            if addr >= base && addr <= last_address(base, size) =>
+                        {
+                            Some("i/o")
+                        }
+                        _ => None,
+                    }
+                });
+            }
+        }
+        out
+    }
+
+    fn dump_address_space<F>(
+        &self,
+        out: &mut String,
+        name: &str,
+        window: (GuestAddress, GuestUsize),
+        holes: &[(GuestAddress, GuestUsize)],
+        kind: F,
+    ) where
+        F: Fn(&IoResource) -> Option<&'static str>,
+    {
+        let mut entries: Vec<(GuestAddress, GuestUsize, String)> = holes
+            .iter()
+            .map(|(addr, size)| (*addr, *size, "(free)".to_string()))
+            .collect();
+        for d in self.descriptors_in_order() {
+            for r in d.resources.iter() {
+                if let Some(kind) = kind(r) {
+                    entries.push((
+                        r.try_unwrap(),
+                        r.size,
+                        format!("(prio 0, {}): {}", kind, d.path),
+                    ));
+                }
+            }
+        }
+        entries.sort_by_key(|e| e.0);
+
+        let _ = writeln!(out, "address-space: {}", name);
+        let _ = writeln!(
+            out,
+            "  {:016x}-{:016x} (prio 0, container): {}",
+            window.0.raw_value(),
+            last_address(window.0, window.1).raw_value(),
+            name
+        );
+        for (addr, size, label) in entries {
+            let _ = writeln!(
+                out,
+                "    {:016x}-{:016x} {}",
+                addr.raw_value(),
+                last_address(addr, size).raw_value(),
+                label
+            );
+        }
+        let _ = writeln!(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        Device, IoResource, IoType, IrqPolarity, IrqResource, IrqTrigger, MsiResource,
+    };
+    use crate::device_manager::*;
+    use std::sync::Arc;
+    use vm_allocator::SystemAllocator;
+    use vm_memory::{GuestAddress, GuestUsize};
+
+    struct DummyDevice(&'static str);
+
+    impl Device for DummyDevice {
+        fn name(&self) -> String {
+            self.0.to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+    }
+
+    fn device_manager() -> DeviceManager {
+        let mut sys_res = SystemAllocator::new(
+            Some(GuestAddress(0x0)),
+            Some(0x1000),
+            GuestAddress(0x1000_0000),
+            0x10_0000,
+            5,
+            15,
+            1,
+        )
+        .unwrap();
+        sys_res.set_msi_range(24, 31).unwrap();
+        let mut dev_mgr = DeviceManager::new(sys_res);
+
+        let mut bus_res = vec![IoResource::new(
+            Some(GuestAddress(0xcf8)),
+            8 as GuestUsize,
+            IoType::Pio,
+        )];
+        let bus = dev_mgr
+            .register_device(
+                Arc::new(DummyDevice("pci0")),
+                None,
+                &mut bus_res,
+                &mut [],
+                None,
+            )
+            .unwrap();
+        let mut dev_res = vec![IoResource::new(
+            Some(GuestAddress(0x1000_0000)),
+            0x1000,
+            IoType::Mmio,
+        )];
+        dev_mgr
+            .register_device(
+                Arc::new(DummyDevice("virtio-\"net\"")),
+                Some(bus),
+                &mut dev_res,
+                &mut [
+                    IrqResource::new(Some(5)),
+                    IrqResource {
+                        trigger: IrqTrigger::Level,
+                        polarity: IrqPolarity::ActiveLow,
+                        ..IrqResource::new(Some(6))
+                    },
+                ],
+                Some(MsiResource::msix(2)),
+            )
+            .unwrap();
+        dev_mgr
+    }
+
+    #[test]
+    fn test_export_json() {
+        let expected = r#"{
+  "version": 1,
+  "devices": [
+    {
+      "instance_id": 1,
+      "name": "pci0",
+      "path": "/pci0",
+      "parent": null,
+      "children": [2],
+      "resources": [
+        { "type": "pio", "addr": "0xcf8", "size": "0x8" }
+      ],
+      "disabled_resources": [],
+      "irqs": [],
+      "msi": null
+    },
+    {
+      "instance_id": 2,
+      "name": "virtio-\"net\"",
+      "path": "/pci0/virtio-\"net\"",
+      "parent": 1,
+      "children": [],
+      "resources": [
+        { "type": "mmio", "addr": "0x10000000", "size": "0x1000" }
+      ],
+      "disabled_resources": [0],
+      "irqs": [
+        { "irq": 5, "trigger": "edge", "polarity": "high", "controller": "ioapic", "shared": false },
+        { "irq": 6, "trigger": "level", "polarity": "low", "controller": "ioapic", "shared": false }
+      ],
+      "msi": { "type": "msix", "base": 24, "count": 2 }
+    }
+  ]
+}
+"#;
+        let mut dev_mgr = device_manager();
+        dev_mgr.set_resource_enabled(2, 0, false).unwrap();
+        assert_eq!(dev_mgr.export_json(), expected);
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let expected = r#"digraph devices {
+  node [shape=box];
+  dev1 [label="/pci0\npio 0xcf8-0xcff"];
+  dev2 [label="/pci0/virtio-\"net\"\nmmio 0x10000000-0x10000fff\nirq 5\nirq 6"];
+  dev1 -> dev2;
+}
+"#;
+        assert_eq!(device_manager().export_dot(), expected);
+    }
+
+    #[test]
+    fn test_address_map() {
+        let expected = "address-space: I/O
+  0000000000000000-0000000000000fff (prio 0, container): I/O
+    0000000000000000-0000000000000cf7 (free)
+    0000000000000cf8-0000000000000cff (prio 0, i/o): /pci0
+    0000000000000d00-0000000000000fff (free)
+
+address-space: memory
+  0000000010000000-00000000100fffff (prio 0, container): memory
+    0000000010000000-0000000010000fff (prio 0, i/o): /pci0/virtio-\"net\"
+    0000000010001000-00000000100fffff (free)
+
+";
+        assert_eq!(device_manager().address_map(), expected);
+    }
+}
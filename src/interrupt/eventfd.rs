@@ -0,0 +1,349 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Interrupt sources backed by Linux eventfds.
+//!
+//! Triggering an interrupt source writes to its eventfd. A VMM typically
+//! registers the eventfds as KVM irqfds so that the hypervisor injects the
+//! interrupt without going back to userspace, or polls them itself.
+
+use super::{
+    Error, InterruptManager, InterruptSourceConfig, InterruptSourceGroup, InterruptSourceType,
+    LegacyIrqSourceConfig, MsiIrqSourceConfig, Result,
+};
+use libc::{c_void, eventfd, read, write, EFD_CLOEXEC, EFD_NONBLOCK};
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+/// Wrapper around a non-blocking Linux eventfd.
+#[derive(Debug)]
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    /// Create a new non-blocking eventfd.
+    pub fn new() -> io::Result<EventFd> {
+        // Safe because eventfd() only creates a new file descriptor.
+        let fd = unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the new file descriptor.
+        Ok(EventFd {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    /// Add `v` to the eventfd counter.
+    pub fn write(&self, v: u64) -> io::Result<()> {
+        // Safe because the buffer is a valid u64 we own.
+        let ret = unsafe {
+            write(
+                self.as_raw_fd(),
+                &v as *const u64 as *const c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if ret <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Read and reset the eventfd counter.
+    ///
+    /// Fails with `io::ErrorKind::WouldBlock` if the counter is zero.
+    pub fn read(&self) -> io::Result<u64> {
+        let mut buf: u64 = 0;
+        // Safe because the buffer is a valid u64 we own.
+        let ret = unsafe {
+            read(
+                self.as_raw_fd(),
+                &mut buf as *mut u64 as *mut c_void,
+                mem::size_of::<u64>(),
+            )
+        };
+        if ret <= 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buf)
+    }
+
+    /// Duplicate the eventfd, both ends share the same counter.
+    pub fn try_clone(&self) -> io::Result<EventFd> {
+        Ok(EventFd {
+            file: self.file.try_clone()?,
+        })
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// Mask state of an interrupt source, and whether an interrupt was raised
+// while it was masked.
+#[derive(Clone, Copy)]
+struct SourceState {
+    masked: bool,
+    pending: bool,
+}
+
+/// Interrupt source group whose sources are backed by eventfds.
+pub struct EventFdInterruptGroup {
+    ty: InterruptSourceType,
+    base: u32,
+    irqfds: Vec<EventFd>,
+    // Both the mask and pending bits of a source are updated under the lock,
+    // so an interrupt raised while the source gets unmasked isn't lost.
+    sources: Mutex<Vec<SourceState>>,
+    configs: Mutex<Vec<InterruptSourceConfig>>,
+}
+
+impl EventFdInterruptGroup {
+    /// Create a group of `count` interrupt sources of type `ty` starting at
+    /// interrupt number `base`.
+    pub fn new(ty: InterruptSourceType, base: u32, count: u32) -> Result<Self> {
+        if count == 0 || count > ty.max_sources() {
+            return Err(Error::NotSupported);
+        }
+        let default_config = match ty {
+            InterruptSourceType::Legacy => {
+                InterruptSourceConfig::LegacyIrq(LegacyIrqSourceConfig::default())
+            }
+            InterruptSourceType::Msi | InterruptSourceType::MsiX => {
+                InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig::default())
+            }
+        };
+        let mut irqfds = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            irqfds.push(EventFd::new().map_err(Error::Io)?);
+        }
+
+        Ok(EventFdInterruptGroup {
+            ty,
+            base,
+            irqfds,
+            // Message signaled interrupts come up masked until the guest
+            // programs and enables them.
+            sources: Mutex::new(vec![
+                SourceState {
+                    masked: ty != InterruptSourceType::Legacy,
+                    pending: false,
+                };
+                count as usize
+            ]),
+            configs: Mutex::new(vec![default_config; count as usize]),
+        })
+    }
+
+    /// Return the eventfd backing the interrupt source at `index`.
+    pub fn irqfd(&self, index: u32) -> Option<&EventFd> {
+        self.irqfds.get(index as usize)
+    }
+
+    fn check_index(&self, index: u32) -> Result<usize> {
+        if index < self.len() {
+            Ok(index as usize)
+        } else {
+            Err(Error::InvalidIndex(index))
+        }
+    }
+}
+
+impl InterruptSourceGroup for EventFdInterruptGroup {
+    fn interrupt_type(&self) -> InterruptSourceType {
+        self.ty
+    }
+
+    fn len(&self) -> u32 {
+        self.irqfds.len() as u32
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn trigger(&self, index: u32) -> Result<()> {
+        let idx = self.check_index(index)?;
+        let mut sources = self.sources.lock().expect("failed to acquire lock");
+        if sources[idx].masked {
+            sources[idx].pending = true;
+            return Ok(());
+        }
+        self.irqfds[idx].write(1).map_err(Error::Io)
+    }
+
+    fn mask(&self, index: u32) -> Result<()> {
+        let idx = self.check_index(index)?;
+        self.sources.lock().expect("failed to acquire lock")[idx].masked = true;
+        Ok(())
+    }
+
+    fn unmask(&self, index: u32) -> Result<()> {
+        let idx = self.check_index(index)?;
+        let mut sources = self.sources.lock().expect("failed to acquire lock");
+        let source = &mut sources[idx];
+        source.masked = false;
+        if mem::replace(&mut source.pending, false) {
+            return self.irqfds[idx].write(1).map_err(Error::Io);
+        }
+        Ok(())
+    }
+
+    fn is_pending(&self, index: u32) -> Result<bool> {
+        let idx = self.check_index(index)?;
+        Ok(self.sources.lock().expect("failed to acquire lock")[idx].pending)
+    }
+
+    fn update(&self, index: u32, config: &InterruptSourceConfig) -> Result<()> {
+        let idx = self.check_index(index)?;
+        match (self.ty, config) {
+            (InterruptSourceType::Legacy, InterruptSourceConfig::LegacyIrq(_))
+            | (InterruptSourceType::Msi, InterruptSourceConfig::MsiIrq(_))
+            | (InterruptSourceType::MsiX, InterruptSourceConfig::MsiIrq(_)) => {
+                self.configs.lock().expect("failed to acquire lock")[idx] = *config;
+                Ok(())
+            }
+            _ => Err(Error::InvalidConfig),
+        }
+    }
+
+    fn config(&self, index: u32) -> Result<InterruptSourceConfig> {
+        let idx = self.check_index(index)?;
+        Ok(self.configs.lock().expect("failed to acquire lock")[idx])
+    }
+}
+
+/// Interrupt manager creating eventfd backed interrupt source groups.
+///
+/// It keeps track of the groups it created so that the VMM can wire their
+/// eventfds, e.g. as KVM irqfds.
+#[derive(Default)]
+pub struct EventFdInterruptManager {
+    groups: Mutex<Vec<Arc<EventFdInterruptGroup>>>,
+}
+
+impl EventFdInterruptManager {
+    /// Create a new `EventFdInterruptManager`.
+    pub fn new() -> Self {
+        EventFdInterruptManager::default()
+    }
+
+    /// Return the group of type `ty` whose first interrupt number is `base`.
+    pub fn group(&self, ty: InterruptSourceType, base: u32) -> Option<Arc<EventFdInterruptGroup>> {
+        self.groups
+            .lock()
+            .expect("failed to acquire lock")
+            .iter()
+            .find(|g| g.ty == ty && g.base == base)
+            .cloned()
+    }
+}
+
+impl InterruptManager for EventFdInterruptManager {
+    fn create_group(
+        &self,
+        ty: InterruptSourceType,
+        base: u32,
+        count: u32,
+    ) -> Result<Arc<dyn InterruptSourceGroup>> {
+        let group = Arc::new(EventFdInterruptGroup::new(ty, base, count)?);
+        self.groups
+            .lock()
+            .expect("failed to acquire lock")
+            .push(group.clone());
+        Ok(group)
+    }
+
+    fn destroy_group(&self, group: Arc<dyn InterruptSourceGroup>) -> Result<()> {
+        let mut groups = self.groups.lock().expect("failed to acquire lock");
+        // Compare the data pointers, the group has been handed out as a trait object.
+        let target = &*group as *const dyn InterruptSourceGroup as *const u8;
+        let idx = groups
+            .iter()
+            .position(|g| &**g as *const EventFdInterruptGroup as *const u8 == target)
+            .ok_or(Error::UnknownGroup)?;
+        groups.remove(idx);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eventfd() {
+        let evt = EventFd::new().unwrap();
+        let clone = evt.try_clone().unwrap();
+        assert_eq!(evt.read().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        evt.write(2).unwrap();
+        clone.write(3).unwrap();
+        assert_eq!(evt.read().unwrap(), 5);
+    }
+
+    #[test]
+    fn test_legacy_group() {
+        let manager = EventFdInterruptManager::new();
+        let group = manager
+            .create_group(InterruptSourceType::Legacy, 5, 1)
+            .unwrap();
+        let irqfd = manager.group(InterruptSourceType::Legacy, 5).unwrap();
+
+        assert_eq!(group.len(), 1);
+        assert_eq!(group.base(), 5);
+        group.trigger(0).unwrap();
+        assert_eq!(irqfd.irqfd(0).unwrap().read().unwrap(), 1);
+        assert!(group.trigger(1).is_err());
+
+        let msi = InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig::default());
+        assert!(group.update(0, &msi).is_err());
+        assert!(manager
+            .create_group(InterruptSourceType::Legacy, 6, 2)
+            .is_err());
+
+        manager.destroy_group(group.clone()).unwrap();
+        assert!(manager.group(InterruptSourceType::Legacy, 5).is_none());
+        assert!(manager.destroy_group(group).is_err());
+    }
+
+    #[test]
+    fn test_msi_group_masking() {
+        let manager = EventFdInterruptManager::new();
+        let group = manager
+            .create_group(InterruptSourceType::MsiX, 24, 4)
+            .unwrap();
+        let irqfds = manager.group(InterruptSourceType::MsiX, 24).unwrap();
+        let irqfd = irqfds.irqfd(2).unwrap();
+
+        let config = InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig {
+            high_addr: 0,
+            low_addr: 0xfee0_0000,
+            data: 0x31,
+        });
+        group.update(2, &config).unwrap();
+        assert_eq!(group.config(2).unwrap(), config);
+
+        // Vectors come up masked, triggering only sets the pending bit.
+        group.trigger(2).unwrap();
+        assert!(group.is_pending(2).unwrap());
+        assert!(irqfd.read().is_err());
+
+        group.unmask(2).unwrap();
+        assert!(!group.is_pending(2).unwrap());
+        assert_eq!(irqfd.read().unwrap(), 1);
+
+        group.trigger(2).unwrap();
+        assert_eq!(irqfd.read().unwrap(), 1);
+        group.mask(2).unwrap();
+        group.trigger(2).unwrap();
+        assert!(irqfd.read().is_err());
+    }
+}
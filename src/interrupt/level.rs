@@ -0,0 +1,290 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Level-triggered legacy interrupt lines shared by several devices.
+//!
+//! Legacy pins like the PCI INTx ones are level-triggered and wired-OR:
+//! several devices can sit on the same line, and the line stays asserted as
+//! long as any of them asserts it. A [SharedIrqLine](struct.SharedIrqLine.html)
+//! wraps the legacy interrupt group of the line and gives each sharer its own
+//! [SharedIrqSource](struct.SharedIrqSource.html). Triggering a source raises
+//! the level of that sharer until it calls `deassert()`, and the line group
+//! is only triggered when the line goes from deasserted to asserted.
+
+use super::{Error, InterruptSourceConfig, InterruptSourceGroup, InterruptSourceType, Result};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Level and mask state of one sharer of a line.
+#[derive(Default)]
+struct SharerState {
+    asserted: bool,
+    masked: bool,
+}
+
+impl SharerState {
+    fn level(&self) -> bool {
+        self.asserted && !self.masked
+    }
+}
+
+/// Level-triggered legacy interrupt line shared by several devices.
+pub struct SharedIrqLine {
+    group: Arc<dyn InterruptSourceGroup>,
+    /// Sharers state mapped by owner id.
+    sharers: Mutex<BTreeMap<u32, SharerState>>,
+}
+
+impl SharedIrqLine {
+    /// Create a line delivering its interrupts through the legacy `group`.
+    pub fn new(group: Arc<dyn InterruptSourceGroup>) -> Result<Self> {
+        if group.interrupt_type() != InterruptSourceType::Legacy {
+            return Err(Error::InvalidConfig);
+        }
+        Ok(SharedIrqLine {
+            group,
+            sharers: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Legacy interrupt group of the line.
+    pub fn group(&self) -> &Arc<dyn InterruptSourceGroup> {
+        &self.group
+    }
+
+    /// IRQ number of the line.
+    pub fn irq(&self) -> u32 {
+        self.group.base()
+    }
+
+    /// Number of devices sharing the line.
+    pub fn sharers(&self) -> usize {
+        self.sharers.lock().expect("failed to acquire lock").len()
+    }
+
+    /// Return true if any unmasked sharer asserts the line.
+    pub fn is_asserted(&self) -> bool {
+        self.sharers
+            .lock()
+            .expect("failed to acquire lock")
+            .values()
+            .any(SharerState::level)
+    }
+
+    /// Add a sharer identified by `owner`, e.g. its device instance id, and
+    /// return the interrupt source it raises the line through.
+    pub fn add_sharer(self: Arc<Self>, owner: u32) -> Result<SharedIrqSource> {
+        {
+            let mut sharers = self.sharers.lock().expect("failed to acquire lock");
+            if sharers.contains_key(&owner) {
+                return Err(Error::InvalidConfig);
+            }
+            sharers.insert(owner, SharerState::default());
+        }
+        Ok(SharedIrqSource { line: self, owner })
+    }
+
+    /// Remove the sharer identified by `owner`, dropping its level.
+    pub fn remove_sharer(&self, owner: u32) {
+        self.sharers
+            .lock()
+            .expect("failed to acquire lock")
+            .remove(&owner);
+    }
+
+    /// Trigger the line again if it is still asserted.
+    ///
+    /// This is meant to be called when the guest acknowledges the interrupt,
+    /// e.g. from a KVM irqfd resample notification, so that sharers which
+    /// didn't get serviced yet are not lost.
+    pub fn resample(&self) -> Result<()> {
+        if self.is_asserted() {
+            self.group.trigger(0)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Update the state of a sharer and trigger the line on a rising edge.
+    fn update_sharer<F>(&self, owner: u32, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut SharerState),
+    {
+        let rising = {
+            let mut sharers = self.sharers.lock().expect("failed to acquire lock");
+            let was_asserted = sharers.values().any(SharerState::level);
+            update(sharers.get_mut(&owner).ok_or(Error::UnknownGroup)?);
+            !was_asserted && sharers.values().any(SharerState::level)
+        };
+        if rising {
+            self.group.trigger(0)?;
+        }
+        Ok(())
+    }
+
+    fn sharer_asserted(&self, owner: u32) -> Result<bool> {
+        self.sharers
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&owner)
+            .map(|s| s.asserted)
+            .ok_or(Error::UnknownGroup)
+    }
+}
+
+/// Interrupt source of one sharer of a `SharedIrqLine`.
+///
+/// `trigger()` asserts the level of the sharer and `deassert()` lowers it.
+/// Masking a source only takes its level out of the line, the other sharers
+/// keep on raising it.
+pub struct SharedIrqSource {
+    line: Arc<SharedIrqLine>,
+    owner: u32,
+}
+
+impl SharedIrqSource {
+    /// Line the source is attached to.
+    pub fn line(&self) -> &Arc<SharedIrqLine> {
+        &self.line
+    }
+
+    fn check_index(index: u32) -> Result<()> {
+        if index != 0 {
+            return Err(Error::InvalidIndex(index));
+        }
+        Ok(())
+    }
+}
+
+impl InterruptSourceGroup for SharedIrqSource {
+    fn interrupt_type(&self) -> InterruptSourceType {
+        InterruptSourceType::Legacy
+    }
+
+    fn len(&self) -> u32 {
+        1
+    }
+
+    fn base(&self) -> u32 {
+        self.line.irq()
+    }
+
+    fn trigger(&self, index: u32) -> Result<()> {
+        Self::check_index(index)?;
+        self.line.update_sharer(self.owner, |s| s.asserted = true)
+    }
+
+    fn deassert(&self, index: u32) -> Result<()> {
+        Self::check_index(index)?;
+        self.line.update_sharer(self.owner, |s| s.asserted = false)
+    }
+
+    fn mask(&self, index: u32) -> Result<()> {
+        Self::check_index(index)?;
+        self.line.update_sharer(self.owner, |s| s.masked = true)
+    }
+
+    fn unmask(&self, index: u32) -> Result<()> {
+        Self::check_index(index)?;
+        self.line.update_sharer(self.owner, |s| s.masked = false)
+    }
+
+    fn is_pending(&self, index: u32) -> Result<bool> {
+        Self::check_index(index)?;
+        self.line.sharer_asserted(self.owner)
+    }
+
+    fn update(&self, index: u32, config: &InterruptSourceConfig) -> Result<()> {
+        Self::check_index(index)?;
+        self.line.group.update(0, config)
+    }
+
+    fn config(&self, index: u32) -> Result<InterruptSourceConfig> {
+        Self::check_index(index)?;
+        self.line.group.config(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::LegacyIrqSourceConfig;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Default)]
+    struct CountingGroup {
+        triggers: AtomicU32,
+    }
+
+    impl InterruptSourceGroup for CountingGroup {
+        fn interrupt_type(&self) -> InterruptSourceType {
+            InterruptSourceType::Legacy
+        }
+        fn len(&self) -> u32 {
+            1
+        }
+        fn base(&self) -> u32 {
+            10
+        }
+        fn trigger(&self, _index: u32) -> Result<()> {
+            self.triggers.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        fn mask(&self, _index: u32) -> Result<()> {
+            Ok(())
+        }
+        fn unmask(&self, _index: u32) -> Result<()> {
+            Ok(())
+        }
+        fn is_pending(&self, _index: u32) -> Result<bool> {
+            Ok(false)
+        }
+        fn update(&self, _index: u32, _config: &InterruptSourceConfig) -> Result<()> {
+            Ok(())
+        }
+        fn config(&self, _index: u32) -> Result<InterruptSourceConfig> {
+            Ok(InterruptSourceConfig::LegacyIrq(
+                LegacyIrqSourceConfig::default(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_shared_line() {
+        let group = Arc::new(CountingGroup::default());
+        let line = Arc::new(SharedIrqLine::new(group.clone()).unwrap());
+        let a = line.clone().add_sharer(1).unwrap();
+        let b = line.clone().add_sharer(2).unwrap();
+        assert!(line.clone().add_sharer(1).is_err());
+        assert_eq!((line.sharers(), a.base()), (2, 10));
+        let triggers = || group.triggers.load(Ordering::SeqCst);
+
+        // Only the rising edge of the wired-OR level triggers the line.
+        a.trigger(0).unwrap();
+        b.trigger(0).unwrap();
+        assert_eq!(triggers(), 1);
+        a.deassert(0).unwrap();
+        assert!(line.is_asserted());
+        line.resample().unwrap();
+        assert_eq!(triggers(), 2);
+        b.deassert(0).unwrap();
+        assert!(!line.is_asserted());
+        line.resample().unwrap();
+        assert_eq!(triggers(), 2);
+
+        // A masked sharer level is held back until unmasked.
+        a.mask(0).unwrap();
+        a.trigger(0).unwrap();
+        assert!(a.is_pending(0).unwrap());
+        assert_eq!(triggers(), 2);
+        a.unmask(0).unwrap();
+        assert_eq!(triggers(), 3);
+        assert!(a.trigger(1).is_err());
+
+        // A removed sharer no longer holds the line.
+        line.remove_sharer(1);
+        assert!(!line.is_asserted());
+        assert!(a.trigger(0).is_err());
+        assert_eq!(line.sharers(), 1);
+    }
+}
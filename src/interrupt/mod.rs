@@ -0,0 +1,181 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Interrupt sources of devices.
+//!
+//! A device raises interrupts through an
+//! [InterruptSourceGroup](trait.InterruptSourceGroup.html), a set of
+//! interrupt sources of the same type sharing a contiguous range of interrupt
+//! numbers:
+//! - a legacy pin based interrupt,
+//! - a PCI MSI capability with up to 32 vectors,
+//! - a PCI MSI-X capability with up to 2048 vectors.
+//!
+//! Groups are created by an [InterruptManager](trait.InterruptManager.html),
+//! which the [DeviceManager](../device_manager/struct.DeviceManager.html) uses
+//! to hand every device the group matching its allocated interrupt resources.
+//! The [eventfd](eventfd/index.html) module provides an implementation where
+//! every interrupt source is backed by a Linux eventfd, suitable to be
+//! registered as a KVM irqfd. Legacy lines shared by several devices are
+//! modelled by the [level](level/index.html) module, and the
+//! [routing](routing/index.html) module builds the GSI routing table of the
+//! allocated interrupts.
+
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+pub mod level;
+pub mod routing;
+
+use std::fmt::{self, Display};
+use std::io;
+use std::result;
+use std::sync::Arc;
+
+/// Errors associated with interrupt sources.
+#[derive(Debug)]
+pub enum Error {
+    /// The interrupt source index is out of the group range.
+    InvalidIndex(u32),
+    /// The configuration doesn't match the interrupt source type.
+    InvalidConfig,
+    /// The interrupt manager can't create groups of this type or size.
+    NotSupported,
+    /// The interrupt group is not managed by this interrupt manager.
+    UnknownGroup,
+    /// An OS level operation failed.
+    Io(io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidIndex(idx) => write!(f, "Interrupt source {} is out of the group", idx),
+            InvalidConfig => write!(f, "Interrupt source configuration is invalid"),
+            NotSupported => write!(f, "Interrupt source group is not supported"),
+            UnknownGroup => write!(f, "Interrupt source group is unknown"),
+            Io(e) => write!(f, "Interrupt source operation failed, err={}", e),
+        }
+    }
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Maximum number of vectors of a PCI MSI capability.
+pub const MAX_MSI_VECTORS: u32 = 32;
+/// Maximum number of vectors of a PCI MSI-X capability.
+pub const MAX_MSIX_VECTORS: u32 = 2048;
+
+/// Type of the interrupt sources of a group.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InterruptSourceType {
+    /// Legacy pin based interrupt, a group holds a single source.
+    Legacy,
+    /// PCI MSI interrupts.
+    Msi,
+    /// PCI MSI-X interrupts.
+    MsiX,
+}
+
+impl InterruptSourceType {
+    /// Maximum number of sources a group of this type can hold.
+    pub fn max_sources(self) -> u32 {
+        match self {
+            InterruptSourceType::Legacy => 1,
+            InterruptSourceType::Msi => MAX_MSI_VECTORS,
+            InterruptSourceType::MsiX => MAX_MSIX_VECTORS,
+        }
+    }
+}
+
+/// Configuration of a legacy interrupt source.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LegacyIrqSourceConfig {}
+
+/// Configuration of a MSI or MSI-X interrupt source, as programmed by the guest.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct MsiIrqSourceConfig {
+    /// High 32 bits of the message address.
+    pub high_addr: u32,
+    /// Low 32 bits of the message address.
+    pub low_addr: u32,
+    /// Message data.
+    pub data: u32,
+}
+
+/// Configuration of an interrupt source.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InterruptSourceConfig {
+    /// Legacy interrupt source configuration.
+    LegacyIrq(LegacyIrqSourceConfig),
+    /// MSI or MSI-X interrupt source configuration.
+    MsiIrq(MsiIrqSourceConfig),
+}
+
+/// Trait for a group of interrupt sources of the same type.
+///
+/// Sources are addressed by their index in the group, source `index` being
+/// wired to interrupt number `base() + index`.
+pub trait InterruptSourceGroup: Send + Sync {
+    /// Type of the interrupt sources.
+    fn interrupt_type(&self) -> InterruptSourceType;
+
+    /// Number of interrupt sources in the group.
+    fn len(&self) -> u32;
+
+    /// Return true if the group has no interrupt source.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// First interrupt number of the group, e.g. the legacy IRQ or the GSI
+    /// routing the first MSI vector.
+    fn base(&self) -> u32;
+
+    /// Raise the interrupt source at `index`.
+    ///
+    /// A masked source only records the interrupt as pending, it is delivered
+    /// once unmasked.
+    fn trigger(&self, index: u32) -> Result<()>;
+
+    /// Lower the level of the interrupt source at `index`.
+    ///
+    /// Only level-triggered sources hold a level, the others ignore it.
+    fn deassert(&self, index: u32) -> Result<()> {
+        let _ = index;
+        Ok(())
+    }
+
+    /// Mask the interrupt source at `index`.
+    fn mask(&self, index: u32) -> Result<()>;
+
+    /// Unmask the interrupt source at `index`, delivering any pending interrupt.
+    fn unmask(&self, index: u32) -> Result<()>;
+
+    /// Return true if the interrupt source at `index` has a pending interrupt.
+    fn is_pending(&self, index: u32) -> Result<bool>;
+
+    /// Update the configuration of the interrupt source at `index`, e.g. when
+    /// the guest reprograms a MSI message.
+    fn update(&self, index: u32, config: &InterruptSourceConfig) -> Result<()>;
+
+    /// Return the current configuration of the interrupt source at `index`.
+    fn config(&self, index: u32) -> Result<InterruptSourceConfig>;
+}
+
+/// Trait for the factory of interrupt source groups.
+pub trait InterruptManager: Send + Sync {
+    /// Create a group of `count` interrupt sources of type `ty` starting at
+    /// interrupt number `base`.
+    fn create_group(
+        &self,
+        ty: InterruptSourceType,
+        base: u32,
+        count: u32,
+    ) -> Result<Arc<dyn InterruptSourceGroup>>;
+
+    /// Release a group created by this manager.
+    fn destroy_group(&self, group: Arc<dyn InterruptSourceGroup>) -> Result<()>;
+}
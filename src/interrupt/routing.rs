@@ -0,0 +1,237 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! GSI routing table.
+//!
+//! Hypervisors like KVM route every GSI either to the pin of an interrupt
+//! controller or to a MSI message. A
+//! [GsiRoutingTable](struct.GsiRoutingTable.html) holds those routes with
+//! the same layout as the KVM `kvm_irq_routing_entry` structures, so that a
+//! VMM can translate it entry by entry into a `KVM_SET_GSI_ROUTING` call.
+//!
+//! The table carries a version bumped on every change: a VMM only needs to
+//! push it again when the version it last pushed is outdated.
+
+use super::{
+    InterruptSourceConfig, InterruptSourceGroup, InterruptSourceType, MsiIrqSourceConfig, Result,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Irqchip id of the x86 master PIC, as in `KVM_IRQCHIP_PIC_MASTER`.
+pub const IRQCHIP_PIC_MASTER: u32 = 0;
+/// Irqchip id of the x86 slave PIC, as in `KVM_IRQCHIP_PIC_SLAVE`.
+pub const IRQCHIP_PIC_SLAVE: u32 = 1;
+/// Irqchip id of the x86 IOAPIC, as in `KVM_IRQCHIP_IOAPIC`.
+pub const IRQCHIP_IOAPIC: u32 = 2;
+/// Irqchip id of the ARM GIC.
+pub const IRQCHIP_GIC: u32 = 0;
+
+/// Route of a GSI.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Route {
+    /// Pin of an interrupt controller, as in `KVM_IRQ_ROUTING_IRQCHIP`.
+    Irqchip {
+        /// Interrupt controller id.
+        irqchip: u32,
+        /// Pin of the interrupt controller.
+        pin: u32,
+    },
+    /// MSI message, as in `KVM_IRQ_ROUTING_MSI`.
+    Msi(MsiIrqSourceConfig),
+}
+
+/// One entry of the routing table.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RoutingEntry {
+    /// Routed GSI.
+    pub gsi: u32,
+    /// Where the GSI is routed to.
+    pub route: Route,
+}
+
+/// GSI routing table.
+///
+/// A GSI may have several routes, e.g. a x86 ISA IRQ is wired to both the
+/// PIC and the IOAPIC.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GsiRoutingTable {
+    routes: BTreeMap<u32, Vec<Route>>,
+    version: u64,
+}
+
+impl GsiRoutingTable {
+    /// Create an empty routing table.
+    pub fn new() -> Self {
+        GsiRoutingTable::default()
+    }
+
+    /// Set the routes of `gsi`, replacing the previous ones.
+    pub fn set(&mut self, gsi: u32, routes: Vec<Route>) {
+        if routes.is_empty() {
+            self.remove(gsi);
+        } else if self.routes.get(&gsi) != Some(&routes) {
+            self.routes.insert(gsi, routes);
+            self.version += 1;
+        }
+    }
+
+    /// Remove the routes of `gsi`.
+    pub fn remove(&mut self, gsi: u32) {
+        if self.routes.remove(&gsi).is_some() {
+            self.version += 1;
+        }
+    }
+
+    /// Routes of `gsi`.
+    pub fn routes(&self, gsi: u32) -> &[Route] {
+        self.routes
+            .get(&gsi)
+            .map_or(&[], |routes| routes.as_slice())
+    }
+
+    /// Entries of the table ordered by GSI.
+    pub fn entries(&self) -> Vec<RoutingEntry> {
+        self.routes
+            .iter()
+            .flat_map(|(gsi, routes)| {
+                routes.iter().map(move |route| RoutingEntry {
+                    gsi: *gsi,
+                    route: *route,
+                })
+            })
+            .collect()
+    }
+
+    /// Number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.routes.values().map(Vec::len).sum()
+    }
+
+    /// Return true if the table has no entry.
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// Version of the table, bumped on every change.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// MSI or MSI-X interrupt group keeping a routing table up to date.
+///
+/// Every MSI message the guest programs through `update()` is recorded as the
+/// route of the GSI of the vector, the rest is forwarded to the wrapped group.
+pub struct RoutedMsiGroup {
+    group: Arc<dyn InterruptSourceGroup>,
+    table: Arc<Mutex<GsiRoutingTable>>,
+}
+
+impl RoutedMsiGroup {
+    /// Wrap `group` to record its MSI messages into `table`.
+    pub fn new(group: Arc<dyn InterruptSourceGroup>, table: Arc<Mutex<GsiRoutingTable>>) -> Self {
+        RoutedMsiGroup { group, table }
+    }
+
+    /// Wrapped interrupt group.
+    pub fn group(&self) -> &Arc<dyn InterruptSourceGroup> {
+        &self.group
+    }
+}
+
+impl InterruptSourceGroup for RoutedMsiGroup {
+    fn interrupt_type(&self) -> InterruptSourceType {
+        self.group.interrupt_type()
+    }
+
+    fn len(&self) -> u32 {
+        self.group.len()
+    }
+
+    fn base(&self) -> u32 {
+        self.group.base()
+    }
+
+    fn trigger(&self, index: u32) -> Result<()> {
+        self.group.trigger(index)
+    }
+
+    fn deassert(&self, index: u32) -> Result<()> {
+        self.group.deassert(index)
+    }
+
+    fn mask(&self, index: u32) -> Result<()> {
+        self.group.mask(index)
+    }
+
+    fn unmask(&self, index: u32) -> Result<()> {
+        self.group.unmask(index)
+    }
+
+    fn is_pending(&self, index: u32) -> Result<bool> {
+        self.group.is_pending(index)
+    }
+
+    fn update(&self, index: u32, config: &InterruptSourceConfig) -> Result<()> {
+        self.group.update(index, config)?;
+        if let InterruptSourceConfig::MsiIrq(msi) = config {
+            self.table
+                .lock()
+                .expect("failed to acquire lock")
+                .set(self.group.base() + index, vec![Route::Msi(*msi)]);
+        }
+        Ok(())
+    }
+
+    fn config(&self, index: u32) -> Result<InterruptSourceConfig> {
+        self.group.config(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routing_table() {
+        let mut table = GsiRoutingTable::new();
+        assert!(table.is_empty());
+        table.set(
+            4,
+            vec![
+                Route::Irqchip {
+                    irqchip: IRQCHIP_PIC_MASTER,
+                    pin: 4,
+                },
+                Route::Irqchip {
+                    irqchip: IRQCHIP_IOAPIC,
+                    pin: 4,
+                },
+            ],
+        );
+        let msi = MsiIrqSourceConfig {
+            high_addr: 0,
+            low_addr: 0xfee0_0000,
+            data: 0x41,
+        };
+        table.set(24, vec![Route::Msi(msi)]);
+        assert_eq!((table.len(), table.version()), (3, 2));
+
+        // Setting the same routes again is not a change.
+        table.set(24, vec![Route::Msi(msi)]);
+        assert_eq!(table.version(), 2);
+        assert_eq!(
+            table.entries()[2],
+            RoutingEntry {
+                gsi: 24,
+                route: Route::Msi(msi),
+            }
+        );
+
+        table.set(4, Vec::new());
+        table.remove(5);
+        assert!(table.routes(4).is_empty());
+        assert_eq!((table.len(), table.version()), (1, 3));
+    }
+}
@@ -10,10 +10,41 @@
 //! of the rust-vmm code that works on device but does not necessarily to
 //! know the implementation details of the device.
 
+extern crate libc;
+extern crate vm_allocator;
 extern crate vm_memory;
 
+pub mod acpi;
 pub mod device;
 pub mod device_manager;
+pub mod event;
+mod export;
+pub mod interrupt;
+pub mod pci;
+pub mod virtio;
 
-pub use self::device::{Device, DeviceDescriptor, IoResource, IoType};
-pub use self::device_manager::{DeviceManager, Error as DeviceManagerError, Range, Result};
+pub use self::device::{
+    Device, DeviceDescriptor, Error as DeviceError, IoResource, IoType, IrqController, IrqPolarity,
+    IrqResource, IrqTrigger, MsiResource,
+};
+pub use self::device_manager::{
+    DeviceManager, Error as DeviceManagerError, IoMetrics, LifecycleOp, Range, Result,
+};
+pub use self::event::{DeviceEvent, DeviceEventKind, DeviceEventListener, DeviceSnapshot};
+pub use self::export::JSON_EXPORT_VERSION;
+pub use self::interrupt::level::{SharedIrqLine, SharedIrqSource};
+pub use self::interrupt::routing::{GsiRoutingTable, Route, RoutedMsiGroup, RoutingEntry};
+pub use self::interrupt::{
+    Error as InterruptError, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
+    InterruptSourceType, MsiIrqSourceConfig,
+};
+pub use self::pci::{
+    AcpiPciHotplug, Error as PciError, PciAddress, PciBar, PciBarType, PciBars, PciBridge,
+    PciBridgeWindows, PciBus, PciCapability, PciConfigMechanism, PciConfiguration,
+    PciConfigurationBuilder, PciDevice, PciEcam, PciExpressPortType, PciExtendedCapability,
+    PciIntxRoute, PciMsix, PciRoot, PciRootBus, PciSegment, PciSriov, PcieRootPort,
+};
+pub use self::virtio::{
+    Error as VirtioError, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioMmio,
+    VirtioPci, VirtioQueue,
+};
@@ -0,0 +1,437 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! ACPI PCI hotplug controller.
+//!
+//! The [AcpiPciHotplug](struct.AcpiPciHotplug.html) controller implements
+//! the PIIX4 style PCI hotplug interface of a root bus, for the guests
+//! relying on ACPI rather than on native PCIe hotplug. It decodes two PIO
+//! ranges:
+//! - the slot bitmaps at 0xae00, a bit per device number of the bus:
+//!   - PCIU, the slots a function was plugged in,
+//!   - PCID, the slots whose function removal is requested,
+//!   - B0EJ, written by the guest with the slots it ejected,
+//!   - PRMV, the slots whose function can be removed,
+//! - the GPE0 block at 0xafe0, to be described in the FADT, with GPE 1
+//!   signaling the slot changes through the SCI.
+//!
+//! Reading the bitmaps has no side effect, so that firmware may read them
+//! again. A PCID bit is cleared once the guest ejects the slot. A PCIU bit
+//! is cleared when the guest acknowledges GPE 1 by clearing its status the
+//! second time after the function was plugged: the GPE handler ran in
+//! between, whether the guest clears the status before running it, as for
+//! the edge-triggered `_E01`, or after.
+//!
+//! The AML of the controller, built by its `Aml` implementation, notifies
+//! the slots in the GPE 1 handler and writes B0EJ from their `_EJ0` method.
+//! Functions are plugged with `DeviceManager::hotplug_pci_device()` and
+//! their removal is requested with `DeviceManager::request_pci_unplug()`;
+//! `DeviceManager::apply_pci_ejects()` then unregisters the functions the
+//! guest ejected.
+
+use super::{read_register_bytes, write_register_bytes, PCI_DEVICES_PER_BUS};
+use crate::acpi::aml::{
+    self, Aml, Arg, Device as AmlDevice, Field, FieldAccess, FieldEntry, FieldUpdate, If, Local,
+    Method, MethodCall, Name, OpRegion, OpRegionSpace, Path, Scope, ONE, ZERO,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use crate::interrupt::{InterruptSourceGroup, InterruptSourceType};
+use std::sync::{Arc, Mutex};
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// PIO address of the slot bitmaps.
+pub const ACPI_PCIHP_BASE: u64 = 0xae00;
+const ACPI_PCIHP_SIZE: GuestUsize = 0x10;
+/// PIO address of the GPE0 block.
+pub const ACPI_GPE0_BASE: u64 = 0xafe0;
+/// Size of the GPE0 block, two bytes of status and two bytes of enable.
+pub const ACPI_GPE0_SIZE: GuestUsize = 4;
+/// General purpose event signaling the slot changes.
+pub const ACPI_PCIHP_GPE: u8 = 1;
+
+const PCIHP_UP: u64 = 0x0;
+const PCIHP_DOWN: u64 = 0x4;
+const PCIHP_EJECT: u64 = 0x8;
+const PCIHP_REMOVABLE: u64 = 0xc;
+// Device 0 is the host bridge.
+const PCIHP_SLOTS: u32 = !1;
+
+// ACPI notification values.
+const NOTIFY_DEVICE_CHECK: u8 = 1;
+const NOTIFY_EJECT_REQUEST: u8 = 3;
+
+#[derive(Default)]
+struct HotplugState {
+    up: u32,
+    // Slots of `up` when GPE 1 was last acknowledged, notified since.
+    notified: u32,
+    down: u32,
+    eject: u32,
+    gpe_status: u16,
+    gpe_enable: u16,
+    group: Option<Arc<dyn InterruptSourceGroup>>,
+    asserted: bool,
+}
+
+impl HotplugState {
+    fn raise_gpe(&mut self) {
+        self.gpe_status |= 1 << ACPI_PCIHP_GPE;
+        self.update_sci();
+    }
+
+    // Assert the SCI while an enabled GPE is pending.
+    fn update_sci(&mut self) {
+        let assert = self.gpe_status & self.gpe_enable != 0;
+        if assert == self.asserted {
+            return;
+        }
+        if let Some(group) = self.group.as_ref() {
+            let ret = if assert {
+                group.trigger(0)
+            } else {
+                group.deassert(0)
+            };
+            if ret.is_ok() {
+                self.asserted = assert;
+            }
+        }
+    }
+}
+
+/// ACPI PCI hotplug controller of a root bus.
+pub struct AcpiPciHotplug {
+    host_bridge: String,
+    state: Mutex<HotplugState>,
+    bases: Mutex<(GuestAddress, GuestAddress)>,
+}
+
+impl AcpiPciHotplug {
+    /// Create the controller of the root bus whose host bridge is the AML
+    /// device `host_bridge`, e.g. `\_SB_.PCI0`.
+    pub fn new(host_bridge: &str) -> Self {
+        AcpiPciHotplug {
+            host_bridge: host_bridge.to_string(),
+            state: Mutex::new(HotplugState::default()),
+            bases: Mutex::new((GuestAddress(ACPI_PCIHP_BASE), GuestAddress(ACPI_GPE0_BASE))),
+        }
+    }
+
+    /// IO resources to request at registration: the slot bitmaps and the
+    /// GPE0 block.
+    pub fn io_resources() -> Vec<IoResource> {
+        vec![
+            IoResource::new(
+                Some(GuestAddress(ACPI_PCIHP_BASE)),
+                ACPI_PCIHP_SIZE,
+                IoType::Pio,
+            ),
+            IoResource::new(
+                Some(GuestAddress(ACPI_GPE0_BASE)),
+                ACPI_GPE0_SIZE,
+                IoType::Pio,
+            ),
+        ]
+    }
+
+    // Report a function plugged in the slot of `device`.
+    pub(crate) fn slot_added(&self, device: u8) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.up |= 1 << device;
+        state.notified &= !(1 << device);
+        state.down &= !(1 << device);
+        state.raise_gpe();
+    }
+
+    // Ask the guest to eject the slot of `device`.
+    pub(crate) fn request_removal(&self, device: u8) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.down |= 1 << device;
+        state.raise_gpe();
+    }
+
+    // Forget the pending events of the slot of `device`, now empty.
+    pub(crate) fn slot_removed(&self, device: u8) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let mask = !(1 << device);
+        state.up &= mask;
+        state.notified &= mask;
+        state.down &= mask;
+        state.eject &= mask;
+    }
+
+    // Take the slots ejected by the guest.
+    pub(crate) fn take_ejects(&self) -> u32 {
+        std::mem::take(&mut self.state.lock().expect("failed to acquire lock").eject)
+    }
+
+    fn read_slots(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock().expect("failed to acquire lock");
+        let value = match offset & !3 {
+            PCIHP_UP => state.up,
+            PCIHP_DOWN => state.down,
+            PCIHP_REMOVABLE => PCIHP_SLOTS,
+            _ => 0,
+        };
+        read_register_bytes(value, offset & 3, data);
+    }
+
+    fn write_slots(&self, offset: u64, data: &[u8]) {
+        if offset & !3 != PCIHP_EJECT {
+            return;
+        }
+        let slots = write_register_bytes(0, offset & 3, data) & PCIHP_SLOTS;
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.eject |= slots;
+        state.down &= !slots;
+    }
+
+    fn read_gpe(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock().expect("failed to acquire lock");
+        let value = u32::from(state.gpe_status) | u32::from(state.gpe_enable) << 16;
+        read_register_bytes(value, offset, data);
+    }
+
+    // The status bits are cleared by writing ones to them. Acknowledging
+    // GPE 1 clears the plugged slots already pending at the previous
+    // acknowledgement.
+    fn write_gpe(&self, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let status = state.gpe_status;
+        for (idx, byte) in data.iter().enumerate() {
+            let byte = u16::from(*byte);
+            match offset as usize + idx {
+                reg @ 0..=1 => state.gpe_status &= !(byte << (reg * 8)),
+                reg @ 2..=3 => {
+                    let shift = (reg - 2) * 8;
+                    state.gpe_enable = state.gpe_enable & !(0xff << shift) | byte << shift;
+                }
+                _ => {}
+            }
+        }
+        let gpe = 1 << ACPI_PCIHP_GPE;
+        if status & gpe != 0 && state.gpe_status & gpe == 0 {
+            state.up &= !state.notified;
+            state.notified = state.up;
+        }
+        state.update_sci();
+    }
+
+    fn slot_device(slot: u8) -> String {
+        format!("S{:02X}", slot)
+    }
+}
+
+impl Aml for AcpiPciHotplug {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let base = self.bases.lock().expect("failed to acquire lock").0;
+        let region = OpRegion::new(
+            "PCST".into(),
+            OpRegionSpace::SystemIo,
+            base.raw_value(),
+            ACPI_PCIHP_SIZE,
+        );
+        let field = Field::new(
+            "PCST".into(),
+            FieldAccess::DWord,
+            FieldUpdate::WriteAsZeros,
+            vec![
+                FieldEntry::Named("PCIU", 32),
+                FieldEntry::Named("PCID", 32),
+                FieldEntry::Named("B0EJ", 32),
+                FieldEntry::Named("PRMV", 32),
+            ],
+        );
+        // Method (PCEJ, 1) { Store (ShiftLeft (One, Arg0), B0EJ) }
+        let b0ej = Path::new("B0EJ");
+        let shift = aml::shift_left(&ZERO, &ONE, &Arg(0));
+        let eject = aml::store(&b0ej, &shift);
+        let pcej = Method::new("PCEJ".into(), 1, false, vec![&eject]);
+
+        // Method (PCNT) notifies the slots with a function plugged in and
+        // the ones whose removal is requested.
+        let (pciu, pcid) = (Path::new("PCIU"), Path::new("PCID"));
+        let up = aml::store(&Local(0), &pciu);
+        let down = aml::store(&Local(1), &pcid);
+        let slots: Vec<u8> = (1..PCI_DEVICES_PER_BUS).collect();
+        let paths: Vec<Path> = slots
+            .iter()
+            .map(|slot| Path::new(&Self::slot_device(*slot)))
+            .collect();
+        let bits: Vec<u32> = slots.iter().map(|slot| 1 << slot).collect();
+        let up_tests: Vec<_> = bits
+            .iter()
+            .map(|bit| aml::and(&ZERO, &Local(0), bit))
+            .collect();
+        let down_tests: Vec<_> = bits
+            .iter()
+            .map(|bit| aml::and(&ZERO, &Local(1), bit))
+            .collect();
+        let checks: Vec<_> = paths
+            .iter()
+            .map(|path| aml::notify(path, &NOTIFY_DEVICE_CHECK))
+            .collect();
+        let eject_requests: Vec<_> = paths
+            .iter()
+            .map(|path| aml::notify(path, &NOTIFY_EJECT_REQUEST))
+            .collect();
+        let mut notifications = Vec::new();
+        for idx in 0..slots.len() {
+            notifications.push(If::new(&up_tests[idx], vec![&checks[idx]]));
+            notifications.push(If::new(&down_tests[idx], vec![&eject_requests[idx]]));
+        }
+        let mut pcnt_body: Vec<&dyn Aml> = vec![&up, &down];
+        pcnt_body.extend(notifications.iter().map(|n| n as &dyn Aml));
+        let pcnt = Method::new("PCNT".into(), 0, false, pcnt_body);
+
+        // Device (Sxx) { Name (_ADR, xx << 16) Name (_SUN, xx)
+        //     Method (_EJ0, 1) { PCEJ (xx) } }
+        let adrs: Vec<Name> = slots
+            .iter()
+            .map(|slot| Name::new("_ADR".into(), &(u32::from(*slot) << 16)))
+            .collect();
+        let suns: Vec<Name> = slots
+            .iter()
+            .map(|slot| Name::new("_SUN".into(), slot))
+            .collect();
+        let calls: Vec<MethodCall> = slots
+            .iter()
+            .map(|slot| MethodCall::new("PCEJ".into(), vec![slot as &dyn Aml]))
+            .collect();
+        let ej0s: Vec<Method> = calls
+            .iter()
+            .map(|call| Method::new("_EJ0".into(), 1, false, vec![call]))
+            .collect();
+        let devices: Vec<AmlDevice> = (0..slots.len())
+            .map(|idx| {
+                AmlDevice::new(
+                    Path::new(&Self::slot_device(slots[idx])),
+                    vec![&adrs[idx], &suns[idx], &ej0s[idx]],
+                )
+            })
+            .collect();
+
+        let mut children: Vec<&dyn Aml> = vec![&region, &field, &pcej, &pcnt];
+        children.extend(devices.iter().map(|d| d as &dyn Aml));
+        let mut bytes = Scope::new(Path::new(&self.host_bridge), children).to_aml_bytes();
+
+        // Scope (\_GPE) { Method (_E01) { <host bridge>.PCNT () } }
+        let call = MethodCall::new(Path::new(&format!("{}.PCNT", self.host_bridge)), Vec::new());
+        let handler = Method::new(
+            Path::new(&format!("_E{:02X}", ACPI_PCIHP_GPE)),
+            0,
+            false,
+            vec![&call],
+        );
+        bytes.extend(Scope::new("\\_GPE".into(), vec![&handler]).to_aml_bytes());
+        bytes
+    }
+}
+
+impl Device for AcpiPciHotplug {
+    fn name(&self) -> String {
+        "acpi-pcihp".to_string()
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
+        let (slots, gpe) = *self.bases.lock().expect("failed to acquire lock");
+        match (
+            addr.checked_offset_from(slots),
+            addr.checked_offset_from(gpe),
+        ) {
+            (Some(offset), _) if offset < ACPI_PCIHP_SIZE => self.read_slots(offset, data),
+            (_, Some(offset)) if offset < ACPI_GPE0_SIZE => self.read_gpe(offset, data),
+            _ => {
+                for byte in data.iter_mut() {
+                    *byte = 0xff;
+                }
+            }
+        }
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], _io_type: IoType) {
+        let (slots, gpe) = *self.bases.lock().expect("failed to acquire lock");
+        match (
+            addr.checked_offset_from(slots),
+            addr.checked_offset_from(gpe),
+        ) {
+            (Some(offset), _) if offset < ACPI_PCIHP_SIZE => self.write_slots(offset, data),
+            (_, Some(offset)) if offset < ACPI_GPE0_SIZE => self.write_gpe(offset, data),
+            _ => {}
+        }
+    }
+
+    fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+        let mut bases = self.bases.lock().expect("failed to acquire lock");
+        let mut pio = res
+            .iter()
+            .filter(|r| r.res_type == IoType::Pio)
+            .filter_map(|r| r.addr);
+        if let (Some(slots), Some(gpe)) = (pio.next(), pio.next()) {
+            *bases = (slots, gpe);
+        }
+    }
+
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+        if group.interrupt_type() != InterruptSourceType::Legacy {
+            return;
+        }
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.group = Some(group);
+        state.asserted = false;
+        state.update_sci();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acpi_pci_hotplug_registers() {
+        let controller = AcpiPciHotplug::new("\\_SB_.PCI0");
+        let read = |addr: u64| {
+            let mut data = [0u8; 4];
+            controller.read(GuestAddress(addr), &mut data, IoType::Pio);
+            u32::from_le_bytes(data)
+        };
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_REMOVABLE), 0xffff_fffe);
+
+        // Plugged slots raise GPE 1 when enabled, and stay reported until
+        // the GPE is acknowledged twice.
+        controller.slot_added(3);
+        assert_eq!(read(ACPI_GPE0_BASE), 0x0000_0002);
+        controller.write(GuestAddress(ACPI_GPE0_BASE + 2), &[0x02], IoType::Pio);
+        assert_eq!(read(ACPI_GPE0_BASE), 0x0002_0002);
+        controller.write(GuestAddress(ACPI_GPE0_BASE), &[0x02], IoType::Pio);
+        assert_eq!(read(ACPI_GPE0_BASE), 0x0002_0000);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_UP), 0x8);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_UP), 0x8);
+        controller.slot_added(5);
+        controller.write(GuestAddress(ACPI_GPE0_BASE), &[0x02], IoType::Pio);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_UP), 0x20);
+        // Writing a clear status bit doesn't acknowledge anything.
+        controller.write(GuestAddress(ACPI_GPE0_BASE), &[0x02], IoType::Pio);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_UP), 0x20);
+        controller.slot_removed(5);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_UP), 0);
+
+        // Removal requests stay pending until the guest ejects the slot.
+        controller.request_removal(3);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_DOWN), 0x8);
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_DOWN), 0x8);
+        controller.write(
+            GuestAddress(ACPI_PCIHP_BASE + PCIHP_EJECT),
+            &0x9u32.to_le_bytes(),
+            IoType::Pio,
+        );
+        assert_eq!(read(ACPI_PCIHP_BASE + PCIHP_DOWN), 0);
+        assert_eq!(controller.take_ejects(), 0x8);
+        assert_eq!(controller.take_ejects(), 0);
+
+        // The GPE handler calls PCNT in the host bridge scope.
+        let aml = controller.to_aml_bytes();
+        let handler = b"\x14\x15_E01\x00\\\x2f\x03_SB_PCI0PCNT";
+        assert!(aml.ends_with(handler));
+        assert_eq!(&aml[..1], &[0x10]);
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI Base Address Registers.
+//!
+//! The six BAR registers of a function start at offset 0x10 of its
+//! configuration space. A guest sizes a BAR by writing all ones to it and
+//! reading back the address bits it kept, then programs the address it
+//! picked. A 64-bit memory BAR spans two consecutive registers, the second
+//! one holding the upper 32 bits of the address.
+//!
+//! [PciBars](struct.PciBars.html) emulates those registers for a function.
+//! Its `io_resources()` are the requests to register the function with, and
+//! the addresses the guest programs are queued as BAR moves until the
+//! `DeviceManager` re-registers the BAR through
+//! `DeviceManager::apply_pci_bar_moves()`. A BAR only moves while the
+//! command register lets it decode its address space, so that the guest can
+//! program both halves of a 64-bit BAR with decoding off, or once it turns
+//! decoding back on.
+
+use super::{write_register_bytes, Error, Result, PCI_COMMAND_IO, PCI_COMMAND_MEMORY};
+use crate::device::{IoResource, IoType};
+use std::sync::Mutex;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// Register index of BAR0 in the configuration space.
+pub const PCI_BAR0_REG: usize = 4;
+/// Number of BAR registers of a function.
+pub const PCI_BAR_REGISTERS: usize = 6;
+
+const BAR_IO_SPACE: u32 = 0x1;
+const BAR_MEM_64BIT: u32 = 0x4;
+const BAR_MEM_PREFETCHABLE: u32 = 0x8;
+const BAR_IO_MIN_SIZE: GuestUsize = 4;
+const BAR_MEM_MIN_SIZE: GuestUsize = 16;
+
+/// Type of the address space a BAR decodes.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PciBarType {
+    /// I/O space BAR.
+    Io,
+    /// 32-bit memory space BAR.
+    Memory32,
+    /// 64-bit memory space BAR, spanning two registers.
+    Memory64,
+}
+
+/// Description of a BAR.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PciBar {
+    /// Address space type.
+    pub bar_type: PciBarType,
+    /// Size, a power of two.
+    pub size: GuestUsize,
+    /// The memory behind the BAR has no read side effects.
+    pub prefetchable: bool,
+    /// Fixed address to place the BAR at. I/O BARs always need one.
+    pub addr: Option<GuestAddress>,
+}
+
+impl PciBar {
+    /// I/O space BAR of `size` bytes at the fixed port `addr`.
+    pub fn io(addr: GuestAddress, size: GuestUsize) -> Self {
+        PciBar {
+            bar_type: PciBarType::Io,
+            size,
+            prefetchable: false,
+            addr: Some(addr),
+        }
+    }
+
+    /// 32-bit memory BAR of `size` bytes.
+    pub fn memory32(size: GuestUsize) -> Self {
+        PciBar {
+            bar_type: PciBarType::Memory32,
+            size,
+            prefetchable: false,
+            addr: None,
+        }
+    }
+
+    /// 64-bit memory BAR of `size` bytes.
+    pub fn memory64(size: GuestUsize) -> Self {
+        PciBar {
+            bar_type: PciBarType::Memory64,
+            size,
+            prefetchable: false,
+            addr: None,
+        }
+    }
+
+    /// Mark the BAR as prefetchable.
+    pub fn prefetchable(self) -> Self {
+        PciBar {
+            prefetchable: true,
+            ..self
+        }
+    }
+
+    /// IO resource to request for the BAR.
+    pub fn io_resource(&self) -> IoResource {
+        let res_type = match self.bar_type {
+            PciBarType::Io => IoType::Pio,
+            PciBarType::Memory32 => IoType::PciMmio32,
+            PciBarType::Memory64 => IoType::PciMmio64,
+        };
+        let res = IoResource::new(self.addr, self.size, res_type);
+        if self.prefetchable {
+            res.prefetchable()
+        } else {
+            res
+        }
+    }
+
+    fn registers(&self) -> usize {
+        match self.bar_type {
+            PciBarType::Memory64 => 2,
+            _ => 1,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        let min_size = match self.bar_type {
+            PciBarType::Io => BAR_IO_MIN_SIZE,
+            _ => BAR_MEM_MIN_SIZE,
+        };
+        let max_size = match self.bar_type {
+            PciBarType::Memory64 => 1 << 63,
+            _ => 1 << 31,
+        };
+        let fixed = match self.bar_type {
+            PciBarType::Io => self.addr.is_some(),
+            _ => true,
+        };
+        self.size.is_power_of_two() && self.size >= min_size && self.size <= max_size && fixed
+    }
+
+    // Address bits of the first register.
+    fn address_mask(&self) -> u32 {
+        match self.bar_type {
+            PciBarType::Io => !0x3,
+            PciBarType::Memory32 | PciBarType::Memory64 => !0xf,
+        }
+    }
+
+    // Low bits of the first register, read-only.
+    fn flags(&self) -> u32 {
+        match self.bar_type {
+            PciBarType::Io => BAR_IO_SPACE,
+            PciBarType::Memory32 | PciBarType::Memory64 => {
+                let mut flags = 0;
+                if self.bar_type == PciBarType::Memory64 {
+                    flags |= BAR_MEM_64BIT;
+                }
+                if self.prefetchable {
+                    flags |= BAR_MEM_PREFETCHABLE;
+                }
+                flags
+            }
+        }
+    }
+}
+
+/// A BAR placed in the BAR registers.
+struct BarSlot {
+    bar: PciBar,
+    /// First BAR register of the BAR.
+    reg: usize,
+    /// Address the BAR is registered at.
+    addr: u64,
+}
+
+struct BarState {
+    slots: Vec<BarSlot>,
+    registers: [u32; PCI_BAR_REGISTERS],
+    /// Command register of the function, enabling the I/O and memory decoding.
+    command: u16,
+    /// BAR moves programmed by the guest, as (BAR index, new address).
+    moves: Vec<(usize, GuestAddress)>,
+}
+
+impl BarState {
+    // Register value of `slot` holding `addr`.
+    fn set_address(&mut self, idx: usize, addr: u64) {
+        let slot = &mut self.slots[idx];
+        slot.addr = addr;
+        let addr = addr & !(slot.bar.size - 1);
+        self.registers[slot.reg] = addr as u32 | slot.bar.flags();
+        if slot.bar.registers() == 2 {
+            self.registers[slot.reg + 1] = (addr >> 32) as u32;
+        }
+    }
+
+    // Address programmed in the registers of `slot`.
+    fn programmed_address(&self, slot: &BarSlot) -> u64 {
+        let low = self.registers[slot.reg] & slot.bar.address_mask();
+        let high = if slot.bar.registers() == 2 {
+            u64::from(self.registers[slot.reg + 1]) << 32
+        } else {
+            0
+        };
+        high | u64::from(low)
+    }
+
+    // Return true if the command register enables the decoding of the
+    // address space of the BAR at `idx`.
+    fn decodes(&self, idx: usize) -> bool {
+        let enable = match self.slots[idx].bar.bar_type {
+            PciBarType::Io => PCI_COMMAND_IO,
+            PciBarType::Memory32 | PciBarType::Memory64 => PCI_COMMAND_MEMORY,
+        };
+        self.command & enable != 0
+    }
+
+    // Queue a move of the BAR at `idx` to the address programmed in its
+    // registers, if it changed and the BAR decodes its address space.
+    fn queue_move(&mut self, idx: usize) {
+        if !self.decodes(idx) {
+            return;
+        }
+        let addr = self.programmed_address(&self.slots[idx]);
+        if addr != self.slots[idx].addr {
+            self.slots[idx].addr = addr;
+            self.moves.retain(|(i, _)| *i != idx);
+            self.moves.push((idx, GuestAddress(addr)));
+        }
+    }
+}
+
+/// BAR registers of a PCI function.
+pub struct PciBars {
+    state: Mutex<BarState>,
+}
+
+impl PciBars {
+    /// Place `bars` in the BAR registers, in order, a 64-bit BAR taking two
+    /// registers.
+    pub fn new(bars: &[PciBar]) -> Result<Self> {
+        let mut slots = Vec::new();
+        let mut reg = 0;
+        for (idx, bar) in bars.iter().enumerate() {
+            if !bar.is_valid() || reg + bar.registers() > PCI_BAR_REGISTERS {
+                return Err(Error::InvalidBar(idx));
+            }
+            slots.push(BarSlot {
+                bar: *bar,
+                reg,
+                addr: 0,
+            });
+            reg += bar.registers();
+        }
+
+        let mut state = BarState {
+            slots,
+            registers: [0; PCI_BAR_REGISTERS],
+            command: 0,
+            moves: Vec::new(),
+        };
+        for idx in 0..state.slots.len() {
+            state.set_address(idx, 0);
+        }
+        Ok(PciBars {
+            state: Mutex::new(state),
+        })
+    }
+
+    /// IO resources to request for the BARs, in BAR order.
+    pub fn io_resources(&self) -> Vec<IoResource> {
+        self.state
+            .lock()
+            .expect("failed to acquire lock")
+            .slots
+            .iter()
+            .map(|slot| slot.bar.io_resource())
+            .collect()
+    }
+
+    /// Set the allocated BAR addresses, from the IO resources given to
+    /// `Device::set_resources()` starting with the BARs ones.
+    pub fn set_resources(&self, res: &[IoResource]) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let count = std::cmp::min(state.slots.len(), res.len());
+        for (idx, r) in res.iter().enumerate().take(count) {
+            if let Some(addr) = r.addr {
+                state.set_address(idx, addr.0);
+            }
+        }
+    }
+
+    /// Address of the BAR at `index`.
+    pub fn address(&self, index: usize) -> Option<GuestAddress> {
+        self.state
+            .lock()
+            .expect("failed to acquire lock")
+            .slots
+            .get(index)
+            .map(|slot| GuestAddress(slot.addr))
+    }
+
+    /// Read the configuration register at `reg_idx` if it is a BAR register.
+    pub fn read_config_register(&self, reg_idx: usize) -> Option<u32> {
+        let reg = reg_idx.checked_sub(PCI_BAR0_REG)?;
+        if reg >= PCI_BAR_REGISTERS {
+            return None;
+        }
+        Some(self.state.lock().expect("failed to acquire lock").registers[reg])
+    }
+
+    /// Write the configuration register at `reg_idx` if it is a BAR
+    /// register. Return false if it is not.
+    ///
+    /// The address bits below the BAR size and the type bits are read-only,
+    /// so that writing all ones reads back the size. The address programmed
+    /// in the registers of the BAR is queued as a BAR move after any write
+    /// but the sizing ones, if the BAR decodes its address space.
+    pub fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) -> bool {
+        let reg = match reg_idx.checked_sub(PCI_BAR0_REG) {
+            Some(reg) if reg < PCI_BAR_REGISTERS => reg,
+            _ => return false,
+        };
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let idx = match state
+            .slots
+            .iter()
+            .position(|s| reg >= s.reg && reg < s.reg + s.bar.registers())
+        {
+            Some(idx) => idx,
+            // Unused BAR registers are hardwired to zero.
+            None => return true,
+        };
+        let (bar, first) = (state.slots[idx].bar, state.slots[idx].reg);
+
+        let value = write_register_bytes(state.registers[reg], offset, data);
+        let mask = !(bar.size - 1);
+        state.registers[reg] = if reg == first {
+            (value & mask as u32 & bar.address_mask()) | bar.flags()
+        } else {
+            value & (mask >> 32) as u32
+        };
+
+        if value != 0xffff_ffff {
+            state.queue_move(idx);
+        }
+        true
+    }
+
+    /// Update the decoding of the BARs from the `command` register of the
+    /// function. The addresses programmed in the BARs whose decoding gets
+    /// enabled are queued as BAR moves.
+    pub fn set_command(&self, command: u16) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.command = command;
+        for idx in 0..state.slots.len() {
+            state.queue_move(idx);
+        }
+    }
+
+    /// Take the BAR moves programmed by the guest since the last call, as
+    /// (BAR index, new address) pairs.
+    pub fn take_moves(&self) -> Vec<(usize, GuestAddress)> {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        std::mem::take(&mut state.moves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(bars: &PciBars, reg: usize, value: u32) {
+        assert!(bars.write_config_register(PCI_BAR0_REG + reg, 0, &value.to_le_bytes()));
+    }
+
+    fn read(bars: &PciBars, reg: usize) -> u32 {
+        bars.read_config_register(PCI_BAR0_REG + reg).unwrap()
+    }
+
+    #[test]
+    fn test_bar_sizing_and_moves() {
+        let bars = PciBars::new(&[
+            PciBar::memory32(0x1000),
+            PciBar::memory64(0x10_0000).prefetchable(),
+            PciBar::io(GuestAddress(0xc000), 0x20),
+        ])
+        .unwrap();
+        let res = bars.io_resources();
+        assert_eq!(
+            res.iter().map(|r| r.res_type).collect::<Vec<IoType>>(),
+            vec![IoType::PciMmio32, IoType::PciMmio64, IoType::Pio]
+        );
+        bars.set_command(PCI_COMMAND_IO | PCI_COMMAND_MEMORY);
+        bars.set_resources(&[
+            IoResource::new(Some(GuestAddress(0xc000_0000)), 0x1000, IoType::PciMmio32),
+            IoResource::new(
+                Some(GuestAddress(0x1_0000_0000)),
+                0x10_0000,
+                IoType::PciMmio64,
+            ),
+            IoResource::new(Some(GuestAddress(0xc000)), 0x20, IoType::Pio),
+        ]);
+        assert_eq!(read(&bars, 0), 0xc000_0000);
+        assert_eq!((read(&bars, 1), read(&bars, 2)), (0xc, 0x1));
+        assert_eq!(read(&bars, 3), 0xc001);
+        assert_eq!(read(&bars, 4), 0);
+        assert_eq!(bars.read_config_register(PCI_BAR0_REG + 6), None);
+
+        // Sizing reads back the size mask and doesn't move the BARs.
+        for reg in 0..4 {
+            let saved = read(&bars, reg);
+            write(&bars, reg, 0xffff_ffff);
+            let size = match reg {
+                0 => 0xffff_f000,
+                1 => 0xfff0_000c,
+                2 => 0xffff_ffff,
+                _ => 0xffff_ffe1,
+            };
+            assert_eq!(read(&bars, reg), size);
+            write(&bars, reg, saved);
+        }
+        assert!(bars.take_moves().is_empty());
+
+        // A BAR moves after the write of any of its registers, whatever
+        // the order the halves of a 64-bit BAR are written in.
+        write(&bars, 0, 0xd000_0000);
+        assert_eq!(bars.take_moves(), vec![(0, GuestAddress(0xd000_0000))]);
+        write(&bars, 2, 0x2);
+        write(&bars, 1, 0x8000_0000);
+        assert_eq!(bars.take_moves(), vec![(1, GuestAddress(0x2_8000_0000))]);
+        assert_eq!(bars.address(1), Some(GuestAddress(0x2_8000_0000)));
+
+        // BARs don't move while their decoding is off, but once it is back.
+        bars.set_command(PCI_COMMAND_IO);
+        write(&bars, 1, 0x4000_0000);
+        write(&bars, 2, 0x3);
+        write(&bars, 3, 0xd000);
+        assert_eq!(bars.take_moves(), vec![(2, GuestAddress(0xd000))]);
+        bars.set_command(PCI_COMMAND_IO | PCI_COMMAND_MEMORY);
+        assert_eq!(bars.take_moves(), vec![(1, GuestAddress(0x3_4000_0000))]);
+
+        assert!(!bars.write_config_register(0, 0, &[0]));
+        assert!(PciBars::new(&[PciBar::memory32(0x1800)]).is_err());
+        assert!(PciBars::new(&[PciBar::memory64(0x1000); 4]).is_err());
+    }
+}
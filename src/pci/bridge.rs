@@ -0,0 +1,367 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI-to-PCI bridges.
+//!
+//! A [PciBridge](struct.PciBridge.html) is a function of its primary bus
+//! forwarding to a secondary bus, with its own range of bus numbers from the
+//! secondary to the subordinate one. Configuration cycles reach the
+//! secondary bus through the `PciRoot` of the hierarchy, and the buses
+//! behind the bridge use the numbers up to the subordinate one.
+//!
+//! The bridge forwards three address windows to its secondary side: an I/O
+//! one, a non-prefetchable memory one below 4 GiB and a prefetchable 64-bit
+//! memory one. They are allocated from the windows of the bus the bridge
+//! sits on when it is registered through
+//! `DeviceManager::register_pci_bridge()`, and the resources of the devices
+//! behind the bridge are in turn allocated from them. Those devices are
+//! registered on the PIO and MMIO buses of the `DeviceManager` like any
+//! other, so the accesses to their ranges reach them directly.
+
+use super::{
+    Error, PciConfiguration, PciConfigurationBuilder, PciDevice, Result, PCI_CLASS_BRIDGE,
+    PCI_IO_BASE, PCI_MEMORY_BASE, PCI_PREF_BASE_UPPER32, PCI_PREF_LIMIT_UPPER32,
+    PCI_PREF_MEMORY_BASE, PCI_PRIMARY_BUS,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use std::result;
+use vm_allocator::{AddressAllocator, Error as AllocatorError};
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// Vendor id of the emulated PCI-to-PCI bridge.
+const PCI_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+/// Device id of the emulated PCI-to-PCI bridge.
+const PCI_BRIDGE_DEVICE_ID: u16 = 0x0001;
+/// PCI-to-PCI bridge subclass.
+const PCI_SUBCLASS_BRIDGE_PCI: u8 = 0x04;
+/// Granularity of the I/O window.
+pub const PCI_BRIDGE_IO_ALIGN: GuestUsize = 0x1000;
+/// Granularity of the memory windows.
+pub const PCI_BRIDGE_MEM_ALIGN: GuestUsize = 0x10_0000;
+const PCI_IO_LIMIT: u64 = 0x1_0000;
+
+/// Windows a bridge forwards to its secondary bus.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PciBridgeWindows {
+    /// I/O window at a fixed port, its base and size 4 KiB aligned.
+    pub io: Option<(GuestAddress, GuestUsize)>,
+    /// Size of the memory window below 4 GiB, 0 or a power of two of at
+    /// least 1 MiB.
+    pub memory: GuestUsize,
+    /// Size of the prefetchable memory window, 0 or a power of two of at
+    /// least 1 MiB. It is allocated above 4 GiB when a 64-bit PCI MMIO window
+    /// is set, and 64-bit BARs behind the bridge are allocated from it.
+    pub prefetchable: GuestUsize,
+}
+
+impl PciBridgeWindows {
+    fn is_valid(&self) -> bool {
+        let memory_valid = |size: GuestUsize| {
+            size == 0 || (size.is_power_of_two() && size >= PCI_BRIDGE_MEM_ALIGN)
+        };
+        let io_valid = match self.io {
+            Some((base, size)) => {
+                size != 0
+                    && base.raw_value() % PCI_BRIDGE_IO_ALIGN == 0
+                    && size % PCI_BRIDGE_IO_ALIGN == 0
+                    && base.raw_value() + size <= PCI_IO_LIMIT
+            }
+            None => true,
+        };
+        io_valid && memory_valid(self.memory) && memory_valid(self.prefetchable)
+    }
+
+    /// IO resources of the windows: the I/O one, the memory one and the
+    /// prefetchable one, leaving out those with a null size.
+    pub fn io_resources(&self) -> Vec<IoResource> {
+        let mut resources = Vec::new();
+        if let Some((base, size)) = self.io {
+            resources.push(IoResource::new(Some(base), size, IoType::Pio));
+        }
+        if self.memory != 0 {
+            resources.push(IoResource::new(None, self.memory, IoType::PciMmio32));
+        }
+        if self.prefetchable != 0 {
+            resources
+                .push(IoResource::new(None, self.prefetchable, IoType::PciMmio64).prefetchable());
+        }
+        resources
+    }
+}
+
+/// PCI-to-PCI bridge function.
+pub struct PciBridge {
+    config: PciConfiguration,
+    secondary_bus: u8,
+    subordinate_bus: u8,
+    windows: PciBridgeWindows,
+}
+
+impl PciBridge {
+    /// Create a bridge to `secondary_bus`, owning the bus numbers up to
+    /// `subordinate_bus` and forwarding `windows`.
+    pub fn new(secondary_bus: u8, subordinate_bus: u8, windows: PciBridgeWindows) -> Result<Self> {
+        let config = PciConfigurationBuilder::new(PCI_BRIDGE_VENDOR_ID, PCI_BRIDGE_DEVICE_ID)
+            .class(PCI_CLASS_BRIDGE, PCI_SUBCLASS_BRIDGE_PCI, 0)
+            .bridge()
+            .build()?;
+        Self::with_configuration(config, secondary_bus, subordinate_bus, windows)
+    }
+
+    /// Create a bridge emulating `config`, a type 1 header laid out by a
+    /// `PciConfigurationBuilder`, e.g. with the capabilities of a PCIe port.
+    pub fn with_configuration(
+        config: PciConfiguration,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+        windows: PciBridgeWindows,
+    ) -> Result<Self> {
+        if secondary_bus == 0 || subordinate_bus < secondary_bus {
+            return Err(Error::InvalidBusRange(secondary_bus, subordinate_bus));
+        }
+        if !windows.is_valid() {
+            return Err(Error::InvalidBridgeWindow);
+        }
+        config.write(PCI_PRIMARY_BUS + 1, &[secondary_bus, subordinate_bus]);
+        Ok(PciBridge {
+            config,
+            secondary_bus,
+            subordinate_bus,
+            windows,
+        })
+    }
+
+    /// Secondary bus number.
+    pub fn secondary_bus(&self) -> u8 {
+        self.secondary_bus
+    }
+
+    /// Last bus number behind the bridge.
+    pub fn subordinate_bus(&self) -> u8 {
+        self.subordinate_bus
+    }
+
+    /// Requested windows.
+    pub fn windows(&self) -> PciBridgeWindows {
+        self.windows
+    }
+
+    /// Configuration space of the bridge.
+    pub fn configuration(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    /// Set the number of the bus the bridge sits on.
+    pub fn set_primary_bus(&self, bus: u8) {
+        self.config.write(PCI_PRIMARY_BUS, &[bus]);
+    }
+
+    /// Program the base and limit registers with the allocated windows.
+    pub fn set_windows(&self, windows: &[IoResource]) {
+        for window in windows.iter() {
+            let base = match window.addr {
+                Some(addr) => addr.raw_value(),
+                None => continue,
+            };
+            let limit = base + window.size - 1;
+            match window.res_type {
+                IoType::Pio => {
+                    self.config.write(
+                        PCI_IO_BASE,
+                        &[(base >> 8) as u8 & 0xf0, (limit >> 8) as u8 & 0xf0],
+                    );
+                }
+                IoType::PciMmio32 => {
+                    self.config
+                        .write_u16(PCI_MEMORY_BASE, (base >> 16) as u16 & 0xfff0);
+                    self.config
+                        .write_u16(PCI_MEMORY_BASE + 2, (limit >> 16) as u16 & 0xfff0);
+                }
+                IoType::PciMmio64 => {
+                    let range_type = self.config.read_u16(PCI_PREF_MEMORY_BASE) & 0xf;
+                    self.config.write_u16(
+                        PCI_PREF_MEMORY_BASE,
+                        ((base >> 16) as u16 & 0xfff0) | range_type,
+                    );
+                    self.config.write_u16(
+                        PCI_PREF_MEMORY_BASE + 2,
+                        ((limit >> 16) as u16 & 0xfff0) | range_type,
+                    );
+                    self.config
+                        .write_u32(PCI_PREF_BASE_UPPER32, (base >> 32) as u32);
+                    self.config
+                        .write_u32(PCI_PREF_LIMIT_UPPER32, (limit >> 32) as u32);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Device for PciBridge {
+    fn name(&self) -> String {
+        "pci-bridge".to_string()
+    }
+
+    fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+
+    fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+
+    fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+}
+
+impl PciDevice for PciBridge {
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config.read_config_register(reg_idx)
+    }
+
+    fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config.write_config_register(reg_idx, offset, data);
+    }
+
+    fn extended_config_space(&self) -> bool {
+        self.config.extended_config_space()
+    }
+}
+
+/// Windows of a registered bridge, the resources of the devices behind it
+/// being allocated from them.
+pub(crate) struct BridgeWindows {
+    /// Bus numbers owned by the bridge.
+    pub(crate) buses: (u8, u8),
+    /// Windows allocated from the bus the bridge sits on.
+    pub(crate) resources: Vec<IoResource>,
+    io: Option<AddressAllocator>,
+    memory: Option<AddressAllocator>,
+    prefetchable: Option<AddressAllocator>,
+}
+
+impl BridgeWindows {
+    pub(crate) fn new(bridge: &PciBridge, resources: Vec<IoResource>) -> Self {
+        let window = |res_type: IoType| {
+            resources
+                .iter()
+                .find(|r| r.res_type == res_type)
+                .and_then(|r| AddressAllocator::new(r.try_unwrap(), r.size, None))
+        };
+        BridgeWindows {
+            buses: (bridge.secondary_bus(), bridge.subordinate_bus()),
+            io: window(IoType::Pio),
+            memory: window(IoType::PciMmio32),
+            prefetchable: window(IoType::PciMmio64),
+            resources,
+        }
+    }
+
+    /// Allocate `res` from the windows, None if the bridge doesn't forward
+    /// its type of resources.
+    pub(crate) fn allocate(
+        &mut self,
+        res: &IoResource,
+    ) -> Option<result::Result<GuestAddress, AllocatorError>> {
+        let aligned = |window: &mut Option<AddressAllocator>| {
+            window
+                .as_mut()
+                .ok_or(AllocatorError::NoneAddress)?
+                .allocate_aligned(res.addr, res.size, res.alignment())
+                .map_err(AllocatorError::AddressAllocate)
+        };
+        let ret = match res.res_type {
+            IoType::Pio => aligned(&mut self.io),
+            IoType::PciMmio32 => aligned(&mut self.memory),
+            // Only prefetchable BARs may be put in the prefetchable window.
+            IoType::PciMmio64 if res.prefetchable => {
+                aligned(&mut self.prefetchable).or_else(|_| aligned(&mut self.memory))
+            }
+            IoType::PciMmio64 => aligned(&mut self.memory),
+            IoType::Mmio | IoType::PhysicalMmio => return None,
+        };
+        Some(ret)
+    }
+
+    /// Free `res` from the windows, returning false if the bridge doesn't
+    /// forward its type of resources.
+    pub(crate) fn free(&mut self, res: &IoResource) -> bool {
+        let windows = match res.res_type {
+            IoType::Pio => vec![&mut self.io],
+            IoType::PciMmio32 | IoType::PciMmio64 => {
+                vec![&mut self.memory, &mut self.prefetchable]
+            }
+            IoType::Mmio | IoType::PhysicalMmio => return false,
+        };
+        for window in windows.into_iter().flatten() {
+            window.free(res.try_unwrap(), res.size);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bridge_windows() {
+        let windows = PciBridgeWindows {
+            io: Some((GuestAddress(0x2000), 0x1000)),
+            memory: 0x10_0000,
+            prefetchable: 0x20_0000,
+        };
+        assert!(PciBridge::new(2, 1, windows).is_err());
+        assert!(PciBridge::new(
+            1,
+            1,
+            PciBridgeWindows {
+                memory: 0x1000,
+                ..windows
+            }
+        )
+        .is_err());
+        let bridge = PciBridge::new(1, 3, windows).unwrap();
+        bridge.set_primary_bus(0);
+        assert_eq!(bridge.read_config_register(6) & 0xff_ffff, 0x03_0100);
+        // Type 1 header of a PCI-to-PCI bridge, with closed windows.
+        assert_eq!(bridge.read_config_register(3) >> 16 & 0xff, 0x01);
+        assert_eq!(bridge.read_config_register(2) >> 16, 0x0604);
+        assert_eq!(bridge.read_config_register(8), 0x0000_fff0);
+
+        let mut resources = windows.io_resources();
+        resources[1].addr = Some(GuestAddress(0xc010_0000));
+        resources[2].addr = Some(GuestAddress(0x1_0020_0000));
+        bridge.set_windows(&resources);
+        assert_eq!(bridge.read_config_register(7) & 0xffff, 0x2020);
+        assert_eq!(bridge.read_config_register(8), 0xc010_c010);
+        assert_eq!(bridge.read_config_register(9), 0x0031_0021);
+        assert_eq!(bridge.read_config_register(10), 1);
+        assert_eq!(bridge.read_config_register(11), 1);
+        // Bus numbers are read-only.
+        bridge.write_config_register(6, 1, &[5]);
+        assert_eq!(bridge.read_config_register(6) & 0xff_ffff, 0x03_0100);
+
+        // Devices behind the bridge are allocated from its windows.
+        let mut allocated = BridgeWindows::new(&bridge, resources);
+        let bar = IoResource::new(None, 0x1_0000, IoType::PciMmio64).prefetchable();
+        assert_eq!(
+            allocated.allocate(&bar).unwrap().unwrap(),
+            GuestAddress(0x1_003f_0000)
+        );
+        // Non-prefetchable 64-bit BARs go to the memory window.
+        let bar = IoResource::new(None, 0x1_0000, IoType::PciMmio64);
+        assert_eq!(
+            allocated.allocate(&bar).unwrap().unwrap(),
+            GuestAddress(0xc01f_0000)
+        );
+        let bar = IoResource::new(None, 0x20_0000, IoType::PciMmio64).prefetchable();
+        assert!(allocated.allocate(&bar).unwrap().is_err());
+        let io = IoResource::new(Some(GuestAddress(0x3000)), 0x10, IoType::Pio);
+        assert!(allocated.allocate(&io).unwrap().is_err());
+        // IO ports without an address are allocated from the window.
+        let io = IoResource::new(None, 0x10, IoType::Pio);
+        assert_eq!(
+            allocated.allocate(&io).unwrap().unwrap(),
+            GuestAddress(0x2ff0)
+        );
+        let mmio = IoResource::new(None, 0x1000, IoType::Mmio);
+        assert!(allocated.allocate(&mmio).is_none());
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI buses and the hierarchy they form behind a host bridge.
+
+use super::{
+    devfn, Error, PciAddress, PciDevice, Result, PCI_CLASS_BRIDGE, PCI_CONFIG_REGISTERS,
+    PCI_DEVICES_PER_BUS, PCI_INTX_PINS,
+};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Functions value read from an empty configuration space.
+const NO_FUNCTION: u32 = 0xffff_ffff;
+
+/// Vendor id of the emulated host bridge.
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x8086;
+/// Device id of the emulated host bridge.
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0d57;
+
+/// Read-only configuration space of the host bridge function at 00:00.0.
+struct HostBridge;
+
+impl PciDevice for HostBridge {
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        match reg_idx {
+            0 => (u32::from(HOST_BRIDGE_DEVICE_ID) << 16) | u32::from(HOST_BRIDGE_VENDOR_ID),
+            // Host bridge subclass is 0, as is the programming interface.
+            2 => u32::from(PCI_CLASS_BRIDGE) << 24,
+            _ => 0,
+        }
+    }
+
+    fn write_config_register(&self, _reg_idx: usize, _offset: u64, _data: &[u8]) {}
+}
+
+/// PCI bus holding the functions of up to 32 devices.
+pub struct PciBus {
+    number: u8,
+    /// Functions mapped by devfn.
+    functions: Mutex<BTreeMap<u8, Arc<dyn PciDevice>>>,
+}
+
+impl PciBus {
+    /// Create an empty bus numbered `number`.
+    pub fn new(number: u8) -> Self {
+        PciBus {
+            number,
+            functions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Bus number.
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Add a PCI function at `devfn`, or as function 0 of the first device
+    /// number without any function. Return the devfn of the function.
+    pub fn add_device(&self, devfn: Option<u8>, dev: Arc<dyn PciDevice>) -> Result<u8> {
+        let mut functions = self.functions.lock().expect("failed to acquire lock");
+        let devfn = match devfn {
+            Some(devfn) => {
+                if functions.contains_key(&devfn) {
+                    return Err(Error::AddressInUse(PciAddress::new(self.number, devfn)));
+                }
+                devfn
+            }
+            None => (0..PCI_DEVICES_PER_BUS)
+                .map(|device| self::devfn(device, 0))
+                .find(|first| !functions.keys().any(|f| f & !0x7 == *first))
+                .ok_or(Error::NoFreeDevice(self.number))?,
+        };
+        functions.insert(devfn, dev);
+        Ok(devfn)
+    }
+
+    /// Remove the PCI function at `devfn`.
+    pub fn remove_device(&self, devfn: u8) -> Option<Arc<dyn PciDevice>> {
+        self.functions
+            .lock()
+            .expect("failed to acquire lock")
+            .remove(&devfn)
+    }
+
+    /// PCI function at `devfn`.
+    pub fn device(&self, devfn: u8) -> Option<Arc<dyn PciDevice>> {
+        self.functions
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&devfn)
+            .cloned()
+    }
+
+    /// Devfn of the functions on the bus, in increasing order.
+    pub fn devfns(&self) -> Vec<u8> {
+        self.functions
+            .lock()
+            .expect("failed to acquire lock")
+            .keys()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Hierarchy of the PCI buses reachable from one host bridge.
+///
+/// The configuration mechanisms decode a `PciAddress` and dispatch the
+/// configuration accesses to the function through the hierarchy. Each
+/// hierarchy is a PCI segment, numbering its buses from 0 to its last bus.
+pub struct PciRoot {
+    /// PCI segment of the hierarchy.
+    segment: u16,
+    /// Last bus number the bridges of the hierarchy may use.
+    last_bus: u8,
+    /// Buses mapped by bus number.
+    buses: Mutex<BTreeMap<u8, Arc<PciBus>>>,
+    /// Interrupt lines the INTA# to INTD# pins of the root bus are routed to.
+    intx_lines: Mutex<Option<[u32; PCI_INTX_PINS as usize]>>,
+}
+
+impl Default for PciRoot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PciRoot {
+    /// Create a hierarchy holding bus 0, with the host bridge function at
+    /// 00:00.0, as PCI segment 0 with all 256 buses.
+    pub fn new() -> Self {
+        Self::with_segment(0, u8::MAX)
+    }
+
+    /// Create a hierarchy holding bus 0, with the host bridge function at
+    /// 00:00.0, as PCI segment `segment` with the buses 0 to `last_bus`.
+    pub fn with_segment(segment: u16, last_bus: u8) -> Self {
+        let bus = PciBus::new(0);
+        bus.functions
+            .lock()
+            .expect("failed to acquire lock")
+            .insert(devfn(0, 0), Arc::new(HostBridge));
+        let mut buses = BTreeMap::new();
+        buses.insert(0, Arc::new(bus));
+        PciRoot {
+            segment,
+            last_bus,
+            buses: Mutex::new(buses),
+            intx_lines: Mutex::new(None),
+        }
+    }
+
+    /// PCI segment of the hierarchy.
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+
+    /// Last bus number of the hierarchy.
+    pub fn last_bus(&self) -> u8 {
+        self.last_bus
+    }
+
+    /// Bus numbered `number`.
+    pub fn bus(&self, number: u8) -> Option<Arc<PciBus>> {
+        self.buses
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&number)
+            .cloned()
+    }
+
+    /// Add an empty bus numbered `number`, e.g. the secondary bus of a
+    /// bridge.
+    pub fn add_bus(&self, number: u8) -> Result<Arc<PciBus>> {
+        let mut buses = self.buses.lock().expect("failed to acquire lock");
+        if buses.contains_key(&number) {
+            return Err(Error::BusInUse(number));
+        }
+        let bus = Arc::new(PciBus::new(number));
+        buses.insert(number, bus.clone());
+        Ok(bus)
+    }
+
+    /// Remove the bus numbered `number`.
+    pub fn remove_bus(&self, number: u8) -> Option<Arc<PciBus>> {
+        self.buses
+            .lock()
+            .expect("failed to acquire lock")
+            .remove(&number)
+    }
+
+    /// Interrupt lines of the hierarchy, once allocated at the registration
+    /// of its root bus.
+    pub fn intx_lines(&self) -> Option<[u32; PCI_INTX_PINS as usize]> {
+        *self.intx_lines.lock().expect("failed to acquire lock")
+    }
+
+    pub(crate) fn set_intx_lines(&self, lines: Option<[u32; PCI_INTX_PINS as usize]>) {
+        *self.intx_lines.lock().expect("failed to acquire lock") = lines;
+    }
+
+    /// PCI function at `addr`.
+    pub fn device(&self, addr: PciAddress) -> Option<Arc<dyn PciDevice>> {
+        self.bus(addr.bus)?.device(addr.devfn())
+    }
+
+    /// Read the configuration register at `reg_idx` of the function at `addr`.
+    ///
+    /// An absent function reads as all ones, and the extended configuration
+    /// space of a function without one reads as zeros.
+    pub fn read_config_register(&self, addr: PciAddress, reg_idx: usize) -> u32 {
+        match self.device(addr) {
+            Some(ref dev) if reg_idx >= PCI_CONFIG_REGISTERS && !dev.extended_config_space() => 0,
+            Some(dev) => dev.read_config_register(reg_idx),
+            None => NO_FUNCTION,
+        }
+    }
+
+    /// Write the configuration register at `reg_idx` of the function at
+    /// `addr`. Writes to an absent function are dropped.
+    pub fn write_config_register(
+        &self,
+        addr: PciAddress,
+        reg_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) {
+        if let Some(dev) = self.device(addr) {
+            if reg_idx < PCI_CONFIG_REGISTERS || dev.extended_config_space() {
+                dev.write_config_register(reg_idx, offset, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Function(u32);
+
+    impl PciDevice for Function {
+        fn read_config_register(&self, _reg_idx: usize) -> u32 {
+            self.0
+        }
+        fn write_config_register(&self, _reg_idx: usize, _offset: u64, _data: &[u8]) {}
+    }
+
+    #[test]
+    fn test_devfn_allocation() {
+        let bus = PciBus::new(0);
+        assert_eq!(bus.add_device(None, Arc::new(Function(1))).unwrap(), 0);
+        assert_eq!(
+            bus.add_device(Some(devfn(1, 2)), Arc::new(Function(2)))
+                .unwrap(),
+            devfn(1, 2)
+        );
+        // Device 1 has a function, so it isn't free anymore.
+        assert_eq!(
+            bus.add_device(None, Arc::new(Function(3))).unwrap(),
+            devfn(2, 0)
+        );
+        assert_eq!(
+            bus.add_device(Some(0), Arc::new(Function(4))),
+            Err(Error::AddressInUse(PciAddress::new(0, 0)))
+        );
+        for _ in 3..PCI_DEVICES_PER_BUS {
+            bus.add_device(None, Arc::new(Function(5))).unwrap();
+        }
+        assert_eq!(
+            bus.add_device(None, Arc::new(Function(6))),
+            Err(Error::NoFreeDevice(0))
+        );
+
+        assert!(bus.remove_device(devfn(1, 2)).is_some());
+        assert_eq!(
+            bus.add_device(None, Arc::new(Function(7))).unwrap(),
+            devfn(1, 0)
+        );
+
+        let root = PciRoot::new();
+        assert_eq!(
+            root.read_config_register(PciAddress::new(0, 0), 0),
+            0x0d57_8086
+        );
+        root.bus(0)
+            .unwrap()
+            .add_device(Some(devfn(3, 0)), Arc::new(Function(0x1234)))
+            .unwrap();
+        assert_eq!(
+            root.read_config_register(PciAddress::new(0, devfn(3, 0)), 0),
+            0x1234
+        );
+        assert_eq!(
+            root.read_config_register(PciAddress::new(0, devfn(3, 1)), 0),
+            NO_FUNCTION
+        );
+        // Function 03.0 has no extended configuration space.
+        assert_eq!(
+            root.read_config_register(PciAddress::new(0, devfn(3, 0)), 0x40),
+            0
+        );
+        assert_eq!(
+            root.read_config_register(PciAddress::new(1, 0), 0),
+            NO_FUNCTION
+        );
+        assert_eq!(format!("{}", PciAddress::new(0, devfn(3, 1))), "00:03.1");
+    }
+}
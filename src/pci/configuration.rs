@@ -0,0 +1,926 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Configuration space of a PCI function.
+//!
+//! A [PciConfigurationBuilder](struct.PciConfigurationBuilder.html) lays out
+//! the type 0 header, or the type 1 header of a PCI-to-PCI bridge, and the
+//! capabilities a device declares, and builds a
+//! [PciConfiguration](struct.PciConfiguration.html) emulating the guest
+//! accesses to it. Every byte carries a writable mask and a write-1-to-clear
+//! mask, bits outside both being read-only. Capabilities are linked from the
+//! capabilities pointer starting at offset 0x40, and extended capabilities
+//! from offset 0x100 of the 4 KiB PCIe configuration space.
+//!
+//! The BAR registers are emulated by the [PciBars](struct.PciBars.html) given
+//! to the builder, and the VF BAR registers of a physical function by its
+//! [PciSriov](struct.PciSriov.html).
+
+use super::{
+    read_register_bytes, Error, PciBars, PciSriov, Result, PCIE_CONFIG_REGISTERS, PCI_BAR0_REG,
+    PCI_BAR_REGISTERS, PCI_CONFIG_REGISTERS, PCI_EXT_CAP_ID_SRIOV,
+};
+use std::sync::Mutex;
+use vm_memory::GuestAddress;
+
+/// Power management capability id.
+pub const PCI_CAP_ID_PM: u8 = 0x01;
+/// MSI capability id.
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+/// Vendor-specific capability id.
+pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// PCI Express capability id.
+pub const PCI_CAP_ID_EXP: u8 = 0x10;
+/// MSI-X capability id.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// Offset of the command register.
+pub const PCI_COMMAND: usize = 0x04;
+/// Offset of the status register.
+pub const PCI_STATUS: usize = 0x06;
+/// Offset of the capabilities pointer.
+pub const PCI_CAPABILITY_LIST: usize = 0x34;
+/// Offset of the interrupt line register.
+pub const PCI_INTERRUPT_LINE: usize = 0x3c;
+/// Offset of the interrupt pin register.
+pub const PCI_INTERRUPT_PIN: usize = 0x3d;
+/// Offset of the primary, secondary and subordinate bus numbers of a bridge.
+pub const PCI_PRIMARY_BUS: usize = 0x18;
+/// Offset of the I/O base and limit registers of a bridge.
+pub const PCI_IO_BASE: usize = 0x1c;
+/// Offset of the memory base and limit registers of a bridge.
+pub const PCI_MEMORY_BASE: usize = 0x20;
+/// Offset of the prefetchable memory base and limit registers of a bridge.
+pub const PCI_PREF_MEMORY_BASE: usize = 0x24;
+/// Offset of the upper 32 bits of the prefetchable memory base of a bridge.
+pub const PCI_PREF_BASE_UPPER32: usize = 0x28;
+/// Offset of the upper 32 bits of the prefetchable memory limit of a bridge.
+pub const PCI_PREF_LIMIT_UPPER32: usize = 0x2c;
+/// Offset of the bridge control register.
+pub const PCI_BRIDGE_CONTROL: usize = 0x3e;
+
+/// Command register: I/O space decoding enabled.
+pub const PCI_COMMAND_IO: u16 = 0x1;
+/// Command register: memory space decoding enabled.
+pub const PCI_COMMAND_MEMORY: u16 = 0x2;
+/// Command register: bus mastering enabled.
+pub const PCI_COMMAND_MASTER: u16 = 0x4;
+/// Command register: INTx emulation disabled.
+pub const PCI_COMMAND_INTX_DISABLE: u16 = 0x400;
+/// Status register: INTx asserted.
+pub const PCI_STATUS_INTERRUPT: u16 = 0x8;
+
+const PCI_STATUS_CAP_LIST: u16 = 0x10;
+const PCI_HEADER_TYPE: usize = 0x0e;
+const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+const PCI_BRIDGE_BAR_REGISTERS: usize = 2;
+const PCI_PREF_RANGE_TYPE_64: u8 = 0x01;
+const PCI_CONFIG_SPACE_SIZE: usize = PCI_CONFIG_REGISTERS * 4;
+const PCIE_CONFIG_SPACE_SIZE: usize = PCIE_CONFIG_REGISTERS * 4;
+const PCI_CAP_START: usize = 0x40;
+const PCI_EXT_CAP_START: usize = 0x100;
+
+/// Port type of a PCI Express function.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PciExpressPortType {
+    /// PCI Express endpoint.
+    Endpoint = 0x0,
+    /// Root port of a root complex.
+    RootPort = 0x4,
+    /// Upstream port of a switch.
+    UpstreamPort = 0x5,
+    /// Downstream port of a switch.
+    DownstreamPort = 0x6,
+    /// Root complex integrated endpoint.
+    RootComplexEndpoint = 0x9,
+}
+
+/// Capability of the legacy configuration space.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PciCapability {
+    /// Power management, with the D0 to D3hot power states.
+    PowerManagement,
+    /// MSI, with a power of two number of `vectors` up to 32.
+    Msi {
+        /// Number of vectors the function can use.
+        vectors: u32,
+        /// The message address may be 64-bit.
+        address64: bool,
+        /// Each vector can be masked.
+        per_vector_mask: bool,
+    },
+    /// MSI-X, the table and PBA living in BARs of the function.
+    MsiX {
+        /// Number of vectors in the table.
+        table_size: u16,
+        /// BAR index of the table.
+        table_bar: u8,
+        /// Offset of the table in its BAR, 8-byte aligned.
+        table_offset: u32,
+        /// BAR index of the pending bit array.
+        pba_bar: u8,
+        /// Offset of the pending bit array in its BAR, 8-byte aligned.
+        pba_offset: u32,
+    },
+    /// PCI Express, making the 4 KiB extended configuration space available.
+    PciExpress {
+        /// Port type of the function.
+        port_type: PciExpressPortType,
+    },
+    /// Vendor-specific capability holding read-only `data`.
+    VendorSpecific(Vec<u8>),
+}
+
+/// Capability of the PCIe extended configuration space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PciExtendedCapability {
+    /// Capability id.
+    pub id: u16,
+    /// Capability version.
+    pub version: u8,
+    /// Capability registers following the 4-byte header.
+    pub data: Vec<u8>,
+    /// Writable mask of `data`, missing bytes are read-only.
+    pub writable: Vec<u8>,
+}
+
+impl PciExtendedCapability {
+    /// Build a read-only extended capability.
+    pub fn new(id: u16, version: u8, data: Vec<u8>) -> Self {
+        PciExtendedCapability {
+            id,
+            version,
+            data,
+            writable: Vec::new(),
+        }
+    }
+}
+
+// Bytes of a capability along with their writable and write-1-to-clear
+// masks. The first byte is the capability id and the second the next
+// pointer, filled in when the list is linked.
+struct CapabilityLayout {
+    bytes: Vec<u8>,
+    writable: Vec<u8>,
+    w1c: Vec<u8>,
+}
+
+impl CapabilityLayout {
+    fn new(id: u8, size: usize) -> Self {
+        let mut bytes = vec![0; size];
+        bytes[0] = id;
+        CapabilityLayout {
+            bytes,
+            writable: vec![0; size],
+            w1c: vec![0; size],
+        }
+    }
+
+    fn set(&mut self, offset: usize, value: &[u8]) {
+        self.bytes[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    fn writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    fn w1c(&mut self, offset: usize, mask: &[u8]) {
+        self.w1c[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+}
+
+impl PciCapability {
+    fn id(&self) -> u8 {
+        match self {
+            PciCapability::PowerManagement => PCI_CAP_ID_PM,
+            PciCapability::Msi { .. } => PCI_CAP_ID_MSI,
+            PciCapability::MsiX { .. } => PCI_CAP_ID_MSIX,
+            PciCapability::PciExpress { .. } => PCI_CAP_ID_EXP,
+            PciCapability::VendorSpecific(_) => PCI_CAP_ID_VNDR,
+        }
+    }
+
+    fn layout(&self) -> Result<CapabilityLayout> {
+        let layout = match self {
+            PciCapability::PowerManagement => {
+                let mut cap = CapabilityLayout::new(PCI_CAP_ID_PM, 8);
+                // Version 3 of the specification.
+                cap.set(2, &3u16.to_le_bytes());
+                // Power state, PME enable and PME status.
+                cap.writable(4, &[0x03, 0x01]);
+                cap.w1c(5, &[0x80]);
+                cap
+            }
+            PciCapability::Msi {
+                vectors,
+                address64,
+                per_vector_mask,
+            } => {
+                if !vectors.is_power_of_two() || *vectors > 32 {
+                    return Err(Error::InvalidCapability(PCI_CAP_ID_MSI.into()));
+                }
+                let data_offset = if *address64 { 12 } else { 8 };
+                let size = if *per_vector_mask {
+                    data_offset + 12
+                } else {
+                    data_offset + 2
+                };
+                let mut cap = CapabilityLayout::new(PCI_CAP_ID_MSI, size);
+                let mut control = (vectors.trailing_zeros() as u16) << 1;
+                if *address64 {
+                    control |= 1 << 7;
+                }
+                if *per_vector_mask {
+                    control |= 1 << 8;
+                }
+                cap.set(2, &control.to_le_bytes());
+                // MSI enable and multiple message enable.
+                cap.writable(2, &[0x71]);
+                cap.writable(4, &[0xfc, 0xff, 0xff, 0xff]);
+                if *address64 {
+                    cap.writable(8, &[0xff; 4]);
+                }
+                cap.writable(data_offset, &[0xff; 2]);
+                if *per_vector_mask {
+                    let mask = if *vectors == 32 {
+                        u32::MAX
+                    } else {
+                        (1 << vectors) - 1
+                    };
+                    cap.writable(data_offset + 4, &mask.to_le_bytes());
+                }
+                cap
+            }
+            PciCapability::MsiX {
+                table_size,
+                table_bar,
+                table_offset,
+                pba_bar,
+                pba_offset,
+            } => {
+                if *table_size == 0
+                    || *table_size > 2048
+                    || *table_bar as usize >= PCI_BAR_REGISTERS
+                    || *pba_bar as usize >= PCI_BAR_REGISTERS
+                    || table_offset & 0x7 != 0
+                    || pba_offset & 0x7 != 0
+                {
+                    return Err(Error::InvalidCapability(PCI_CAP_ID_MSIX.into()));
+                }
+                let mut cap = CapabilityLayout::new(PCI_CAP_ID_MSIX, 12);
+                cap.set(2, &(table_size - 1).to_le_bytes());
+                // Function mask and MSI-X enable.
+                cap.writable(3, &[0xc0]);
+                cap.set(4, &(table_offset | u32::from(*table_bar)).to_le_bytes());
+                cap.set(8, &(pba_offset | u32::from(*pba_bar)).to_le_bytes());
+                cap
+            }
+            PciCapability::PciExpress { port_type } => {
+                let mut cap = CapabilityLayout::new(PCI_CAP_ID_EXP, 0x3c);
+                // Capability version 2.
+                cap.set(2, &(0x2 | (*port_type as u16) << 4).to_le_bytes());
+                // Device control and status.
+                cap.writable(0x08, &[0xff, 0xff]);
+                cap.w1c(0x0a, &[0x0f]);
+                // x1 link at 2.5 GT/s.
+                cap.set(0x0c, &0x11u32.to_le_bytes());
+                cap.writable(0x10, &[0xff, 0x0f]);
+                cap.set(0x12, &0x11u16.to_le_bytes());
+                match port_type {
+                    PciExpressPortType::RootPort | PciExpressPortType::DownstreamPort => {
+                        // Slot control and status.
+                        cap.writable(0x18, &[0xff, 0x1f]);
+                        cap.w1c(0x1a, &[0x1f, 0x01]);
+                    }
+                    _ => {}
+                }
+                if *port_type == PciExpressPortType::RootPort {
+                    // Root control and PME status.
+                    cap.writable(0x1c, &[0x1f]);
+                    cap.w1c(0x22, &[0x01]);
+                }
+                cap.writable(0x28, &[0xff, 0xff]);
+                cap.set(0x2c, &0x2u32.to_le_bytes());
+                cap.writable(0x30, &[0xff, 0xff]);
+                cap
+            }
+            PciCapability::VendorSpecific(data) => {
+                if data.len() + 3 > 0xff {
+                    return Err(Error::InvalidCapability(PCI_CAP_ID_VNDR.into()));
+                }
+                let mut cap = CapabilityLayout::new(PCI_CAP_ID_VNDR, data.len() + 3);
+                cap.set(2, &[(data.len() + 3) as u8]);
+                cap.set(3, data);
+                cap
+            }
+        };
+        Ok(layout)
+    }
+}
+
+/// Builder of the configuration space of a PCI function.
+pub struct PciConfigurationBuilder {
+    vendor_id: u16,
+    device_id: u16,
+    revision: u8,
+    class: (u8, u8, u8),
+    subsystem: (u16, u16),
+    interrupt_pin: u8,
+    bridge: bool,
+    bars: Option<PciBars>,
+    capabilities: Vec<PciCapability>,
+    extended_capabilities: Vec<PciExtendedCapability>,
+    sriov: Option<PciSriov>,
+}
+
+impl PciConfigurationBuilder {
+    /// Start the configuration space of a function identified by
+    /// `vendor_id` and `device_id`.
+    pub fn new(vendor_id: u16, device_id: u16) -> Self {
+        PciConfigurationBuilder {
+            vendor_id,
+            device_id,
+            revision: 0,
+            class: (0, 0, 0),
+            subsystem: (0, 0),
+            interrupt_pin: 0,
+            bridge: false,
+            bars: None,
+            capabilities: Vec::new(),
+            extended_capabilities: Vec::new(),
+            sriov: None,
+        }
+    }
+
+    /// Set the revision id.
+    pub fn revision(mut self, revision: u8) -> Self {
+        self.revision = revision;
+        self
+    }
+
+    /// Set the class code, subclass and programming interface.
+    pub fn class(mut self, class: u8, subclass: u8, prog_if: u8) -> Self {
+        self.class = (class, subclass, prog_if);
+        self
+    }
+
+    /// Set the subsystem vendor id and subsystem id.
+    pub fn subsystem(mut self, vendor_id: u16, id: u16) -> Self {
+        self.subsystem = (vendor_id, id);
+        self
+    }
+
+    /// Set the INTx pin used by the function, 1 for INTA# to 4 for INTD#.
+    pub fn interrupt_pin(mut self, pin: u8) -> Self {
+        self.interrupt_pin = pin;
+        self
+    }
+
+    /// Lay out the type 1 header of a PCI-to-PCI bridge rather than the
+    /// type 0 one. The bus numbers are read-only, the windows base and limit
+    /// registers writable, and the BARs limited to the first two registers.
+    /// A bridge has no subsystem ids in its header.
+    pub fn bridge(mut self) -> Self {
+        self.bridge = true;
+        self
+    }
+
+    /// Set the BAR registers of the function.
+    pub fn bars(mut self, bars: PciBars) -> Self {
+        self.bars = Some(bars);
+        self
+    }
+
+    /// Add a capability, linked after the ones added before.
+    pub fn capability(mut self, capability: PciCapability) -> Self {
+        self.capabilities.push(capability);
+        self
+    }
+
+    /// Add an extended capability, linked after the ones added before.
+    pub fn extended_capability(mut self, capability: PciExtendedCapability) -> Self {
+        self.extended_capabilities.push(capability);
+        self
+    }
+
+    /// Make the function an SR-IOV physical function, adding the SR-IOV
+    /// extended capability after the ones added before.
+    pub fn sriov(mut self, sriov: PciSriov) -> Self {
+        self.extended_capabilities.push(sriov.extended_capability());
+        self.sriov = Some(sriov);
+        self
+    }
+
+    /// Lay out the header and the capabilities.
+    pub fn build(self) -> Result<PciConfiguration> {
+        let extended = !self.extended_capabilities.is_empty()
+            || self.capabilities.iter().any(|c| c.id() == PCI_CAP_ID_EXP);
+        let size = if extended {
+            PCIE_CONFIG_SPACE_SIZE
+        } else {
+            PCI_CONFIG_SPACE_SIZE
+        };
+        let mut space = ConfigSpace {
+            bytes: vec![0; size],
+            writable: vec![0; size],
+            w1c: vec![0; size],
+            initial: Vec::new(),
+        };
+
+        space.set(0x00, &self.vendor_id.to_le_bytes());
+        space.set(0x02, &self.device_id.to_le_bytes());
+        space.set(
+            0x08,
+            &[self.revision, self.class.2, self.class.1, self.class.0],
+        );
+        if self.bridge {
+            space.set(PCI_HEADER_TYPE, &[PCI_HEADER_TYPE_BRIDGE]);
+            // Windows are closed, with their base above their limit.
+            space.set(PCI_IO_BASE, &[0xf0, 0x00]);
+            space.set(PCI_MEMORY_BASE, &[0xf0, 0xff, 0x00, 0x00]);
+            space.set(
+                PCI_PREF_MEMORY_BASE,
+                &[
+                    0xf0 | PCI_PREF_RANGE_TYPE_64,
+                    0xff,
+                    PCI_PREF_RANGE_TYPE_64,
+                    0x00,
+                ],
+            );
+            // Secondary latency timer, windows and bridge control.
+            space.writable(PCI_PRIMARY_BUS + 3, &[0xff]);
+            space.writable(PCI_IO_BASE, &[0xf0, 0xf0]);
+            space.writable(PCI_MEMORY_BASE, &[0xf0, 0xff, 0xf0, 0xff]);
+            space.writable(PCI_PREF_MEMORY_BASE, &[0xf0, 0xff, 0xf0, 0xff]);
+            space.writable(PCI_PREF_BASE_UPPER32, &[0xff; 8]);
+            space.writable(PCI_BRIDGE_CONTROL, &[0xff, 0x0f]);
+            // Error bits of the secondary status register.
+            space.w1c(PCI_IO_BASE + 3, &[0xf9]);
+        } else {
+            space.set(0x2c, &self.subsystem.0.to_le_bytes());
+            space.set(0x2e, &self.subsystem.1.to_le_bytes());
+        }
+        space.set(PCI_INTERRUPT_PIN, &[self.interrupt_pin]);
+        space.writable(
+            PCI_COMMAND,
+            &(PCI_COMMAND_IO
+                | PCI_COMMAND_MEMORY
+                | PCI_COMMAND_MASTER
+                | 0x140
+                | PCI_COMMAND_INTX_DISABLE)
+                .to_le_bytes(),
+        );
+        // Error bits of the status register.
+        space.w1c(PCI_STATUS + 1, &[0xf9]);
+        // Cache line size and interrupt line.
+        space.writable(0x0c, &[0xff]);
+        space.writable(PCI_INTERRUPT_LINE, &[0xff]);
+
+        let mut capabilities = Vec::new();
+        let mut offset = PCI_CAP_START;
+        let mut prev_next = PCI_CAPABILITY_LIST;
+        for capability in self.capabilities.iter() {
+            let layout = capability.layout()?;
+            if offset + layout.bytes.len() > PCI_CONFIG_SPACE_SIZE {
+                return Err(Error::CapabilitySpaceFull);
+            }
+            space.set(offset, &layout.bytes);
+            space.writable(offset, &layout.writable);
+            space.w1c(offset, &layout.w1c);
+            space.bytes[prev_next] = offset as u8;
+            prev_next = offset + 1;
+            capabilities.push((capability.id(), offset));
+            offset = (offset + layout.bytes.len() + 3) & !3;
+        }
+        if !capabilities.is_empty() {
+            let status = u16::from_le_bytes([space.bytes[PCI_STATUS], space.bytes[PCI_STATUS + 1]])
+                | PCI_STATUS_CAP_LIST;
+            space.set(PCI_STATUS, &status.to_le_bytes());
+        }
+
+        let mut extended_capabilities = Vec::new();
+        let mut offset = PCI_EXT_CAP_START;
+        let mut prev_header: Option<usize> = None;
+        for capability in self.extended_capabilities.iter() {
+            if capability.version > 0xf || capability.writable.len() > capability.data.len() {
+                return Err(Error::InvalidCapability(capability.id));
+            }
+            if offset + 4 + capability.data.len() > PCIE_CONFIG_SPACE_SIZE {
+                return Err(Error::CapabilitySpaceFull);
+            }
+            let header = u32::from(capability.id) | (u32::from(capability.version) << 16);
+            space.set(offset, &header.to_le_bytes());
+            space.set(offset + 4, &capability.data);
+            space.writable(offset + 4, &capability.writable);
+            if let Some(prev) = prev_header {
+                let header = space.read_u32(prev) | ((offset as u32) << 20);
+                space.set(prev, &header.to_le_bytes());
+            }
+            prev_header = Some(offset);
+            extended_capabilities.push((capability.id, offset));
+            offset = (offset + 4 + capability.data.len() + 3) & !3;
+        }
+        space.initial = space.bytes.clone();
+
+        Ok(PciConfiguration {
+            space: Mutex::new(space),
+            bar_registers: if self.bridge {
+                PCI_BRIDGE_BAR_REGISTERS
+            } else {
+                PCI_BAR_REGISTERS
+            },
+            bars: self.bars,
+            sriov: self.sriov,
+            capabilities,
+            extended_capabilities,
+        })
+    }
+}
+
+struct ConfigSpace {
+    bytes: Vec<u8>,
+    writable: Vec<u8>,
+    w1c: Vec<u8>,
+    /// Power-on values of the bytes.
+    initial: Vec<u8>,
+}
+
+impl ConfigSpace {
+    fn set(&mut self, offset: usize, value: &[u8]) {
+        self.bytes[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    fn writable(&mut self, offset: usize, mask: &[u8]) {
+        self.writable[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    fn w1c(&mut self, offset: usize, mask: &[u8]) {
+        self.w1c[offset..offset + mask.len()].copy_from_slice(mask);
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(&self.bytes[offset..offset + 4]);
+        u32::from_le_bytes(value)
+    }
+}
+
+/// Configuration space of a PCI function.
+pub struct PciConfiguration {
+    space: Mutex<ConfigSpace>,
+    /// Number of registers holding BARs, from BAR0.
+    bar_registers: usize,
+    bars: Option<PciBars>,
+    sriov: Option<PciSriov>,
+    /// Capability ids and offsets, in list order.
+    capabilities: Vec<(u8, usize)>,
+    /// Extended capability ids and offsets, in list order.
+    extended_capabilities: Vec<(u16, usize)>,
+}
+
+impl PciConfiguration {
+    /// Return true if the 4 KiB PCIe extended configuration space is
+    /// implemented.
+    pub fn extended_config_space(&self) -> bool {
+        self.space
+            .lock()
+            .expect("failed to acquire lock")
+            .bytes
+            .len()
+            > PCI_CONFIG_SPACE_SIZE
+    }
+
+    /// BAR registers of the function.
+    pub fn bars(&self) -> Option<&PciBars> {
+        self.bars.as_ref()
+    }
+
+    /// SR-IOV capability of a physical function.
+    pub fn sriov(&self) -> Option<&PciSriov> {
+        self.sriov.as_ref()
+    }
+
+    // SR-IOV capability and its offset.
+    fn sriov_capability(&self) -> Option<(&PciSriov, usize)> {
+        let sriov = self.sriov.as_ref()?;
+        Some((
+            sriov,
+            self.extended_capability_offset(PCI_EXT_CAP_ID_SRIOV)?,
+        ))
+    }
+
+    /// Take the BAR moves programmed by the guest, see `PciBars::take_moves()`.
+    pub fn take_bar_moves(&self) -> Vec<(usize, GuestAddress)> {
+        self.bars
+            .as_ref()
+            .map_or_else(Vec::new, PciBars::take_moves)
+    }
+
+    /// Offset of the first capability with `id`.
+    pub fn capability_offset(&self, id: u8) -> Option<usize> {
+        self.capabilities
+            .iter()
+            .find(|(cap_id, _)| *cap_id == id)
+            .map(|(_, offset)| *offset)
+    }
+
+    /// Offset of the first extended capability with `id`.
+    pub fn extended_capability_offset(&self, id: u16) -> Option<usize> {
+        self.extended_capabilities
+            .iter()
+            .find(|(cap_id, _)| *cap_id == id)
+            .map(|(_, offset)| *offset)
+    }
+
+    fn is_bar_register(&self, reg_idx: usize) -> bool {
+        (PCI_BAR0_REG..PCI_BAR0_REG + self.bar_registers).contains(&reg_idx)
+    }
+
+    /// Read the configuration register at `reg_idx`, on behalf of the guest.
+    pub fn read_config_register(&self, reg_idx: usize) -> u32 {
+        if self.is_bar_register(reg_idx) {
+            if let Some(value) = self
+                .bars
+                .as_ref()
+                .and_then(|b| b.read_config_register(reg_idx))
+            {
+                return value;
+            }
+        }
+        if let Some(value) = self
+            .sriov_capability()
+            .and_then(|(sriov, offset)| sriov.read_config_register(offset, reg_idx))
+        {
+            return value;
+        }
+        let space = self.space.lock().expect("failed to acquire lock");
+        match space.bytes.get(reg_idx * 4..reg_idx * 4 + 4) {
+            Some(_) => space.read_u32(reg_idx * 4),
+            None => 0xffff_ffff,
+        }
+    }
+
+    /// Write the configuration register at `reg_idx` on behalf of the guest,
+    /// honouring the writable and write-1-to-clear masks.
+    pub fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if self.is_bar_register(reg_idx) {
+            if let Some(bars) = self.bars.as_ref() {
+                bars.write_config_register(reg_idx, offset, data);
+            }
+            return;
+        }
+        let sriov = self.sriov_capability();
+        if let Some((sriov, cap)) = sriov {
+            if sriov.write_config_register(cap, reg_idx, offset, data) {
+                return;
+            }
+        }
+        {
+            let mut space = self.space.lock().expect("failed to acquire lock");
+            let start = reg_idx * 4 + offset as usize;
+            if start + data.len() > space.bytes.len() || offset as usize + data.len() > 4 {
+                return;
+            }
+            for (idx, value) in data.iter().enumerate() {
+                let at = start + idx;
+                let (writable, w1c) = (space.writable[at], space.w1c[at]);
+                space.bytes[at] =
+                    ((space.bytes[at] & !writable) | (value & writable)) & !(value & w1c);
+            }
+            if let Some(msi) = self.capability_offset(PCI_CAP_ID_MSI) {
+                if reg_idx == msi / 4 {
+                    // Multiple Message Enable is at most Multiple Message Capable.
+                    let control = u16::from_le_bytes([space.bytes[msi + 2], space.bytes[msi + 3]]);
+                    let capable = (control >> 1) & 0x7;
+                    if (control >> 4) & 0x7 > capable {
+                        let control = (control & !0x70) | (capable << 4);
+                        space.set(msi + 2, &control.to_le_bytes());
+                    }
+                }
+            }
+        }
+        if let Some((_, cap)) = sriov {
+            let (control, _) = PciSriov::control_registers(cap);
+            if reg_idx == control / 4 {
+                self.update_sriov_control();
+            }
+        }
+        if reg_idx == PCI_COMMAND / 4 {
+            self.update_command();
+        }
+    }
+
+    // Forward the command register to the BARs.
+    fn update_command(&self) {
+        if let Some(bars) = self.bars.as_ref() {
+            bars.set_command(self.read_u16(PCI_COMMAND));
+        }
+    }
+
+    // Forward the SR-IOV control and NumVFs registers to the VFs.
+    fn update_sriov_control(&self) {
+        if let Some((sriov, cap)) = self.sriov_capability() {
+            let (control, num_vfs) = PciSriov::control_registers(cap);
+            sriov.update_control(self.read_u16(control), self.read_u16(num_vfs));
+        }
+    }
+
+    /// Restore the writable and write-1-to-clear bits to their power-on
+    /// values, as on a function level reset. The command register goes
+    /// back to 0, disabling the BARs, and MSI, MSI-X and the VFs are
+    /// disabled. The BAR addresses are kept.
+    pub fn reset(&self) {
+        {
+            let mut space = self.space.lock().expect("failed to acquire lock");
+            let space = &mut *space;
+            for (at, byte) in space.bytes.iter_mut().enumerate() {
+                let mask = space.writable[at] | space.w1c[at];
+                *byte = (*byte & !mask) | (space.initial[at] & mask);
+            }
+        }
+        self.update_sriov_control();
+        self.update_command();
+    }
+
+    /// Read `data.len()` bytes at `offset`, on behalf of the device.
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        let space = self.space.lock().expect("failed to acquire lock");
+        match space.bytes.get(offset..offset + data.len()) {
+            Some(bytes) => data.copy_from_slice(bytes),
+            None => read_register_bytes(0xffff_ffff, 0, data),
+        }
+    }
+
+    /// Read the byte at `offset`.
+    pub fn read_u8(&self, offset: usize) -> u8 {
+        let mut data = [0u8; 1];
+        self.read(offset, &mut data);
+        data[0]
+    }
+
+    /// Read the 16-bit register at `offset`.
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        let mut data = [0u8; 2];
+        self.read(offset, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    /// Read the 32-bit register at `offset`.
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        let mut data = [0u8; 4];
+        self.read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Write `data` at `offset` on behalf of the device, ignoring the masks,
+    /// e.g. to set read-only status bits.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        let mut space = self.space.lock().expect("failed to acquire lock");
+        if offset + data.len() <= space.bytes.len() {
+            space.set(offset, data);
+        }
+    }
+
+    /// Write the 16-bit register at `offset` on behalf of the device.
+    pub fn write_u16(&self, offset: usize, value: u16) {
+        self.write(offset, &value.to_le_bytes());
+    }
+
+    /// Write the 32-bit register at `offset` on behalf of the device.
+    pub fn write_u32(&self, offset: usize, value: u32) {
+        self.write(offset, &value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::PciBar;
+
+    fn write_reg(config: &PciConfiguration, offset: usize, data: &[u8]) {
+        config.write_config_register(offset / 4, (offset % 4) as u64, data);
+    }
+
+    #[test]
+    fn test_configuration_builder() {
+        let config = PciConfigurationBuilder::new(0x1af4, 0x1041)
+            .revision(1)
+            .class(0x02, 0x00, 0x00)
+            .subsystem(0x1af4, 0x0001)
+            .interrupt_pin(1)
+            .bars(PciBars::new(&[PciBar::memory64(0x4000)]).unwrap())
+            .capability(PciCapability::PowerManagement)
+            .capability(PciCapability::Msi {
+                vectors: 4,
+                address64: true,
+                per_vector_mask: false,
+            })
+            .capability(PciCapability::MsiX {
+                table_size: 3,
+                table_bar: 0,
+                table_offset: 0x2000,
+                pba_bar: 0,
+                pba_offset: 0x3000,
+            })
+            .capability(PciCapability::VendorSpecific(vec![0xaa, 0xbb]))
+            .capability(PciCapability::PciExpress {
+                port_type: PciExpressPortType::Endpoint,
+            })
+            .extended_capability(PciExtendedCapability::new(0x0001, 2, vec![0; 8]))
+            .extended_capability(PciExtendedCapability {
+                id: 0x000b,
+                version: 1,
+                data: vec![0x12, 0x34],
+                writable: vec![0x00, 0xff],
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(config.read_config_register(0), 0x1041_1af4);
+        assert_eq!(config.read_config_register(2), 0x0200_0001);
+        assert_eq!(config.read_config_register(11), 0x0001_1af4);
+        assert_eq!(config.read_u8(PCI_INTERRUPT_PIN), 1);
+        assert_eq!(config.read_config_register(4), 0x4);
+        assert!(config.extended_config_space());
+
+        // Walk the capability list.
+        let mut caps = Vec::new();
+        let mut next = config.read_u8(PCI_CAPABILITY_LIST) as usize;
+        while next != 0 {
+            caps.push((config.read_u8(next), next));
+            next = config.read_u8(next + 1) as usize;
+        }
+        assert_eq!(
+            caps,
+            vec![
+                (PCI_CAP_ID_PM, 0x40),
+                (PCI_CAP_ID_MSI, 0x48),
+                (PCI_CAP_ID_MSIX, 0x58),
+                (PCI_CAP_ID_VNDR, 0x64),
+                (PCI_CAP_ID_EXP, 0x6c),
+            ]
+        );
+        assert_ne!(config.read_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST, 0);
+        assert_eq!(config.read_u16(0x4a), 0x0084);
+        assert_eq!(config.read_u32(0x5c), 0x2000);
+        assert_eq!(config.read_u8(0x66), 5);
+        assert_eq!(config.capability_offset(PCI_CAP_ID_EXP), Some(0x6c));
+        assert_eq!(config.read_u16(0x6e), 0x0002);
+
+        // Extended capabilities are linked from 0x100.
+        assert_eq!(config.read_u32(0x100), 0x10c2_0001);
+        assert_eq!(config.read_u32(0x10c), 0x0001_000b);
+        assert_eq!(config.extended_capability_offset(0x000b), Some(0x10c));
+        write_reg(&config, 0x110, &[0xff, 0xff]);
+        assert_eq!(config.read_u16(0x110), 0xff12);
+
+        // Read-only, writable and write-1-to-clear bits.
+        write_reg(&config, 0x00, &[0, 0, 0, 0]);
+        assert_eq!(config.read_config_register(0), 0x1041_1af4);
+        write_reg(&config, PCI_COMMAND, &[0xff, 0xff]);
+        assert_eq!(config.read_u16(PCI_COMMAND), 0x0547);
+        config.write_u16(
+            PCI_STATUS,
+            0x8000 | PCI_STATUS_CAP_LIST | PCI_STATUS_INTERRUPT,
+        );
+        write_reg(&config, PCI_STATUS, &[0xff, 0xff]);
+        assert_eq!(
+            config.read_u16(PCI_STATUS),
+            PCI_STATUS_CAP_LIST | PCI_STATUS_INTERRUPT
+        );
+        // Multiple Message Enable is clamped to the 4 vectors.
+        write_reg(&config, 0x4a, &[0xff, 0xff]);
+        assert_eq!(config.read_u16(0x4a), 0x00a5);
+        write_reg(&config, 0x4a, &[0x11, 0x00]);
+        assert_eq!(config.read_u16(0x4a), 0x0095);
+        write_reg(&config, 0x5a, &[0xff, 0xff]);
+        assert_eq!(config.read_u16(0x5a), 0xc002);
+
+        // BAR registers are forwarded to the BARs.
+        write_reg(&config, 0x10, &[0xff; 4]);
+        assert_eq!(config.read_config_register(4), 0xffff_c004);
+
+        // A reset restores the power-on values of the writable bits only.
+        config.reset();
+        assert_eq!(config.read_u16(PCI_COMMAND), 0);
+        assert_eq!(
+            config.read_u16(PCI_STATUS),
+            PCI_STATUS_CAP_LIST | PCI_STATUS_INTERRUPT
+        );
+        assert_eq!(config.read_u16(0x4a), 0x0084);
+        assert_eq!(config.read_u16(0x5a), 0x0002);
+        assert_eq!(config.read_u16(0x110), 0x3412);
+        assert_eq!(config.read_config_register(4), 0xffff_c004);
+
+        assert!(PciConfigurationBuilder::new(0, 0)
+            .capability(PciCapability::VendorSpecific(vec![0; 200]))
+            .build()
+            .is_err());
+        let legacy = PciConfigurationBuilder::new(0, 0).build().unwrap();
+        assert!(!legacy.extended_config_space());
+        assert_eq!(
+            legacy.read_config_register(PCI_CONFIG_REGISTERS),
+            0xffff_ffff
+        );
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCIe enhanced configuration access mechanism (ECAM).
+//!
+//! The configuration space of every function is mapped in a MMIO region,
+//! the offset of an access from the start of the region giving:
+//! - bits 27:20 the bus number,
+//! - bits 19:15 the device number and bits 14:12 the function number,
+//! - bits 11:2 the register index in the 4 KiB configuration space,
+//! - bits 1:0 the byte offset inside the register.
+
+use super::{
+    read_register_bytes, Error, PciAddress, PciConfigMechanism, PciRoot, Result,
+    PCIE_CONFIG_REGISTERS,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use std::sync::{Arc, Mutex};
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// Size of the ECAM region of one bus.
+pub const PCIE_ECAM_BUS_SIZE: GuestUsize = 1 << 20;
+
+/// PCIe memory-mapped configuration space device.
+pub struct PciEcam {
+    root: Arc<PciRoot>,
+    address: GuestAddress,
+    end_bus: u8,
+    base: Mutex<Option<GuestAddress>>,
+}
+
+impl PciEcam {
+    /// Create the ECAM region of the `root` hierarchy at `address`, mapping
+    /// the buses 0 to `end_bus`.
+    ///
+    /// As the MCFG table requires, the region must be naturally aligned: its
+    /// address is a multiple of its size rounded up to a power of two, e.g.
+    /// 1 MiB for a single bus or 256 MiB for a whole segment.
+    pub fn new(root: Arc<PciRoot>, address: GuestAddress, end_bus: u8) -> Result<Self> {
+        let ecam = PciEcam {
+            root,
+            address,
+            end_bus,
+            base: Mutex::new(None),
+        };
+        if address.raw_value() & (ecam.size().next_power_of_two() - 1) != 0 {
+            return Err(Error::InvalidEcamAddress(address));
+        }
+        Ok(ecam)
+    }
+
+    /// Last bus number mapped by the region.
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+
+    /// Size of the region.
+    pub fn size(&self) -> GuestUsize {
+        (GuestUsize::from(self.end_bus) + 1) * PCIE_ECAM_BUS_SIZE
+    }
+
+    /// Base address of the region once allocated, e.g. for the MCFG table.
+    pub fn base(&self) -> Option<GuestAddress> {
+        *self.base.lock().expect("failed to acquire lock")
+    }
+
+    /// IO resources to request at registration: the ECAM region at its
+    /// address, allocated from the MMIO window of the `SystemAllocator`.
+    pub fn io_resources(&self) -> Vec<IoResource> {
+        vec![IoResource::new(
+            Some(self.address),
+            self.size(),
+            IoType::Mmio,
+        )]
+    }
+
+    // Decode an access, None when it is outside of the region or crosses a
+    // register boundary.
+    fn target(&self, addr: GuestAddress, len: usize) -> Option<(PciAddress, usize, u64)> {
+        let offset = addr.checked_offset_from(self.base()?)?;
+        if offset >= self.size() || (offset & 0x3) + len as u64 > 4 {
+            return None;
+        }
+        let pci_addr = PciAddress::new((offset >> 20) as u8, (offset >> 12) as u8);
+        let reg_idx = ((offset >> 2) as usize) & (PCIE_CONFIG_REGISTERS - 1);
+        Some((pci_addr, reg_idx, offset & 0x3))
+    }
+}
+
+impl Device for PciEcam {
+    fn name(&self) -> String {
+        "pci-ecam".to_string()
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
+        let (value, byte) = match self.target(addr, data.len()) {
+            Some((pci_addr, reg_idx, byte)) => {
+                (self.root.read_config_register(pci_addr, reg_idx), byte)
+            }
+            None => (0xffff_ffff, 0),
+        };
+        read_register_bytes(value, byte, data);
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], _io_type: IoType) {
+        if let Some((pci_addr, reg_idx, byte)) = self.target(addr, data.len()) {
+            self.root
+                .write_config_register(pci_addr, reg_idx, byte, data);
+        }
+    }
+
+    fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+        if let Some(region) = res.iter().find(|r| r.res_type == IoType::Mmio) {
+            *self.base.lock().expect("failed to acquire lock") = region.addr;
+        }
+    }
+}
+
+impl PciConfigMechanism for PciEcam {
+    fn root(&self) -> &Arc<PciRoot> {
+        &self.root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::{devfn, PciDevice};
+
+    struct ExtendedFunction;
+
+    impl PciDevice for ExtendedFunction {
+        fn read_config_register(&self, reg_idx: usize) -> u32 {
+            reg_idx as u32
+        }
+        fn write_config_register(&self, _reg_idx: usize, _offset: u64, _data: &[u8]) {}
+        fn extended_config_space(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_ecam_decoding() {
+        let root = Arc::new(PciRoot::new());
+        root.bus(0)
+            .unwrap()
+            .add_device(Some(devfn(2, 1)), Arc::new(ExtendedFunction))
+            .unwrap();
+        let ecam = PciEcam::new(root.clone(), GuestAddress(0xe000_0000), 0).unwrap();
+        assert_eq!(ecam.size(), 0x10_0000);
+        // A region of 3 buses is aligned on 4 MiB.
+        assert_eq!(
+            PciEcam::new(root.clone(), GuestAddress(0xe010_0000), 2).err(),
+            Some(Error::InvalidEcamAddress(GuestAddress(0xe010_0000)))
+        );
+        assert!(PciEcam::new(root, GuestAddress(0xe040_0000), 2).is_ok());
+        ecam.set_resources(
+            &[IoResource::new(
+                Some(GuestAddress(0xe000_0000)),
+                ecam.size(),
+                IoType::Mmio,
+            )],
+            &[],
+        );
+        let function =
+            |devfn: u8, offset: u64| GuestAddress(0xe000_0000 + (u64::from(devfn) << 12) + offset);
+
+        let mut data = [0u8; 4];
+        ecam.read(function(0, 0), &mut data, IoType::Mmio);
+        assert_eq!(data, [0x86, 0x80, 0x57, 0x0d]);
+
+        // Extended registers only reach functions implementing them.
+        ecam.read(function(devfn(2, 1), 0x104), &mut data, IoType::Mmio);
+        assert_eq!(u32::from_le_bytes(data), 0x41);
+        ecam.read(function(0, 0x104), &mut data, IoType::Mmio);
+        assert_eq!(data, [0; 4]);
+        let mut byte = [0u8; 1];
+        ecam.read(function(devfn(2, 1), 0xffd), &mut byte, IoType::Mmio);
+        assert_eq!(byte[0], 0x03);
+
+        // Absent functions and accesses crossing a register read all ones.
+        ecam.read(function(devfn(3, 0), 0), &mut data, IoType::Mmio);
+        assert_eq!(data, [0xff; 4]);
+        ecam.read(function(0, 2), &mut data, IoType::Mmio);
+        assert_eq!(data, [0xff; 4]);
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI INTx routing.
+//!
+//! A PCI function raises a legacy interrupt on one of the INTA# to INTD#
+//! pins its interrupt pin register declares. A bridge swizzles the pins of
+//! the devices on its secondary bus onto its own pins, depending on their
+//! device number, and the same swizzling maps the pins of the devices on
+//! the root bus onto the four interrupt lines of the hierarchy.
+//!
+//! The `DeviceManager` allocates the four lines of a hierarchy when its root
+//! bus is registered, routes every function declaring a pin to one of them,
+//! and exposes the resulting map of the root bus through
+//! `DeviceManager::pci_intx_routing()`, from which the ACPI `_PRT` or the
+//! FDT `interrupt-map` of the host bridge is generated.
+
+use super::{PCI_DEVICES_PER_BUS, PCI_INTERRUPT_PIN};
+
+/// Number of INTx pins of a PCI function, and of interrupt lines of a PCI
+/// hierarchy.
+pub const PCI_INTX_PINS: u8 = 4;
+
+/// Pin `pin`, 1 for INTA# to 4 for INTD#, of the device numbered `device`
+/// behind a bridge or the root bus, as seen on the other side of it.
+pub fn swizzle(pin: u8, device: u8) -> u8 {
+    (pin - 1 + device) % PCI_INTX_PINS + 1
+}
+
+/// Interrupt pin declared in the configuration register holding it, 0 if
+/// the function uses no INTx pin.
+pub(crate) fn interrupt_pin(register: u32) -> u8 {
+    match (register >> ((PCI_INTERRUPT_PIN % 4) * 8)) as u8 {
+        pin @ 1..=4 => pin,
+        _ => 0,
+    }
+}
+
+/// Interrupt line an INTx pin of a device on the root bus is routed to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PciIntxRoute {
+    /// Device number on the root bus.
+    pub device: u8,
+    /// Pin, 1 for INTA# to 4 for INTD#.
+    pub pin: u8,
+    /// Interrupt line.
+    pub irq: u32,
+}
+
+/// Routing map of the pins of every device number of the root bus onto the
+/// `lines` of the hierarchy.
+pub(crate) fn routing_map(lines: &[u32; PCI_INTX_PINS as usize]) -> Vec<PciIntxRoute> {
+    let mut routes = Vec::with_capacity(PCI_DEVICES_PER_BUS as usize * PCI_INTX_PINS as usize);
+    for device in 0..PCI_DEVICES_PER_BUS {
+        for pin in 1..=PCI_INTX_PINS {
+            routes.push(PciIntxRoute {
+                device,
+                pin,
+                irq: lines[(swizzle(pin, device) - 1) as usize],
+            });
+        }
+    }
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swizzle() {
+        assert_eq!(swizzle(1, 0), 1);
+        assert_eq!(swizzle(1, 1), 2);
+        assert_eq!(swizzle(4, 1), 1);
+        assert_eq!(swizzle(3, 31), 2);
+        assert_eq!(interrupt_pin(0x0000_020b), 2);
+        assert_eq!(interrupt_pin(0x0000_050b), 0);
+
+        let routes = routing_map(&[5, 6, 7, 8]);
+        assert_eq!(routes.len(), 128);
+        assert_eq!(
+            routes[4 * 2 + 3],
+            PciIntxRoute {
+                device: 2,
+                pin: 4,
+                irq: 6,
+            }
+        );
+    }
+}
@@ -0,0 +1,1724 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Registration of PCI host bridges, bridges and functions with the
+//! `DeviceManager`.
+
+use super::intx::{interrupt_pin, routing_map};
+use super::{
+    swizzle, AcpiPciHotplug, BridgeWindows, Error as PciError, PciAddress, PciBridge,
+    PciConfigMechanism, PciDevice, PciIntxRoute, PciRoot, PcieRootPort, PCI_EXT_CAP_ID_SRIOV,
+    PCI_INTERRUPT_LINE, PCI_INTX_PINS,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource, MsiResource};
+use crate::device_manager::{DeviceManager, Error, Result};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use vm_allocator::PciSegmentWindows;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/// PCI segment served by a registered hierarchy, as firmware tables
+/// describe it, e.g. the MCFG table and the `_CRS` of its host bridge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PciSegment {
+    /// PCI segment number.
+    pub segment: u16,
+    /// Instance id of the first root bus registered for the hierarchy.
+    pub root_bus: u32,
+    /// First and last bus numbers of the segment.
+    pub buses: (u8, u8),
+    /// ECAM region of the segment, if one is registered.
+    pub ecam: Option<(GuestAddress, GuestUsize)>,
+    /// Windows the resources behind the segment are allocated from, the
+    /// PCI windows of the `SystemAllocator` for a segment without its own.
+    pub windows: PciSegmentWindows,
+}
+
+// Registers the virtual function of the given index at the given address,
+// with the IO resources of its BARs.
+type VfSpawner = Arc<
+    dyn Fn(&mut DeviceManager, u16, PciAddress, &mut [IoResource]) -> Result<u32> + Send + Sync,
+>;
+
+/// Virtual functions of a registered SR-IOV physical function.
+pub(crate) struct VirtualFunctions {
+    physical_function: Arc<dyn PciDevice>,
+    spawn: VfSpawner,
+    /// VF BAR apertures, allocated from the bus of the physical function.
+    apertures: Vec<IoResource>,
+    /// Instance ids of the registered virtual functions, in VF order.
+    vfs: Vec<u32>,
+}
+
+impl DeviceManager {
+    /// Register the configuration mechanism of a PCI hierarchy, a
+    /// `PciRootBus` or a `PciEcam`, with bus 0 of the hierarchy as PCI bus.
+    ///
+    /// `resources` is usually the `io_resources()` of the mechanism. The
+    /// four shared interrupt lines of the hierarchy are allocated along with
+    /// its first root bus. Each hierarchy serves its own PCI segment: the
+    /// BARs and bridge windows behind it are allocated from the windows
+    /// the `SystemAllocator` has for the segment, if any.
+    /// Return the instance id of the root bus, to be given as parent bus of
+    /// the PCI functions behind it.
+    pub fn register_pci_root_bus<T>(
+        &mut self,
+        bus: Arc<T>,
+        parent_bus: Option<u32>,
+        resources: &mut [IoResource],
+    ) -> Result<u32>
+    where
+        T: PciConfigMechanism + 'static,
+    {
+        let root = bus.root().clone();
+        if self
+            .pci_buses
+            .values()
+            .any(|(r, _)| !Arc::ptr_eq(r, &root) && r.segment() == root.segment())
+        {
+            return Err(Error::Pci(PciError::SegmentInUse(root.segment())));
+        }
+        let allocated = root.intx_lines().is_none();
+        if allocated {
+            let mut lines = [0; PCI_INTX_PINS as usize];
+            for idx in 0..lines.len() {
+                match self.resource.allocate_shared_irq(None) {
+                    Ok(line) => lines[idx] = line,
+                    Err(e) => {
+                        for line in lines[..idx].iter() {
+                            self.resource.free_irq(Some(*line));
+                        }
+                        return Err(Error::IrqAllocate(e));
+                    }
+                }
+            }
+            root.set_intx_lines(Some(lines));
+        }
+
+        match self.register_device(bus, parent_bus, resources, &mut [], None) {
+            Ok(id) => {
+                self.pci_buses.insert(id, (root, 0));
+                Ok(id)
+            }
+            Err(e) => {
+                if allocated {
+                    self.release_intx_lines(&root);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Register a PCI function behind the PCI bus device `parent_bus`.
+    ///
+    /// The function is added at `devfn` on the bus, or as function 0 of the
+    /// first free device number, before being registered like any other
+    /// device with its `resources`, `interrupts` and `msi` requests.
+    ///
+    /// A function declaring an interrupt pin gets the interrupt line of the
+    /// hierarchy the pin is swizzled to as first legacy IRQ, ahead of
+    /// `interrupts`, and the line is written to its interrupt line register.
+    /// Return the instance id and the PCI address of the function.
+    pub fn register_pci_device<T>(
+        &mut self,
+        parent_bus: u32,
+        dev: Arc<T>,
+        devfn: Option<u8>,
+        resources: &mut [IoResource],
+        interrupts: &mut [IrqResource],
+        msi: Option<MsiResource>,
+    ) -> Result<(u32, PciAddress)>
+    where
+        T: Device + PciDevice + 'static,
+    {
+        let (root, number) = self
+            .pci_buses
+            .get(&parent_bus)
+            .cloned()
+            .ok_or(Error::Pci(PciError::NotPciBus(parent_bus)))?;
+        let bus = root
+            .bus(number)
+            .ok_or(Error::Pci(PciError::BusNonExist(number)))?;
+        let devfn = bus.add_device(devfn, dev.clone()).map_err(Error::Pci)?;
+        let addr = PciAddress::new(number, devfn);
+
+        let reg_idx = PCI_INTERRUPT_LINE / 4;
+        let line = self.intx_line(
+            &root,
+            addr,
+            interrupt_pin(dev.read_config_register(reg_idx)),
+        );
+        let mut irqs: Vec<IrqResource> = line
+            .map(|line| IrqResource::shared(Some(line)))
+            .into_iter()
+            .chain(interrupts.iter().cloned())
+            .collect();
+
+        match self.register_device(dev.clone(), Some(parent_bus), resources, &mut irqs, msi) {
+            Ok(id) => {
+                let first = irqs.len() - interrupts.len();
+                interrupts.copy_from_slice(&irqs[first..]);
+                if let Some(line) = line {
+                    dev.write_config_register(reg_idx, 0, &[line as u8]);
+                }
+                self.pci_functions.insert(id, (root, addr));
+                Ok((id, addr))
+            }
+            Err(e) => {
+                bus.remove_device(devfn);
+                Err(e)
+            }
+        }
+    }
+
+    /// Register a PCI-to-PCI bridge behind the PCI bus device `parent_bus`.
+    ///
+    /// The bridge is added as a PCI function like `register_pci_device()`
+    /// does, and its windows are allocated from the ones of the parent bus.
+    /// Its bus numbers must be behind the parent bus and not owned by another
+    /// bridge. Return the instance id of the bridge, to be given as parent
+    /// bus of the functions behind it, and its PCI address.
+    pub fn register_pci_bridge(
+        &mut self,
+        parent_bus: u32,
+        bridge: Arc<PciBridge>,
+        devfn: Option<u8>,
+    ) -> Result<(u32, PciAddress)> {
+        self.register_bridge_function(parent_bus, &bridge, bridge.clone(), devfn)
+    }
+
+    /// Register a PCIe root port with a hotplug slot behind the PCI bus
+    /// device `parent_bus`, like `register_pci_bridge()` does.
+    ///
+    /// Functions are then plugged in its slot through
+    /// `hotplug_pci_device()` and unplugged through `request_pci_unplug()`.
+    pub fn register_pcie_root_port(
+        &mut self,
+        parent_bus: u32,
+        port: Arc<PcieRootPort>,
+        devfn: Option<u8>,
+    ) -> Result<(u32, PciAddress)> {
+        let (id, addr) =
+            self.register_bridge_function(parent_bus, port.bridge(), port.clone(), devfn)?;
+        self.pci_hotplug_ports.insert(id, port);
+        Ok((id, addr))
+    }
+
+    // Register `dev`, the function implementing `bridge`.
+    fn register_bridge_function<T>(
+        &mut self,
+        parent_bus: u32,
+        bridge: &PciBridge,
+        dev: Arc<T>,
+        devfn: Option<u8>,
+    ) -> Result<(u32, PciAddress)>
+    where
+        T: Device + PciDevice + 'static,
+    {
+        let (root, number) = self
+            .pci_buses
+            .get(&parent_bus)
+            .cloned()
+            .ok_or(Error::Pci(PciError::NotPciBus(parent_bus)))?;
+        let (secondary, subordinate) = (bridge.secondary_bus(), bridge.subordinate_bus());
+        let (first, last) = match self.pci_bridges.get(&parent_bus) {
+            Some(windows) => (u16::from(windows.buses.0) + 1, windows.buses.1),
+            None => (u16::from(number) + 1, root.last_bus()),
+        };
+        if u16::from(secondary) < first || subordinate > last {
+            return Err(Error::Pci(PciError::InvalidBusRange(
+                secondary,
+                subordinate,
+            )));
+        }
+        // The bus numbers of the bridges above are the only ones which may
+        // contain the new range.
+        let mut ancestors = self.ancestors(parent_bus)?;
+        ancestors.push(parent_bus);
+        let conflict = self
+            .pci_bridges
+            .iter()
+            .filter(|(id, _)| {
+                !ancestors.contains(id)
+                    && matches!(self.pci_buses.get(id), Some((r, _)) if Arc::ptr_eq(r, &root))
+            })
+            .map(|(_, windows)| windows.buses)
+            .find(|buses| buses.0 <= subordinate && secondary <= buses.1);
+        if let Some(buses) = conflict {
+            return Err(Error::Pci(PciError::BusInUse(buses.0)));
+        }
+
+        root.add_bus(secondary).map_err(Error::Pci)?;
+        let mut windows = bridge.windows().io_resources();
+        if let Err(e) = self.allocate_io_resources(Some(parent_bus), &mut windows) {
+            root.remove_bus(secondary);
+            return Err(e);
+        }
+        bridge.set_primary_bus(number);
+        bridge.set_windows(&windows);
+
+        match self.register_pci_device(parent_bus, dev, devfn, &mut Vec::new(), &mut [], None) {
+            Ok((id, addr)) => {
+                self.pci_buses.insert(id, (root, secondary));
+                self.pci_bridges
+                    .insert(id, BridgeWindows::new(bridge, windows));
+                Ok((id, addr))
+            }
+            Err(e) => {
+                self.free_io_resources(Some(parent_bus), &windows);
+                root.remove_bus(secondary);
+                Err(e)
+            }
+        }
+    }
+
+    /// Register the ACPI PCI hotplug `controller` of the root bus device
+    /// `root_bus`, with its `resources`, usually its `io_resources()`, and
+    /// the SCI in `interrupts`.
+    ///
+    /// Functions are then plugged in the free slots of the root bus through
+    /// `hotplug_pci_device()`. Return the instance id of the controller.
+    pub fn register_acpi_pci_hotplug(
+        &mut self,
+        root_bus: u32,
+        controller: Arc<AcpiPciHotplug>,
+        resources: &mut [IoResource],
+        interrupts: &mut [IrqResource],
+    ) -> Result<u32> {
+        if !self.pci_buses.contains_key(&root_bus) || self.pci_bridges.contains_key(&root_bus) {
+            return Err(Error::Pci(PciError::NotPciBus(root_bus)));
+        }
+        if self.acpi_hotplug_controller(root_bus).is_some() {
+            return Err(Error::Exist);
+        }
+        let id = self.register_device(controller.clone(), None, resources, interrupts, None)?;
+        self.pci_acpi_hotplug.insert(id, (root_bus, controller));
+        Ok(id)
+    }
+
+    fn acpi_hotplug_controller(&self, root_bus: u32) -> Option<Arc<AcpiPciHotplug>> {
+        self.pci_acpi_hotplug
+            .values()
+            .find(|(bus, _)| *bus == root_bus)
+            .map(|(_, controller)| controller.clone())
+    }
+
+    /// Plug a PCI function in a hotplug slot of the PCI bus device `bus`:
+    /// the slot of a PCIe root port, or a free slot of a root bus managed by
+    /// an ACPI PCI hotplug controller.
+    ///
+    /// The function is registered as function 0 of the slot like
+    /// `register_pci_device()` does, with its `resources`, `interrupts` and
+    /// `msi` requests, and the guest is notified of its presence. Return
+    /// the instance id and the PCI address of the function.
+    pub fn hotplug_pci_device<T>(
+        &mut self,
+        bus: u32,
+        dev: Arc<T>,
+        resources: &mut [IoResource],
+        interrupts: &mut [IrqResource],
+        msi: Option<MsiResource>,
+    ) -> Result<(u32, PciAddress)>
+    where
+        T: Device + PciDevice + 'static,
+    {
+        if let Some(slot) = self.pci_hotplug_ports.get(&bus).cloned() {
+            if slot.card_present() {
+                return Err(Error::Pci(PciError::SlotOccupied(bus)));
+            }
+            let ret = self.register_pci_device(bus, dev, Some(0), resources, interrupts, msi)?;
+            slot.set_card_present(true);
+            return Ok(ret);
+        }
+        let controller = self
+            .acpi_hotplug_controller(bus)
+            .ok_or(Error::Pci(PciError::NotHotplugSlot(bus)))?;
+        let (id, addr) = self.register_pci_device(bus, dev, None, resources, interrupts, msi)?;
+        controller.slot_added(addr.device);
+        Ok((id, addr))
+    }
+
+    /// Ask the guest to release the hotplugged PCI function `instance_id`,
+    /// by pressing the attention button of its PCIe slot or by requesting
+    /// its ejection through ACPI.
+    ///
+    /// The function is unregistered by `apply_pci_ejects()` once the guest
+    /// released it.
+    pub fn request_pci_unplug(&mut self, instance_id: u32) -> Result<()> {
+        let bus = self
+            .parent_bus(instance_id)?
+            .filter(|_| self.pci_functions.contains_key(&instance_id))
+            .ok_or(Error::Pci(PciError::NotHotplugSlot(instance_id)))?;
+        if let Some(slot) = self.pci_hotplug_ports.get(&bus) {
+            slot.press_attention_button();
+            return Ok(());
+        }
+        let controller = self
+            .acpi_hotplug_controller(bus)
+            .ok_or(Error::Pci(PciError::NotHotplugSlot(instance_id)))?;
+        if let Some(addr) = self.pci_address(instance_id) {
+            controller.request_removal(addr.device);
+        }
+        Ok(())
+    }
+
+    /// Unregister the hotplugged PCI functions the guest released: the ones
+    /// whose PCIe slot it powered off, and the ones it ejected through ACPI.
+    ///
+    /// This is meant to be called after the guest wrote the configuration
+    /// space of a PCIe port or the registers of an ACPI PCI hotplug
+    /// controller. The functions are unregistered along with their subtrees
+    /// and the slots are reported empty. Return the unregistered instance
+    /// ids.
+    pub fn apply_pci_ejects(&mut self) -> Result<Vec<u32>> {
+        let mut ejected: Vec<u32> = self
+            .pci_hotplug_ports
+            .iter()
+            .filter(|(_, slot)| slot.take_eject_request())
+            .flat_map(|(id, _)| self.children(*id).unwrap_or(&[]).to_vec())
+            .collect();
+        for (bus, controller) in self.pci_acpi_hotplug.values() {
+            let slots = controller.take_ejects();
+            let children = self.children(*bus).unwrap_or(&[]);
+            ejected.extend(children.iter().filter(|id| {
+                matches!(self.pci_address(**id), Some(addr) if slots & (1 << addr.device) != 0)
+            }));
+        }
+        ejected.sort();
+
+        let mut removed = Vec::new();
+        for id in ejected {
+            removed.append(&mut self.unregister_subtree(id)?);
+        }
+        Ok(removed)
+    }
+
+    /// Create the virtual functions of the registered SR-IOV physical
+    /// function `instance_id` with `factory`, given the VF index.
+    ///
+    /// The VF BAR apertures of its `PciSriov` are allocated from the windows
+    /// of the bus of the physical function. Once the guest enabled them,
+    /// `apply_pci_sriov()` registers the virtual functions as its children,
+    /// each with its BARs carved out of the apertures and the `msi` request.
+    pub fn register_pci_sriov<V, F>(
+        &mut self,
+        instance_id: u32,
+        msi: Option<MsiResource>,
+        factory: F,
+    ) -> Result<()>
+    where
+        V: Device + PciDevice + 'static,
+        F: Fn(u16) -> Arc<V> + Send + Sync + 'static,
+    {
+        let physical_function = self
+            .pci_functions
+            .get(&instance_id)
+            .and_then(|(root, addr)| root.device(*addr))
+            .filter(|dev| dev.sriov().is_some())
+            .ok_or(Error::Pci(PciError::NoSriov(instance_id)))?;
+        if self.pci_sriov.contains_key(&instance_id) {
+            return Err(Error::Exist);
+        }
+        let parent_bus = self
+            .get_descriptor(instance_id)
+            .and_then(|desc| desc.parent_bus);
+        let sriov = physical_function.sriov().ok_or(Error::NonExist)?;
+        let mut apertures = sriov.io_resources();
+        self.allocate_io_resources(parent_bus, &mut apertures)?;
+        sriov.set_resources(&apertures);
+
+        let spawn: VfSpawner = Arc::new(move |dev_mgr, index, addr, resources| {
+            dev_mgr.register_virtual_function(instance_id, factory(index), addr, resources, msi)
+        });
+        self.pci_sriov.insert(
+            instance_id,
+            VirtualFunctions {
+                physical_function,
+                spawn,
+                apertures,
+                vfs: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    // Register `vf` at `addr`, as a child of the physical function `pf`.
+    fn register_virtual_function<V>(
+        &mut self,
+        pf: u32,
+        vf: Arc<V>,
+        addr: PciAddress,
+        resources: &mut [IoResource],
+        msi: Option<MsiResource>,
+    ) -> Result<u32>
+    where
+        V: Device + PciDevice + 'static,
+    {
+        let root = self
+            .pci_functions
+            .get(&pf)
+            .map(|(root, _)| root.clone())
+            .ok_or(Error::ParentNonExist)?;
+        let bus = root
+            .bus(addr.bus)
+            .ok_or(Error::Pci(PciError::BusNonExist(addr.bus)))?;
+        bus.add_device(Some(addr.devfn()), vf.clone())
+            .map_err(Error::Pci)?;
+        match self.register_device(vf, Some(pf), resources, &mut [], msi) {
+            Ok(id) => {
+                self.pci_functions.insert(id, (root, addr));
+                Ok(id)
+            }
+            Err(e) => {
+                bus.remove_device(addr.devfn());
+                Err(e)
+            }
+        }
+    }
+
+    /// Register or unregister the virtual functions of the SR-IOV physical
+    /// functions whose VF Enable the guest toggled.
+    ///
+    /// This is meant to be called after the guest wrote the configuration
+    /// space of a function. The enabled virtual functions are registered at
+    /// the routing ids given by the SR-IOV capability, and removed in reverse
+    /// order once disabled. Every physical function is handled, the first
+    /// failure being returned once all of them have been tried.
+    ///
+    /// A physical function whose virtual functions can't all be registered
+    /// gets none of the new ones: it keeps those it had until the guest
+    /// toggles VF Enable again.
+    pub fn apply_pci_sriov(&mut self) -> Result<()> {
+        let mut pfs: Vec<u32> = self.pci_sriov.keys().cloned().collect();
+        pfs.sort();
+        let mut ret = Ok(());
+        for pf in pfs {
+            if let Err(e) = self.apply_virtual_functions(pf) {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+        ret
+    }
+
+    // Register or unregister the virtual functions of the physical function
+    // `pf` if the guest toggled its VF Enable.
+    fn apply_virtual_functions(&mut self, pf: u32) -> Result<()> {
+        let (physical_function, spawn, vfs) = match self.pci_sriov.get(&pf) {
+            Some(v) => (v.physical_function.clone(), v.spawn.clone(), v.vfs.clone()),
+            None => return Ok(()),
+        };
+        let sriov = match physical_function.sriov() {
+            Some(sriov) => sriov,
+            None => return Ok(()),
+        };
+        let num_vfs = match sriov.take_num_vfs_change() {
+            Some(num_vfs) => usize::from(num_vfs),
+            None => return Ok(()),
+        };
+
+        for id in vfs.iter().skip(num_vfs).rev() {
+            self.unregister_device(*id)?;
+        }
+        // Check the routing ids of all the new virtual functions first.
+        let pf_addr = self.pci_functions[&pf].1;
+        let pf_rid = u32::from(pf_addr.bus) << 8 | u32::from(pf_addr.devfn());
+        let addrs = (vfs.len()..num_vfs)
+            .map(|index| {
+                let rid = pf_rid + sriov.vf_routing_offset(index as u16);
+                if rid > 0xffff {
+                    return Err(Error::Pci(PciError::InvalidCapability(
+                        PCI_EXT_CAP_ID_SRIOV,
+                    )));
+                }
+                Ok((index as u16, PciAddress::new((rid >> 8) as u8, rid as u8)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut added = Vec::new();
+        for (index, addr) in addrs {
+            let mut resources = sriov.vf_io_resources(index);
+            match spawn(self, index, addr, &mut resources) {
+                Ok(id) => added.push(id),
+                Err(e) => {
+                    for id in added.iter().rev() {
+                        let _ = self.unregister_device(*id);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(v) = self.pci_sriov.get_mut(&pf) {
+            v.vfs.extend(added);
+        }
+        Ok(())
+    }
+
+    /// Move the BARs the guest reprogrammed to their new addresses.
+    ///
+    /// This is meant to be called after the guest wrote the configuration
+    /// space of a function. Every BAR move is applied through
+    /// `relocate_resource()`; the first failure is returned once all of them
+    /// have been tried, the failing BAR being set back to its previous
+    /// address through `Device::set_resources()`. The VF BAR apertures of
+    /// the SR-IOV physical functions are moved along with the BARs of their
+    /// virtual functions.
+    pub fn apply_pci_bar_moves(&mut self) -> Result<()> {
+        let mut functions: Vec<(u32, PciAddress)> = self
+            .pci_functions
+            .iter()
+            .map(|(id, (_, addr))| (*id, *addr))
+            .collect();
+        functions.sort_by_key(|(_, addr)| *addr);
+
+        let mut ret = Ok(());
+        for (id, addr) in functions {
+            let moves = match self
+                .pci_functions
+                .get(&id)
+                .and_then(|(root, _)| root.device(addr))
+            {
+                Some(dev) => dev.take_bar_moves(),
+                None => continue,
+            };
+            for (index, bar_addr) in moves {
+                if let Err(e) = self.relocate_resource(id, index, bar_addr) {
+                    // Program the previous address back into the BAR.
+                    if let Some(d) = self.get_descriptor(id) {
+                        d.device.set_resources(&d.resources, &d.irqs);
+                    }
+                    if ret.is_ok() {
+                        ret = Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut pfs: Vec<u32> = self.pci_sriov.keys().cloned().collect();
+        pfs.sort();
+        for pf in pfs {
+            let moves = match self.pci_sriov.get(&pf) {
+                Some(v) => v
+                    .physical_function
+                    .sriov()
+                    .map_or_else(Vec::new, |sriov| sriov.take_bar_moves()),
+                None => continue,
+            };
+            for (index, aperture_addr) in moves {
+                if let Err(e) = self.move_vf_aperture(pf, index, aperture_addr) {
+                    if ret.is_ok() {
+                        ret = Err(e);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    // Move the VF BAR aperture at `index` of the physical function `pf` to
+    // `addr`, along with the BARs of its virtual functions. The aperture is
+    // programmed back at its previous address if it can't be allocated at
+    // the new one.
+    fn move_vf_aperture(&mut self, pf: u32, index: usize, addr: GuestAddress) -> Result<()> {
+        let (physical_function, mut apertures, vfs) = match self.pci_sriov.get(&pf) {
+            Some(v) => (
+                v.physical_function.clone(),
+                v.apertures.clone(),
+                v.vfs.clone(),
+            ),
+            None => return Err(Error::NonExist),
+        };
+        let sriov = physical_function.sriov().ok_or(Error::NonExist)?;
+        let old = *apertures.get(index).ok_or(Error::ResourceNonExist(index))?;
+        if old.addr == Some(addr) {
+            return Ok(());
+        }
+        let parent = self.get_descriptor(pf).and_then(|desc| desc.parent_bus);
+
+        self.free_io_resources(parent, &[old]);
+        let mut new = vec![IoResource {
+            addr: Some(addr),
+            ..old
+        }];
+        if let Err(e) = self.allocate_io_resources(parent, &mut new) {
+            // Put the old aperture back, it was only released above.
+            let _ = self.allocate_io_resources(parent, &mut [old]);
+            sriov.set_resources(&apertures);
+            return Err(match e {
+                Error::IoResourceAllocate(_, e) => Error::IoResourceAllocate(index, e),
+                e => e,
+            });
+        }
+        apertures[index] = new[0];
+        sriov.set_resources(&apertures);
+        if let Some(v) = self.pci_sriov.get_mut(&pf) {
+            v.apertures = apertures;
+        }
+
+        let mut ret = Ok(());
+        for (vf, id) in vfs.iter().enumerate() {
+            let bar = sriov.vf_io_resources(vf as u16)[index];
+            if let Err(e) = self.relocate_resource(*id, index, bar.try_unwrap()) {
+                if ret.is_ok() {
+                    ret = Err(e);
+                }
+            }
+        }
+        ret
+    }
+
+    /// Return the routing of the INTx pins of every device number of a root
+    /// bus onto the interrupt lines of its hierarchy, to describe in the
+    /// ACPI `_PRT` or the FDT `interrupt-map` of the host bridge. The pins
+    /// of the devices behind bridges are swizzled up to the root bus first.
+    pub fn pci_intx_routing(&self, root_bus: u32) -> Result<Vec<PciIntxRoute>> {
+        let (root, _) = self
+            .pci_buses
+            .get(&root_bus)
+            .ok_or(Error::Pci(PciError::NotPciBus(root_bus)))?;
+        Ok(root
+            .intx_lines()
+            .map_or_else(Vec::new, |lines| routing_map(&lines)))
+    }
+
+    // Interrupt line the `pin` of the function at `addr` is routed to,
+    // swizzling it through the bridges up to the root bus.
+    fn intx_line(&self, root: &Arc<PciRoot>, addr: PciAddress, pin: u8) -> Option<u32> {
+        if pin == 0 {
+            return None;
+        }
+        let (mut addr, mut pin) = (addr, pin);
+        while let Some(bridge) = self.pci_bridge_of_bus(root, addr.bus) {
+            pin = swizzle(pin, addr.device);
+            addr = bridge;
+        }
+        let lines = root.intx_lines()?;
+        Some(lines[(swizzle(pin, addr.device) - 1) as usize])
+    }
+
+    // Address of the bridge whose secondary bus is `bus`.
+    fn pci_bridge_of_bus(&self, root: &Arc<PciRoot>, bus: u8) -> Option<PciAddress> {
+        self.pci_bridges
+            .iter()
+            .filter(|(_, windows)| windows.buses.0 == bus)
+            .filter_map(|(id, _)| self.pci_functions.get(id))
+            .find(|(r, _)| Arc::ptr_eq(r, root))
+            .map(|(_, addr)| *addr)
+    }
+
+    fn release_intx_lines(&mut self, root: &Arc<PciRoot>) {
+        if let Some(lines) = root.intx_lines() {
+            for line in lines.iter() {
+                self.resource.free_irq(Some(*line));
+            }
+            root.set_intx_lines(None);
+        }
+    }
+
+    /// Describe the PCI segments of the registered hierarchies, in
+    /// increasing segment order.
+    pub fn pci_segments(&self) -> Vec<PciSegment> {
+        let mut segments: BTreeMap<u16, PciSegment> = BTreeMap::new();
+        let mut root_buses: Vec<u32> = self
+            .pci_buses
+            .keys()
+            .filter(|id| !self.pci_bridges.contains_key(id))
+            .cloned()
+            .collect();
+        root_buses.sort();
+        for id in root_buses {
+            let root = &self.pci_buses[&id].0;
+            let ecam = self.get_descriptor(id).and_then(|desc| {
+                desc.resources
+                    .iter()
+                    .find(|r| r.res_type == IoType::Mmio)
+                    .and_then(|r| r.addr.map(|addr| (addr, r.size)))
+            });
+            let segment = segments
+                .entry(root.segment())
+                .or_insert_with(|| PciSegment {
+                    segment: root.segment(),
+                    root_bus: id,
+                    buses: (0, root.last_bus()),
+                    ecam: None,
+                    windows: self
+                        .resource
+                        .pci_segment_windows(root.segment())
+                        .unwrap_or_else(|| PciSegmentWindows {
+                            io: None,
+                            mmio32: self.resource.pci_mmio32_window(),
+                            mmio64: self.resource.pci_mmio64_window(),
+                        }),
+                });
+            segment.ecam = segment.ecam.or(ecam);
+        }
+        segments.into_values().collect()
+    }
+
+    /// Return the PCI address of a registered PCI function.
+    pub fn pci_address(&self, instance_id: u32) -> Option<PciAddress> {
+        self.pci_functions.get(&instance_id).map(|(_, addr)| *addr)
+    }
+
+    // Take a removed device out of the PCI hierarchy, releasing the windows
+    // of a bridge to its parent bus, and the interrupt lines of the hierarchy
+    // along with its last root bus. A function removed from a hotplug slot
+    // leaves it empty.
+    pub(crate) fn release_pci(&mut self, instance_id: u32, parent_bus: Option<u32>) {
+        if let Some(slot) = parent_bus.and_then(|id| self.pci_hotplug_ports.get(&id)) {
+            slot.set_card_present(false);
+        }
+        self.pci_hotplug_ports.remove(&instance_id);
+        if let Some(v) = self.pci_sriov.remove(&instance_id) {
+            self.free_io_resources(parent_bus, &v.apertures);
+        }
+        if let Some(v) = parent_bus.and_then(|id| self.pci_sriov.get_mut(&id)) {
+            v.vfs.retain(|id| *id != instance_id);
+        }
+        self.pci_acpi_hotplug
+            .retain(|id, (bus, _)| *id != instance_id && *bus != instance_id);
+        let bridge = self.pci_bridges.remove(&instance_id);
+        if let Some((root, _)) = self.pci_buses.remove(&instance_id) {
+            match bridge {
+                Some(windows) => {
+                    self.free_io_resources(parent_bus, &windows.resources);
+                    root.remove_bus(windows.buses.0);
+                }
+                None => {
+                    let last_root_bus = !self.pci_buses.iter().any(|(id, (r, _))| {
+                        Arc::ptr_eq(r, &root) && !self.pci_bridges.contains_key(id)
+                    });
+                    if last_root_bus {
+                        self.release_intx_lines(&root);
+                    }
+                }
+            }
+        }
+        if let Some((root, addr)) = self.pci_functions.remove(&instance_id) {
+            if let Some(bus) = root.bus(addr.bus) {
+                bus.remove_device(addr.devfn());
+            }
+            let controller = parent_bus.and_then(|id| self.acpi_hotplug_controller(id));
+            let slot_empty = !self.pci_functions.values().any(|(r, a)| {
+                Arc::ptr_eq(r, &root) && a.bus == addr.bus && a.device == addr.device
+            });
+            if let (Some(controller), true) = (controller, slot_empty) {
+                controller.slot_removed(addr.device);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{Device, IoResource, IoType, IrqResource};
+    use crate::device_manager::*;
+    use crate::pci::{
+        devfn, AcpiPciHotplug, Error as PciError, PciBar, PciBars, PciBridge, PciBridgeWindows,
+        PciCapability, PciConfiguration, PciConfigurationBuilder, PciDevice, PciEcam,
+        PciExpressPortType, PciRoot, PciRootBus, PciSegment, PciSriov, PcieRootPort,
+        ACPI_PCIHP_BASE, PCI_CAP_ID_EXP, PCI_COMMAND_MEMORY, PCI_EXT_CAP_ID_SRIOV,
+        PCI_SRIOV_CTRL_MSE, PCI_SRIOV_CTRL_VFE,
+    };
+    use std::sync::{Arc, Mutex};
+    use vm_allocator::PciSegmentWindows;
+    use vm_memory::{Address, GuestAddress};
+
+    struct TestFunction {
+        config: Mutex<[u32; 64]>,
+    }
+
+    impl TestFunction {
+        fn new(vendor_device: u32) -> Self {
+            let mut config = [0u32; 64];
+            config[0] = vendor_device;
+            TestFunction {
+                config: Mutex::new(config),
+            }
+        }
+    }
+
+    impl Device for TestFunction {
+        fn name(&self) -> String {
+            "pci-test".to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+    }
+
+    impl PciDevice for TestFunction {
+        fn read_config_register(&self, reg_idx: usize) -> u32 {
+            self.config.lock().expect("failed to acquire lock")[reg_idx]
+        }
+        fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+            let mut config = self.config.lock().expect("failed to acquire lock");
+            config[reg_idx] = crate::pci::write_register_bytes(config[reg_idx], offset, data);
+        }
+    }
+
+    fn config_read(dev_mgr: &DeviceManager, bus: u8, devfn: u8, reg_idx: u32) -> u32 {
+        let address =
+            0x8000_0000 | (u32::from(bus) << 16) | (u32::from(devfn) << 8) | (reg_idx << 2);
+        dev_mgr
+            .write(GuestAddress(0xcf8), &address.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        let mut data = [0u8; 4];
+        dev_mgr
+            .read(GuestAddress(0xcfc), &mut data, IoType::Pio)
+            .unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn test_pci_device_registration() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let pci_root = Arc::new(PciRoot::new());
+        let root_bus = Arc::new(PciRootBus::new(pci_root.clone()));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+        // The ECAM region gives access to the same hierarchy.
+        let ecam = Arc::new(PciEcam::new(pci_root, GuestAddress(0x1000_0000), 0).unwrap());
+        dev_mgr.register_pci_root_bus(ecam.clone(), Some(root), &mut ecam.io_resources())?;
+        let ecam_base = ecam.base().unwrap();
+
+        // The host bridge sits at 00:00.0, so the function lands on device 1.
+        let (id, addr) = dev_mgr.register_pci_device(
+            root,
+            Arc::new(TestFunction::new(0x1111_1af4)),
+            None,
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(addr.to_string(), "00:01.0");
+        assert_eq!(dev_mgr.pci_address(id), Some(addr));
+        assert_eq!(dev_mgr.parent_bus(id)?, Some(root));
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 0), 0), 0x1111_1af4);
+
+        // Byte writes through CONFIG_DATA land at the port offset.
+        let address: u32 = 0x8000_0000 | (u32::from(devfn(1, 0)) << 8) | (4 << 2);
+        dev_mgr
+            .write(GuestAddress(0xcf8), &address.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr
+            .write(GuestAddress(0xcfe), &[0x5a], IoType::Pio)
+            .unwrap();
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 0), 4), 0x005a_0000);
+        let mut data = [0u8; 4];
+        dev_mgr
+            .read(ecam_base.unchecked_add(0x8010), &mut data, IoType::Mmio)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x005a_0000);
+
+        match dev_mgr.register_pci_device(
+            root,
+            Arc::new(TestFunction::new(0)),
+            Some(devfn(1, 0)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        ) {
+            Err(Error::Pci(PciError::AddressInUse(a))) => assert_eq!(a, addr),
+            _ => panic!("devfn 01.0 should be in use"),
+        }
+        match dev_mgr.register_pci_device(
+            id,
+            Arc::new(TestFunction::new(0)),
+            None,
+            &mut Vec::new(),
+            &mut [],
+            None,
+        ) {
+            Err(Error::Pci(PciError::NotPciBus(bus))) => assert_eq!(bus, id),
+            _ => panic!("a PCI function is not a PCI bus"),
+        }
+
+        dev_mgr.unregister_device(id)?;
+        assert_eq!(dev_mgr.pci_address(id), None);
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 0), 0), 0xffff_ffff);
+        Ok(())
+    }
+
+    struct BarFunction {
+        bars: PciBars,
+    }
+
+    impl Device for BarFunction {
+        fn name(&self) -> String {
+            "pci-bars".to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+            self.bars.set_resources(res);
+        }
+    }
+
+    impl PciDevice for BarFunction {
+        fn read_config_register(&self, reg_idx: usize) -> u32 {
+            self.bars.read_config_register(reg_idx).unwrap_or(0)
+        }
+        fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+            self.bars.write_config_register(reg_idx, offset, data);
+        }
+        fn take_bar_moves(&self) -> Vec<(usize, GuestAddress)> {
+            self.bars.take_moves()
+        }
+    }
+
+    #[test]
+    fn test_pci_bar_moves() -> Result<()> {
+        let sys_res = test_pci_system_allocator(
+            (GuestAddress(0xc000_0000), 0x1000_0000),
+            Some((GuestAddress(0x1_0000_0000), 0x1_0000_0000)),
+        );
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+
+        let function = Arc::new(BarFunction {
+            bars: PciBars::new(&[
+                PciBar::memory32(0x4000),
+                PciBar::memory64(0x10_0000).prefetchable(),
+                PciBar::memory32(0x100),
+            ])
+            .unwrap(),
+        });
+        function.bars.set_command(PCI_COMMAND_MEMORY);
+        let mut resources = function.bars.io_resources();
+        let (id, addr) = dev_mgr.register_pci_device(
+            root,
+            function.clone(),
+            None,
+            &mut resources,
+            &mut [],
+            None,
+        )?;
+        // BARs are naturally aligned, the 64-bit one above 4 GiB.
+        assert_eq!(resources[0].addr, Some(GuestAddress(0xcfff_c000)));
+        assert_eq!(resources[1].addr, Some(GuestAddress(0x1_fff0_0000)));
+        // A BAR smaller than a page doesn't take a whole page.
+        assert_eq!(resources[2].addr, Some(GuestAddress(0xcfff_bf00)));
+        assert_eq!(config_read(&dev_mgr, 0, addr.devfn(), 5), 0xfff0_000c);
+
+        // The guest moves the 32-bit BAR through the configuration space.
+        let address: u32 = 0x8000_0000 | (u32::from(addr.devfn()) << 8) | (4 << 2);
+        dev_mgr
+            .write(GuestAddress(0xcf8), &address.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr
+            .write(
+                GuestAddress(0xcfc),
+                &0xc000_0000u32.to_le_bytes(),
+                IoType::Pio,
+            )
+            .unwrap();
+        dev_mgr.apply_pci_bar_moves()?;
+        assert_eq!(
+            dev_mgr.get_descriptor(id).unwrap().resources[0].addr,
+            Some(GuestAddress(0xc000_0000))
+        );
+        let mut data = [0u8; 4];
+        assert!(dev_mgr
+            .read(GuestAddress(0xc000_0000), &mut data, IoType::Mmio)
+            .is_ok());
+        assert!(dev_mgr
+            .read(GuestAddress(0xcfff_c000), &mut data, IoType::Mmio)
+            .is_err());
+
+        // A move outside of the PCI windows is refused.
+        dev_mgr
+            .write(
+                GuestAddress(0xcfc),
+                &0x1000_0000u32.to_le_bytes(),
+                IoType::Pio,
+            )
+            .unwrap();
+        assert!(dev_mgr.apply_pci_bar_moves().is_err());
+        assert_eq!(config_read(&dev_mgr, 0, addr.devfn(), 4), 0xc000_0000);
+        assert_eq!(
+            dev_mgr.get_descriptor(id).unwrap().resources[0].addr,
+            Some(GuestAddress(0xc000_0000))
+        );
+
+        // The small BAR moves to an address aligned on its size only.
+        let address: u32 = 0x8000_0000 | (u32::from(addr.devfn()) << 8) | (7 << 2);
+        dev_mgr
+            .write(GuestAddress(0xcf8), &address.to_le_bytes(), IoType::Pio)
+            .unwrap();
+        dev_mgr
+            .write(
+                GuestAddress(0xcfc),
+                &0xc000_4100u32.to_le_bytes(),
+                IoType::Pio,
+            )
+            .unwrap();
+        dev_mgr.apply_pci_bar_moves()?;
+        assert_eq!(config_read(&dev_mgr, 0, addr.devfn(), 7), 0xc000_4100);
+        assert_eq!(
+            dev_mgr.get_descriptor(id).unwrap().resources[2].addr,
+            Some(GuestAddress(0xc000_4100))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_segments() -> Result<()> {
+        let mut sys_res = test_pci_system_allocator((GuestAddress(0xc000_0000), 0x1000_0000), None);
+        let windows = PciSegmentWindows {
+            io: Some((GuestAddress(0x2000), 0x1000)),
+            mmio32: Some((GuestAddress(0x1800_0000), 0x100_0000)),
+            mmio64: None,
+        };
+        sys_res.add_pci_segment(1, windows).unwrap();
+        let mut dev_mgr = DeviceManager::new(sys_res);
+
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root0 =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+        let ecam = Arc::new(
+            PciEcam::new(
+                Arc::new(PciRoot::with_segment(1, 15)),
+                GuestAddress(0x1000_0000),
+                15,
+            )
+            .unwrap(),
+        );
+        let root1 = dev_mgr.register_pci_root_bus(ecam.clone(), None, &mut ecam.io_resources())?;
+        // Segment 1 is served by its own hierarchy only.
+        let other = Arc::new(
+            PciEcam::new(
+                Arc::new(PciRoot::with_segment(1, 15)),
+                GuestAddress(0x1100_0000),
+                15,
+            )
+            .unwrap(),
+        );
+        match dev_mgr.register_pci_root_bus(other.clone(), None, &mut other.io_resources()) {
+            Err(Error::Pci(PciError::SegmentInUse(1))) => (),
+            _ => panic!("segment 1 should be in use"),
+        }
+
+        let bars = [
+            PciBar::memory32(0x4000),
+            PciBar::memory64(0x10_0000),
+            PciBar::io(GuestAddress(0x2000), 0x20),
+        ];
+        let function = Arc::new(BarFunction {
+            bars: PciBars::new(&bars[..2]).unwrap(),
+        });
+        let mut resources = function.bars.io_resources();
+        dev_mgr.register_pci_device(root0, function, None, &mut resources, &mut [], None)?;
+        assert_eq!(resources[0].addr, Some(GuestAddress(0xcfff_c000)));
+        assert_eq!(resources[1].addr, Some(GuestAddress(0xcfe0_0000)));
+
+        // The BARs behind segment 1 land in its windows, the 64-bit one in
+        // the 32-bit window as the segment has no window above 4 GiB.
+        let function = Arc::new(BarFunction {
+            bars: PciBars::new(&bars).unwrap(),
+        });
+        let mut resources = function.bars.io_resources();
+        let (id, _) =
+            dev_mgr.register_pci_device(root1, function, None, &mut resources, &mut [], None)?;
+        assert_eq!(resources[0].addr, Some(GuestAddress(0x18ff_c000)));
+        assert_eq!(resources[1].addr, Some(GuestAddress(0x18e0_0000)));
+        assert_eq!(resources[2].addr, Some(GuestAddress(0x2000)));
+
+        assert_eq!(
+            dev_mgr.pci_segments(),
+            vec![
+                PciSegment {
+                    segment: 0,
+                    root_bus: root0,
+                    buses: (0, 255),
+                    ecam: None,
+                    windows: PciSegmentWindows {
+                        io: None,
+                        mmio32: Some((GuestAddress(0xc000_0000), 0x1000_0000)),
+                        mmio64: None,
+                    },
+                },
+                PciSegment {
+                    segment: 1,
+                    root_bus: root1,
+                    buses: (0, 15),
+                    ecam: Some((ecam.base().unwrap(), 0x100_0000)),
+                    windows,
+                },
+            ]
+        );
+
+        // The ranges go back to the windows of the segment.
+        dev_mgr.unregister_device(id)?;
+        let function = Arc::new(BarFunction {
+            bars: PciBars::new(&bars).unwrap(),
+        });
+        let mut resources = function.bars.io_resources();
+        dev_mgr.register_pci_device(root1, function, None, &mut resources, &mut [], None)?;
+        assert_eq!(resources[0].addr, Some(GuestAddress(0x18ff_c000)));
+        assert_eq!(resources[2].addr, Some(GuestAddress(0x2000)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_bridge() -> Result<()> {
+        let sys_res = test_pci_system_allocator(
+            (GuestAddress(0xc000_0000), 0x1000_0000),
+            Some((GuestAddress(0x1_0000_0000), 0x1_0000_0000)),
+        );
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+
+        let windows = PciBridgeWindows {
+            io: Some((GuestAddress(0x2000), 0x1000)),
+            memory: 0x10_0000,
+            prefetchable: 0x20_0000,
+        };
+        let (bridge_id, bridge_addr) = dev_mgr.register_pci_bridge(
+            root,
+            Arc::new(PciBridge::new(1, 2, windows).unwrap()),
+            None,
+        )?;
+        assert_eq!(bridge_addr.to_string(), "00:01.0");
+        assert_eq!(
+            config_read(&dev_mgr, 0, bridge_addr.devfn(), 6) & 0xff_ffff,
+            0x02_0100
+        );
+        assert_eq!(
+            config_read(&dev_mgr, 0, bridge_addr.devfn(), 8),
+            0xcff0_cff0
+        );
+        assert_eq!(config_read(&dev_mgr, 0, bridge_addr.devfn(), 10), 1);
+
+        // Bus numbers must be behind the parent bus and not already owned.
+        match dev_mgr.register_pci_bridge(
+            root,
+            Arc::new(PciBridge::new(2, 3, PciBridgeWindows::default()).unwrap()),
+            None,
+        ) {
+            Err(Error::Pci(PciError::BusInUse(1))) => {}
+            _ => panic!("bus 2 is owned by the first bridge"),
+        }
+        match dev_mgr.register_pci_bridge(
+            bridge_id,
+            Arc::new(PciBridge::new(2, 3, PciBridgeWindows::default()).unwrap()),
+            None,
+        ) {
+            Err(Error::Pci(PciError::InvalidBusRange(2, 3))) => {}
+            _ => panic!("bus 3 is not behind the first bridge"),
+        }
+
+        // Functions behind the bridge get their BARs from its windows.
+        let function = Arc::new(BarFunction {
+            bars: PciBars::new(&[
+                PciBar::memory32(0x4000),
+                PciBar::memory64(0x10_0000).prefetchable(),
+                PciBar::io(GuestAddress(0x2100), 0x20),
+                PciBar::memory64(0x1_0000),
+            ])
+            .unwrap(),
+        });
+        let mut resources = function.bars.io_resources();
+        let (id, addr) = dev_mgr.register_pci_device(
+            bridge_id,
+            function.clone(),
+            None,
+            &mut resources,
+            &mut [],
+            None,
+        )?;
+        assert_eq!(addr.to_string(), "01:00.0");
+        assert_eq!(resources[0].addr, Some(GuestAddress(0xcfff_c000)));
+        assert_eq!(resources[1].addr, Some(GuestAddress(0x1_fff0_0000)));
+        // The non-prefetchable 64-bit BAR lands in the memory window.
+        assert_eq!(resources[3].addr, Some(GuestAddress(0xcffe_0000)));
+        assert_eq!(config_read(&dev_mgr, 1, addr.devfn(), 4), 0xcfff_c000);
+        let mut data = [0u8; 4];
+        assert!(dev_mgr
+            .read(GuestAddress(0xcfff_c000), &mut data, IoType::Mmio)
+            .is_ok());
+        // The prefetchable window is full and IO ports must be in the window.
+        assert!(dev_mgr
+            .register_pci_device(
+                bridge_id,
+                Arc::new(BarFunction {
+                    bars: PciBars::new(&[PciBar::memory64(0x20_0000).prefetchable()]).unwrap(),
+                }),
+                None,
+                &mut PciBars::new(&[PciBar::memory64(0x20_0000).prefetchable()])
+                    .unwrap()
+                    .io_resources(),
+                &mut [],
+                None,
+            )
+            .is_err());
+        assert!(dev_mgr
+            .register_pci_device(
+                bridge_id,
+                Arc::new(TestFunction::new(0)),
+                None,
+                &mut [IoResource::new(
+                    Some(GuestAddress(0x3000)),
+                    0x10,
+                    IoType::Pio
+                )],
+                &mut [],
+                None,
+            )
+            .is_err());
+
+        // BAR moves stay inside the bridge windows.
+        assert!(dev_mgr
+            .relocate_resource(id, 0, GuestAddress(0xc000_0000))
+            .is_err());
+        dev_mgr.relocate_resource(id, 0, GuestAddress(0xcff0_0000))?;
+
+        // A nested bridge takes its windows from the first one.
+        let (nested, _) = dev_mgr.register_pci_bridge(
+            bridge_id,
+            Arc::new(
+                PciBridge::new(
+                    2,
+                    2,
+                    PciBridgeWindows {
+                        prefetchable: 0x10_0000,
+                        ..PciBridgeWindows::default()
+                    },
+                )
+                .unwrap(),
+            ),
+            None,
+        )?;
+        assert_eq!(config_read(&dev_mgr, 1, devfn(1, 0), 10), 1);
+        assert_eq!(config_read(&dev_mgr, 1, devfn(1, 0), 9), 0xffe1_ffe1);
+
+        assert!(dev_mgr.unregister_device(bridge_id).is_err());
+        assert_eq!(dev_mgr.unregister_subtree(bridge_id)?.len(), 3);
+        assert_eq!(config_read(&dev_mgr, 1, 0, 0), 0xffff_ffff);
+        assert_eq!(dev_mgr.pci_address(nested), None);
+        // The windows are released with the bridge.
+        dev_mgr.register_pci_bridge(
+            root,
+            Arc::new(PciBridge::new(1, 2, windows).unwrap()),
+            None,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_intx_routing() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+        let routes = dev_mgr.pci_intx_routing(root)?;
+        assert_eq!(routes.len(), 128);
+        let lines: Vec<u32> = routes[..4].iter().map(|route| route.irq).collect();
+        for line in lines.iter() {
+            assert_eq!(dev_mgr.allocator().irq_sharers(*line), 1);
+        }
+
+        // INTA# of device 1 is swizzled onto INTB# of the root bus.
+        let function = Arc::new(TestFunction::new(0x1111_1af4));
+        function.write_config_register(15, 1, &[1]);
+        let (id, addr) = dev_mgr.register_pci_device(
+            root,
+            function.clone(),
+            None,
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(addr.device, 1);
+        assert_eq!(
+            config_read(&dev_mgr, 0, addr.devfn(), 15),
+            0x0100 | lines[1]
+        );
+        let irqs = &dev_mgr.get_descriptor(id).unwrap().irqs;
+        assert_eq!(irqs[0].irq, Some(lines[1]));
+        assert!(irqs[0].shared);
+        assert_eq!(dev_mgr.allocator().irq_sharers(lines[1]), 2);
+
+        // INTB# of device 0 behind the bridge at 00:02.0 is swizzled twice.
+        let (bridge_id, bridge_addr) = dev_mgr.register_pci_bridge(
+            root,
+            Arc::new(PciBridge::new(1, 1, PciBridgeWindows::default()).unwrap()),
+            None,
+        )?;
+        assert_eq!(bridge_addr.device, 2);
+        let function = Arc::new(TestFunction::new(0x1111_1af4));
+        function.write_config_register(15, 1, &[2]);
+        let mut interrupts = [IrqResource::new(None)];
+        let (_, addr) = dev_mgr.register_pci_device(
+            bridge_id,
+            function,
+            Some(devfn(0, 0)),
+            &mut Vec::new(),
+            &mut interrupts,
+            None,
+        )?;
+        assert_eq!(
+            config_read(&dev_mgr, addr.bus, addr.devfn(), 15),
+            0x0200 | lines[3]
+        );
+        assert!(interrupts[0].irq.is_some());
+        assert!(!interrupts[0].shared);
+
+        dev_mgr.unregister_device(id)?;
+        assert_eq!(dev_mgr.allocator().irq_sharers(lines[1]), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pcie_hotplug() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+        let port = Arc::new(PcieRootPort::new(1, 1, 1, PciBridgeWindows::default()).unwrap());
+        let (port_id, _) = dev_mgr.register_pcie_root_port(root, port.clone(), None)?;
+        match dev_mgr.request_pci_unplug(port_id) {
+            Err(Error::Pci(PciError::NotHotplugSlot(_))) => {}
+            _ => panic!("the root bus has no hotplug slot"),
+        }
+
+        let (id, addr) = dev_mgr.hotplug_pci_device(
+            port_id,
+            Arc::new(TestFunction::new(0x1111_1af4)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(addr.to_string(), "01:00.0");
+        assert!(port.card_present());
+        assert_eq!(config_read(&dev_mgr, 1, 0, 0), 0x1111_1af4);
+        match dev_mgr.hotplug_pci_device(
+            port_id,
+            Arc::new(TestFunction::new(0)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        ) {
+            Err(Error::Pci(PciError::SlotOccupied(_))) => {}
+            _ => panic!("the slot is occupied"),
+        }
+
+        // The function is only ejected once the guest powered the slot off.
+        let sltctl = port
+            .bridge()
+            .configuration()
+            .capability_offset(PCI_CAP_ID_EXP)
+            .unwrap()
+            + 0x18;
+        port.write_config_register(sltctl / 4, 0, &[0x00, 0x00]);
+        assert!(dev_mgr.apply_pci_ejects()?.is_empty());
+        dev_mgr.request_pci_unplug(id)?;
+        port.write_config_register(sltctl / 4, 0, &[0x00, 0x04]);
+        assert_eq!(dev_mgr.apply_pci_ejects()?, vec![id]);
+        assert!(!port.card_present());
+        assert!(dev_mgr.get_descriptor(id).is_none());
+        assert_eq!(config_read(&dev_mgr, 1, 0, 0), 0xffff_ffff);
+
+        // A new function can be plugged in and is removed with the port.
+        dev_mgr.hotplug_pci_device(
+            port_id,
+            Arc::new(TestFunction::new(0x1111_1af4)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+        dev_mgr.unregister_subtree(port_id)?;
+        assert!(dev_mgr.pci_hotplug_ports.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_acpi_pci_hotplug() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+        match dev_mgr.hotplug_pci_device(
+            root,
+            Arc::new(TestFunction::new(0)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        ) {
+            Err(Error::Pci(PciError::NotHotplugSlot(_))) => {}
+            _ => panic!("the root bus has no hotplug controller"),
+        }
+
+        let controller = Arc::new(AcpiPciHotplug::new("\\_SB_.PCI0"));
+        dev_mgr.register_acpi_pci_hotplug(
+            root,
+            controller,
+            &mut AcpiPciHotplug::io_resources(),
+            &mut [IrqResource::new(Some(9))],
+        )?;
+        let (id, addr) = dev_mgr.hotplug_pci_device(
+            root,
+            Arc::new(TestFunction::new(0x1111_1af4)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+        assert_eq!(addr.to_string(), "00:01.0");
+        let mut data = [0u8; 4];
+        dev_mgr
+            .read(GuestAddress(ACPI_PCIHP_BASE), &mut data, IoType::Pio)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x2);
+
+        // The function is unregistered once the guest ejected its slot.
+        dev_mgr.request_pci_unplug(id)?;
+        assert!(dev_mgr.apply_pci_ejects()?.is_empty());
+        dev_mgr
+            .write(
+                GuestAddress(ACPI_PCIHP_BASE + 8),
+                &0x2u32.to_le_bytes(),
+                IoType::Pio,
+            )
+            .unwrap();
+        assert_eq!(dev_mgr.apply_pci_ejects()?, vec![id]);
+        assert!(dev_mgr.get_descriptor(id).is_none());
+        assert_eq!(config_read(&dev_mgr, 0, addr.devfn(), 0), 0xffff_ffff);
+        Ok(())
+    }
+
+    struct PhysicalFunction {
+        config: PciConfiguration,
+    }
+
+    impl Device for PhysicalFunction {
+        fn name(&self) -> String {
+            "pci-pf".to_string()
+        }
+        fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+        fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+        fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+            self.config.bars().unwrap().set_resources(res);
+        }
+    }
+
+    impl PciDevice for PhysicalFunction {
+        fn read_config_register(&self, reg_idx: usize) -> u32 {
+            self.config.read_config_register(reg_idx)
+        }
+        fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+            self.config.write_config_register(reg_idx, offset, data);
+        }
+        fn extended_config_space(&self) -> bool {
+            self.config.extended_config_space()
+        }
+        fn sriov(&self) -> Option<&PciSriov> {
+            self.config.sriov()
+        }
+    }
+
+    #[test]
+    fn test_pci_sriov() -> Result<()> {
+        let sys_res = test_pci_system_allocator((GuestAddress(0xc000_0000), 0x1000_0000), None);
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+
+        let sriov = PciSriov::new(4, 0x10ca, 1, 1, &[PciBar::memory32(0x4000)]).unwrap();
+        let mut resources = PciBars::new(&[PciBar::memory32(0x4000)])
+            .unwrap()
+            .io_resources();
+        let pf = Arc::new(PhysicalFunction {
+            config: PciConfigurationBuilder::new(0x8086, 0x10c9)
+                .bars(PciBars::new(&[PciBar::memory32(0x4000)]).unwrap())
+                .capability(PciCapability::PciExpress {
+                    port_type: PciExpressPortType::Endpoint,
+                })
+                .sriov(sriov)
+                .build()
+                .unwrap(),
+        });
+        let (pf_id, _) = dev_mgr.register_pci_device(
+            root,
+            pf.clone(),
+            Some(devfn(1, 0)),
+            &mut resources,
+            &mut [],
+            None,
+        )?;
+        assert_eq!(resources[0].addr, Some(GuestAddress(0xcfff_c000)));
+        match dev_mgr.register_pci_sriov(root, None, |_| Arc::new(TestFunction::new(0))) {
+            Err(Error::Pci(PciError::NoSriov(id))) => assert_eq!(id, root),
+            _ => panic!("the root bus has no SR-IOV capability"),
+        }
+        dev_mgr.register_pci_sriov(pf_id, None, |_| {
+            Arc::new(BarFunction {
+                bars: PciBars::new(&[PciBar::memory32(0x4000)]).unwrap(),
+            })
+        })?;
+        // The aperture holds the BARs of the four virtual functions.
+        let sriov = pf.config.sriov().unwrap();
+        assert_eq!(
+            sriov.vf_io_resources(3)[0].addr,
+            Some(GuestAddress(0xcfff_8000))
+        );
+
+        // The guest enables three virtual functions.
+        let offset = pf
+            .config
+            .extended_capability_offset(PCI_EXT_CAP_ID_SRIOV)
+            .unwrap();
+        pf.write_config_register((offset + 0x10) / 4, 0, &[3, 0]);
+        dev_mgr.apply_pci_sriov()?;
+        assert!(dev_mgr.get_descriptor(pf_id).unwrap().children.is_empty());
+        let control = PCI_SRIOV_CTRL_VFE | PCI_SRIOV_CTRL_MSE;
+        pf.write_config_register((offset + 0x08) / 4, 0, &control.to_le_bytes());
+        dev_mgr.apply_pci_sriov()?;
+        let vfs = dev_mgr.get_descriptor(pf_id).unwrap().children.clone();
+        assert_eq!(vfs.len(), 3);
+        for (index, id) in vfs.iter().enumerate() {
+            let addr = dev_mgr.pci_address(*id).unwrap();
+            assert_eq!(addr.to_string(), format!("00:01.{}", index + 1));
+            assert_eq!(
+                dev_mgr.get_descriptor(*id).unwrap().resources[0].addr,
+                Some(GuestAddress(0xcffe_c000 + index as u64 * 0x4000))
+            );
+        }
+        match dev_mgr.unregister_device(pf_id) {
+            Err(Error::HasChildren(_)) => (),
+            _ => panic!("the physical function has virtual functions"),
+        }
+
+        // Disabling them unregisters the virtual functions, which come back
+        // at the same place once enabled again.
+        pf.write_config_register((offset + 0x08) / 4, 0, &[0, 0]);
+        dev_mgr.apply_pci_sriov()?;
+        assert!(dev_mgr.get_descriptor(pf_id).unwrap().children.is_empty());
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 1), 0), 0xffff_ffff);
+        pf.write_config_register((offset + 0x08) / 4, 0, &control.to_le_bytes());
+        dev_mgr.apply_pci_sriov()?;
+        let vfs = dev_mgr.get_descriptor(pf_id).unwrap().children.clone();
+        assert_eq!(vfs.len(), 3);
+        assert_eq!(
+            dev_mgr.get_descriptor(vfs[0]).unwrap().resources[0].addr,
+            Some(GuestAddress(0xcffe_c000))
+        );
+
+        // Programming the VF BAR moves the aperture and the BARs of the
+        // virtual functions, as long as the aperture can be allocated there.
+        let vf_bar = (offset + 0x24) / 4;
+        pf.write_config_register(vf_bar, 0, &0xc001_0000u32.to_le_bytes());
+        dev_mgr.apply_pci_bar_moves()?;
+        assert_eq!(
+            dev_mgr.get_descriptor(vfs[2]).unwrap().resources[0].addr,
+            Some(GuestAddress(0xc001_8000))
+        );
+        // The base only has to be aligned on the BAR size of one virtual
+        // function.
+        pf.write_config_register(vf_bar, 0, &0xc002_4000u32.to_le_bytes());
+        dev_mgr.apply_pci_bar_moves()?;
+        assert_eq!(
+            dev_mgr.get_descriptor(vfs[2]).unwrap().resources[0].addr,
+            Some(GuestAddress(0xc002_c000))
+        );
+        // The aperture would overlap the BAR of the physical function.
+        pf.write_config_register(vf_bar, 0, &0xcfff_0000u32.to_le_bytes());
+        assert!(dev_mgr.apply_pci_bar_moves().is_err());
+        assert_eq!(pf.read_config_register(vf_bar), 0xc002_4000);
+        assert_eq!(
+            dev_mgr.get_descriptor(vfs[0]).unwrap().resources[0].addr,
+            Some(GuestAddress(0xc002_4000))
+        );
+
+        // The apertures go back to the PCI window with the physical function.
+        assert_eq!(dev_mgr.unregister_subtree(pf_id)?.len(), 4);
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 0), 0), 0xffff_ffff);
+        assert_eq!(
+            dev_mgr.resource.pci_mmio32_free_ranges(),
+            vec![(GuestAddress(0xc000_0000), 0x1000_0000)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pci_sriov_failure() -> Result<()> {
+        let sys_res = test_pci_system_allocator((GuestAddress(0xc000_0000), 0x1000_0000), None);
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+
+        // Physical functions with two virtual functions each: the second
+        // one of device 1 collides with a function, the second one of
+        // device 3 has no routing id.
+        let vf_routing = [(1, 1), (1, 1), (8, 0xffe8)];
+        let mut pfs = Vec::new();
+        for (device, (offset, stride)) in vf_routing.iter().enumerate() {
+            let sriov =
+                PciSriov::new(2, 0x10ca, *offset, *stride, &[PciBar::memory32(0x4000)]).unwrap();
+            let pf = Arc::new(PhysicalFunction {
+                config: PciConfigurationBuilder::new(0x8086, 0x10c9)
+                    .bars(PciBars::new(&[]).unwrap())
+                    .capability(PciCapability::PciExpress {
+                        port_type: PciExpressPortType::Endpoint,
+                    })
+                    .sriov(sriov)
+                    .build()
+                    .unwrap(),
+            });
+            let (pf_id, _) = dev_mgr.register_pci_device(
+                root,
+                pf.clone(),
+                Some(devfn(device as u8 + 1, 0)),
+                &mut Vec::new(),
+                &mut [],
+                None,
+            )?;
+            dev_mgr.register_pci_sriov(pf_id, None, |_| {
+                Arc::new(BarFunction {
+                    bars: PciBars::new(&[PciBar::memory32(0x4000)]).unwrap(),
+                })
+            })?;
+            pfs.push((pf_id, pf));
+        }
+        dev_mgr.register_pci_device(
+            root,
+            Arc::new(TestFunction::new(0x1234_8086)),
+            Some(devfn(1, 2)),
+            &mut Vec::new(),
+            &mut [],
+            None,
+        )?;
+
+        // Every physical function is handled, those failing keep no virtual
+        // function.
+        for (_, pf) in pfs.iter() {
+            let offset = pf
+                .config
+                .extended_capability_offset(PCI_EXT_CAP_ID_SRIOV)
+                .unwrap();
+            pf.write_config_register((offset + 0x10) / 4, 0, &[2, 0]);
+            let control = PCI_SRIOV_CTRL_VFE | PCI_SRIOV_CTRL_MSE;
+            pf.write_config_register((offset + 0x08) / 4, 0, &control.to_le_bytes());
+        }
+        assert!(dev_mgr.apply_pci_sriov().is_err());
+        let children: Vec<usize> = pfs
+            .iter()
+            .map(|(id, _)| dev_mgr.get_descriptor(*id).unwrap().children.len())
+            .collect();
+        assert_eq!(children, vec![0, 2, 0]);
+        assert_eq!(config_read(&dev_mgr, 0, devfn(1, 1), 0), 0xffff_ffff);
+        assert_eq!(config_read(&dev_mgr, 0, devfn(4, 0), 0), 0xffff_ffff);
+        assert!(dev_mgr.apply_pci_sriov().is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI subsystem.
+//!
+//! A PCI function is a [Device](../device/trait.Device.html) which also
+//! implements [PciDevice](trait.PciDevice.html) to expose its configuration
+//! space. Functions sit on a [PciBus](struct.PciBus.html), and the buses
+//! reachable from one host bridge form a [PciRoot](struct.PciRoot.html)
+//! hierarchy.
+//!
+//! Configuration mechanisms give the guest access to the configuration space
+//! of the functions of a hierarchy:
+//! - the [PciRootBus](struct.PciRootBus.html) decodes the CF8/CFC
+//!   configuration mechanism #1 on the PIO bus,
+//! - the [PciEcam](struct.PciEcam.html) decodes the PCIe memory-mapped
+//!   configuration space on the MMIO bus, including the 4 KiB extended
+//!   configuration space of every function.
+//!
+//! Either one is registered with the
+//! [DeviceManager](../device_manager/struct.DeviceManager.html) as the root
+//! bus of the hierarchy, and PCI functions are registered behind it through
+//! `DeviceManager::register_pci_device()`, which gives them a device and
+//! function number on the bus. A [PciBridge](struct.PciBridge.html)
+//! registered through `DeviceManager::register_pci_bridge()` adds a secondary
+//! bus to the hierarchy, PCI functions being registered behind it the same
+//! way. Functions declaring an interrupt pin get the interrupt line it is
+//! swizzled to through the bridges, as described by
+//! `DeviceManager::pci_intx_routing()`. A
+//! [PcieRootPort](struct.PcieRootPort.html) is a bridge with a native
+//! hotplug slot, registered through `DeviceManager::register_pcie_root_port()`,
+//! in which functions are plugged and unplugged while the guest runs. Guests
+//! relying on ACPI hotplug rather use the slots of a root bus managed by an
+//! [AcpiPciHotplug](struct.AcpiPciHotplug.html) controller, registered
+//! through `DeviceManager::register_acpi_pci_hotplug()`.
+//!
+//! Each hierarchy is a PCI segment, whose BARs are allocated from the windows
+//! the `SystemAllocator` has for it, if any. `DeviceManager::pci_segments()`
+//! describes them for the firmware tables.
+//!
+//! Devices usually emulate their configuration space with a
+//! [PciConfiguration](struct.PciConfiguration.html), laid out by a
+//! [PciConfigurationBuilder](struct.PciConfigurationBuilder.html) from the
+//! header fields, BARs and capabilities of the function. Functions using
+//! MSI-X embed a [PciMsix](struct.PciMsix.html) emulating the vector table
+//! and pending bit array living in their BARs. SR-IOV physical functions
+//! build theirs with a [PciSriov](struct.PciSriov.html), whose virtual
+//! functions are registered through `DeviceManager::register_pci_sriov()`.
+
+mod acpi_hotplug;
+mod bar;
+mod bridge;
+mod bus;
+mod configuration;
+mod ecam;
+mod intx;
+mod manager;
+mod msix;
+mod root_bus;
+mod root_port;
+mod sriov;
+
+pub use self::acpi_hotplug::{
+    AcpiPciHotplug, ACPI_GPE0_BASE, ACPI_GPE0_SIZE, ACPI_PCIHP_BASE, ACPI_PCIHP_GPE,
+};
+pub use self::bar::{PciBar, PciBarType, PciBars, PCI_BAR0_REG, PCI_BAR_REGISTERS};
+pub(crate) use self::bridge::BridgeWindows;
+pub use self::bridge::{PciBridge, PciBridgeWindows, PCI_BRIDGE_IO_ALIGN, PCI_BRIDGE_MEM_ALIGN};
+pub use self::bus::{PciBus, PciRoot};
+pub use self::configuration::{
+    PciCapability, PciConfiguration, PciConfigurationBuilder, PciExpressPortType,
+    PciExtendedCapability, PCI_BRIDGE_CONTROL, PCI_CAPABILITY_LIST, PCI_CAP_ID_EXP, PCI_CAP_ID_MSI,
+    PCI_CAP_ID_MSIX, PCI_CAP_ID_PM, PCI_CAP_ID_VNDR, PCI_COMMAND, PCI_COMMAND_INTX_DISABLE,
+    PCI_COMMAND_IO, PCI_COMMAND_MASTER, PCI_COMMAND_MEMORY, PCI_INTERRUPT_LINE, PCI_INTERRUPT_PIN,
+    PCI_IO_BASE, PCI_MEMORY_BASE, PCI_PREF_BASE_UPPER32, PCI_PREF_LIMIT_UPPER32,
+    PCI_PREF_MEMORY_BASE, PCI_PRIMARY_BUS, PCI_STATUS, PCI_STATUS_INTERRUPT,
+};
+pub use self::ecam::PciEcam;
+pub use self::intx::{swizzle, PciIntxRoute, PCI_INTX_PINS};
+pub use self::manager::PciSegment;
+pub(crate) use self::manager::VirtualFunctions;
+pub use self::msix::{
+    PciMsix, MSIX_CONTROL_ENABLE, MSIX_CONTROL_FUNCTION_MASK, MSIX_TABLE_ENTRY_SIZE,
+};
+pub use self::root_bus::PciRootBus;
+pub use self::root_port::PcieRootPort;
+pub use self::sriov::{PciSriov, PCI_EXT_CAP_ID_SRIOV, PCI_SRIOV_CTRL_MSE, PCI_SRIOV_CTRL_VFE};
+
+use crate::device::Device;
+use std::sync::Arc;
+use vm_memory::GuestAddress;
+
+use std::fmt::{self, Display};
+use std::result;
+
+/// Errors associated with the PCI subsystem.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// No device number is left on the bus.
+    NoFreeDevice(u8),
+    /// The PCI address is already used by another function.
+    AddressInUse(PciAddress),
+    /// The bus doesn't exist in the PCI hierarchy.
+    BusNonExist(u8),
+    /// The device given as parent is not a PCI bus.
+    NotPciBus(u32),
+    /// The BAR at the given index has an invalid size or doesn't fit in the
+    /// BAR registers.
+    InvalidBar(usize),
+    /// The capability with the given id has invalid parameters.
+    InvalidCapability(u16),
+    /// The capabilities don't fit in the configuration space.
+    CapabilitySpaceFull,
+    /// The secondary and subordinate bus numbers of a bridge don't form a
+    /// valid range behind its parent bus.
+    InvalidBusRange(u8, u8),
+    /// The bus number is already used in the PCI hierarchy.
+    BusInUse(u8),
+    /// A bridge window has an invalid base or size.
+    InvalidBridgeWindow,
+    /// The device is not in, or is not, a PCI hotplug slot.
+    NotHotplugSlot(u32),
+    /// The hotplug slot already holds a function.
+    SlotOccupied(u32),
+    /// The PCI segment is already served by another hierarchy.
+    SegmentInUse(u16),
+    /// The device is not a PCI function with an SR-IOV capability.
+    NoSriov(u32),
+    /// The ECAM region isn't naturally aligned at the given address.
+    InvalidEcamAddress(GuestAddress),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            NoFreeDevice(bus) => write!(f, "No device number left on PCI bus {:02x}", bus),
+            AddressInUse(addr) => write!(f, "PCI address {} is already used", addr),
+            BusNonExist(bus) => write!(f, "PCI bus {:02x} doesn't exist", bus),
+            NotPciBus(id) => write!(f, "Device {} is not a PCI bus", id),
+            InvalidBar(idx) => write!(f, "Invalid PCI BAR {}", idx),
+            InvalidCapability(id) => write!(f, "Invalid PCI capability {:#04x}", id),
+            CapabilitySpaceFull => write!(f, "PCI capabilities don't fit in config space"),
+            InvalidBusRange(secondary, subordinate) => write!(
+                f,
+                "Invalid PCI bus range {:02x}-{:02x}",
+                secondary, subordinate
+            ),
+            BusInUse(bus) => write!(f, "PCI bus {:02x} is already used", bus),
+            InvalidBridgeWindow => write!(f, "Invalid PCI bridge window"),
+            NotHotplugSlot(id) => write!(f, "Device {} has no PCI hotplug slot", id),
+            SlotOccupied(id) => write!(f, "PCI hotplug slot of device {} is occupied", id),
+            SegmentInUse(segment) => write!(f, "PCI segment {:04x} is already used", segment),
+            NoSriov(id) => write!(f, "Device {} has no SR-IOV capability", id),
+            InvalidEcamAddress(addr) => write!(f, "Unaligned ECAM region at {:#x}", addr.0),
+        }
+    }
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Number of device numbers on a PCI bus.
+pub const PCI_DEVICES_PER_BUS: u8 = 32;
+/// Number of functions of a PCI device.
+pub const PCI_FUNCTIONS_PER_DEVICE: u8 = 8;
+/// Number of 32-bit registers in the legacy configuration space of a function.
+pub const PCI_CONFIG_REGISTERS: usize = 64;
+/// Number of 32-bit registers in the PCIe extended configuration space of a
+/// function.
+pub const PCIE_CONFIG_REGISTERS: usize = 1024;
+/// Bridge class code.
+pub const PCI_CLASS_BRIDGE: u8 = 0x06;
+
+/// Build the devfn byte of a device and function number.
+pub fn devfn(device: u8, function: u8) -> u8 {
+    (device << 3) | (function & 0x7)
+}
+
+/// Address of a PCI function.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    /// Bus number.
+    pub bus: u8,
+    /// Device number, from 0 to 31.
+    pub device: u8,
+    /// Function number, from 0 to 7.
+    pub function: u8,
+}
+
+impl PciAddress {
+    /// Build the address of a function from its bus number and devfn byte.
+    pub fn new(bus: u8, devfn: u8) -> Self {
+        PciAddress {
+            bus,
+            device: devfn >> 3,
+            function: devfn & 0x7,
+        }
+    }
+
+    /// Devfn byte of the address.
+    pub fn devfn(&self) -> u8 {
+        devfn(self.device, self.function)
+    }
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+/// Trait for PCI functions exposing a configuration space.
+pub trait PciDevice: Send + Sync {
+    /// Read the 32-bit configuration register at `reg_idx`.
+    fn read_config_register(&self, reg_idx: usize) -> u32;
+
+    /// Write `data` at byte `offset` of the configuration register at
+    /// `reg_idx`. The access doesn't cross the register boundary.
+    fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]);
+
+    /// Return true if the function implements the 4 KiB PCIe extended
+    /// configuration space. Registers past the first 256 bytes are only
+    /// accessed on such functions, they read as zeros on the others.
+    fn extended_config_space(&self) -> bool {
+        false
+    }
+
+    /// Take the BAR moves programmed by the guest since the last call, as
+    /// (IO resource index, new address) pairs, usually from
+    /// `PciBars::take_moves()`.
+    fn take_bar_moves(&self) -> Vec<(usize, GuestAddress)> {
+        Vec::new()
+    }
+
+    /// SR-IOV capability of a physical function, usually from
+    /// `PciConfiguration::sriov()`.
+    fn sriov(&self) -> Option<&PciSriov> {
+        None
+    }
+}
+
+/// Trait for the devices giving the guest access to the configuration space
+/// of a PCI hierarchy.
+pub trait PciConfigMechanism: Device {
+    /// PCI hierarchy served by the device.
+    fn root(&self) -> &Arc<PciRoot>;
+}
+
+// Extract the bytes accessed at `offset` of a 32-bit register.
+pub(crate) fn read_register_bytes(value: u32, offset: u64, data: &mut [u8]) {
+    for (idx, byte) in data.iter_mut().enumerate() {
+        let shift = (offset as usize + idx) * 8;
+        *byte = if shift < 32 {
+            (value >> shift) as u8
+        } else {
+            0xff
+        };
+    }
+}
+
+// Merge the bytes written at `offset` into a 32-bit register value.
+pub(crate) fn write_register_bytes(value: u32, offset: u64, data: &[u8]) -> u32 {
+    let mut value = value;
+    for (idx, byte) in data.iter().enumerate() {
+        let shift = (offset as usize + idx) * 8;
+        if shift < 32 {
+            value = (value & !(0xff << shift)) | (u32::from(*byte) << shift);
+        }
+    }
+    value
+}
@@ -0,0 +1,522 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! MSI-X vector table and pending bit array.
+//!
+//! A PCI function using MSI-X embeds a [PciMsix](struct.PciMsix.html) and
+//! forwards to it:
+//! - the accesses to the BAR holding the table and the PBA, through
+//!   `read()` and `write()`, which tell whether the access hit one of them,
+//! - the MSI-X control register of its configuration space, through
+//!   `set_control()`,
+//! - the MSI-X interrupt group from `Device::set_interrupt_group()`.
+//!
+//! The messages programmed by the guest are pushed to the interrupt group,
+//! and a vector is masked in the group whenever MSI-X is disabled, the
+//! function is masked or the vector itself is masked. The group records the
+//! interrupts raised on masked vectors, they show up in the PBA and are
+//! delivered once the vector is unmasked.
+
+use super::{
+    read_register_bytes, Error, PciCapability, Result, PCI_BAR_REGISTERS, PCI_CAP_ID_MSIX,
+};
+use crate::interrupt::{
+    Error as InterruptError, InterruptSourceConfig, InterruptSourceGroup, InterruptSourceType,
+    MsiIrqSourceConfig, Result as InterruptResult, MAX_MSIX_VECTORS,
+};
+use std::sync::{Arc, Mutex};
+use vm_memory::GuestUsize;
+
+/// Size of a MSI-X table entry.
+pub const MSIX_TABLE_ENTRY_SIZE: u64 = 16;
+/// MSI-X control register: function mask.
+pub const MSIX_CONTROL_FUNCTION_MASK: u16 = 0x4000;
+/// MSI-X control register: MSI-X enable.
+pub const MSIX_CONTROL_ENABLE: u16 = 0x8000;
+const MSIX_VECTOR_CONTROL_MASK: u32 = 0x1;
+
+#[derive(Copy, Clone)]
+struct MsixEntry {
+    low_addr: u32,
+    high_addr: u32,
+    data: u32,
+    vector_control: u32,
+}
+
+impl MsixEntry {
+    fn register(&self, idx: u64) -> u32 {
+        match idx {
+            0 => self.low_addr,
+            1 => self.high_addr,
+            2 => self.data,
+            _ => self.vector_control,
+        }
+    }
+
+    fn set_register(&mut self, idx: u64, value: u32) {
+        match idx {
+            0 => self.low_addr = value & !0x3,
+            1 => self.high_addr = value,
+            2 => self.data = value,
+            _ => self.vector_control = value & MSIX_VECTOR_CONTROL_MASK,
+        }
+    }
+
+    fn config(&self) -> InterruptSourceConfig {
+        InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig {
+            high_addr: self.high_addr,
+            low_addr: self.low_addr,
+            data: self.data,
+        })
+    }
+}
+
+impl Default for MsixEntry {
+    fn default() -> Self {
+        MsixEntry {
+            low_addr: 0,
+            high_addr: 0,
+            data: 0,
+            vector_control: MSIX_VECTOR_CONTROL_MASK,
+        }
+    }
+}
+
+struct MsixState {
+    entries: Vec<MsixEntry>,
+    enabled: bool,
+    function_mask: bool,
+    group: Option<Arc<dyn InterruptSourceGroup>>,
+}
+
+impl MsixState {
+    fn masked(&self, vector: usize) -> bool {
+        !self.enabled
+            || self.function_mask
+            || self.entries[vector].vector_control & MSIX_VECTOR_CONTROL_MASK != 0
+    }
+
+    // Mirror the mask of `vector` into the interrupt group, delivering its
+    // pending interrupt when it gets unmasked.
+    fn sync_mask(&self, vector: usize) -> InterruptResult<()> {
+        match self.group.as_ref() {
+            Some(group) if self.masked(vector) => group.mask(vector as u32),
+            Some(group) => group.unmask(vector as u32),
+            None => Ok(()),
+        }
+    }
+
+    fn sync_config(&self, vector: usize) -> InterruptResult<()> {
+        match self.group.as_ref() {
+            Some(group) => group.update(vector as u32, &self.entries[vector].config()),
+            None => Ok(()),
+        }
+    }
+
+    fn pending_bits(&self, qword: usize) -> u64 {
+        let group = match self.group.as_ref() {
+            Some(group) => group,
+            None => return 0,
+        };
+        let mut bits = 0;
+        for bit in 0..64 {
+            let vector = qword * 64 + bit;
+            if vector >= self.entries.len() {
+                break;
+            }
+            if group.is_pending(vector as u32).unwrap_or(false) {
+                bits |= 1 << bit;
+            }
+        }
+        bits
+    }
+}
+
+/// MSI-X table and PBA of a PCI function.
+pub struct PciMsix {
+    table_bar: usize,
+    table_offset: u64,
+    pba_bar: usize,
+    pba_offset: u64,
+    state: Mutex<MsixState>,
+}
+
+impl PciMsix {
+    /// Create the MSI-X structures of `vectors` vectors, the table living at
+    /// `table_offset` of BAR `table_bar` and the PBA at `pba_offset` of BAR
+    /// `pba_bar`. Offsets must be 8-byte aligned and the two structures must
+    /// not overlap.
+    pub fn new(
+        vectors: u16,
+        table_bar: usize,
+        table_offset: u64,
+        pba_bar: usize,
+        pba_offset: u64,
+    ) -> Result<Self> {
+        let msix = PciMsix {
+            table_bar,
+            table_offset,
+            pba_bar,
+            pba_offset,
+            state: Mutex::new(MsixState {
+                entries: vec![MsixEntry::default(); vectors as usize],
+                enabled: false,
+                function_mask: false,
+                group: None,
+            }),
+        };
+        let overlap = table_bar == pba_bar
+            && table_offset < pba_offset + msix.pba_size()
+            && pba_offset < table_offset + msix.table_size();
+        if vectors == 0
+            || u32::from(vectors) > MAX_MSIX_VECTORS
+            || table_bar >= PCI_BAR_REGISTERS
+            || pba_bar >= PCI_BAR_REGISTERS
+            || table_offset & 0x7 != 0
+            || pba_offset & 0x7 != 0
+            || table_offset + msix.table_size() > u64::from(u32::MAX)
+            || pba_offset + msix.pba_size() > u64::from(u32::MAX)
+            || overlap
+        {
+            return Err(Error::InvalidCapability(PCI_CAP_ID_MSIX.into()));
+        }
+        Ok(msix)
+    }
+
+    /// Number of vectors.
+    pub fn vectors(&self) -> u16 {
+        self.state
+            .lock()
+            .expect("failed to acquire lock")
+            .entries
+            .len() as u16
+    }
+
+    /// Size of the vector table.
+    pub fn table_size(&self) -> GuestUsize {
+        u64::from(self.vectors()) * MSIX_TABLE_ENTRY_SIZE
+    }
+
+    /// Size of the pending bit array.
+    pub fn pba_size(&self) -> GuestUsize {
+        // One bit per vector, in 64-bit words.
+        ((u64::from(self.vectors()) + 63) >> 6) << 3
+    }
+
+    /// Minimum size of a BAR holding both the table and the PBA, rounded up
+    /// to a power of two.
+    pub fn bar_size(&self) -> GuestUsize {
+        let end = std::cmp::max(
+            self.table_offset + self.table_size(),
+            self.pba_offset + self.pba_size(),
+        );
+        end.next_power_of_two()
+    }
+
+    /// MSI-X capability describing the structures, to add to the
+    /// configuration space of the function.
+    pub fn capability(&self) -> PciCapability {
+        PciCapability::MsiX {
+            table_size: self.vectors(),
+            table_bar: self.table_bar as u8,
+            table_offset: self.table_offset as u32,
+            pba_bar: self.pba_bar as u8,
+            pba_offset: self.pba_offset as u32,
+        }
+    }
+
+    /// Set the MSI-X interrupt group delivering the vectors, and push the
+    /// current messages and masks to it.
+    pub fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) -> InterruptResult<()> {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        if group.interrupt_type() != InterruptSourceType::MsiX
+            || group.len() < state.entries.len() as u32
+        {
+            return Err(InterruptError::InvalidConfig);
+        }
+        state.group = Some(group);
+        for vector in 0..state.entries.len() {
+            state.sync_config(vector)?;
+            state.sync_mask(vector)?;
+        }
+        Ok(())
+    }
+
+    /// Return true if MSI-X is enabled by the guest.
+    pub fn enabled(&self) -> bool {
+        self.state.lock().expect("failed to acquire lock").enabled
+    }
+
+    /// Return true if `vector` is masked, by itself or for the whole
+    /// function.
+    pub fn is_masked(&self, vector: u16) -> bool {
+        let state = self.state.lock().expect("failed to acquire lock");
+        (vector as usize) >= state.entries.len() || state.masked(vector as usize)
+    }
+
+    /// Update the enable and function mask bits from the MSI-X control
+    /// register written by the guest, delivering the pending interrupts of
+    /// the vectors it unmasks.
+    pub fn set_control(&self, control: u16) -> InterruptResult<()> {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let enabled = control & MSIX_CONTROL_ENABLE != 0;
+        let function_mask = control & MSIX_CONTROL_FUNCTION_MASK != 0;
+        if state.enabled == enabled && state.function_mask == function_mask {
+            return Ok(());
+        }
+        state.enabled = enabled;
+        state.function_mask = function_mask;
+        for vector in 0..state.entries.len() {
+            state.sync_mask(vector)?;
+        }
+        Ok(())
+    }
+
+    /// Raise `vector`. An interrupt raised on a masked vector is pending until
+    /// the vector gets unmasked.
+    pub fn trigger(&self, vector: u16) -> InterruptResult<()> {
+        let state = self.state.lock().expect("failed to acquire lock");
+        if (vector as usize) >= state.entries.len() {
+            return Err(InterruptError::InvalidIndex(u32::from(vector)));
+        }
+        match state.group.as_ref() {
+            Some(group) => group.trigger(u32::from(vector)),
+            None => Err(InterruptError::NotSupported),
+        }
+    }
+
+    /// Put the vectors back into their masked reset state and disable MSI-X.
+    pub fn reset(&self) -> InterruptResult<()> {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.enabled = false;
+        state.function_mask = false;
+        for vector in 0..state.entries.len() {
+            state.entries[vector] = MsixEntry::default();
+            state.sync_config(vector)?;
+            state.sync_mask(vector)?;
+        }
+        Ok(())
+    }
+
+    // Decode an access at `offset` of BAR `bar`: Some(true) for the table,
+    // Some(false) for the PBA, along with the offset in the structure.
+    fn target(&self, bar: usize, offset: u64) -> Option<(bool, u64)> {
+        if bar == self.table_bar
+            && offset >= self.table_offset
+            && offset < self.table_offset + self.table_size()
+        {
+            return Some((true, offset - self.table_offset));
+        }
+        if bar == self.pba_bar
+            && offset >= self.pba_offset
+            && offset < self.pba_offset + self.pba_size()
+        {
+            return Some((false, offset - self.pba_offset));
+        }
+        None
+    }
+
+    /// Read at `offset` of BAR `bar`, returning false if the access doesn't
+    /// hit the table or the PBA. Only aligned 4 and 8-byte accesses are
+    /// supported, the others read as zeros.
+    pub fn read(&self, bar: usize, offset: u64, data: &mut [u8]) -> bool {
+        let (table, offset) = match self.target(bar, offset) {
+            Some(target) => target,
+            None => return false,
+        };
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        if (data.len() != 4 && data.len() != 8) || offset % data.len() as u64 != 0 {
+            return true;
+        }
+
+        let state = self.state.lock().expect("failed to acquire lock");
+        let value = if table {
+            let entry = &state.entries[(offset / MSIX_TABLE_ENTRY_SIZE) as usize];
+            let reg = (offset % MSIX_TABLE_ENTRY_SIZE) / 4;
+            if data.len() == 8 {
+                u64::from(entry.register(reg)) | (u64::from(entry.register(reg + 1)) << 32)
+            } else {
+                u64::from(entry.register(reg))
+            }
+        } else {
+            state.pending_bits((offset / 8) as usize) >> ((offset % 8) * 8)
+        };
+        read_register_bytes(value as u32, 0, &mut data[..4]);
+        if data.len() == 8 {
+            read_register_bytes((value >> 32) as u32, 0, &mut data[4..]);
+        }
+        true
+    }
+
+    /// Write at `offset` of BAR `bar`, returning false if the access doesn't
+    /// hit the table or the PBA. The PBA is read-only, and only aligned 4 and
+    /// 8-byte accesses to the table are supported.
+    pub fn write(&self, bar: usize, offset: u64, data: &[u8]) -> bool {
+        let (table, offset) = match self.target(bar, offset) {
+            Some(target) => target,
+            None => return false,
+        };
+        if !table || (data.len() != 4 && data.len() != 8) || offset % data.len() as u64 != 0 {
+            return true;
+        }
+
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let vector = (offset / MSIX_TABLE_ENTRY_SIZE) as usize;
+        let reg = (offset % MSIX_TABLE_ENTRY_SIZE) / 4;
+        let was_masked = state.masked(vector);
+        for (idx, chunk) in data.chunks(4).enumerate() {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(chunk);
+            state.entries[vector].set_register(reg + idx as u64, u32::from_le_bytes(value));
+        }
+        // Errors of the interrupt layer can't be reported to the guest. The
+        // message is pushed before the vector gets unmasked.
+        let _ = state.sync_config(vector);
+        if state.masked(vector) != was_masked {
+            let _ = state.sync_mask(vector);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct RecordingGroup {
+        masked: Mutex<Vec<bool>>,
+        pending: Mutex<Vec<bool>>,
+        configs: Mutex<Vec<InterruptSourceConfig>>,
+        delivered: Mutex<Vec<u32>>,
+    }
+
+    impl RecordingGroup {
+        fn new(count: usize) -> Self {
+            RecordingGroup {
+                masked: Mutex::new(vec![true; count]),
+                pending: Mutex::new(vec![false; count]),
+                configs: Mutex::new(vec![
+                    InterruptSourceConfig::MsiIrq(
+                        MsiIrqSourceConfig::default()
+                    );
+                    count
+                ]),
+                delivered: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    impl InterruptSourceGroup for RecordingGroup {
+        fn interrupt_type(&self) -> InterruptSourceType {
+            InterruptSourceType::MsiX
+        }
+        fn len(&self) -> u32 {
+            self.masked.lock().unwrap().len() as u32
+        }
+        fn base(&self) -> u32 {
+            24
+        }
+        fn trigger(&self, index: u32) -> InterruptResult<()> {
+            if self.masked.lock().unwrap()[index as usize] {
+                self.pending.lock().unwrap()[index as usize] = true;
+            } else {
+                self.delivered.lock().unwrap().push(index);
+            }
+            Ok(())
+        }
+        fn mask(&self, index: u32) -> InterruptResult<()> {
+            self.masked.lock().unwrap()[index as usize] = true;
+            Ok(())
+        }
+        fn unmask(&self, index: u32) -> InterruptResult<()> {
+            self.masked.lock().unwrap()[index as usize] = false;
+            if std::mem::take(&mut self.pending.lock().unwrap()[index as usize]) {
+                self.delivered.lock().unwrap().push(index);
+            }
+            Ok(())
+        }
+        fn is_pending(&self, index: u32) -> InterruptResult<bool> {
+            Ok(self.pending.lock().unwrap()[index as usize])
+        }
+        fn update(&self, index: u32, config: &InterruptSourceConfig) -> InterruptResult<()> {
+            self.configs.lock().unwrap()[index as usize] = *config;
+            Ok(())
+        }
+        fn config(&self, index: u32) -> InterruptResult<InterruptSourceConfig> {
+            Ok(self.configs.lock().unwrap()[index as usize])
+        }
+    }
+
+    #[test]
+    fn test_msix_table_and_pba() {
+        assert!(PciMsix::new(4, 0, 0, 0, 0x20).is_err());
+        assert!(PciMsix::new(4, 0, 0, 6, 0x800).is_err());
+        let msix = PciMsix::new(3, 0, 0, 0, 0x800).unwrap();
+        assert_eq!(msix.table_size(), 0x30);
+        assert_eq!(msix.pba_size(), 8);
+        assert_eq!(msix.bar_size(), 0x1000);
+        let group = Arc::new(RecordingGroup::new(3));
+        msix.set_interrupt_group(group.clone()).unwrap();
+
+        // Vectors come up masked.
+        let mut data = [0u8; 4];
+        assert!(msix.read(0, 0x1c, &mut data));
+        assert_eq!(u32::from_le_bytes(data), 1);
+        assert!(!msix.read(0, 0x30, &mut data));
+        assert!(!msix.read(1, 0, &mut data));
+
+        // Program vector 1 with a 64-bit access, then its data.
+        assert!(msix.write(0, 0x10, &0x0000_0001_fee0_1003u64.to_le_bytes()));
+        assert!(msix.write(0, 0x18, &0x41u32.to_le_bytes()));
+        assert_eq!(
+            group.config(1).unwrap(),
+            InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig {
+                high_addr: 1,
+                low_addr: 0xfee0_1000,
+                data: 0x41,
+            })
+        );
+        let mut qword = [0u8; 8];
+        msix.read(0, 0x10, &mut qword);
+        assert_eq!(u64::from_le_bytes(qword), 0x0000_0001_fee0_1000);
+
+        // Unmasking the vector is not enough while MSI-X is disabled.
+        msix.write(0, 0x1c, &0u32.to_le_bytes());
+        assert!(msix.is_masked(1));
+        msix.trigger(1).unwrap();
+        msix.read(0, 0x800, &mut qword);
+        assert_eq!(u64::from_le_bytes(qword), 0x2);
+        assert!(group.delivered.lock().unwrap().is_empty());
+
+        // Enabling MSI-X delivers the pending interrupt.
+        msix.set_control(MSIX_CONTROL_ENABLE).unwrap();
+        assert!(!msix.is_masked(1));
+        assert_eq!(*group.delivered.lock().unwrap(), vec![1]);
+        msix.read(0, 0x800, &mut qword);
+        assert_eq!(u64::from_le_bytes(qword), 0);
+        msix.trigger(1).unwrap();
+        assert_eq!(*group.delivered.lock().unwrap(), vec![1, 1]);
+
+        // The function mask holds interrupts, the PBA is read-only.
+        msix.set_control(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK)
+            .unwrap();
+        msix.trigger(1).unwrap();
+        assert!(msix.write(0, 0x800, &[0; 8]));
+        msix.read(0, 0x800, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x2);
+        msix.set_control(MSIX_CONTROL_ENABLE).unwrap();
+        assert_eq!(*group.delivered.lock().unwrap(), vec![1, 1, 1]);
+
+        // Vectors out of range can't be raised.
+        assert!(msix.trigger(3).is_err());
+        msix.reset().unwrap();
+        assert!(msix.is_masked(1));
+        assert!(!msix.enabled());
+        assert_eq!(
+            group.config(1).unwrap(),
+            InterruptSourceConfig::MsiIrq(MsiIrqSourceConfig::default())
+        );
+    }
+}
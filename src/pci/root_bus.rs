@@ -0,0 +1,202 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCI host bridge decoding the configuration mechanism #1.
+//!
+//! The guest writes the address of a configuration register to the
+//! CONFIG_ADDRESS port at 0xcf8:
+//! - bit 31 enables the configuration access,
+//! - bits 23:16 hold the bus number,
+//! - bits 15:11 the device number and bits 10:8 the function number,
+//! - bits 7:2 the register index.
+//!
+//! and then accesses the register through the CONFIG_DATA ports 0xcfc to
+//! 0xcff, the port offset giving the byte offset inside the register.
+//!
+//! Only 32-bit accesses at 0xcf8 reach CONFIG_ADDRESS: narrower ones are
+//! meant for other registers, such as the reset control register at 0xcf9
+//! of PIIX and ICH chipsets, and are ignored.
+
+use super::{
+    read_register_bytes, write_register_bytes, PciAddress, PciConfigMechanism, PciRoot,
+    PCI_CONFIG_REGISTERS,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use std::sync::{Arc, Mutex};
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// PIO address of the CONFIG_ADDRESS register.
+pub const PCI_CONFIG_ADDRESS_PORT: u64 = 0xcf8;
+/// Size of the CONFIG_ADDRESS and CONFIG_DATA ports.
+const PCI_CONFIG_IO_SIZE: GuestUsize = 8;
+/// Offset of CONFIG_DATA from CONFIG_ADDRESS.
+const CONFIG_DATA_OFFSET: u64 = 4;
+const CONFIG_ENABLE: u32 = 0x8000_0000;
+
+/// PCI host bridge device serving the CF8/CFC configuration ports.
+pub struct PciRootBus {
+    root: Arc<PciRoot>,
+    config_address: Mutex<u32>,
+    base: Mutex<GuestAddress>,
+}
+
+impl PciRootBus {
+    /// Create the host bridge of the `root` hierarchy.
+    pub fn new(root: Arc<PciRoot>) -> Self {
+        PciRootBus {
+            root,
+            config_address: Mutex::new(0),
+            base: Mutex::new(GuestAddress(PCI_CONFIG_ADDRESS_PORT)),
+        }
+    }
+
+    /// IO resources to request at registration: the CONFIG_ADDRESS and
+    /// CONFIG_DATA ports.
+    pub fn io_resources() -> Vec<IoResource> {
+        vec![IoResource::new(
+            Some(GuestAddress(PCI_CONFIG_ADDRESS_PORT)),
+            PCI_CONFIG_IO_SIZE,
+            IoType::Pio,
+        )]
+    }
+
+    // Decode CONFIG_ADDRESS, None when the configuration access is disabled.
+    fn target(&self) -> Option<(PciAddress, usize)> {
+        let config = *self.config_address.lock().expect("failed to acquire lock");
+        if config & CONFIG_ENABLE == 0 {
+            return None;
+        }
+        let addr = PciAddress::new((config >> 16) as u8, (config >> 8) as u8);
+        let reg_idx = ((config >> 2) & 0x3f) as usize;
+        Some((addr, reg_idx))
+    }
+
+    fn offset(&self, addr: GuestAddress) -> Option<u64> {
+        addr.checked_offset_from(*self.base.lock().expect("failed to acquire lock"))
+    }
+}
+
+impl Device for PciRootBus {
+    fn name(&self) -> String {
+        "pci-root".to_string()
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
+        let offset = match self.offset(addr) {
+            Some(offset) => offset,
+            None => return,
+        };
+        if offset < CONFIG_DATA_OFFSET {
+            let config = if offset == 0 && data.len() == 4 {
+                *self.config_address.lock().expect("failed to acquire lock")
+            } else {
+                0xffff_ffff
+            };
+            read_register_bytes(config, offset, data);
+            return;
+        }
+
+        let byte = offset - CONFIG_DATA_OFFSET;
+        let value = match self.target() {
+            Some((pci_addr, reg_idx))
+                if reg_idx < PCI_CONFIG_REGISTERS && byte + data.len() as u64 <= 4 =>
+            {
+                self.root.read_config_register(pci_addr, reg_idx)
+            }
+            _ => 0xffff_ffff,
+        };
+        read_register_bytes(value, byte, data);
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], _io_type: IoType) {
+        let offset = match self.offset(addr) {
+            Some(offset) => offset,
+            None => return,
+        };
+        if offset < CONFIG_DATA_OFFSET {
+            if offset == 0 && data.len() == 4 {
+                let mut config = self.config_address.lock().expect("failed to acquire lock");
+                *config = write_register_bytes(*config, offset, data);
+            }
+            return;
+        }
+
+        let byte = offset - CONFIG_DATA_OFFSET;
+        if byte + data.len() as u64 > 4 {
+            return;
+        }
+        if let Some((pci_addr, reg_idx)) = self.target() {
+            self.root
+                .write_config_register(pci_addr, reg_idx, byte, data);
+        }
+    }
+
+    fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+        if let Some(port) = res.iter().find(|r| r.res_type == IoType::Pio) {
+            *self.base.lock().expect("failed to acquire lock") = port.try_unwrap();
+        }
+    }
+}
+
+impl PciConfigMechanism for PciRootBus {
+    fn root(&self) -> &Arc<PciRoot> {
+        &self.root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::{devfn, PCI_CLASS_BRIDGE};
+
+    fn config_address(bus: u8, devfn: u8, reg_idx: usize) -> [u8; 4] {
+        let value = CONFIG_ENABLE
+            | (u32::from(bus) << 16)
+            | (u32::from(devfn) << 8)
+            | ((reg_idx as u32) << 2);
+        [
+            value as u8,
+            (value >> 8) as u8,
+            (value >> 16) as u8,
+            (value >> 24) as u8,
+        ]
+    }
+
+    #[test]
+    fn test_config_mechanism() {
+        let bus = PciRootBus::new(Arc::new(PciRoot::new()));
+        let port = |offset| GuestAddress(PCI_CONFIG_ADDRESS_PORT + offset);
+
+        bus.write(port(0), &config_address(0, 0, 0), IoType::Pio);
+        let mut data = [0u8; 4];
+        bus.read(port(0), &mut data, IoType::Pio);
+        assert_eq!(data, config_address(0, 0, 0));
+
+        bus.read(port(4), &mut data, IoType::Pio);
+        assert_eq!(data, [0x86, 0x80, 0x57, 0x0d]);
+        let mut word = [0u8; 2];
+        bus.read(port(6), &mut word, IoType::Pio);
+        assert_eq!(word, [0x57, 0x0d]);
+
+        bus.write(port(0), &config_address(0, 0, 2), IoType::Pio);
+        let mut class = [0u8; 1];
+        bus.read(port(7), &mut class, IoType::Pio);
+        assert_eq!(class[0], PCI_CLASS_BRIDGE);
+
+        // Absent functions and disabled accesses read as all ones.
+        bus.write(port(0), &config_address(0, devfn(1, 0), 0), IoType::Pio);
+        bus.read(port(4), &mut data, IoType::Pio);
+        assert_eq!(data, [0xff; 4]);
+        bus.write(port(0), &[0, 0, 0, 0], IoType::Pio);
+        bus.read(port(4), &mut data, IoType::Pio);
+        assert_eq!(data, [0xff; 4]);
+
+        // Narrower accesses don't reach CONFIG_ADDRESS.
+        bus.write(port(1), &[0x06], IoType::Pio);
+        bus.write(port(0), &[0x00, 0x80], IoType::Pio);
+        bus.read(port(0), &mut data, IoType::Pio);
+        assert_eq!(data, [0; 4]);
+        bus.read(port(1), &mut class, IoType::Pio);
+        assert_eq!(class[0], 0xff);
+    }
+}
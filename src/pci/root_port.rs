@@ -0,0 +1,379 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! PCIe root ports with a native hotplug slot.
+//!
+//! A [PcieRootPort](struct.PcieRootPort.html) is a `PciBridge` carrying a
+//! PCI Express capability which implements a hotplug capable slot, with an
+//! attention button, a power controller, power and attention indicators,
+//! presence detection and command completion. The guest `pciehp` driver
+//! drives it through the slot registers of the capability and is notified of
+//! the slot events through the INTx pin of the port.
+//!
+//! A function is plugged in the slot with
+//! `DeviceManager::hotplug_pci_device()`, which registers it on the
+//! secondary bus and reports it present. Its removal is requested with
+//! `DeviceManager::request_pci_unplug()`, which presses the attention button:
+//! once the guest powered the slot off, `DeviceManager::apply_pci_ejects()`
+//! unregisters the function and reports the slot empty.
+
+use super::{
+    PciBridge, PciBridgeWindows, PciCapability, PciConfigurationBuilder, PciDevice,
+    PciExpressPortType, Result, PCI_CAP_ID_EXP, PCI_CLASS_BRIDGE, PCI_COMMAND,
+    PCI_COMMAND_INTX_DISABLE, PCI_STATUS, PCI_STATUS_INTERRUPT,
+};
+use crate::device::{Device, IoResource, IoType, IrqResource};
+use crate::interrupt::{InterruptSourceGroup, InterruptSourceType};
+use std::sync::{Arc, Mutex};
+use vm_memory::GuestAddress;
+
+/// Vendor id of the emulated root port.
+const PCIE_ROOT_PORT_VENDOR_ID: u16 = 0x1b36;
+/// Device id of the emulated root port.
+const PCIE_ROOT_PORT_DEVICE_ID: u16 = 0x000c;
+const PCI_SUBCLASS_BRIDGE_PCI: u8 = 0x04;
+
+// Registers of the PCI Express capability.
+const PCI_EXP_FLAGS: usize = 0x02;
+const PCI_EXP_LNKCAP: usize = 0x0c;
+const PCI_EXP_LNKSTA: usize = 0x12;
+const PCI_EXP_SLTCAP: usize = 0x14;
+const PCI_EXP_SLTCTL: usize = 0x18;
+const PCI_EXP_SLTSTA: usize = 0x1a;
+
+const PCI_EXP_FLAGS_SLOT: u16 = 0x0100;
+const PCI_EXP_LNKCAP_DLLLARC: u32 = 0x0010_0000;
+const PCI_EXP_LNKSTA_DLLLA: u16 = 0x2000;
+
+// Attention button, power controller, attention and power indicators,
+// hotplug capable.
+const PCI_EXP_SLTCAP_HOTPLUG: u32 = 0x5b;
+const PCI_EXP_SLTCAP_PSN_SHIFT: u32 = 19;
+
+const PCI_EXP_SLTCTL_ABPE: u16 = 0x0001;
+const PCI_EXP_SLTCTL_PDCE: u16 = 0x0008;
+const PCI_EXP_SLTCTL_CCIE: u16 = 0x0010;
+const PCI_EXP_SLTCTL_HPIE: u16 = 0x0020;
+const PCI_EXP_SLTCTL_AIC_OFF: u16 = 0x00c0;
+const PCI_EXP_SLTCTL_PIC_OFF: u16 = 0x0300;
+const PCI_EXP_SLTCTL_PCC: u16 = 0x0400;
+const PCI_EXP_SLTCTL_DLLSCE: u16 = 0x1000;
+
+const PCI_EXP_SLTSTA_ABP: u16 = 0x0001;
+const PCI_EXP_SLTSTA_PDC: u16 = 0x0008;
+const PCI_EXP_SLTSTA_CC: u16 = 0x0010;
+const PCI_EXP_SLTSTA_PDS: u16 = 0x0040;
+const PCI_EXP_SLTSTA_DLLSC: u16 = 0x0100;
+
+// Slot events and the slot control bits enabling their interrupt.
+const SLOT_EVENTS: [(u16, u16); 4] = [
+    (PCI_EXP_SLTSTA_ABP, PCI_EXP_SLTCTL_ABPE),
+    (PCI_EXP_SLTSTA_PDC, PCI_EXP_SLTCTL_PDCE),
+    (PCI_EXP_SLTSTA_CC, PCI_EXP_SLTCTL_CCIE),
+    (PCI_EXP_SLTSTA_DLLSC, PCI_EXP_SLTCTL_DLLSCE),
+];
+
+#[derive(Default)]
+struct SlotState {
+    group: Option<Arc<dyn InterruptSourceGroup>>,
+    asserted: bool,
+    eject: bool,
+}
+
+/// PCIe root port with a hotplug slot.
+pub struct PcieRootPort {
+    bridge: PciBridge,
+    cap: usize,
+    state: Mutex<SlotState>,
+}
+
+impl PcieRootPort {
+    /// Create a root port to `secondary_bus`, owning the bus numbers up to
+    /// `subordinate_bus` and forwarding `windows`, with an empty and powered
+    /// off slot numbered `slot`.
+    pub fn new(
+        slot: u16,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+        windows: PciBridgeWindows,
+    ) -> Result<Self> {
+        let config =
+            PciConfigurationBuilder::new(PCIE_ROOT_PORT_VENDOR_ID, PCIE_ROOT_PORT_DEVICE_ID)
+                .class(PCI_CLASS_BRIDGE, PCI_SUBCLASS_BRIDGE_PCI, 0)
+                .bridge()
+                .interrupt_pin(1)
+                .capability(PciCapability::PciExpress {
+                    port_type: PciExpressPortType::RootPort,
+                })
+                .build()?;
+        // The capability was just added, it can't be missing.
+        let cap = config.capability_offset(PCI_CAP_ID_EXP).unwrap();
+        let flags = config.read_u16(cap + PCI_EXP_FLAGS);
+        config.write_u16(cap + PCI_EXP_FLAGS, flags | PCI_EXP_FLAGS_SLOT);
+        let link = config.read_u32(cap + PCI_EXP_LNKCAP);
+        config.write_u32(cap + PCI_EXP_LNKCAP, link | PCI_EXP_LNKCAP_DLLLARC);
+        config.write_u32(
+            cap + PCI_EXP_SLTCAP,
+            PCI_EXP_SLTCAP_HOTPLUG | u32::from(slot) << PCI_EXP_SLTCAP_PSN_SHIFT,
+        );
+        config.write_u16(
+            cap + PCI_EXP_SLTCTL,
+            PCI_EXP_SLTCTL_AIC_OFF | PCI_EXP_SLTCTL_PIC_OFF | PCI_EXP_SLTCTL_PCC,
+        );
+
+        Ok(PcieRootPort {
+            bridge: PciBridge::with_configuration(config, secondary_bus, subordinate_bus, windows)?,
+            cap,
+            state: Mutex::new(SlotState::default()),
+        })
+    }
+
+    /// Bridge part of the port.
+    pub fn bridge(&self) -> &PciBridge {
+        &self.bridge
+    }
+
+    /// Return true if a function is present in the slot.
+    pub fn card_present(&self) -> bool {
+        self.slot_status() & PCI_EXP_SLTSTA_PDS != 0
+    }
+
+    /// Return true if the guest powered the slot on.
+    pub fn slot_powered(&self) -> bool {
+        self.slot_control() & PCI_EXP_SLTCTL_PCC == 0
+    }
+
+    /// Press the attention button, asking the guest to release the function
+    /// in the slot and power it off.
+    pub fn press_attention_button(&self) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        self.set_slot_status(PCI_EXP_SLTSTA_ABP);
+        self.update_interrupt(&mut state);
+    }
+
+    // Report a function plugged in the slot, or removed from it.
+    pub(crate) fn set_card_present(&self, present: bool) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        if present == self.card_present() {
+            return;
+        }
+        let config = self.bridge.configuration();
+        let status = self.slot_status() & !PCI_EXP_SLTSTA_PDS;
+        let link = config.read_u16(self.cap + PCI_EXP_LNKSTA) & !PCI_EXP_LNKSTA_DLLLA;
+        let (status, link) = if present {
+            (status | PCI_EXP_SLTSTA_PDS, link | PCI_EXP_LNKSTA_DLLLA)
+        } else {
+            state.eject = false;
+            (status, link)
+        };
+        config.write_u16(
+            self.cap + PCI_EXP_SLTSTA,
+            status | PCI_EXP_SLTSTA_PDC | PCI_EXP_SLTSTA_DLLSC,
+        );
+        config.write_u16(self.cap + PCI_EXP_LNKSTA, link);
+        self.update_interrupt(&mut state);
+    }
+
+    // Take the request to eject the function the guest powered off.
+    pub(crate) fn take_eject_request(&self) -> bool {
+        std::mem::take(&mut self.state.lock().expect("failed to acquire lock").eject)
+    }
+
+    fn slot_control(&self) -> u16 {
+        self.bridge
+            .configuration()
+            .read_u16(self.cap + PCI_EXP_SLTCTL)
+    }
+
+    fn slot_status(&self) -> u16 {
+        self.bridge
+            .configuration()
+            .read_u16(self.cap + PCI_EXP_SLTSTA)
+    }
+
+    fn set_slot_status(&self, events: u16) {
+        let status = self.slot_status();
+        self.bridge
+            .configuration()
+            .write_u16(self.cap + PCI_EXP_SLTSTA, status | events);
+    }
+
+    // Assert the INTx pin while an enabled slot event is pending.
+    fn update_interrupt(&self, state: &mut SlotState) {
+        let config = self.bridge.configuration();
+        let (control, status) = (self.slot_control(), self.slot_status());
+        let pending = control & PCI_EXP_SLTCTL_HPIE != 0
+            && SLOT_EVENTS
+                .iter()
+                .any(|(event, enable)| status & event != 0 && control & enable != 0);
+        let pci_status = config.read_u16(PCI_STATUS) & !PCI_STATUS_INTERRUPT;
+        if pending {
+            config.write_u16(PCI_STATUS, pci_status | PCI_STATUS_INTERRUPT);
+        } else {
+            config.write_u16(PCI_STATUS, pci_status);
+        }
+
+        let assert = pending && config.read_u16(PCI_COMMAND) & PCI_COMMAND_INTX_DISABLE == 0;
+        if assert == state.asserted {
+            return;
+        }
+        if let Some(group) = state.group.as_ref() {
+            let ret = if assert {
+                group.trigger(0)
+            } else {
+                group.deassert(0)
+            };
+            if ret.is_ok() {
+                state.asserted = assert;
+            }
+        }
+    }
+}
+
+impl Device for PcieRootPort {
+    fn name(&self) -> String {
+        "pcie-root-port".to_string()
+    }
+
+    fn read(&self, _addr: GuestAddress, _data: &mut [u8], _io_type: IoType) {}
+
+    fn write(&self, _addr: GuestAddress, _data: &[u8], _io_type: IoType) {}
+
+    fn set_resources(&self, _res: &[IoResource], _irqs: &[IrqResource]) {}
+
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+        if group.interrupt_type() != InterruptSourceType::Legacy {
+            return;
+        }
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.group = Some(group);
+        state.asserted = false;
+        self.update_interrupt(&mut state);
+    }
+}
+
+impl PciDevice for PcieRootPort {
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.bridge.read_config_register(reg_idx)
+    }
+
+    fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        let control = self.slot_control();
+        self.bridge.write_config_register(reg_idx, offset, data);
+
+        // Slot control writes complete at once, and powering the slot off
+        // with a function in it asks for its ejection.
+        let start = reg_idx * 4 + offset as usize;
+        let sltctl = self.cap + PCI_EXP_SLTCTL;
+        if start < sltctl + 2 && sltctl < start + data.len() {
+            self.set_slot_status(PCI_EXP_SLTSTA_CC);
+            let powered_off = self.slot_control() & !control & PCI_EXP_SLTCTL_PCC != 0;
+            if powered_off && self.card_present() {
+                state.eject = true;
+            }
+        }
+        self.update_interrupt(&mut state);
+    }
+
+    fn extended_config_space(&self) -> bool {
+        self.bridge.extended_config_space()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::{InterruptSourceConfig, Result as InterruptResult};
+
+    #[derive(Default)]
+    struct LevelGroup {
+        level: Mutex<bool>,
+    }
+
+    impl InterruptSourceGroup for LevelGroup {
+        fn interrupt_type(&self) -> InterruptSourceType {
+            InterruptSourceType::Legacy
+        }
+        fn len(&self) -> u32 {
+            1
+        }
+        fn base(&self) -> u32 {
+            5
+        }
+        fn trigger(&self, _index: u32) -> InterruptResult<()> {
+            *self.level.lock().unwrap() = true;
+            Ok(())
+        }
+        fn deassert(&self, _index: u32) -> InterruptResult<()> {
+            *self.level.lock().unwrap() = false;
+            Ok(())
+        }
+        fn mask(&self, _index: u32) -> InterruptResult<()> {
+            Ok(())
+        }
+        fn unmask(&self, _index: u32) -> InterruptResult<()> {
+            Ok(())
+        }
+        fn is_pending(&self, _index: u32) -> InterruptResult<bool> {
+            Ok(false)
+        }
+        fn update(&self, _index: u32, _config: &InterruptSourceConfig) -> InterruptResult<()> {
+            Ok(())
+        }
+        fn config(&self, _index: u32) -> InterruptResult<InterruptSourceConfig> {
+            Ok(InterruptSourceConfig::LegacyIrq(Default::default()))
+        }
+    }
+
+    #[test]
+    fn test_root_port_slot() {
+        let port = PcieRootPort::new(3, 1, 1, PciBridgeWindows::default()).unwrap();
+        let group = Arc::new(LevelGroup::default());
+        port.set_interrupt_group(group.clone());
+        let cap = port.cap;
+        let sltctl = (cap + PCI_EXP_SLTCTL) / 4;
+        let read_u16 =
+            |offset: usize| (port.read_config_register(offset / 4) >> (offset % 4 * 8)) as u16;
+
+        assert_eq!(port.read_config_register(15) >> 8 & 0xff, 1);
+        assert_ne!(read_u16(cap + PCI_EXP_FLAGS) & PCI_EXP_FLAGS_SLOT, 0);
+        assert_eq!(
+            port.read_config_register((cap + PCI_EXP_SLTCAP) / 4),
+            0x5b | 3 << 19
+        );
+        assert!(!port.card_present());
+        assert!(!port.slot_powered());
+
+        // Plugging a function in raises the interrupt once it is enabled.
+        port.set_card_present(true);
+        assert!(!*group.level.lock().unwrap());
+        let enable = PCI_EXP_SLTCTL_HPIE | PCI_EXP_SLTCTL_PDCE | PCI_EXP_SLTCTL_ABPE;
+        port.write_config_register(sltctl, 0, &enable.to_le_bytes());
+        assert!(*group.level.lock().unwrap());
+        assert_eq!(
+            read_u16(cap + PCI_EXP_SLTSTA),
+            PCI_EXP_SLTSTA_PDS | PCI_EXP_SLTSTA_PDC | PCI_EXP_SLTSTA_CC | PCI_EXP_SLTSTA_DLLSC
+        );
+        assert_ne!(read_u16(cap + PCI_EXP_LNKSTA) & PCI_EXP_LNKSTA_DLLLA, 0);
+        assert!(port.slot_powered());
+        assert!(!port.take_eject_request());
+
+        // Clearing the events lowers it.
+        port.write_config_register(sltctl, 2, &[0x1f, 0x01]);
+        assert!(!*group.level.lock().unwrap());
+        assert_eq!(read_u16(cap + PCI_EXP_SLTSTA), PCI_EXP_SLTSTA_PDS);
+
+        // The guest powers the slot off after the attention button press.
+        port.press_attention_button();
+        assert!(*group.level.lock().unwrap());
+        port.write_config_register(sltctl, 2, &[0x01]);
+        port.write_config_register(sltctl, 0, &(enable | PCI_EXP_SLTCTL_PCC).to_le_bytes());
+        assert!(port.take_eject_request());
+        assert!(!port.take_eject_request());
+        port.set_card_present(false);
+        assert!(!port.card_present());
+        assert_eq!(read_u16(cap + PCI_EXP_LNKSTA) & PCI_EXP_LNKSTA_DLLLA, 0);
+        assert!(*group.level.lock().unwrap());
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Single Root I/O Virtualization of PCIe physical functions.
+//!
+//! A physical function declaring a [PciSriov](struct.PciSriov.html) carries
+//! the SR-IOV extended capability, through which the guest sets the number
+//! of virtual functions and enables them. Each VF BAR describes the BAR of
+//! one virtual function: the BARs of all of them are packed in an aperture,
+//! the BAR of VF `n` sitting `n` BAR sizes after the aperture base. The
+//! address the guest programs in a VF BAR while VF Memory Space Enable is
+//! set is queued as an aperture move, like a BAR move, until
+//! `DeviceManager::apply_pci_bar_moves()` moves the aperture and the BARs
+//! of the virtual functions.
+//!
+//! Virtual functions are created by the factory given to
+//! `DeviceManager::register_pci_sriov()`, which also reserves the apertures
+//! from the windows of the bus of the physical function. Once the guest
+//! toggled VF Enable,
+//! `DeviceManager::apply_pci_sriov()` registers them as children of the
+//! physical function, at the routing ids given by the First VF Offset and
+//! VF Stride registers, or unregisters them. As on real hardware, their
+//! vendor and device id registers should read as all ones.
+
+use super::{
+    Error, PciBar, PciBarType, PciBars, PciExtendedCapability, Result, PCI_BAR0_REG,
+    PCI_BAR_REGISTERS, PCI_COMMAND_MEMORY,
+};
+use crate::device::IoResource;
+use std::sync::Mutex;
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// SR-IOV extended capability id.
+pub const PCI_EXT_CAP_ID_SRIOV: u16 = 0x10;
+
+// Registers of the SR-IOV capability, from the capability header.
+const PCI_SRIOV_CTRL: usize = 0x08;
+const PCI_SRIOV_INITIAL_VF: usize = 0x0c;
+const PCI_SRIOV_TOTAL_VF: usize = 0x0e;
+const PCI_SRIOV_NUM_VF: usize = 0x10;
+const PCI_SRIOV_VF_OFFSET: usize = 0x14;
+const PCI_SRIOV_VF_STRIDE: usize = 0x16;
+const PCI_SRIOV_VF_DID: usize = 0x1a;
+const PCI_SRIOV_SUP_PGSIZE: usize = 0x1c;
+const PCI_SRIOV_SYS_PGSIZE: usize = 0x20;
+const PCI_SRIOV_BAR: usize = 0x24;
+const PCI_SRIOV_SIZE: usize = 0x40;
+
+/// VF Enable bit of the SR-IOV control register.
+pub const PCI_SRIOV_CTRL_VFE: u16 = 0x1;
+/// VF Memory Space Enable bit of the SR-IOV control register.
+pub const PCI_SRIOV_CTRL_MSE: u16 = 0x8;
+const PCI_SRIOV_CTRL_ARI: u16 = 0x10;
+// Only 4 KiB system pages are supported, VF BARs span at least one.
+const PCI_SRIOV_PGSIZE_4K: u32 = 0x1;
+const PCI_SRIOV_MIN_BAR_SIZE: GuestUsize = 0x1000;
+
+struct SriovState {
+    /// VF Enable, as last programmed by the guest.
+    enabled: bool,
+    /// Number of virtual functions the guest enabled or disabled since the
+    /// last call to `take_num_vfs_change()`.
+    num_vfs_change: Option<u16>,
+    /// Apertures of the VF BARs, in VF BAR order.
+    apertures: Vec<IoResource>,
+}
+
+/// SR-IOV capability of a physical function.
+pub struct PciSriov {
+    total_vfs: u16,
+    vf_device_id: u16,
+    first_vf_offset: u16,
+    vf_stride: u16,
+    /// VF BAR registers, each BAR sized for one virtual function.
+    bars: PciBars,
+    state: Mutex<SriovState>,
+}
+
+impl PciSriov {
+    /// Build the capability of a physical function supporting up to
+    /// `total_vfs` virtual functions identified by `vf_device_id`.
+    ///
+    /// Virtual function `n` sits `first_vf_offset + n * vf_stride` routing
+    /// ids after the physical function, and its BARs are described by
+    /// `vf_bars`, memory BARs of at least 4 KiB.
+    pub fn new(
+        total_vfs: u16,
+        vf_device_id: u16,
+        first_vf_offset: u16,
+        vf_stride: u16,
+        vf_bars: &[PciBar],
+    ) -> Result<Self> {
+        if total_vfs == 0 || first_vf_offset == 0 || (total_vfs > 1 && vf_stride == 0) {
+            return Err(Error::InvalidCapability(PCI_EXT_CAP_ID_SRIOV));
+        }
+        if let Some(idx) = vf_bars
+            .iter()
+            .position(|b| b.bar_type == PciBarType::Io || b.size < PCI_SRIOV_MIN_BAR_SIZE)
+        {
+            return Err(Error::InvalidBar(idx));
+        }
+        let bars = PciBars::new(vf_bars)?;
+        Ok(PciSriov {
+            total_vfs,
+            vf_device_id,
+            first_vf_offset,
+            vf_stride,
+            bars,
+            state: Mutex::new(SriovState {
+                enabled: false,
+                num_vfs_change: None,
+                apertures: Vec::new(),
+            }),
+        })
+    }
+
+    /// Maximum number of virtual functions.
+    pub fn total_vfs(&self) -> u16 {
+        self.total_vfs
+    }
+
+    /// Routing id offset of virtual function `index` from the physical
+    /// function.
+    pub fn vf_routing_offset(&self, index: u16) -> u32 {
+        u32::from(self.first_vf_offset) + u32::from(index) * u32::from(self.vf_stride)
+    }
+
+    /// IO resources to request for the VF BAR apertures, in VF BAR order.
+    /// An aperture holds the BARs of all the virtual functions, and is
+    /// aligned on the BAR size of one virtual function.
+    pub fn io_resources(&self) -> Vec<IoResource> {
+        self.bars
+            .io_resources()
+            .iter()
+            .map(|r| {
+                let size = r.size * GuestUsize::from(self.total_vfs);
+                IoResource {
+                    addr: None,
+                    size,
+                    ..*r
+                }
+                .aligned(r.size)
+            })
+            .collect()
+    }
+
+    /// Set the allocated apertures, in the order of `io_resources()`.
+    pub fn set_resources(&self, res: &[IoResource]) {
+        let count = std::cmp::min(self.bars.io_resources().len(), res.len());
+        let apertures = res[..count].to_vec();
+        self.bars.set_resources(&apertures);
+        self.state.lock().expect("failed to acquire lock").apertures = apertures;
+    }
+
+    /// IO resources of the BARs of virtual function `index`, carved out of
+    /// the apertures. Empty until the apertures are allocated.
+    pub fn vf_io_resources(&self, index: u16) -> Vec<IoResource> {
+        let state = self.state.lock().expect("failed to acquire lock");
+        self.bars
+            .io_resources()
+            .iter()
+            .zip(state.apertures.iter())
+            .filter_map(|(bar, aperture)| {
+                let offset = bar.size * GuestUsize::from(index);
+                aperture.addr.map(|base| IoResource {
+                    addr: Some(base.unchecked_add(offset)),
+                    ..*bar
+                })
+            })
+            .collect()
+    }
+
+    /// Take the VF BAR moves programmed by the guest since the last call, as
+    /// (VF BAR index, new aperture base) pairs.
+    ///
+    /// The guest only has to align the base on the BAR size of one virtual
+    /// function, the move failing if the aperture can't be allocated there.
+    pub fn take_bar_moves(&self) -> Vec<(usize, GuestAddress)> {
+        self.bars.take_moves()
+    }
+
+    /// Take the number of virtual functions the guest enabled, or 0 when it
+    /// disabled them, if VF Enable changed since the last call.
+    pub fn take_num_vfs_change(&self) -> Option<u16> {
+        self.state
+            .lock()
+            .expect("failed to acquire lock")
+            .num_vfs_change
+            .take()
+    }
+
+    // Extended capability to lay out in the configuration space of the
+    // physical function, the VF BARs being emulated apart.
+    pub(crate) fn extended_capability(&self) -> PciExtendedCapability {
+        // Offsets in the data following the 4-byte header.
+        let at = |reg: usize| reg - 4;
+        let mut data = vec![0u8; PCI_SRIOV_SIZE - 4];
+        let mut set = |reg: usize, value: &[u8]| {
+            data[at(reg)..at(reg) + value.len()].copy_from_slice(value);
+        };
+        set(PCI_SRIOV_INITIAL_VF, &self.total_vfs.to_le_bytes());
+        set(PCI_SRIOV_TOTAL_VF, &self.total_vfs.to_le_bytes());
+        set(PCI_SRIOV_VF_OFFSET, &self.first_vf_offset.to_le_bytes());
+        set(PCI_SRIOV_VF_STRIDE, &self.vf_stride.to_le_bytes());
+        set(PCI_SRIOV_VF_DID, &self.vf_device_id.to_le_bytes());
+        set(PCI_SRIOV_SUP_PGSIZE, &PCI_SRIOV_PGSIZE_4K.to_le_bytes());
+        set(PCI_SRIOV_SYS_PGSIZE, &PCI_SRIOV_PGSIZE_4K.to_le_bytes());
+
+        let mut writable = vec![0u8; at(PCI_SRIOV_NUM_VF) + 2];
+        let control = PCI_SRIOV_CTRL_VFE | PCI_SRIOV_CTRL_MSE | PCI_SRIOV_CTRL_ARI;
+        writable[at(PCI_SRIOV_CTRL)..at(PCI_SRIOV_CTRL) + 2]
+            .copy_from_slice(&control.to_le_bytes());
+        writable[at(PCI_SRIOV_NUM_VF)..].copy_from_slice(&[0xff, 0xff]);
+        PciExtendedCapability {
+            id: PCI_EXT_CAP_ID_SRIOV,
+            version: 1,
+            data,
+            writable,
+        }
+    }
+
+    // Index of the VF BAR register at `reg_idx` of a capability at `offset`.
+    fn vf_bar_register(offset: usize, reg_idx: usize) -> Option<usize> {
+        let reg = reg_idx.checked_sub((offset + PCI_SRIOV_BAR) / 4)?;
+        if reg < PCI_BAR_REGISTERS {
+            Some(reg)
+        } else {
+            None
+        }
+    }
+
+    // Read the register at `reg_idx` of the capability at `offset` if it is
+    // a VF BAR register.
+    pub(crate) fn read_config_register(&self, offset: usize, reg_idx: usize) -> Option<u32> {
+        let reg = Self::vf_bar_register(offset, reg_idx)?;
+        self.bars.read_config_register(PCI_BAR0_REG + reg)
+    }
+
+    // Write the register at `reg_idx` of the capability at `offset` if it is
+    // a VF BAR register, return false if it is not.
+    pub(crate) fn write_config_register(
+        &self,
+        offset: usize,
+        reg_idx: usize,
+        byte: u64,
+        data: &[u8],
+    ) -> bool {
+        let reg = match Self::vf_bar_register(offset, reg_idx) {
+            Some(reg) => reg,
+            None => return false,
+        };
+        self.bars
+            .write_config_register(PCI_BAR0_REG + reg, byte, data);
+        true
+    }
+
+    // Record the control and NumVFs registers after a guest write to the
+    // control register, which may have toggled VF Enable or VF Memory Space
+    // Enable.
+    pub(crate) fn update_control(&self, control: u16, num_vfs: u16) {
+        self.bars.set_command(if control & PCI_SRIOV_CTRL_MSE != 0 {
+            PCI_COMMAND_MEMORY
+        } else {
+            0
+        });
+        let enabled = control & PCI_SRIOV_CTRL_VFE != 0;
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        if enabled != state.enabled {
+            state.enabled = enabled;
+            state.num_vfs_change = Some(if enabled {
+                std::cmp::min(num_vfs, self.total_vfs)
+            } else {
+                0
+            });
+        }
+    }
+
+    // Offsets of the control and NumVFs registers in a capability at
+    // `offset`.
+    pub(crate) fn control_registers(offset: usize) -> (usize, usize) {
+        (offset + PCI_SRIOV_CTRL, offset + PCI_SRIOV_NUM_VF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::IoType;
+    use crate::pci::PciConfigurationBuilder;
+
+    #[test]
+    fn test_sriov_capability() {
+        assert!(PciSriov::new(0, 0x10ca, 1, 1, &[]).is_err());
+        assert_eq!(
+            PciSriov::new(4, 0x10ca, 1, 1, &[PciBar::memory32(0x100)]).err(),
+            Some(Error::InvalidBar(0))
+        );
+
+        let sriov = PciSriov::new(
+            3,
+            0x10ca,
+            0x80,
+            2,
+            &[
+                PciBar::memory32(0x4000),
+                PciBar::memory64(0x1000).prefetchable(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(sriov.vf_routing_offset(2), 0x84);
+        let mut apertures = sriov.io_resources();
+        assert_eq!(
+            apertures,
+            vec![
+                IoResource::new(None, 0xc000, IoType::PciMmio32).aligned(0x4000),
+                IoResource::new(None, 0x3000, IoType::PciMmio64)
+                    .prefetchable()
+                    .aligned(0x1000),
+            ]
+        );
+        apertures[0].addr = Some(GuestAddress(0xc000_0000));
+        apertures[1].addr = Some(GuestAddress(0x1_0000_0000));
+
+        let config = PciConfigurationBuilder::new(0x8086, 0x10c9)
+            .sriov(sriov)
+            .build()
+            .unwrap();
+        let offset = config
+            .extended_capability_offset(PCI_EXT_CAP_ID_SRIOV)
+            .unwrap();
+        let sriov = config.sriov().unwrap();
+        sriov.set_resources(&apertures);
+        assert_eq!(config.read_u32(offset), 0x0001_0010);
+        assert_eq!(config.read_u32(offset + PCI_SRIOV_INITIAL_VF), 0x0003_0003);
+        assert_eq!(config.read_u32(offset + PCI_SRIOV_VF_OFFSET), 0x0002_0080);
+        assert_eq!(config.read_u16(offset + PCI_SRIOV_VF_DID), 0x10ca);
+
+        // VF BARs hold the apertures, and are sized per virtual function.
+        let bar = (offset + PCI_SRIOV_BAR) / 4;
+        assert_eq!(config.read_config_register(bar), 0xc000_0000);
+        assert_eq!(config.read_config_register(bar + 1), 0x0000_000c);
+        assert_eq!(config.read_config_register(bar + 2), 0x1);
+        config.write_config_register(bar, 0, &[0xff; 4]);
+        assert_eq!(config.read_config_register(bar), 0xffff_c000);
+        config.write_config_register(bar, 0, &0xc000_0000u32.to_le_bytes());
+        // Another address only moves the aperture once VF Memory Space
+        // Enable is set.
+        config.write_config_register(bar, 0, &0xd000_0000u32.to_le_bytes());
+        assert_eq!(config.read_config_register(bar), 0xd000_0000);
+        assert!(sriov.take_bar_moves().is_empty());
+        assert_eq!(config.read_config_register(bar + PCI_BAR_REGISTERS), 0);
+
+        assert_eq!(
+            sriov.vf_io_resources(2),
+            vec![
+                IoResource::new(Some(GuestAddress(0xc000_8000)), 0x4000, IoType::PciMmio32),
+                IoResource::new(Some(GuestAddress(0x1_0000_2000)), 0x1000, IoType::PciMmio64)
+                    .prefetchable(),
+            ]
+        );
+
+        // NumVFs is taken when VF Enable is set, capped to TotalVFs.
+        let (control, num_vfs) = PciSriov::control_registers(offset);
+        config.write_config_register(num_vfs / 4, 0, &[5, 0]);
+        assert_eq!(sriov.take_num_vfs_change(), None);
+        config.write_config_register(control / 4, 0, &[0x09, 0x00]);
+        assert_eq!(config.read_u16(control), 0x9);
+        assert_eq!(sriov.take_num_vfs_change(), Some(3));
+        assert_eq!(sriov.take_bar_moves(), vec![(0, GuestAddress(0xd000_0000))]);
+        assert_eq!(sriov.take_num_vfs_change(), None);
+        config.write_config_register(control / 4, 0, &[0x08, 0x00]);
+        assert_eq!(sriov.take_num_vfs_change(), Some(0));
+    }
+}
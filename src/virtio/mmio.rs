@@ -0,0 +1,438 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio-mmio transport, version 2.
+//!
+//! The device is a 4 KiB MMIO window of 32-bit registers, followed at offset
+//! 0x100 by the device configuration space. The driver is notified through
+//! one IRQ, the interrupt status register telling it whether buffers were
+//! used or the configuration changed: the IRQ is raised while any status bit
+//! isn't acknowledged, and lowered once they all are.
+
+use super::{
+    set_address_half, Error, Result, VirtioCommon, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType, VIRTIO_ISR_CONFIG, VIRTIO_ISR_QUEUE,
+};
+use crate::device::{self, Device, IoResource, IoType, IrqResource};
+use crate::interrupt::{InterruptSourceGroup, InterruptSourceType};
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+use vm_memory::{Address, GuestAddress, GuestUsize};
+
+/// Size of the MMIO window of a virtio-mmio device.
+pub const VIRTIO_MMIO_SIZE: GuestUsize = 0x1000;
+
+// "virt" in little endian.
+const VIRTIO_MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
+const VIRTIO_MMIO_VERSION_2: u32 = 2;
+const VIRTIO_MMIO_VENDOR_ID: u32 = 0;
+
+// Registers of the transport.
+const VIRTIO_MMIO_MAGIC: u64 = 0x000;
+const VIRTIO_MMIO_VERSION: u64 = 0x004;
+const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+const VIRTIO_MMIO_VENDOR: u64 = 0x00c;
+const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+const VIRTIO_MMIO_STATUS: u64 = 0x070;
+const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u64 = 0x090;
+const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u64 = 0x094;
+const VIRTIO_MMIO_QUEUE_USED_LOW: u64 = 0x0a0;
+const VIRTIO_MMIO_QUEUE_USED_HIGH: u64 = 0x0a4;
+const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+#[derive(Default)]
+struct InterruptState {
+    group: Option<Arc<dyn InterruptSourceGroup>>,
+    status: u32,
+    config_generation: u32,
+}
+
+// Interrupt status of the transport and IRQ it is signaled through.
+#[derive(Default)]
+struct MmioInterrupt {
+    state: Mutex<InterruptState>,
+}
+
+impl MmioInterrupt {
+    // Clear the acknowledged status `bits`, lowering the IRQ once none is
+    // left.
+    fn ack(&self, bits: u32) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.status &= !bits;
+        if state.status == 0 {
+            if let Some(group) = state.group.as_ref() {
+                let _ = group.deassert(0);
+            }
+        }
+    }
+}
+
+impl VirtioInterrupt for MmioInterrupt {
+    fn trigger(&self, int_type: VirtioInterruptType) -> Result<()> {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.status |= match int_type {
+            VirtioInterruptType::Config => {
+                state.config_generation = state.config_generation.wrapping_add(1);
+                VIRTIO_ISR_CONFIG
+            }
+            VirtioInterruptType::Queue(_) => VIRTIO_ISR_QUEUE,
+        };
+        let group = state.group.as_ref().ok_or(Error::NoInterrupt)?;
+        group.trigger(0).map_err(Error::Interrupt)
+    }
+}
+
+/// Virtio device exposed through a virtio-mmio transport.
+pub struct VirtioMmio {
+    common: Mutex<VirtioCommon>,
+    interrupt: Arc<MmioInterrupt>,
+    resources: Mutex<(Option<GuestAddress>, Option<u32>)>,
+}
+
+impl VirtioMmio {
+    /// Expose the `backend` device. It is registered with the
+    /// `io_resources()` and one level-triggered IRQ resource, which may be
+    /// shared, e.g. `IrqResource::shared(None)`.
+    pub fn new(backend: Box<dyn VirtioDevice>) -> Self {
+        VirtioMmio {
+            common: Mutex::new(VirtioCommon::new(backend)),
+            interrupt: Arc::new(MmioInterrupt::default()),
+            resources: Mutex::new((None, None)),
+        }
+    }
+
+    /// IO resources to request at registration: the MMIO window, placed by
+    /// the allocator.
+    pub fn io_resources() -> Vec<IoResource> {
+        vec![IoResource::new(None, VIRTIO_MMIO_SIZE, IoType::Mmio)]
+    }
+
+    /// Base address of the window once allocated, e.g. for the kernel
+    /// command line or the device tree.
+    pub fn base(&self) -> Option<GuestAddress> {
+        self.resources.lock().expect("failed to acquire lock").0
+    }
+
+    /// IRQ of the device once allocated.
+    pub fn irq(&self) -> Option<u32> {
+        self.resources.lock().expect("failed to acquire lock").1
+    }
+
+    /// Device status, as set by the driver.
+    pub fn status(&self) -> u8 {
+        self.common.lock().expect("failed to acquire lock").status
+    }
+
+    fn read_register(&self, offset: u64) -> u32 {
+        let common = self.common.lock().expect("failed to acquire lock");
+        match offset {
+            VIRTIO_MMIO_MAGIC => VIRTIO_MMIO_MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => VIRTIO_MMIO_VERSION_2,
+            VIRTIO_MMIO_DEVICE_ID => common.backend.device_type(),
+            VIRTIO_MMIO_VENDOR => VIRTIO_MMIO_VENDOR_ID,
+            VIRTIO_MMIO_DEVICE_FEATURES => common.device_features_word(),
+            VIRTIO_MMIO_QUEUE_NUM_MAX => common.queue().map_or(0, |q| u32::from(q.max_size)),
+            VIRTIO_MMIO_QUEUE_READY => common.queue().map_or(0, |q| q.ready as u32),
+            VIRTIO_MMIO_INTERRUPT_STATUS => {
+                self.interrupt
+                    .state
+                    .lock()
+                    .expect("failed to acquire lock")
+                    .status
+            }
+            VIRTIO_MMIO_STATUS => u32::from(common.status),
+            VIRTIO_MMIO_CONFIG_GENERATION => {
+                self.interrupt
+                    .state
+                    .lock()
+                    .expect("failed to acquire lock")
+                    .config_generation
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, offset: u64, value: u32) {
+        let mut common = self.common.lock().expect("failed to acquire lock");
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => common.device_features_select = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => common.set_driver_features_word(value),
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => common.driver_features_select = value,
+            VIRTIO_MMIO_QUEUE_SEL => common.queue_select = value as u16,
+            VIRTIO_MMIO_QUEUE_NOTIFY => common.queue_notify(value as u16),
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt.ack(value),
+            VIRTIO_MMIO_STATUS => {
+                common.set_status(value as u8, self.interrupt.clone());
+                // A reset drops the pending interrupts along with the IRQ.
+                if value == 0 {
+                    self.interrupt.ack(!0);
+                }
+            }
+            _ => {
+                if let Some(queue) = common.configurable_queue() {
+                    match offset {
+                        VIRTIO_MMIO_QUEUE_NUM
+                            if value > 0 && value <= u32::from(queue.max_size) =>
+                        {
+                            queue.size = value as u16
+                        }
+                        VIRTIO_MMIO_QUEUE_READY => queue.ready = value == 1,
+                        VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                            set_address_half(&mut queue.desc_table, value, false)
+                        }
+                        VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                            set_address_half(&mut queue.desc_table, value, true)
+                        }
+                        VIRTIO_MMIO_QUEUE_AVAIL_LOW => {
+                            set_address_half(&mut queue.avail_ring, value, false)
+                        }
+                        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
+                            set_address_half(&mut queue.avail_ring, value, true)
+                        }
+                        VIRTIO_MMIO_QUEUE_USED_LOW => {
+                            set_address_half(&mut queue.used_ring, value, false)
+                        }
+                        VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                            set_address_half(&mut queue.used_ring, value, true)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    fn offset(&self, addr: GuestAddress) -> Option<u64> {
+        addr.checked_offset_from(self.base()?)
+    }
+}
+
+impl Device for VirtioMmio {
+    fn name(&self) -> String {
+        String::from("virtio-mmio")
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
+        let offset = match self.offset(addr) {
+            Some(offset) => offset,
+            None => return,
+        };
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let common = self.common.lock().expect("failed to acquire lock");
+            common
+                .backend
+                .read_config(offset - VIRTIO_MMIO_CONFIG, data);
+        } else if data.len() == 4 && offset % 4 == 0 {
+            data.copy_from_slice(&self.read_register(offset).to_le_bytes());
+        } else {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], _io_type: IoType) {
+        let offset = match self.offset(addr) {
+            Some(offset) => offset,
+            None => return,
+        };
+        if offset >= VIRTIO_MMIO_CONFIG {
+            let mut common = self.common.lock().expect("failed to acquire lock");
+            common
+                .backend
+                .write_config(offset - VIRTIO_MMIO_CONFIG, data);
+        } else if offset % 4 == 0 {
+            if let Ok(bytes) = data.try_into() {
+                self.write_register(offset, u32::from_le_bytes(bytes));
+            }
+        }
+    }
+
+    fn set_resources(&self, res: &[IoResource], irqs: &[IrqResource]) {
+        let mut resources = self.resources.lock().expect("failed to acquire lock");
+        if let Some(window) = res.iter().find(|r| r.res_type == IoType::Mmio) {
+            resources.0 = window.addr;
+        }
+        resources.1 = irqs.first().and_then(|irq| irq.irq);
+    }
+
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+        if group.interrupt_type() != InterruptSourceType::Legacy {
+            return;
+        }
+        self.interrupt
+            .state
+            .lock()
+            .expect("failed to acquire lock")
+            .group = Some(group);
+    }
+
+    fn reset(&self) -> device::Result<()> {
+        self.common.lock().expect("failed to acquire lock").reset();
+        self.interrupt.ack(!0);
+        Ok(())
+    }
+
+    fn acpi_hid(&self) -> Option<String> {
+        // Hardware id matched by the Linux virtio-mmio driver.
+        Some(String::from("LNRO0005"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_utils::{Backend, BackendState};
+    use super::super::{
+        VirtioQueue, VIRTIO_F_VERSION_1, VIRTIO_STATUS_ACKNOWLEDGE, VIRTIO_STATUS_DRIVER,
+        VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK,
+    };
+    use super::*;
+    use crate::device_manager::{test_system_allocator, DeviceManager, Result};
+    use crate::interrupt::eventfd::EventFdInterruptManager;
+
+    fn read(dev_mgr: &DeviceManager, base: GuestAddress, offset: u64) -> Result<u32> {
+        let mut data = [0u8; 4];
+        dev_mgr.read(GuestAddress(base.0 + offset), &mut data, IoType::Mmio)?;
+        Ok(u32::from_le_bytes(data))
+    }
+
+    fn write(dev_mgr: &DeviceManager, base: GuestAddress, offset: u64, value: u32) -> Result<()> {
+        dev_mgr.write(
+            GuestAddress(base.0 + offset),
+            &value.to_le_bytes(),
+            IoType::Mmio,
+        )
+    }
+
+    #[test]
+    fn test_virtio_mmio() -> Result<()> {
+        let sys_res = test_system_allocator(GuestAddress(0x1000_0000));
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+
+        let state = Arc::new(Mutex::new(BackendState::default()));
+        let dev = Arc::new(VirtioMmio::new(Box::new(Backend {
+            device_type: 2,
+            queue_sizes: vec![256, 64],
+            features: 1 << 5,
+            state: state.clone(),
+        })));
+        dev_mgr.register_device(
+            dev.clone(),
+            None,
+            &mut VirtioMmio::io_resources(),
+            &mut [IrqResource::shared(None)],
+            None,
+        )?;
+        let base = dev.base().unwrap();
+        let irqfds = irq_mgr
+            .group(InterruptSourceType::Legacy, dev.irq().unwrap())
+            .unwrap();
+
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_MAGIC)?, 0x7472_6976);
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_VERSION)?, 2);
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_DEVICE_ID)?, 2);
+
+        // Feature negotiation fails without VIRTIO_F_VERSION_1.
+        let status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+        write(&dev_mgr, base, VIRTIO_MMIO_STATUS, u32::from(status))?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_DEVICE_FEATURES)?, 1 << 5);
+        write(&dev_mgr, base, VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1)?;
+        assert_eq!(
+            read(&dev_mgr, base, VIRTIO_MMIO_DEVICE_FEATURES)?,
+            1 << (VIRTIO_F_VERSION_1 - 32)
+        );
+        write(&dev_mgr, base, VIRTIO_MMIO_DRIVER_FEATURES, 1 << 5)?;
+        let status = status | VIRTIO_STATUS_FEATURES_OK;
+        write(&dev_mgr, base, VIRTIO_MMIO_STATUS, u32::from(status))?;
+        assert_eq!(
+            dev.status(),
+            VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER
+        );
+        write(&dev_mgr, base, VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_DRIVER_FEATURES, 1)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_STATUS, u32::from(status))?;
+        assert_eq!(dev.status(), status);
+        assert_eq!(state.lock().unwrap().acked_features, 1 << 32 | 1 << 5);
+
+        // Configure the second queue.
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_SEL, 1)?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NUM_MAX)?, 64);
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NUM, 128)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NUM, 32)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_DESC_LOW, 0x1000)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_DESC_HIGH, 0x1)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_AVAIL_LOW, 0x2000)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_USED_LOW, 0x3000)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_READY, 1)?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_QUEUE_READY)?, 1);
+
+        // DRIVER_OK activates the backend, which can then use the IRQ.
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NOTIFY, 1)?;
+        let status = status | VIRTIO_STATUS_DRIVER_OK;
+        write(&dev_mgr, base, VIRTIO_MMIO_STATUS, u32::from(status))?;
+        assert_eq!(dev.status(), status);
+        let interrupt = {
+            let state = state.lock().unwrap();
+            assert!(!state.queues[0].ready);
+            assert_eq!(
+                state.queues[1],
+                VirtioQueue {
+                    max_size: 64,
+                    size: 32,
+                    ready: true,
+                    desc_table: GuestAddress(0x1_0000_1000),
+                    avail_ring: GuestAddress(0x2000),
+                    used_ring: GuestAddress(0x3000),
+                }
+            );
+            state.interrupt.clone().unwrap()
+        };
+        // The queues can't be changed any more.
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_READY, 0)?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_QUEUE_READY)?, 1);
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NOTIFY, 1)?;
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_NOTIFY, 2)?;
+        assert_eq!(state.lock().unwrap().notified, vec![1]);
+
+        interrupt.trigger(VirtioInterruptType::Queue(1)).unwrap();
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        // The level-triggered line is only raised once until acknowledged.
+        assert_eq!(irqfds.irqfd(0).unwrap().read().unwrap(), 1);
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_INTERRUPT_STATUS)?, 3);
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_CONFIG_GENERATION)?, 1);
+        write(&dev_mgr, base, VIRTIO_MMIO_INTERRUPT_ACK, 3)?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_INTERRUPT_STATUS)?, 0);
+
+        // The configuration space allows any access width.
+        let mut data = [0u8; 2];
+        dev_mgr.read(GuestAddress(base.0 + 0x104), &mut data, IoType::Mmio)?;
+        assert_eq!(data, [4, 5]);
+
+        // Writing 0 to the status resets the device and drops the pending
+        // interrupts.
+        interrupt.trigger(VirtioInterruptType::Queue(1)).unwrap();
+        assert_eq!(irqfds.irqfd(0).unwrap().read().unwrap(), 1);
+        write(&dev_mgr, base, VIRTIO_MMIO_STATUS, 0)?;
+        assert_eq!(dev.status(), 0);
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_INTERRUPT_STATUS)?, 0);
+        assert_eq!(state.lock().unwrap().resets, 1);
+        write(&dev_mgr, base, VIRTIO_MMIO_QUEUE_SEL, 1)?;
+        assert_eq!(read(&dev_mgr, base, VIRTIO_MMIO_QUEUE_READY)?, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio devices and their transports.
+//!
+//! A virtio device is split in two. Its backend implements the
+//! [VirtioDevice](trait.VirtioDevice.html) trait with the part specific to
+//! the device type: the features it offers, its configuration space and the
+//! processing of its virtqueues. A transport gives the guest driver access to
+//! it and implements the rest of the device: feature negotiation, virtqueue
+//! configuration and the device status state machine. Once the driver set
+//! DRIVER_OK, the backend is activated with the configured
+//! [VirtioQueue](struct.VirtioQueue.html)s and a
+//! [VirtioInterrupt](trait.VirtioInterrupt.html) signaling used buffers and
+//! configuration changes, and the transport forwards it the queue
+//! notifications of the driver.
+//!
+//! The [VirtioMmio](struct.VirtioMmio.html) transport is a virtio-mmio
+//! device of version 2, registered on the MMIO bus with one IRQ. The
+//! [VirtioPci](struct.VirtioPci.html) transport is a modern virtio-pci
+//! function, registered behind a PCI bus with MSI-X vectors.
+
+mod mmio;
+mod pci;
+#[cfg(test)]
+mod test_utils;
+
+pub use self::mmio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+pub use self::pci::{VirtioPci, VIRTIO_MSI_NO_VECTOR};
+
+use crate::interrupt::Error as InterruptError;
+use std::fmt::{self, Display};
+use std::result;
+use std::sync::Arc;
+use vm_memory::GuestAddress;
+
+/// The driver acknowledged the device.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 0x01;
+/// The driver knows how to drive the device.
+pub const VIRTIO_STATUS_DRIVER: u8 = 0x02;
+/// The driver is set up and ready to drive the device.
+pub const VIRTIO_STATUS_DRIVER_OK: u8 = 0x04;
+/// The driver acknowledged the features it understands.
+pub const VIRTIO_STATUS_FEATURES_OK: u8 = 0x08;
+/// The device hit an error it can't recover from without a reset.
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u8 = 0x40;
+/// The driver gave up on the device.
+pub const VIRTIO_STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit of the devices complying with virtio 1.0 or later.
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+/// Interrupt status bit of the used buffer notifications.
+pub const VIRTIO_ISR_QUEUE: u32 = 0x1;
+/// Interrupt status bit of the configuration change notifications.
+pub const VIRTIO_ISR_CONFIG: u32 = 0x2;
+
+/// Errors of the virtio devices.
+#[derive(Debug)]
+pub enum Error {
+    /// The backend failed to activate.
+    Activate(String),
+    /// The transport has no interrupt to deliver notifications through.
+    NoInterrupt,
+    /// The interrupt couldn't be delivered.
+    Interrupt(InterruptError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Activate(e) => write!(f, "Failed to activate the virtio device: {}", e),
+            NoInterrupt => write!(f, "The virtio transport has no interrupt"),
+            Interrupt(e) => write!(f, "Failed to deliver the virtio interrupt: {}", e),
+        }
+    }
+}
+
+/// Simplify the `Result` type.
+pub type Result<T> = result::Result<T, Error>;
+
+// Replace the low or high half of `addr` by `value`.
+pub(crate) fn set_address_half(addr: &mut GuestAddress, value: u32, high: bool) {
+    let value = u64::from(value);
+    addr.0 = if high {
+        (addr.0 & 0xffff_ffff) | value << 32
+    } else {
+        (addr.0 & !0xffff_ffff) | value
+    };
+}
+
+/// Virtqueue, as configured by the driver.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VirtioQueue {
+    /// Maximum size the device supports.
+    pub max_size: u16,
+    /// Size chosen by the driver.
+    pub size: u16,
+    /// The driver enabled the queue.
+    pub ready: bool,
+    /// Guest address of the descriptor table.
+    pub desc_table: GuestAddress,
+    /// Guest address of the driver area, or available ring.
+    pub avail_ring: GuestAddress,
+    /// Guest address of the device area, or used ring.
+    pub used_ring: GuestAddress,
+}
+
+impl VirtioQueue {
+    /// Build a queue of up to `max_size` entries, in its reset state.
+    pub fn new(max_size: u16) -> Self {
+        VirtioQueue {
+            max_size,
+            size: max_size,
+            ready: false,
+            desc_table: GuestAddress(0),
+            avail_ring: GuestAddress(0),
+            used_ring: GuestAddress(0),
+        }
+    }
+}
+
+/// Notification from the device to the driver.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VirtioInterruptType {
+    /// The configuration space changed.
+    Config,
+    /// Buffers were used in the virtqueue at the given index.
+    Queue(u16),
+}
+
+/// Trait for the transports delivering the device notifications.
+pub trait VirtioInterrupt: Send + Sync {
+    /// Notify the driver of `int_type`.
+    fn trigger(&self, int_type: VirtioInterruptType) -> Result<()>;
+}
+
+/// Trait for the device type specific part of a virtio device.
+#[allow(unused_variables)]
+pub trait VirtioDevice: Send {
+    /// Virtio device id, e.g. 1 for a network device.
+    fn device_type(&self) -> u32;
+
+    /// Maximum size of each virtqueue, the device having one per entry.
+    fn queue_max_sizes(&self) -> &[u16];
+
+    /// Feature bits offered by the device. The transport always offers
+    /// `VIRTIO_F_VERSION_1` as well.
+    fn features(&self) -> u64;
+
+    /// Feature bits accepted by the driver, once it set FEATURES_OK.
+    fn ack_features(&mut self, features: u64) {}
+
+    /// Read the device configuration space at `offset`.
+    fn read_config(&self, offset: u64, data: &mut [u8]);
+
+    /// Write the device configuration space at `offset`.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {}
+
+    /// Start processing the ready `queues`, notifying the driver through
+    /// `interrupt`.
+    fn activate(
+        &mut self,
+        queues: Vec<VirtioQueue>,
+        interrupt: Arc<dyn VirtioInterrupt>,
+    ) -> Result<()>;
+
+    /// The driver made buffers available in the virtqueue at `index`.
+    fn queue_notify(&mut self, index: u16) {}
+
+    /// Stop processing the virtqueues and go back to the initial state, when
+    /// the driver resets the device.
+    fn reset(&mut self) {}
+}
+
+// Part of a virtio device common to the transports: the feature words and
+// virtqueue selected by the driver, the negotiated features, the virtqueues
+// and the device status.
+pub(crate) struct VirtioCommon {
+    pub(crate) backend: Box<dyn VirtioDevice>,
+    pub(crate) device_features_select: u32,
+    pub(crate) driver_features_select: u32,
+    pub(crate) driver_features: u64,
+    pub(crate) queue_select: u16,
+    pub(crate) queues: Vec<VirtioQueue>,
+    pub(crate) status: u8,
+}
+
+impl VirtioCommon {
+    pub(crate) fn new(backend: Box<dyn VirtioDevice>) -> Self {
+        let queues = backend
+            .queue_max_sizes()
+            .iter()
+            .map(|size| VirtioQueue::new(*size))
+            .collect();
+        VirtioCommon {
+            backend,
+            device_features_select: 0,
+            driver_features_select: 0,
+            driver_features: 0,
+            queue_select: 0,
+            queues,
+            status: 0,
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.backend.features() | 1 << VIRTIO_F_VERSION_1
+    }
+
+    // Device features word selected by the driver.
+    pub(crate) fn device_features_word(&self) -> u32 {
+        match self.device_features_select {
+            0 => self.device_features() as u32,
+            1 => (self.device_features() >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    // Set the driver features word selected by the driver, until it set
+    // FEATURES_OK.
+    pub(crate) fn set_driver_features_word(&mut self, value: u32) {
+        if self.status & VIRTIO_STATUS_DRIVER == 0 || self.status & VIRTIO_STATUS_FEATURES_OK != 0 {
+            return;
+        }
+        let value = u64::from(value);
+        self.driver_features = match self.driver_features_select {
+            0 => (self.driver_features & !0xffff_ffff) | value,
+            1 => (self.driver_features & 0xffff_ffff) | value << 32,
+            _ => self.driver_features,
+        };
+    }
+
+    // Driver features word selected by the driver.
+    pub(crate) fn driver_features_word(&self) -> u32 {
+        match self.driver_features_select {
+            0 => self.driver_features as u32,
+            1 => (self.driver_features >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    // Virtqueue selected by the driver.
+    pub(crate) fn queue(&self) -> Option<&VirtioQueue> {
+        self.queues.get(usize::from(self.queue_select))
+    }
+
+    // Virtqueue selected by the driver, if it may still be configured.
+    pub(crate) fn configurable_queue(&mut self) -> Option<&mut VirtioQueue> {
+        if self.status & VIRTIO_STATUS_DRIVER_OK != 0 {
+            return None;
+        }
+        self.queues.get_mut(usize::from(self.queue_select))
+    }
+
+    // Forward a notification of the virtqueue at `index` to the backend.
+    pub(crate) fn queue_notify(&mut self, index: u16) {
+        if self.status & VIRTIO_STATUS_DRIVER_OK != 0 && usize::from(index) < self.queues.len() {
+            self.backend.queue_notify(index);
+        }
+    }
+
+    // Move to the `status` written by the driver. Status bits are only
+    // cleared by a reset, when 0 is written. FEATURES_OK isn't kept if the
+    // driver accepted features the device doesn't offer, or not
+    // VIRTIO_F_VERSION_1, and DRIVER_OK activates the backend.
+    pub(crate) fn set_status(&mut self, status: u8, interrupt: Arc<dyn VirtioInterrupt>) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        if status & self.status != self.status {
+            return;
+        }
+        let mut status = status;
+        let set = status & !self.status;
+        if set & VIRTIO_STATUS_FEATURES_OK != 0 {
+            let features = self.driver_features;
+            if features & !self.device_features() != 0 || features & 1 << VIRTIO_F_VERSION_1 == 0 {
+                status &= !VIRTIO_STATUS_FEATURES_OK;
+            } else {
+                self.backend.ack_features(features);
+            }
+        }
+        if set & VIRTIO_STATUS_DRIVER_OK != 0 {
+            if status & VIRTIO_STATUS_FEATURES_OK == 0 {
+                status &= !VIRTIO_STATUS_DRIVER_OK;
+            } else if self
+                .backend
+                .activate(self.queues.clone(), interrupt.clone())
+                .is_err()
+            {
+                status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+                let _ = interrupt.trigger(VirtioInterruptType::Config);
+            }
+        }
+        self.status = status;
+    }
+
+    // Put the device back into its reset state.
+    pub(crate) fn reset(&mut self) {
+        self.backend.reset();
+        self.device_features_select = 0;
+        self.driver_features_select = 0;
+        self.driver_features = 0;
+        self.queue_select = 0;
+        for queue in self.queues.iter_mut() {
+            *queue = VirtioQueue::new(queue.max_size);
+        }
+        self.status = 0;
+    }
+}
@@ -0,0 +1,732 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio-pci modern transport.
+//!
+//! The function has a single 64-bit memory BAR holding the virtio
+//! structures, each located by a vendor-specific capability of the
+//! configuration space:
+//! - 0x0000: the common configuration, for features, virtqueues and status,
+//! - 0x1000: the ISR status, cleared when read,
+//! - 0x2000: the device configuration space,
+//! - 0x3000: the notification area, a 32-bit register per virtqueue,
+//! - 0x4000: the MSI-X table, the PBA being at 0x6000.
+//!
+//! The configuration changes and each virtqueue are signaled on the MSI-X
+//! vector the driver assigned them in the common configuration.
+//!
+//! The BAR can also be accessed through the configuration space: the driver
+//! programs the offset and the length of the access in the PCI configuration
+//! access capability, then reads or writes its `pci_cfg_data` field.
+
+use super::{
+    set_address_half, Error, Result, VirtioCommon, VirtioDevice, VirtioInterrupt,
+    VirtioInterruptType, VIRTIO_ISR_CONFIG, VIRTIO_ISR_QUEUE,
+};
+use crate::device::{self, Device, IoResource, IoType, IrqResource, MsiResource};
+use crate::interrupt::{InterruptSourceGroup, InterruptSourceType};
+use crate::pci::{
+    self, PciBar, PciBars, PciCapability, PciConfiguration, PciConfigurationBuilder, PciDevice,
+    PciMsix, PCI_CAPABILITY_LIST, PCI_CAP_ID_MSIX, PCI_CAP_ID_VNDR,
+};
+use std::sync::{Arc, Mutex};
+use vm_memory::GuestAddress;
+
+/// Vector number telling no MSI-X vector is assigned.
+pub const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+// Device ids of the modern devices are offset by their virtio device id.
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x0040;
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+// Structure types of the virtio capabilities.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+const VIRTIO_PCI_CAP_PCI_CFG: u8 = 5;
+
+// Fields of the PCI configuration access capability.
+const PCI_CFG_BAR: usize = 4;
+const PCI_CFG_OFFSET: usize = 8;
+const PCI_CFG_LENGTH: usize = 12;
+const PCI_CFG_DATA: usize = 16;
+
+// Layout of the BAR.
+const VIRTIO_PCI_BAR_SIZE: u64 = 0x8000;
+const VIRTIO_PCI_COMMON_OFFSET: u64 = 0x0000;
+const VIRTIO_PCI_COMMON_SIZE: u64 = 0x38;
+const VIRTIO_PCI_ISR_OFFSET: u64 = 0x1000;
+const VIRTIO_PCI_ISR_SIZE: u64 = 1;
+const VIRTIO_PCI_DEVICE_OFFSET: u64 = 0x2000;
+const VIRTIO_PCI_DEVICE_SIZE: u64 = 0x1000;
+const VIRTIO_PCI_NOTIFY_OFFSET: u64 = 0x3000;
+const VIRTIO_PCI_NOTIFY_SIZE: u64 = 0x1000;
+const VIRTIO_PCI_NOTIFY_MULTIPLIER: u32 = 4;
+const VIRTIO_PCI_MSIX_TABLE_OFFSET: u64 = 0x4000;
+const VIRTIO_PCI_MSIX_PBA_OFFSET: u64 = 0x6000;
+
+// Fields of the common configuration.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_CONFIG_MSIX_VECTOR: u64 = 0x10;
+const COMMON_NUM_QUEUES: u64 = 0x12;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+// Vendor-specific capability locating a structure of type `cfg_type` in
+// BAR 0, followed by the `extra` fields of the structure type.
+fn virtio_capability(cfg_type: u8, offset: u64, length: u64, extra: &[u8]) -> PciCapability {
+    // Structure type, BAR, id and padding.
+    let mut data = vec![cfg_type, 0, 0, 0, 0];
+    data.extend_from_slice(&(offset as u32).to_le_bytes());
+    data.extend_from_slice(&(length as u32).to_le_bytes());
+    data.extend_from_slice(extra);
+    PciCapability::VendorSpecific(data)
+}
+
+// Offset of the PCI configuration access capability.
+fn pci_cfg_capability(config: &PciConfiguration) -> Option<usize> {
+    let mut cap = usize::from(config.read_u8(PCI_CAPABILITY_LIST));
+    while cap != 0 {
+        if config.read_u8(cap) == PCI_CAP_ID_VNDR
+            && config.read_u8(cap + 3) == VIRTIO_PCI_CAP_PCI_CFG
+        {
+            return Some(cap);
+        }
+        cap = usize::from(config.read_u8(cap + 1));
+    }
+    None
+}
+
+struct InterruptState {
+    isr: u8,
+    config_generation: u8,
+    config_vector: u16,
+    queue_vectors: Vec<u16>,
+}
+
+// ISR status and MSI-X vectors assigned by the driver.
+struct PciInterrupt {
+    msix: Arc<PciMsix>,
+    state: Mutex<InterruptState>,
+}
+
+impl PciInterrupt {
+    // Vector to assign, or no vector if it is out of the table.
+    fn vector(&self, vector: u16) -> u16 {
+        if vector < self.msix.vectors() {
+            vector
+        } else {
+            VIRTIO_MSI_NO_VECTOR
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().expect("failed to acquire lock");
+        state.isr = 0;
+        state.config_vector = VIRTIO_MSI_NO_VECTOR;
+        for vector in state.queue_vectors.iter_mut() {
+            *vector = VIRTIO_MSI_NO_VECTOR;
+        }
+    }
+}
+
+impl VirtioInterrupt for PciInterrupt {
+    fn trigger(&self, int_type: VirtioInterruptType) -> Result<()> {
+        let vector = {
+            let mut state = self.state.lock().expect("failed to acquire lock");
+            match int_type {
+                VirtioInterruptType::Config => {
+                    state.config_generation = state.config_generation.wrapping_add(1);
+                    state.isr |= VIRTIO_ISR_CONFIG as u8;
+                    state.config_vector
+                }
+                VirtioInterruptType::Queue(index) => {
+                    state.isr |= VIRTIO_ISR_QUEUE as u8;
+                    state
+                        .queue_vectors
+                        .get(usize::from(index))
+                        .cloned()
+                        .unwrap_or(VIRTIO_MSI_NO_VECTOR)
+                }
+            }
+        };
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            return Err(Error::NoInterrupt);
+        }
+        self.msix.trigger(vector).map_err(Error::Interrupt)
+    }
+}
+
+/// Virtio device exposed as a modern virtio-pci function.
+pub struct VirtioPci {
+    config: PciConfiguration,
+    /// Offset of the PCI configuration access capability.
+    pci_cfg: Option<usize>,
+    msix: Arc<PciMsix>,
+    common: Mutex<VirtioCommon>,
+    interrupt: Arc<PciInterrupt>,
+}
+
+impl VirtioPci {
+    /// Expose the `backend` device, with a MSI-X vector for the
+    /// configuration changes and one per virtqueue. It is registered with
+    /// the `io_resources()` of its BAR and the `msi_resource()`.
+    pub fn new(backend: Box<dyn VirtioDevice>) -> pci::Result<Self> {
+        let queues = backend.queue_max_sizes().len();
+        // The table must not run into the PBA, which bounds the number of
+        // virtqueues.
+        let msix = Arc::new(PciMsix::new(
+            queues as u16 + 1,
+            0,
+            VIRTIO_PCI_MSIX_TABLE_OFFSET,
+            0,
+            VIRTIO_PCI_MSIX_PBA_OFFSET,
+        )?);
+
+        let device_type = backend.device_type();
+        let (class, subclass) = match device_type {
+            // Network and block devices.
+            1 => (0x02, 0x00),
+            2 => (0x01, 0x80),
+            _ => (0xff, 0x00),
+        };
+        let config = PciConfigurationBuilder::new(
+            VIRTIO_PCI_VENDOR_ID,
+            VIRTIO_PCI_DEVICE_ID_BASE + device_type as u16,
+        )
+        .revision(VIRTIO_PCI_REVISION)
+        .class(class, subclass, 0)
+        .subsystem(VIRTIO_PCI_VENDOR_ID, VIRTIO_PCI_SUBSYSTEM_ID)
+        .bars(PciBars::new(&[PciBar::memory64(VIRTIO_PCI_BAR_SIZE)])?)
+        .capability(msix.capability())
+        .capability(virtio_capability(
+            VIRTIO_PCI_CAP_COMMON_CFG,
+            VIRTIO_PCI_COMMON_OFFSET,
+            VIRTIO_PCI_COMMON_SIZE,
+            &[],
+        ))
+        .capability(virtio_capability(
+            VIRTIO_PCI_CAP_NOTIFY_CFG,
+            VIRTIO_PCI_NOTIFY_OFFSET,
+            VIRTIO_PCI_NOTIFY_SIZE,
+            &VIRTIO_PCI_NOTIFY_MULTIPLIER.to_le_bytes(),
+        ))
+        .capability(virtio_capability(
+            VIRTIO_PCI_CAP_ISR_CFG,
+            VIRTIO_PCI_ISR_OFFSET,
+            VIRTIO_PCI_ISR_SIZE,
+            &[],
+        ))
+        .capability(virtio_capability(
+            VIRTIO_PCI_CAP_DEVICE_CFG,
+            VIRTIO_PCI_DEVICE_OFFSET,
+            VIRTIO_PCI_DEVICE_SIZE,
+            &[],
+        ))
+        // The driver sets the BAR, offset and length, `pci_cfg_data` follows.
+        .capability(virtio_capability(VIRTIO_PCI_CAP_PCI_CFG, 0, 0, &[0; 4]))
+        .build()?;
+        let pci_cfg = pci_cfg_capability(&config);
+
+        let interrupt = Arc::new(PciInterrupt {
+            msix: msix.clone(),
+            state: Mutex::new(InterruptState {
+                isr: 0,
+                config_generation: 0,
+                config_vector: VIRTIO_MSI_NO_VECTOR,
+                queue_vectors: vec![VIRTIO_MSI_NO_VECTOR; queues],
+            }),
+        });
+        Ok(VirtioPci {
+            config,
+            pci_cfg,
+            msix,
+            common: Mutex::new(VirtioCommon::new(backend)),
+            interrupt,
+        })
+    }
+
+    /// IO resources to request at registration: the BAR.
+    pub fn io_resources(&self) -> Vec<IoResource> {
+        self.config
+            .bars()
+            .map_or_else(Vec::new, PciBars::io_resources)
+    }
+
+    /// MSI-X vectors to request at registration.
+    pub fn msi_resource(&self) -> MsiResource {
+        MsiResource::msix(u32::from(self.msix.vectors()))
+    }
+
+    /// Device status, as set by the driver.
+    pub fn status(&self) -> u8 {
+        self.common.lock().expect("failed to acquire lock").status
+    }
+
+    // Offset of `addr` in the BAR.
+    fn bar_offset(&self, addr: GuestAddress) -> Option<u64> {
+        let base = self.config.bars()?.address(0)?;
+        addr.0.checked_sub(base.0)
+    }
+
+    // Offset in the BAR and length of the access programmed in the PCI
+    // configuration access capability at `cap`, if the driver set a valid one.
+    fn pci_cfg_window(&self, cap: usize) -> Option<(u64, usize)> {
+        let offset = self.config.read_u32(cap + PCI_CFG_OFFSET);
+        let length = self.config.read_u32(cap + PCI_CFG_LENGTH);
+        if self.config.read_u8(cap + PCI_CFG_BAR) != 0
+            || ![1, 2, 4].contains(&length)
+            || offset & (length - 1) != 0
+        {
+            return None;
+        }
+        Some((u64::from(offset), length as usize))
+    }
+
+    fn read_bar(&self, offset: u64, data: &mut [u8]) {
+        if self.msix.read(0, offset, data) {
+            return;
+        }
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        if offset < VIRTIO_PCI_COMMON_OFFSET + VIRTIO_PCI_COMMON_SIZE {
+            self.read_common(offset - VIRTIO_PCI_COMMON_OFFSET, data);
+        } else if offset == VIRTIO_PCI_ISR_OFFSET && !data.is_empty() {
+            let mut state = self.interrupt.state.lock().expect("failed to acquire lock");
+            data[0] = std::mem::take(&mut state.isr);
+        } else if (VIRTIO_PCI_DEVICE_OFFSET..VIRTIO_PCI_DEVICE_OFFSET + VIRTIO_PCI_DEVICE_SIZE)
+            .contains(&offset)
+        {
+            let common = self.common.lock().expect("failed to acquire lock");
+            common
+                .backend
+                .read_config(offset - VIRTIO_PCI_DEVICE_OFFSET, data);
+        }
+    }
+
+    fn write_bar(&self, offset: u64, data: &[u8]) {
+        if self.msix.write(0, offset, data) {
+            return;
+        }
+        if offset < VIRTIO_PCI_COMMON_OFFSET + VIRTIO_PCI_COMMON_SIZE {
+            self.write_common(offset - VIRTIO_PCI_COMMON_OFFSET, data);
+        } else if (VIRTIO_PCI_DEVICE_OFFSET..VIRTIO_PCI_DEVICE_OFFSET + VIRTIO_PCI_DEVICE_SIZE)
+            .contains(&offset)
+        {
+            let mut common = self.common.lock().expect("failed to acquire lock");
+            common
+                .backend
+                .write_config(offset - VIRTIO_PCI_DEVICE_OFFSET, data);
+        } else if (VIRTIO_PCI_NOTIFY_OFFSET..VIRTIO_PCI_NOTIFY_OFFSET + VIRTIO_PCI_NOTIFY_SIZE)
+            .contains(&offset)
+        {
+            let index =
+                (offset - VIRTIO_PCI_NOTIFY_OFFSET) / u64::from(VIRTIO_PCI_NOTIFY_MULTIPLIER);
+            let mut common = self.common.lock().expect("failed to acquire lock");
+            common.queue_notify(index as u16);
+        }
+    }
+
+    // Read a field of the common configuration, with its own width or as a
+    // 32-bit half of the 64-bit ones.
+    fn read_common(&self, offset: u64, data: &mut [u8]) {
+        let common = self.common.lock().expect("failed to acquire lock");
+        let interrupt = self.interrupt.state.lock().expect("failed to acquire lock");
+        let select = usize::from(common.queue_select);
+        let queue = common.queue();
+        let value = match (offset, data.len()) {
+            (COMMON_DEVICE_FEATURE_SELECT, 4) => u64::from(common.device_features_select),
+            (COMMON_DEVICE_FEATURE, 4) => u64::from(common.device_features_word()),
+            (COMMON_DRIVER_FEATURE_SELECT, 4) => u64::from(common.driver_features_select),
+            (COMMON_DRIVER_FEATURE, 4) => u64::from(common.driver_features_word()),
+            (COMMON_CONFIG_MSIX_VECTOR, 2) => u64::from(interrupt.config_vector),
+            (COMMON_NUM_QUEUES, 2) => common.queues.len() as u64,
+            (COMMON_DEVICE_STATUS, 1) => u64::from(common.status),
+            (COMMON_CONFIG_GENERATION, 1) => u64::from(interrupt.config_generation),
+            (COMMON_QUEUE_SELECT, 2) => u64::from(common.queue_select),
+            (COMMON_QUEUE_SIZE, 2) => queue.map_or(0, |q| u64::from(q.size)),
+            (COMMON_QUEUE_MSIX_VECTOR, 2) => interrupt
+                .queue_vectors
+                .get(select)
+                .map_or(u64::from(VIRTIO_MSI_NO_VECTOR), |v| u64::from(*v)),
+            (COMMON_QUEUE_ENABLE, 2) => queue.map_or(0, |q| q.ready as u64),
+            (COMMON_QUEUE_NOTIFY_OFF, 2) => select as u64,
+            (_, 4) | (_, 8)
+                if (COMMON_QUEUE_DESC..COMMON_QUEUE_DEVICE + 8).contains(&offset)
+                    && offset & (data.len() as u64 - 1) == 0 =>
+            {
+                let addr = queue.map_or(0, |q| match offset & !0x7 {
+                    COMMON_QUEUE_DESC => q.desc_table.0,
+                    COMMON_QUEUE_DRIVER => q.avail_ring.0,
+                    _ => q.used_ring.0,
+                });
+                addr >> ((offset & 0x7) * 8)
+            }
+            _ => 0,
+        };
+        for (idx, byte) in data.iter_mut().enumerate() {
+            *byte = (value >> (idx * 8)) as u8;
+        }
+    }
+
+    // Write a field of the common configuration, with its own width or as a
+    // 32-bit half of the 64-bit ones.
+    fn write_common(&self, offset: u64, data: &[u8]) {
+        let mut value = 0u64;
+        for (idx, byte) in data.iter().enumerate() {
+            value |= u64::from(*byte) << (idx * 8);
+        }
+        let mut common = self.common.lock().expect("failed to acquire lock");
+        match (offset, data.len()) {
+            (COMMON_DEVICE_FEATURE_SELECT, 4) => common.device_features_select = value as u32,
+            (COMMON_DRIVER_FEATURE_SELECT, 4) => common.driver_features_select = value as u32,
+            (COMMON_DRIVER_FEATURE, 4) => common.set_driver_features_word(value as u32),
+            (COMMON_CONFIG_MSIX_VECTOR, 2) => {
+                let vector = self.interrupt.vector(value as u16);
+                self.interrupt
+                    .state
+                    .lock()
+                    .expect("failed to acquire lock")
+                    .config_vector = vector;
+            }
+            (COMMON_DEVICE_STATUS, 1) => {
+                common.set_status(value as u8, self.interrupt.clone());
+                if value == 0 {
+                    self.interrupt.reset();
+                }
+            }
+            (COMMON_QUEUE_SELECT, 2) => common.queue_select = value as u16,
+            (COMMON_QUEUE_MSIX_VECTOR, 2) => {
+                let vector = self.interrupt.vector(value as u16);
+                let mut state = self.interrupt.state.lock().expect("failed to acquire lock");
+                if let Some(v) = state
+                    .queue_vectors
+                    .get_mut(usize::from(common.queue_select))
+                {
+                    *v = vector;
+                }
+            }
+            _ => {
+                if let Some(queue) = common.configurable_queue() {
+                    match (offset, data.len()) {
+                        (COMMON_QUEUE_SIZE, 2)
+                            if value > 0 && value <= u64::from(queue.max_size) =>
+                        {
+                            queue.size = value as u16
+                        }
+                        (COMMON_QUEUE_ENABLE, 2) => queue.ready = value == 1,
+                        (_, 4) | (_, 8)
+                            if (COMMON_QUEUE_DESC..COMMON_QUEUE_DEVICE + 8).contains(&offset)
+                                && offset & (data.len() as u64 - 1) == 0 =>
+                        {
+                            let addr = match offset & !0x7 {
+                                COMMON_QUEUE_DESC => &mut queue.desc_table,
+                                COMMON_QUEUE_DRIVER => &mut queue.avail_ring,
+                                _ => &mut queue.used_ring,
+                            };
+                            let high = offset & 0x7 != 0;
+                            set_address_half(addr, value as u32, high);
+                            if data.len() == 8 {
+                                set_address_half(addr, (value >> 32) as u32, true);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Device for VirtioPci {
+    fn name(&self) -> String {
+        String::from("virtio-pci")
+    }
+
+    fn read(&self, addr: GuestAddress, data: &mut [u8], _io_type: IoType) {
+        if let Some(offset) = self.bar_offset(addr) {
+            self.read_bar(offset, data);
+        }
+    }
+
+    fn write(&self, addr: GuestAddress, data: &[u8], _io_type: IoType) {
+        if let Some(offset) = self.bar_offset(addr) {
+            self.write_bar(offset, data);
+        }
+    }
+
+    fn set_resources(&self, res: &[IoResource], _irqs: &[IrqResource]) {
+        if let Some(bars) = self.config.bars() {
+            bars.set_resources(res);
+        }
+    }
+
+    fn set_interrupt_group(&self, group: Arc<dyn InterruptSourceGroup>) {
+        if group.interrupt_type() == InterruptSourceType::MsiX {
+            let _ = self.msix.set_interrupt_group(group);
+        }
+    }
+
+    fn reset(&self) -> device::Result<()> {
+        self.common.lock().expect("failed to acquire lock").reset();
+        self.interrupt.reset();
+        // The command register and the MSI-X enable bit go back to 0 too,
+        // and so does the access programmed in the PCI_CFG capability.
+        self.config.reset();
+        if let Some(cap) = self.pci_cfg {
+            self.config.write(cap + PCI_CFG_BAR, &[0]);
+            self.config.write(cap + PCI_CFG_OFFSET, &[0; 8]);
+        }
+        self.msix
+            .reset()
+            .map_err(|e| device::Error::Failed(e.to_string()))
+    }
+}
+
+impl PciDevice for VirtioPci {
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        if let Some(cap) = self.pci_cfg {
+            if reg_idx == (cap + PCI_CFG_DATA) / 4 {
+                let mut data = [0u8; 4];
+                if let Some((offset, length)) = self.pci_cfg_window(cap) {
+                    self.read_bar(offset, &mut data[..length]);
+                }
+                return u32::from_le_bytes(data);
+            }
+        }
+        self.config.read_config_register(reg_idx)
+    }
+
+    fn write_config_register(&self, reg_idx: usize, offset: u64, data: &[u8]) {
+        if let Some(cap) = self.pci_cfg {
+            if reg_idx == (cap + PCI_CFG_DATA) / 4 {
+                if let Some((bar_offset, length)) = self.pci_cfg_window(cap) {
+                    if offset == 0 && data.len() >= length {
+                        self.write_bar(bar_offset, &data[..length]);
+                    }
+                }
+                return;
+            }
+            // The BAR, offset and length fields are set by the driver.
+            let start = reg_idx * 4 + offset as usize;
+            if start >= cap + PCI_CFG_BAR && start + data.len() <= cap + PCI_CFG_DATA {
+                for (idx, byte) in data.iter().enumerate() {
+                    let at = start + idx;
+                    if at == cap + PCI_CFG_BAR || at >= cap + PCI_CFG_OFFSET {
+                        self.config.write(at, &[*byte]);
+                    }
+                }
+                return;
+            }
+        }
+        self.config.write_config_register(reg_idx, offset, data);
+        if let Some(cap) = self.config.capability_offset(PCI_CAP_ID_MSIX) {
+            if reg_idx == cap / 4 {
+                let _ = self.msix.set_control(self.config.read_u16(cap + 2));
+            }
+        }
+    }
+
+    fn take_bar_moves(&self) -> Vec<(usize, GuestAddress)> {
+        self.config.take_bar_moves()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_utils::{Backend, BackendState};
+    use super::super::{
+        VirtioQueue, VIRTIO_STATUS_ACKNOWLEDGE, VIRTIO_STATUS_DRIVER, VIRTIO_STATUS_DRIVER_OK,
+        VIRTIO_STATUS_FEATURES_OK,
+    };
+    use super::*;
+    use crate::device_manager::{test_pci_system_allocator, DeviceManager, Result};
+    use crate::interrupt::eventfd::EventFdInterruptManager;
+    use crate::pci::{
+        PciRoot, PciRootBus, MSIX_CONTROL_ENABLE, PCI_CAPABILITY_LIST, PCI_CAP_ID_VNDR,
+        PCI_COMMAND, PCI_COMMAND_MEMORY,
+    };
+
+    fn read(dev_mgr: &DeviceManager, addr: u64, len: usize) -> Result<u64> {
+        let mut data = [0u8; 8];
+        dev_mgr.read(GuestAddress(addr), &mut data[..len], IoType::Mmio)?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    fn write(dev_mgr: &DeviceManager, addr: u64, len: usize, value: u64) -> Result<()> {
+        dev_mgr.write(
+            GuestAddress(addr),
+            &value.to_le_bytes()[..len],
+            IoType::Mmio,
+        )
+    }
+
+    #[test]
+    fn test_virtio_pci() -> Result<()> {
+        let mut sys_res = test_pci_system_allocator((GuestAddress(0xc000_0000), 0x1000_0000), None);
+        sys_res.set_msi_range(24, 63).unwrap();
+        let mut dev_mgr = DeviceManager::new(sys_res);
+        let irq_mgr = Arc::new(EventFdInterruptManager::new());
+        dev_mgr.set_interrupt_manager(irq_mgr.clone());
+        let root_bus = Arc::new(PciRootBus::new(Arc::new(PciRoot::new())));
+        let root =
+            dev_mgr.register_pci_root_bus(root_bus, None, &mut PciRootBus::io_resources())?;
+
+        let state = Arc::new(Mutex::new(BackendState::default()));
+        let dev = Arc::new(
+            VirtioPci::new(Box::new(Backend {
+                device_type: 1,
+                queue_sizes: vec![256, 256],
+                features: 1 << 16,
+                state: state.clone(),
+            }))
+            .unwrap(),
+        );
+        let mut resources = dev.io_resources();
+        dev_mgr.register_pci_device(
+            root,
+            dev.clone(),
+            None,
+            &mut resources,
+            &mut [],
+            Some(dev.msi_resource()),
+        )?;
+        let bar = resources[0].addr.unwrap().0;
+        let irqfds = irq_mgr.group(InterruptSourceType::MsiX, 24).unwrap();
+        assert_eq!(dev.read_config_register(0), 0x1041_1af4);
+
+        // Walk the virtio capabilities.
+        let mut cap = dev.read_config_register(PCI_CAPABILITY_LIST / 4) as usize & 0xff;
+        let mut structures = Vec::new();
+        while cap != 0 {
+            let header = dev.read_config_register(cap / 4);
+            if header as u8 == PCI_CAP_ID_VNDR {
+                let offset = dev.read_config_register(cap / 4 + 2);
+                structures.push(((header >> 24) as u8, offset));
+            }
+            cap = (header >> 8) as usize & 0xff;
+        }
+        assert_eq!(
+            structures,
+            vec![(1, 0x0000), (2, 0x3000), (3, 0x1000), (4, 0x2000), (5, 0)]
+        );
+
+        // Enable MSI-X and unmask the first two vectors.
+        let msix = dev.config.capability_offset(PCI_CAP_ID_MSIX).unwrap();
+        dev.write_config_register(msix / 4, 2, &MSIX_CONTROL_ENABLE.to_le_bytes());
+        write(&dev_mgr, bar + 0x400c, 4, 0)?;
+        write(&dev_mgr, bar + 0x401c, 4, 0)?;
+
+        // Negotiate the features.
+        assert_eq!(read(&dev_mgr, bar + COMMON_NUM_QUEUES, 2)?, 2);
+        let status = VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER;
+        write(&dev_mgr, bar + COMMON_DEVICE_STATUS, 1, u64::from(status))?;
+        assert_eq!(read(&dev_mgr, bar + COMMON_DEVICE_FEATURE, 4)?, 1 << 16);
+        write(&dev_mgr, bar + COMMON_DRIVER_FEATURE, 4, 1 << 16)?;
+        write(&dev_mgr, bar + COMMON_DRIVER_FEATURE_SELECT, 4, 1)?;
+        write(&dev_mgr, bar + COMMON_DRIVER_FEATURE, 4, 1)?;
+        let status = status | VIRTIO_STATUS_FEATURES_OK;
+        write(&dev_mgr, bar + COMMON_DEVICE_STATUS, 1, u64::from(status))?;
+        assert_eq!(
+            read(&dev_mgr, bar + COMMON_DEVICE_STATUS, 1)?,
+            u64::from(status)
+        );
+
+        // Configure the first queue, with vector 1, and the configuration
+        // vector 0. Vector 3 is out of the table.
+        write(&dev_mgr, bar + COMMON_CONFIG_MSIX_VECTOR, 2, 0)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_MSIX_VECTOR, 2, 3)?;
+        assert_eq!(
+            read(&dev_mgr, bar + COMMON_QUEUE_MSIX_VECTOR, 2)?,
+            u64::from(VIRTIO_MSI_NO_VECTOR)
+        );
+        write(&dev_mgr, bar + COMMON_QUEUE_MSIX_VECTOR, 2, 1)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_SIZE, 2, 128)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_DESC, 8, 0x1_0000_1000)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_DRIVER, 4, 0x2000)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_DEVICE + 4, 4, 0x2)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_DEVICE, 4, 0x3000)?;
+        write(&dev_mgr, bar + COMMON_QUEUE_ENABLE, 2, 1)?;
+        assert_eq!(read(&dev_mgr, bar + COMMON_QUEUE_DESC + 4, 4)?, 1);
+
+        let status = status | VIRTIO_STATUS_DRIVER_OK;
+        write(&dev_mgr, bar + COMMON_DEVICE_STATUS, 1, u64::from(status))?;
+        assert_eq!(dev.status(), status);
+        let interrupt = {
+            let state = state.lock().unwrap();
+            assert_eq!(
+                state.queues[0],
+                VirtioQueue {
+                    max_size: 256,
+                    size: 128,
+                    ready: true,
+                    desc_table: GuestAddress(0x1_0000_1000),
+                    avail_ring: GuestAddress(0x2000),
+                    used_ring: GuestAddress(0x2_0000_3000),
+                }
+            );
+            assert!(!state.queues[1].ready);
+            state.interrupt.clone().unwrap()
+        };
+
+        // Notifications are forwarded to the backend.
+        write(&dev_mgr, bar + 0x3004, 2, 1)?;
+        write(&dev_mgr, bar + 0x3000, 2, 0)?;
+        assert_eq!(state.lock().unwrap().notified, vec![1, 0]);
+
+        // Each interrupt is delivered on its vector, the second queue has none.
+        interrupt.trigger(VirtioInterruptType::Queue(0)).unwrap();
+        assert_eq!(irqfds.irqfd(1).unwrap().read().unwrap(), 1);
+        interrupt.trigger(VirtioInterruptType::Config).unwrap();
+        assert_eq!(irqfds.irqfd(0).unwrap().read().unwrap(), 1);
+        assert!(interrupt.trigger(VirtioInterruptType::Queue(1)).is_err());
+        assert_eq!(read(&dev_mgr, bar + COMMON_CONFIG_GENERATION, 1)?, 1);
+        assert_eq!(read(&dev_mgr, bar + VIRTIO_PCI_ISR_OFFSET, 1)?, 3);
+        assert_eq!(read(&dev_mgr, bar + VIRTIO_PCI_ISR_OFFSET, 1)?, 0);
+
+        assert_eq!(read(&dev_mgr, bar + 0x2002, 2)?, 0x0302);
+
+        // The BAR is also reachable through the PCI configuration access
+        // capability.
+        let cap = dev.pci_cfg.unwrap();
+        let data = (cap + PCI_CFG_DATA) / 4;
+        dev.write_config_register((cap + PCI_CFG_OFFSET) / 4, 0, &0x2002u32.to_le_bytes());
+        dev.write_config_register((cap + PCI_CFG_LENGTH) / 4, 0, &2u32.to_le_bytes());
+        assert_eq!(dev.read_config_register(data), 0x0302);
+        dev.write_config_register((cap + PCI_CFG_OFFSET) / 4, 0, &0x16u32.to_le_bytes());
+        dev.write_config_register(data, 0, &[1, 0]);
+        assert_eq!(read(&dev_mgr, bar + COMMON_QUEUE_SELECT, 2)?, 1);
+        // Unaligned accesses are dropped.
+        dev.write_config_register((cap + PCI_CFG_OFFSET) / 4, 0, &0x2001u32.to_le_bytes());
+        assert_eq!(dev.read_config_register(data), 0);
+
+        // Writing 0 to the status resets the device.
+        write(&dev_mgr, bar + COMMON_DEVICE_STATUS, 1, 0)?;
+        assert_eq!(dev.status(), 0);
+        assert_eq!(read(&dev_mgr, bar + COMMON_QUEUE_ENABLE, 2)?, 0);
+
+        // A device reset also brings the configuration space back to its
+        // power-on values.
+        dev.write_config_register(PCI_COMMAND / 4, 0, &PCI_COMMAND_MEMORY.to_le_bytes());
+        assert_eq!(dev.config.read_u16(PCI_COMMAND), PCI_COMMAND_MEMORY);
+        dev.reset().unwrap();
+        assert_eq!(dev.config.read_u16(PCI_COMMAND), 0);
+        assert_eq!(dev.config.read_u16(msix + 2) & MSIX_CONTROL_ENABLE, 0);
+        Ok(())
+    }
+}
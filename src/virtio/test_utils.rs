@@ -0,0 +1,61 @@
+// Copyright © 2019 Intel Corporation. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+
+//! Virtio backend shared by the unit tests of the transports.
+
+use super::{Result, VirtioDevice, VirtioInterrupt, VirtioQueue};
+use std::sync::{Arc, Mutex};
+
+// What the backend got from its transport.
+#[derive(Default)]
+pub(super) struct BackendState {
+    pub(super) acked_features: u64,
+    pub(super) queues: Vec<VirtioQueue>,
+    pub(super) interrupt: Option<Arc<dyn VirtioInterrupt>>,
+    pub(super) notified: Vec<u16>,
+    pub(super) resets: u32,
+}
+
+// Backend whose configuration space reads back the offset of each byte.
+pub(super) struct Backend {
+    pub(super) device_type: u32,
+    pub(super) queue_sizes: Vec<u16>,
+    pub(super) features: u64,
+    pub(super) state: Arc<Mutex<BackendState>>,
+}
+
+impl VirtioDevice for Backend {
+    fn device_type(&self) -> u32 {
+        self.device_type
+    }
+    fn queue_max_sizes(&self) -> &[u16] {
+        &self.queue_sizes
+    }
+    fn features(&self) -> u64 {
+        self.features
+    }
+    fn ack_features(&mut self, features: u64) {
+        self.state.lock().unwrap().acked_features = features;
+    }
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = offset as u8 + i as u8;
+        }
+    }
+    fn activate(
+        &mut self,
+        queues: Vec<VirtioQueue>,
+        interrupt: Arc<dyn VirtioInterrupt>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.queues = queues;
+        state.interrupt = Some(interrupt);
+        Ok(())
+    }
+    fn queue_notify(&mut self, index: u16) {
+        self.state.lock().unwrap().notified.push(index);
+    }
+    fn reset(&mut self) {
+        self.state.lock().unwrap().resets += 1;
+    }
+}
@@ -96,8 +96,9 @@ impl AddressAllocator {
     }
 
     fn align_address(&self, address: GuestAddress) -> Option<GuestAddress> {
-        let align_adjust = if address.raw_value() % self.alignment != 0 {
-            self.alignment - (address.raw_value() % self.alignment)
+        let remainder = address.raw_value() % self.alignment;
+        let align_adjust = if remainder != 0 {
+            self.alignment - remainder
         } else {
             0
         };
@@ -117,6 +118,15 @@ impl AddressAllocator {
             return Err(Error::UnalignedAddress);
         }
 
+        self.free_range_at(aligned_address, req_size)
+    }
+
+    // Check the `req_size` bytes at `aligned_address` are free.
+    fn free_range_at(
+        &self,
+        aligned_address: GuestAddress,
+        req_size: GuestUsize,
+    ) -> Result<GuestAddress> {
         // The aligned address should be within the address space range.
         if aligned_address > self.end || aligned_address < self.base {
             return Err(Error::Overflow);
@@ -184,6 +194,53 @@ impl AddressAllocator {
         Err(Error::Overflow)
     }
 
+    // Find the free range of `req_size` bytes aligned on `align` at the top
+    // of the first gap large enough for it.
+    fn first_aligned_range(&self, req_size: GuestUsize, align: GuestUsize) -> Result<GuestAddress> {
+        let mut prev_end_address = self.base;
+
+        for (address, size) in self.ranges.iter() {
+            if let Some(start) = address.raw_value().checked_sub(req_size) {
+                let start = GuestAddress(start - start % align);
+                if start >= prev_end_address {
+                    return Ok(start);
+                }
+            }
+            prev_end_address = std::cmp::max(prev_end_address, address.unchecked_add(*size));
+        }
+
+        Err(Error::Overflow)
+    }
+
+    /// Allocates a range of addresses aligned on `align` bytes, e.g. a
+    /// naturally aligned PCI BAR. The alignment of the managed region still
+    /// applies to the ranges it picks when it is larger than `align`, while a
+    /// requested `address` only has to be aligned on `align`.
+    pub fn allocate_aligned(
+        &mut self,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align: GuestUsize,
+    ) -> Result<GuestAddress> {
+        if size == 0 {
+            return Err(Error::NullRequest);
+        }
+        if !align.is_power_of_two() {
+            return Err(Error::UnalignedAddress);
+        }
+        let new_addr = match address {
+            Some(req_address) if req_address.raw_value() % align != 0 => {
+                return Err(Error::UnalignedAddress);
+            }
+            Some(req_address) => self.free_range_at(req_address, size)?,
+            None => self.first_aligned_range(size, std::cmp::max(align, self.alignment))?,
+        };
+
+        self.ranges.insert(new_addr, size);
+
+        Ok(new_addr)
+    }
+
     /// Allocates a range of addresses from the managed region. Returns `Some(allocated_address)`
     /// when successful, or `None` if an area of `size` can't be allocated.
     pub fn allocate(
@@ -205,6 +262,34 @@ impl AddressAllocator {
         Ok(new_addr)
     }
 
+    /// Returns the base address and the size of the managed region.
+    pub fn window(&self) -> (GuestAddress, GuestUsize) {
+        (
+            self.base,
+            self.end.unchecked_sub(self.base.raw_value()).raw_value() + 1,
+        )
+    }
+
+    /// Returns the free holes of the managed region as (address, size) pairs,
+    /// ordered by address.
+    pub fn free_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        let mut holes = Vec::new();
+        let mut prev_end_address = self.base;
+
+        for (address, size) in self.ranges.iter() {
+            if *address > prev_end_address {
+                holes.push((
+                    prev_end_address,
+                    address
+                        .unchecked_sub(prev_end_address.raw_value())
+                        .raw_value(),
+                ));
+            }
+            prev_end_address = std::cmp::max(prev_end_address, address.unchecked_add(*size));
+        }
+        holes
+    }
+
     /// Free an already allocated address range.
     /// We can only free a range if it matches exactly an already allocated range.
     pub fn free(&mut self, address: GuestAddress, size: GuestUsize) {
@@ -227,7 +312,7 @@ mod tests {
     #[test]
     fn new_fails_overflow() {
         assert_eq!(
-            AddressAllocator::new(GuestAddress(u64::max_value()), 0x100, None),
+            AddressAllocator::new(GuestAddress(u64::MAX), 0x100, None),
             None
         );
     }
@@ -362,6 +447,59 @@ mod tests {
         assert!(pool.allocate(Some(GuestAddress(0x1200)), 0x800).is_err());
     }
 
+    #[test]
+    fn allocate_aligned() {
+        let mut pool =
+            AddressAllocator::new(GuestAddress(0x1_0000), 0x3_1000, Some(0x1000)).unwrap();
+        // The end of the region is not aligned on the request size.
+        assert_eq!(
+            pool.allocate_aligned(None, 0x1_0000, 0x1_0000).unwrap(),
+            GuestAddress(0x3_0000)
+        );
+        assert_eq!(
+            pool.allocate_aligned(None, 0x2000, 0x2000).unwrap(),
+            GuestAddress(0x2_e000)
+        );
+        // The remaining space below 0x2e000 can't hold an aligned 0x20000.
+        assert!(pool.allocate_aligned(None, 0x2_0000, 0x2_0000).is_err());
+        assert_eq!(
+            pool.allocate_aligned(None, 0x1_0000, 0x1_0000).unwrap(),
+            GuestAddress(0x1_0000)
+        );
+        assert!(pool
+            .allocate_aligned(Some(GuestAddress(0x2_1000)), 0x2000, 0x2000)
+            .is_err());
+        assert_eq!(
+            pool.allocate_aligned(Some(GuestAddress(0x2_2000)), 0x2000, 0x2000)
+                .unwrap(),
+            GuestAddress(0x2_2000)
+        );
+        assert!(pool.allocate_aligned(None, 0x1000, 0x3000).is_err());
+        // A requested address only has to be aligned on the request.
+        assert_eq!(
+            pool.allocate_aligned(Some(GuestAddress(0x2_4100)), 0x100, 0x100)
+                .unwrap(),
+            GuestAddress(0x2_4100)
+        );
+        assert!(pool
+            .allocate_aligned(Some(GuestAddress(0x2_4280)), 0x100, 0x100)
+            .is_err());
+    }
+
+    #[test]
+    fn free_ranges() {
+        let mut pool = AddressAllocator::new(GuestAddress(0x1000), 0x1000, Some(0x100)).unwrap();
+        assert_eq!(pool.window(), (GuestAddress(0x1000), 0x1000));
+        assert_eq!(pool.free_ranges(), vec![(GuestAddress(0x1000), 0x1000)]);
+
+        pool.allocate(Some(GuestAddress(0x1200)), 0x800).unwrap();
+        pool.allocate(None, 0x100).unwrap();
+        assert_eq!(
+            pool.free_ranges(),
+            vec![(GuestAddress(0x1000), 0x100), (GuestAddress(0x1a00), 0x600)]
+        );
+    }
+
     #[test]
     fn allocate_address_fail_free_and_realloc() {
         let mut pool = AddressAllocator::new(GuestAddress(0x1000), 0x1000, Some(0x100)).unwrap();
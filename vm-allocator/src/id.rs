@@ -14,6 +14,8 @@ use std::result;
 pub enum Error {
     Overflow,
     Duplicated,
+    NullRequest,
+    UnalignedId,
 }
 
 impl Display for Error {
@@ -24,6 +26,8 @@ impl Display for Error {
         match self {
             Overflow => write!(f, "Integer is overflow"),
             Duplicated => write!(f, "Integer being allocated is duplicated"),
+            NullRequest => write!(f, "Integer block being allocated is empty"),
+            UnalignedId => write!(f, "Integer block being allocated is unaligned"),
         }
     }
 }
@@ -110,6 +114,74 @@ impl IdAllocator {
         Ok(new)
     }
 
+    // Return true if none of the numbers of [first, first + count) is used.
+    fn block_is_free(&self, first: u32, count: u32) -> bool {
+        let idx = match self.used_map.binary_search(&first) {
+            Ok(_) => return false,
+            Err(idx) => idx,
+        };
+        match self.used_map.get(idx) {
+            Some(&next) => u64::from(next) >= u64::from(first) + u64::from(count),
+            None => true,
+        }
+    }
+
+    /// Allocates a block of `count` contiguous numbers whose first number is
+    /// a multiple of `align`. Returns `Ok(first_id)` when successful.
+    ///
+    /// * `number` - A specific first number trying to allocate, or None means
+    ///   the lowest suitable block.
+    /// * `count` - The number of ids in the block.
+    /// * `align` - The alignment of the first id, must be a power of two.
+    pub fn allocate_block(&mut self, number: Option<u32>, count: u32, align: u32) -> Result<u32> {
+        if count == 0 || !align.is_power_of_two() {
+            return Err(Error::NullRequest);
+        }
+        let fits = |first: u32| {
+            u64::from(first) >= u64::from(self.start)
+                && u64::from(first) + u64::from(count) - 1 <= u64::from(self.end)
+        };
+
+        let first = match number {
+            Some(num) => {
+                if num % align != 0 {
+                    return Err(Error::UnalignedId);
+                }
+                if !fits(num) {
+                    return Err(Error::Overflow);
+                }
+                if !self.block_is_free(num, count) {
+                    return Err(Error::Duplicated);
+                }
+                num
+            }
+            None => {
+                let align = u64::from(align);
+                let mut candidate = (u64::from(self.start) + align - 1) & !(align - 1);
+                loop {
+                    if candidate > u64::from(u32::MAX) || !fits(candidate as u32) {
+                        return Err(Error::Overflow);
+                    }
+                    if self.block_is_free(candidate as u32, count) {
+                        break candidate as u32;
+                    }
+                    candidate += align;
+                }
+            }
+        };
+
+        self.used_map.extend(first..=first + (count - 1));
+        self.used_map.sort();
+        Ok(first)
+    }
+
+    /// Free a block of `count` ids starting at `first`.
+    pub fn free_block(&mut self, first: u32, count: u32) {
+        let last = u64::from(first) + u64::from(count);
+        self.used_map
+            .retain(|&id| id < first || u64::from(id) >= last);
+    }
+
     /// Free an already allocated id and will keep the order.
     pub fn free(&mut self, number: u32) {
         if let Ok(idx) = self.used_map.binary_search(&number) {
@@ -117,3 +189,32 @@ impl IdAllocator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_block() {
+        let mut pool = IdAllocator::new(24, 63).unwrap();
+        assert_eq!(pool.allocate(Some(25)).unwrap(), 25);
+
+        // Blocks are aligned and skip over used numbers.
+        assert_eq!(pool.allocate_block(None, 4, 4).unwrap(), 28);
+        assert_eq!(pool.allocate_block(None, 8, 8).unwrap(), 32);
+        assert_eq!(pool.allocate_block(None, 3, 1).unwrap(), 40);
+        assert_eq!(pool.allocate_block(None, 2, 1).unwrap(), 26);
+        assert_eq!(pool.allocate(None).unwrap(), 24);
+
+        assert!(pool.allocate_block(Some(34), 2, 2).is_err());
+        assert!(pool.allocate_block(Some(43), 2, 2).is_err());
+        assert!(pool.allocate_block(Some(60), 8, 8).is_err());
+        assert!(pool.allocate_block(None, 0, 1).is_err());
+        assert!(pool.allocate_block(None, 32, 32).is_err());
+        assert_eq!(pool.allocate_block(Some(48), 16, 16).unwrap(), 48);
+
+        pool.free_block(32, 8);
+        assert_eq!(pool.allocate_block(None, 8, 8).unwrap(), 32);
+        assert_eq!(pool.allocate(None).unwrap(), 43);
+    }
+}
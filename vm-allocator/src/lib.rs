@@ -18,4 +18,4 @@ mod system;
 
 pub use crate::address::AddressAllocator;
 pub use crate::id::IdAllocator;
-pub use crate::system::{Error, SystemAllocator};
+pub use crate::system::{Error, PciSegmentWindows, PciWindow, SystemAllocator};
@@ -13,6 +13,7 @@ use crate::address::AddressAllocator;
 use crate::id::IdAllocator;
 
 use libc::{sysconf, _SC_PAGESIZE};
+use std::collections::BTreeMap;
 use std::fmt::{self, Display};
 use std::result;
 use std::sync::{Arc, Mutex};
@@ -26,6 +27,14 @@ pub enum Error {
     AddressAllocate(crate::address::Error),
     /// Id allocation failed.
     IdAllocate(crate::id::Error),
+    /// No MSI range has been set.
+    NoneMsiRange,
+    /// Too many MSI vectors in one block.
+    MsiCount(u32),
+    /// The PCI segment is already set, or doesn't exist.
+    InvalidPciSegment(u16),
+    /// The PCI MMIO windows are already set.
+    PciWindowsSet,
 }
 
 impl Display for Error {
@@ -37,6 +46,10 @@ impl Display for Error {
             NoneAddress => write!(f, "The address being allocated is null"),
             AddressAllocate(e) => write!(f, "Address being allocated failed, err={}", e),
             IdAllocate(e) => write!(f, "Address being allocated failed, err={}", e),
+            NoneMsiRange => write!(f, "No MSI range to allocate vectors from"),
+            MsiCount(count) => write!(f, "Invalid number of MSI vectors {}", count),
+            InvalidPciSegment(segment) => write!(f, "Invalid PCI segment {:04x}", segment),
+            PciWindowsSet => write!(f, "The PCI MMIO windows are already set"),
         }
     }
 }
@@ -50,12 +63,52 @@ fn pagesize() -> usize {
     unsafe { sysconf(_SC_PAGESIZE) as usize }
 }
 
+/// Maximum number of vectors in a multi-message MSI block.
+const MAX_MSI_BLOCK: u32 = 32;
+
+/// GSIs handed out for MSI and MSI-X vectors, with the blocks they belong to.
+struct MsiPool {
+    gsi: IdAllocator,
+    /// Allocated blocks, first GSI mapped to the number of reserved GSIs.
+    blocks: BTreeMap<u32, u32>,
+}
+
+/// Windows of a PCI segment, carved out of the IO and MMIO address spaces.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct PciSegmentWindows {
+    /// IO window, for the PIO resources of the segment.
+    pub io: Option<(GuestAddress, GuestUsize)>,
+    /// MMIO window below 4 GiB, for 32-bit BARs.
+    pub mmio32: Option<(GuestAddress, GuestUsize)>,
+    /// MMIO window above 4 GiB, for 64-bit BARs.
+    pub mmio64: Option<(GuestAddress, GuestUsize)>,
+}
+
+/// Allocators of the windows of a PCI segment.
+struct PciSegment {
+    windows: PciSegmentWindows,
+    io: Option<AddressAllocator>,
+    mmio32: Option<AddressAllocator>,
+    mmio64: Option<AddressAllocator>,
+}
+
+/// Type of the window of a PCI segment to allocate from.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PciWindow {
+    /// IO window.
+    Io,
+    /// MMIO window below 4 GiB.
+    Mmio32,
+    /// MMIO window above 4 GiB, falling back to the one below.
+    Mmio64,
+}
+
 /// Manages allocating system resources such as address space and interrupt numbers.
 ///
 /// # Example - Use the `SystemAddress` builder.
 ///
 /// ```
-/// # use vm_allocator::SystemAllocator;
+/// # use vm_allocator::{PciSegmentWindows, PciWindow, SystemAllocator};
 /// # use vm_memory::{Address, GuestAddress, GuestUsize};
 ///   let mut allocator = SystemAllocator::new(
 ///           Some(GuestAddress(0x1000)), Some(0x10000),
@@ -63,15 +116,60 @@ fn pagesize() -> usize {
 ///           5, 24, 1).unwrap();
 ///    assert_eq!(allocator.allocate_irq(None).unwrap(), 5);
 ///    assert_eq!(allocator.allocate_irq(Some(7)).unwrap(), 7);
+///    assert_eq!(allocator.allocate_shared_irq(Some(9)).unwrap(), 9);
+///    assert_eq!(allocator.allocate_shared_irq(Some(9)).unwrap(), 9);
+///    assert!(allocator.allocate_irq(Some(9)).is_err());
+///    allocator.free_irq(Some(9));
+///    assert_eq!(allocator.irq_sharers(9), 1);
 ///    assert_eq!(allocator.allocate_mmio_addresses(None, 0x1000).unwrap(), GuestAddress(0x1ffff000));
 ///
+///    allocator.set_msi_range(24, 255).unwrap();
+///    assert_eq!(allocator.allocate_msix(None, 3).unwrap(), 24);
+///    assert_eq!(allocator.allocate_msi(None, 3).unwrap(), 28);
+///    assert_eq!(allocator.msi_blocks(), vec![(24, 3), (28, 4)]);
+///
+///    allocator.set_pci_mmio_windows(
+///            (GuestAddress(0x1000_0000), 0x400_0000),
+///            Some((GuestAddress(0x1400_0000), 0x400_0000))).unwrap();
+///    assert!(allocator.set_pci_mmio_windows((GuestAddress(0x1000_0000), 0x400_0000), None)
+///            .is_err());
+///    assert!(allocator.allocate_mmio_addresses(Some(GuestAddress(0x1000_0000)), 0x1000)
+///            .is_err());
+///    assert_eq!(allocator.allocate_pci_mmio32_addresses(None, 0x4000, 0x4000).unwrap(),
+///               GuestAddress(0x13ff_c000));
+///    assert_eq!(allocator.allocate_pci_mmio64_addresses(None, 0x10_0000, 0x10_0000).unwrap(),
+///               GuestAddress(0x17f0_0000));
+///
+///    let windows = PciSegmentWindows {
+///        io: None,
+///        mmio32: Some((GuestAddress(0x1800_0000), 0x100_0000)),
+///        mmio64: None,
+///    };
+///    allocator.add_pci_segment(1, windows).unwrap();
+///    assert!(allocator.add_pci_segment(1, windows).is_err());
+///    assert_eq!(allocator.allocate_pci_segment_addresses(1, PciWindow::Mmio64, None, 0x1000,
+///               0x1000).unwrap(), GuestAddress(0x18ff_f000));
+///    assert!(allocator.allocate_mmio_addresses(Some(GuestAddress(0x1800_0000)), 0x1000)
+///            .is_err());
+///    allocator.remove_pci_segment(1).unwrap();
+///    assert_eq!(allocator.allocate_mmio_addresses(Some(GuestAddress(0x1800_0000)), 0x1000)
+///               .unwrap(), GuestAddress(0x1800_0000));
 /// ```
 #[derive(Clone)]
 pub struct SystemAllocator {
     io_address_space: Arc<Mutex<Option<AddressAllocator>>>,
     mmio_address_space: Arc<Mutex<AddressAllocator>>,
     irq: Arc<Mutex<IdAllocator>>,
+    /// Shared IRQ lines mapped to their number of sharers.
+    shared_irqs: Arc<Mutex<BTreeMap<u32, u32>>>,
     instance_id: Arc<Mutex<IdAllocator>>,
+    msi: Arc<Mutex<Option<MsiPool>>>,
+    /// PCI MMIO window below 4 GiB, for 32-bit BARs.
+    pci_mmio32: Arc<Mutex<Option<AddressAllocator>>>,
+    /// PCI MMIO window above 4 GiB, for 64-bit BARs.
+    pci_mmio64: Arc<Mutex<Option<AddressAllocator>>>,
+    /// Windows of the PCI segments, mapped by segment number.
+    pci_segments: Arc<Mutex<BTreeMap<u16, PciSegment>>>,
 }
 
 impl SystemAllocator {
@@ -106,13 +204,376 @@ impl SystemAllocator {
                 Some(page_size),
             )?)),
             irq: Arc::new(Mutex::new(IdAllocator::new(first_irq, last_irq)?)),
+            shared_irqs: Arc::new(Mutex::new(BTreeMap::new())),
             instance_id: Arc::new(Mutex::new(IdAllocator::new(
                 first_instance_id,
-                u32::max_value(),
+                u32::MAX,
             )?)),
+            msi: Arc::new(Mutex::new(None)),
+            pci_mmio32: Arc::new(Mutex::new(None)),
+            pci_mmio64: Arc::new(Mutex::new(None)),
+            pci_segments: Arc::new(Mutex::new(BTreeMap::new())),
         })
     }
 
+    /// Sets the MMIO windows PCI BARs are allocated from. They are carved out
+    /// of the MMIO address space and shared by all the clones of this
+    /// `SystemAllocator`. The windows can only be set once.
+    ///
+    /// * `mmio32` - The base address and size of the window below 4 GiB.
+    /// * `mmio64` - The base address and size of the window above 4 GiB, if any.
+    pub fn set_pci_mmio_windows(
+        &mut self,
+        mmio32: (GuestAddress, GuestUsize),
+        mmio64: Option<(GuestAddress, GuestUsize)>,
+    ) -> Result<()> {
+        let pci_mmio32 = self.pci_mmio32.clone();
+        let mut pci_mmio32 = pci_mmio32.lock().expect("failed to acquire lock");
+        if pci_mmio32.is_some() {
+            return Err(Error::PciWindowsSet);
+        }
+
+        let window = |range: (GuestAddress, GuestUsize)| {
+            Self::pci_window_allocator(range)
+                .ok_or(Error::AddressAllocate(crate::address::Error::Overflow))
+        };
+        let mmio32_window = window(mmio32)?;
+        let mmio64_window = match mmio64 {
+            Some(range) => Some(window(range)?),
+            None => None,
+        };
+
+        self.allocate_mmio_addresses(Some(mmio32.0), mmio32.1)?;
+        if let Some((base, size)) = mmio64 {
+            if let Err(e) = self.allocate_mmio_addresses(Some(base), size) {
+                self.free_mmio_addresses(mmio32.0, mmio32.1);
+                return Err(e);
+            }
+        }
+        *pci_mmio32 = Some(mmio32_window);
+        *self.pci_mmio64.lock().expect("failed to acquire lock") = mmio64_window;
+        Ok(())
+    }
+
+    // Allocator of a PCI window. BARs are aligned on their own size, which
+    // may be less than a page.
+    fn pci_window_allocator((base, size): (GuestAddress, GuestUsize)) -> Option<AddressAllocator> {
+        AddressAllocator::new(base, size, Some(1))
+    }
+
+    fn allocate_pci_window(
+        window: &Mutex<Option<AddressAllocator>>,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align: GuestUsize,
+    ) -> Result<GuestAddress> {
+        window
+            .lock()
+            .expect("failed to acquire lock")
+            .as_mut()
+            .ok_or(Error::NoneAddress)?
+            .allocate_aligned(address, size, align)
+            .map_err(Error::AddressAllocate)
+    }
+
+    /// Reserves a range of `size` bytes aligned on `align` bytes for a 32-bit
+    /// PCI BAR from the PCI MMIO window below 4 GiB.
+    /// * `address` - A specific address trying to allocate, or None means no specific value.
+    /// * `size` - The BAR size.
+    /// * `align` - A power of two, the BAR size for a single BAR.
+    pub fn allocate_pci_mmio32_addresses(
+        &mut self,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align: GuestUsize,
+    ) -> Result<GuestAddress> {
+        Self::allocate_pci_window(&self.pci_mmio32, address, size, align)
+    }
+
+    /// Reserves a range of `size` bytes aligned on `align` bytes for a 64-bit
+    /// PCI BAR. The PCI MMIO window above 4 GiB is tried first, then the one
+    /// below.
+    /// * `address` - A specific address trying to allocate, or None means no specific value.
+    /// * `size` - The BAR size.
+    /// * `align` - A power of two, the BAR size for a single BAR.
+    pub fn allocate_pci_mmio64_addresses(
+        &mut self,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align: GuestUsize,
+    ) -> Result<GuestAddress> {
+        Self::allocate_pci_window(&self.pci_mmio64, address, size, align)
+            .or_else(|_| Self::allocate_pci_window(&self.pci_mmio32, address, size, align))
+    }
+
+    /// Returns the base address and the size of the PCI MMIO window below
+    /// 4 GiB, if any.
+    pub fn pci_mmio32_window(&self) -> Option<(GuestAddress, GuestUsize)> {
+        self.pci_mmio32
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map(AddressAllocator::window)
+    }
+
+    /// Returns the base address and the size of the PCI MMIO window above
+    /// 4 GiB, if any.
+    pub fn pci_mmio64_window(&self) -> Option<(GuestAddress, GuestUsize)> {
+        self.pci_mmio64
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map(AddressAllocator::window)
+    }
+
+    /// Returns the free holes of the PCI MMIO window below 4 GiB.
+    pub fn pci_mmio32_free_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        self.pci_mmio32
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map_or_else(Vec::new, AddressAllocator::free_ranges)
+    }
+
+    /// Returns the free holes of the PCI MMIO window above 4 GiB.
+    pub fn pci_mmio64_free_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        self.pci_mmio64
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map_or_else(Vec::new, AddressAllocator::free_ranges)
+    }
+
+    /// Free a PCI BAR range from whichever PCI MMIO window it belongs to.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_pci_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
+        for window in [&self.pci_mmio32, &self.pci_mmio64].iter() {
+            if let Some(w) = window.lock().expect("failed to acquire lock").as_mut() {
+                w.free(address, size);
+            }
+        }
+    }
+
+    /// Adds the PCI segment `segment`, whose resources are allocated from
+    /// its own `windows`. The windows are reserved from the IO and MMIO
+    /// address spaces until the segment is removed.
+    pub fn add_pci_segment(&mut self, segment: u16, windows: PciSegmentWindows) -> Result<()> {
+        if self.pci_segment_windows(segment).is_some() {
+            return Err(Error::InvalidPciSegment(segment));
+        }
+
+        let mut carved: Vec<(bool, GuestAddress, GuestUsize)> = Vec::new();
+        let ranges = [
+            (true, windows.io),
+            (false, windows.mmio32),
+            (false, windows.mmio64),
+        ];
+        for (io, range) in ranges.iter() {
+            let (base, size) = match range {
+                Some(range) => *range,
+                None => continue,
+            };
+            let ret = if *io {
+                self.allocate_io_addresses(base, size)
+            } else {
+                self.allocate_mmio_addresses(Some(base), size)
+            };
+            if let Err(e) = ret {
+                for (io, base, size) in carved {
+                    self.free_carved(io, base, size);
+                }
+                return Err(e);
+            }
+            carved.push((*io, base, size));
+        }
+
+        let window =
+            |range: Option<(GuestAddress, GuestUsize)>| range.and_then(Self::pci_window_allocator);
+        self.pci_segments
+            .lock()
+            .expect("failed to acquire lock")
+            .insert(
+                segment,
+                PciSegment {
+                    windows,
+                    io: window(windows.io),
+                    mmio32: window(windows.mmio32),
+                    mmio64: window(windows.mmio64),
+                },
+            );
+        Ok(())
+    }
+
+    fn free_carved(&mut self, io: bool, base: GuestAddress, size: GuestUsize) {
+        if io {
+            self.free_io_addresses(base, size);
+        } else {
+            self.free_mmio_addresses(base, size);
+        }
+    }
+
+    /// Removes the PCI segment `segment`, giving its windows back to the IO
+    /// and MMIO address spaces.
+    pub fn remove_pci_segment(&mut self, segment: u16) -> Result<()> {
+        let removed = self
+            .pci_segments
+            .lock()
+            .expect("failed to acquire lock")
+            .remove(&segment)
+            .ok_or(Error::InvalidPciSegment(segment))?;
+        let windows = removed.windows;
+        if let Some((base, size)) = windows.io {
+            self.free_carved(true, base, size);
+        }
+        for (base, size) in windows.mmio32.iter().chain(windows.mmio64.iter()) {
+            self.free_carved(false, *base, *size);
+        }
+        Ok(())
+    }
+
+    /// Returns the windows of the PCI segment `segment`, if it was added.
+    pub fn pci_segment_windows(&self, segment: u16) -> Option<PciSegmentWindows> {
+        self.pci_segments
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&segment)
+            .map(|s| s.windows)
+    }
+
+    /// Reserves `size` bytes from the `window` of the PCI segment `segment`.
+    /// MMIO ranges are aligned on `align` bytes, the size of a PCI BAR.
+    /// * `address` - A specific address trying to allocate, or None means no specific value.
+    pub fn allocate_pci_segment_addresses(
+        &mut self,
+        segment: u16,
+        window: PciWindow,
+        address: Option<GuestAddress>,
+        size: GuestUsize,
+        align: GuestUsize,
+    ) -> Result<GuestAddress> {
+        let mut segments = self.pci_segments.lock().expect("failed to acquire lock");
+        let s = segments
+            .get_mut(&segment)
+            .ok_or(Error::InvalidPciSegment(segment))?;
+        let aligned = |w: &mut Option<AddressAllocator>| {
+            w.as_mut()
+                .ok_or(Error::NoneAddress)?
+                .allocate_aligned(address, size, align)
+                .map_err(Error::AddressAllocate)
+        };
+        match window {
+            PciWindow::Io => {
+                s.io.as_mut()
+                    .ok_or(Error::NoneAddress)?
+                    .allocate(address, size)
+                    .map_err(Error::AddressAllocate)
+            }
+            PciWindow::Mmio32 => aligned(&mut s.mmio32),
+            PciWindow::Mmio64 => aligned(&mut s.mmio64).or_else(|_| aligned(&mut s.mmio32)),
+        }
+    }
+
+    /// Free a range from whichever window of the PCI segment `segment` it
+    /// belongs to.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_pci_segment_addresses(
+        &mut self,
+        segment: u16,
+        address: GuestAddress,
+        size: GuestUsize,
+    ) {
+        if let Some(s) = self
+            .pci_segments
+            .lock()
+            .expect("failed to acquire lock")
+            .get_mut(&segment)
+        {
+            for w in [&mut s.io, &mut s.mmio32, &mut s.mmio64].iter_mut() {
+                if let Some(w) = w.as_mut() {
+                    w.free(address, size);
+                }
+            }
+        }
+    }
+
+    /// Sets the range of GSIs that MSI and MSI-X vectors are routed through.
+    /// It is shared by all the clones of this `SystemAllocator`, and replaces
+    /// any range set before.
+    ///
+    /// * `first_gsi` - The first GSI to give out.
+    /// * `last_gsi` - The last GSI to give out.
+    pub fn set_msi_range(&mut self, first_gsi: u32, last_gsi: u32) -> Result<()> {
+        if first_gsi > last_gsi {
+            return Err(Error::IdAllocate(crate::id::Error::Overflow));
+        }
+        let gsi = IdAllocator::new(first_gsi, last_gsi)
+            .ok_or(Error::IdAllocate(crate::id::Error::Overflow))?;
+        *self.msi.lock().expect("failed to acquire lock") = Some(MsiPool {
+            gsi,
+            blocks: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    fn allocate_msi_block(&mut self, base: Option<u32>, size: u32, align: u32) -> Result<u32> {
+        let mut msi = self.msi.lock().expect("failed to acquire lock");
+        let pool = msi.as_mut().ok_or(Error::NoneMsiRange)?;
+        let first = pool
+            .gsi
+            .allocate_block(base, size, align)
+            .map_err(Error::IdAllocate)?;
+        pool.blocks.insert(first, size);
+        Ok(first)
+    }
+
+    /// Reserves a block of GSIs for `count` multi-message MSI vectors.
+    ///
+    /// Multi-message MSI requires a power of two number of vectors, so the
+    /// block holds `count` rounded up to a power of two GSIs and its first GSI
+    /// is aligned on the block size. Returns the first GSI of the block.
+    /// * `base` - A specific first GSI trying to allocate, or None means no specific value.
+    /// * `count` - The number of vectors, from 1 to 32.
+    pub fn allocate_msi(&mut self, base: Option<u32>, count: u32) -> Result<u32> {
+        if count == 0 || count > MAX_MSI_BLOCK {
+            return Err(Error::MsiCount(count));
+        }
+        let size = count.next_power_of_two();
+        self.allocate_msi_block(base, size, size)
+    }
+
+    /// Reserves a block of `count` contiguous GSIs for MSI-X vectors.
+    /// Returns the first GSI of the block.
+    /// * `base` - A specific first GSI trying to allocate, or None means no specific value.
+    /// * `count` - The number of vectors.
+    pub fn allocate_msix(&mut self, base: Option<u32>, count: u32) -> Result<u32> {
+        if count == 0 {
+            return Err(Error::MsiCount(count));
+        }
+        self.allocate_msi_block(base, count, 1)
+    }
+
+    /// Free a block of MSI or MSI-X GSIs.
+    /// Only free a block if `base` matches exactly the first GSI of an allocated block.
+    pub fn free_msi(&mut self, base: u32) {
+        if let Some(pool) = self.msi.lock().expect("failed to acquire lock").as_mut() {
+            if let Some(size) = pool.blocks.remove(&base) {
+                pool.gsi.free_block(base, size);
+            }
+        }
+    }
+
+    /// Returns the MSI and MSI-X blocks handed out as (first GSI, number of
+    /// GSIs) pairs, ordered by GSI.
+    pub fn msi_blocks(&self) -> Vec<(u32, u32)> {
+        self.msi
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map_or_else(Vec::new, |pool| {
+                pool.blocks.iter().map(|(b, s)| (*b, *s)).collect()
+            })
+    }
+
     /// Reserves the next available system irq number.
     /// * `irq` - A specific value trying to allocate, or None means no specific value.
     pub fn allocate_irq(&mut self, irq: Option<u32>) -> Result<u32> {
@@ -123,6 +584,42 @@ impl SystemAllocator {
             .map_err(Error::IdAllocate)
     }
 
+    /// Reserves a level-triggered irq line which can be shared with other devices.
+    ///
+    /// A line already shared is joined, while a line reserved through
+    /// `allocate_irq()` is refused. Without a specific value a free line is
+    /// preferred, falling back to the shared line with the fewest sharers
+    /// once all lines are taken.
+    /// * `irq` - A specific value trying to allocate, or None means no specific value.
+    pub fn allocate_shared_irq(&mut self, irq: Option<u32>) -> Result<u32> {
+        let mut ids = self.irq.lock().expect("failed to acquire lock");
+        let mut shared = self.shared_irqs.lock().expect("failed to acquire lock");
+        let line = match irq {
+            Some(i) if shared.contains_key(&i) => i,
+            Some(i) => ids.allocate(Some(i)).map_err(Error::IdAllocate)?,
+            None => match ids.allocate(None) {
+                Ok(i) => i,
+                Err(e) => shared
+                    .iter()
+                    .min_by_key(|(_, sharers)| **sharers)
+                    .map(|(i, _)| *i)
+                    .ok_or(Error::IdAllocate(e))?,
+            },
+        };
+        *shared.entry(line).or_insert(0) += 1;
+        Ok(line)
+    }
+
+    /// Returns the number of devices sharing `irq`, 0 if it isn't a shared line.
+    pub fn irq_sharers(&self, irq: u32) -> u32 {
+        self.shared_irqs
+            .lock()
+            .expect("failed to acquire lock")
+            .get(&irq)
+            .cloned()
+            .unwrap_or(0)
+    }
+
     /// Reserves the next available system device instance id number.
     pub fn allocate_instance_id(&mut self) -> Result<u32> {
         self.instance_id
@@ -134,11 +631,22 @@ impl SystemAllocator {
 
     /// Free an interrupt number.
     /// Only free an `irq` if it matches exactly an already allocated one.
+    /// A shared line is only released once its last sharer frees it.
     pub fn free_irq(&mut self, irq: Option<u32>) {
-        match irq {
-            Some(i) => self.irq.lock().expect("failed to acquire lock").free(i),
+        let i = match irq {
+            Some(i) => i,
             None => return,
+        };
+        let mut ids = self.irq.lock().expect("failed to acquire lock");
+        let mut shared = self.shared_irqs.lock().expect("failed to acquire lock");
+        if let Some(sharers) = shared.get_mut(&i) {
+            *sharers -= 1;
+            if *sharers > 0 {
+                return;
+            }
+            shared.remove(&i);
         }
+        ids.free(i);
     }
 
     /// Free an instance id.
@@ -183,6 +691,40 @@ impl SystemAllocator {
             .map_err(Error::AddressAllocate)
     }
 
+    /// Returns the base address and the size of the IO address space, if any.
+    pub fn io_window(&self) -> Option<(GuestAddress, GuestUsize)> {
+        self.io_address_space
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map(AddressAllocator::window)
+    }
+
+    /// Returns the free holes of the IO address space.
+    pub fn io_free_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        self.io_address_space
+            .lock()
+            .expect("failed to acquire lock")
+            .as_ref()
+            .map_or_else(Vec::new, AddressAllocator::free_ranges)
+    }
+
+    /// Returns the base address and the size of the MMIO address space.
+    pub fn mmio_window(&self) -> (GuestAddress, GuestUsize) {
+        self.mmio_address_space
+            .lock()
+            .expect("failed to acquire lock")
+            .window()
+    }
+
+    /// Returns the free holes of the MMIO address space.
+    pub fn mmio_free_ranges(&self) -> Vec<(GuestAddress, GuestUsize)> {
+        self.mmio_address_space
+            .lock()
+            .expect("failed to acquire lock")
+            .free_ranges()
+    }
+
     /// Free an IO address range.
     /// We can only free a range if it matches exactly an already allocated range.
     pub fn free_io_addresses(&mut self, address: GuestAddress, size: GuestUsize) {